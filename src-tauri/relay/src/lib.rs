@@ -0,0 +1,167 @@
+// Relay for Topaz peers that can't accept connections. A host waits in a room named
+// after its network's relay secret, clients join the room, and the relay pairs each
+// client with a connection the host opens back for it. Frames are encrypted end to end
+// by the peers, the relay only passes them along
+pub mod rendezvous;
+
+use axum::{
+    extract::{
+        ws::{Message, WebSocket, WebSocketUpgrade},
+        Path, State,
+    },
+    http::StatusCode,
+    response::{IntoResponse, Response},
+    routing::get,
+    Router,
+};
+use futures_util::stream::{SplitSink, SplitStream};
+use futures_util::{SinkExt, StreamExt};
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::sync::{mpsc, oneshot};
+
+// Clients whose host doesn't connect back within this time are dropped
+const HOST_ACCEPT_TIMEOUT: Duration = Duration::from_secs(10);
+// Keeps idle host connections from being closed by proxies and NATs on the way
+const HOST_PING_INTERVAL: Duration = Duration::from_secs(30);
+
+#[derive(Default)]
+struct Rooms {
+    // Control connection of the host waiting in each room, told the ID of every
+    // client that joins
+    hosts: HashMap<String, mpsc::UnboundedSender<String>>,
+    // Clients waiting for their host to connect back, by room and connection ID
+    pending: HashMap<(String, String), oneshot::Sender<WebSocket>>,
+}
+
+type SharedRooms = Arc<Mutex<Rooms>>;
+
+async fn host(ws: WebSocketUpgrade, Path(room): Path<String>, State(rooms): State<SharedRooms>) -> Response {
+    if rooms.lock().unwrap().hosts.contains_key(&room) {
+        return (StatusCode::CONFLICT, "A host is already waiting in this room").into_response();
+    }
+    ws.on_upgrade(move |socket| run_host(socket, room, rooms))
+}
+
+async fn run_host(mut socket: WebSocket, room: String, rooms: SharedRooms) {
+    let (tx, mut rx) = mpsc::unbounded_channel();
+    {
+        let mut rooms = rooms.lock().unwrap();
+        if rooms.hosts.contains_key(&room) {
+            return;
+        }
+        rooms.hosts.insert(room.clone(), tx);
+    }
+    println!("Host joined room {}", room);
+
+    let mut ping = tokio::time::interval(HOST_PING_INTERVAL);
+    loop {
+        tokio::select! {
+            Some(connection_id) = rx.recv() => {
+                if socket.send(Message::Text(connection_id.into())).await.is_err() {
+                    break;
+                }
+            }
+            _ = ping.tick() => {
+                if socket.send(Message::Ping(Default::default())).await.is_err() {
+                    break;
+                }
+            }
+            message = socket.recv() => match message {
+                Some(Ok(Message::Close(_))) | Some(Err(_)) | None => break,
+                Some(Ok(_)) => {}
+            },
+        }
+    }
+
+    rooms.lock().unwrap().hosts.remove(&room);
+    println!("Host left room {}", room);
+}
+
+// Random 128-bit ID in hex. Only the host of the room is told it, so nobody else can
+// guess it and take the client's place on the accepting side
+fn new_connection_id() -> String {
+    let mut id = [0u8; 16];
+    getrandom::getrandom(&mut id).expect("no system random number generator");
+    id.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+async fn connect(ws: WebSocketUpgrade, Path(room): Path<String>, State(rooms): State<SharedRooms>) -> Response {
+    let (connection_id, host) = {
+        let rooms = rooms.lock().unwrap();
+        let Some(host) = rooms.hosts.get(&room).cloned() else {
+            return (StatusCode::NOT_FOUND, "No host is waiting in this room").into_response();
+        };
+        (new_connection_id(), host)
+    };
+    ws.on_upgrade(move |socket| run_client(socket, room, connection_id, host, rooms))
+}
+
+async fn run_client(
+    socket: WebSocket,
+    room: String,
+    connection_id: String,
+    host: mpsc::UnboundedSender<String>,
+    rooms: SharedRooms,
+) {
+    let (tx, rx) = oneshot::channel();
+    let key = (room.clone(), connection_id.clone());
+    rooms.lock().unwrap().pending.insert(key.clone(), tx);
+    let host_socket = match host.send(connection_id.clone()) {
+        Ok(()) => tokio::time::timeout(HOST_ACCEPT_TIMEOUT, rx)
+            .await
+            .ok()
+            .and_then(Result::ok),
+        Err(_) => None,
+    };
+    rooms.lock().unwrap().pending.remove(&key);
+
+    match host_socket {
+        Some(host_socket) => splice(socket, host_socket).await,
+        None => eprintln!("Host in room {} did not accept connection {}", room, connection_id),
+    }
+}
+
+async fn accept(
+    ws: WebSocketUpgrade,
+    Path((room, connection_id)): Path<(String, String)>,
+    State(rooms): State<SharedRooms>,
+) -> Response {
+    let Some(client) = rooms.lock().unwrap().pending.remove(&(room, connection_id)) else {
+        return (StatusCode::NOT_FOUND, "No client is waiting for this connection").into_response();
+    };
+    ws.on_upgrade(move |socket| async move {
+        let _ = client.send(socket);
+    })
+}
+
+async fn forward(mut from: SplitStream<WebSocket>, mut to: SplitSink<WebSocket, Message>) {
+    while let Some(Ok(message)) = from.next().await {
+        match message {
+            Message::Binary(_) if to.send(message).await.is_err() => break,
+            Message::Close(_) => break,
+            _ => {}
+        }
+    }
+    let _ = to.close().await;
+}
+
+// Pass frames both ways until either side goes away
+async fn splice(client: WebSocket, host: WebSocket) {
+    let (client_tx, client_rx) = client.split();
+    let (host_tx, host_rx) = host.split();
+    tokio::select! {
+        _ = forward(client_rx, host_tx) => {}
+        _ = forward(host_rx, client_tx) => {}
+    }
+}
+
+// Routes of the relay, for hosts waiting in rooms and the clients joining them
+pub fn router() -> Router {
+    Router::new()
+        .route("/host/{room}", get(host))
+        .route("/connect/{room}", get(connect))
+        .route("/accept/{room}/{connection_id}", get(accept))
+        .with_state(SharedRooms::default())
+}
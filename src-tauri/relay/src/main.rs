@@ -0,0 +1,30 @@
+// Relay server. See the library for how hosts and clients are paired
+const DEFAULT_LISTEN_ADDR: &str = "0.0.0.0:8081";
+
+#[tokio::main]
+async fn main() {
+    let listen_addr = std::env::args()
+        .nth(1)
+        .unwrap_or_else(|| DEFAULT_LISTEN_ADDR.to_string());
+    let app = topaz_relay::router();
+
+    let listener = match tokio::net::TcpListener::bind(&listen_addr).await {
+        Ok(listener) => listener,
+        Err(e) => {
+            eprintln!("Failed to listen on {}: {}", listen_addr, e);
+            std::process::exit(1);
+        }
+    };
+    println!("Relay listening on {}", listen_addr);
+    // Peers that can reach each other directly meet on the same address, over UDP
+    if let Ok(addr) = listener.local_addr() {
+        tokio::spawn(async move {
+            if let Err(e) = topaz_relay::rendezvous::run_rendezvous(addr).await {
+                eprintln!("Rendezvous service failed: {}", e);
+            }
+        });
+    }
+    if let Err(e) = axum::serve(listener, app).await {
+        eprintln!("Relay failed: {}", e);
+    }
+}
@@ -0,0 +1,124 @@
+// Rendezvous service for peers punching direct QUIC connections through their NATs. A host
+// waits in its network's room and learns the address of every client that asks for it;
+// the client learns the host's. Both then send to each other, opening their NATs, and
+// the rest happens without the relay
+use quinn::rustls::pki_types::PrivatePkcs8KeyDer;
+use quinn::{Connection, Endpoint, SendStream, ServerConfig};
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::io::{AsyncBufReadExt, BufReader};
+use tokio::sync::mpsc;
+
+// How long a peer has to read its last answer before the connection is closed
+const CLOSE_LINGER: Duration = Duration::from_secs(5);
+
+#[derive(Default)]
+struct Hosts {
+    // Address each waiting host was seen at, and where to announce clients to it
+    rooms: HashMap<String, (SocketAddr, mpsc::UnboundedSender<SocketAddr>)>,
+}
+
+type SharedHosts = Arc<Mutex<Hosts>>;
+
+async fn reply(send: &mut SendStream, line: String) -> bool {
+    send.write_all(format!("{}\n", line).as_bytes()).await.is_ok()
+}
+
+async fn run_host(connection: &Connection, send: &mut SendStream, room: String, hosts: SharedHosts) {
+    let observed = connection.remote_address();
+    let (tx, mut rx) = mpsc::unbounded_channel();
+    let taken = {
+        let mut hosts = hosts.lock().unwrap();
+        let taken = hosts.rooms.contains_key(&room);
+        if !taken {
+            hosts.rooms.insert(room.clone(), (observed, tx.clone()));
+        }
+        taken
+    };
+    if taken {
+        let _ = reply(send, "error a host is already waiting in this room".to_string()).await;
+        return;
+    }
+    println!("Host at {} waiting for direct connections in room {}", observed, room);
+
+    if reply(send, format!("ok {}", observed)).await {
+        loop {
+            tokio::select! {
+                Some(client) = rx.recv() => {
+                    if !reply(send, format!("client {}", client)).await {
+                        break;
+                    }
+                }
+                _ = connection.closed() => break,
+            }
+        }
+    }
+
+    // A host that reconnected in the meantime keeps the room
+    let mut hosts = hosts.lock().unwrap();
+    if hosts.rooms.get(&room).is_some_and(|(_, waiting)| waiting.same_channel(&tx)) {
+        hosts.rooms.remove(&room);
+        println!("Host at {} left room {}", observed, room);
+    }
+}
+
+async fn run_client(connection: &Connection, send: &mut SendStream, room: String, hosts: SharedHosts) {
+    let host = hosts.lock().unwrap().rooms.get(&room).cloned();
+    let Some((host_addr, host)) = host else {
+        let _ = reply(send, "error no host is waiting in this room".to_string()).await;
+        return;
+    };
+    let client = connection.remote_address();
+    if host.send(client).is_err() {
+        let _ = reply(send, "error the host went away".to_string()).await;
+        return;
+    }
+    println!("Introduced client at {} to host at {}", client, host_addr);
+    let _ = reply(send, format!("host {}", host_addr)).await;
+}
+
+async fn handle(connection: Connection, hosts: SharedHosts) {
+    let Ok((mut send, recv)) = connection.accept_bi().await else {
+        return;
+    };
+    let mut lines = BufReader::new(recv).lines();
+    let Ok(Some(request)) = lines.next_line().await else {
+        return;
+    };
+    match request.split_once(' ') {
+        Some(("host", room)) => run_host(&connection, &mut send, room.to_string(), hosts).await,
+        Some(("connect", room)) => run_client(&connection, &mut send, room.to_string(), hosts).await,
+        _ => {
+            let _ = reply(&mut send, format!("error unknown request '{}'", request)).await;
+        }
+    }
+    // Give the peer time to read the answer before closing on it
+    let _ = send.finish();
+    let _ = tokio::time::timeout(CLOSE_LINGER, connection.closed()).await;
+    connection.close(0u32.into(), b"");
+}
+
+fn server_config() -> Result<ServerConfig, String> {
+    let certified = rcgen::generate_simple_self_signed(vec!["topaz".to_string()]).map_err(|e| e.to_string())?;
+    let key = PrivatePkcs8KeyDer::from(certified.key_pair.serialize_der());
+    ServerConfig::with_single_cert(vec![certified.cert.der().clone()], key.into()).map_err(|e| e.to_string())
+}
+
+// Serve rendezvous requests over UDP on the relay's address
+pub async fn run_rendezvous(listen_addr: SocketAddr) -> Result<(), String> {
+    let endpoint = Endpoint::server(server_config()?, listen_addr).map_err(|e| e.to_string())?;
+    println!("Rendezvous service listening on {} (UDP)", listen_addr);
+    let hosts = SharedHosts::default();
+    while let Some(incoming) = endpoint.accept().await {
+        let hosts = hosts.clone();
+        tokio::spawn(async move {
+            match incoming.await {
+                Ok(connection) => handle(connection, hosts).await,
+                Err(e) => eprintln!("Rendezvous connection failed: {}", e),
+            }
+        });
+    }
+    Ok(())
+}
@@ -0,0 +1,345 @@
+use crate::server_guard::PeerConnection;
+use crate::types::{AccessLogEntry, AccessLogFilter};
+use crate::url_path::{decode_path_segment, encode_path_segment, is_safe_file_name};
+use axum::body::Body;
+use axum::extract::{ConnectInfo, Request};
+use axum::middleware::Next;
+use axum::response::Response;
+use futures_util::StreamExt;
+use std::collections::hash_map::Entry;
+use std::collections::HashMap;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, BufRead, BufReader, Write};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::{Instant, SystemTime, UNIX_EPOCH};
+use tokio::sync::broadcast;
+
+pub const ACCESS_LOG_DIR_PATH: &str = "../configs/access_logs";
+// Header clients send their device ID in, so hosts can tell who fetched what
+pub const DEVICE_ID_HEADER: &str = "x-topaz-device";
+// Logs are rotated once they grow past this size, keeping this many old files
+const MAX_LOG_FILE_BYTES: u64 = 10 * 1024 * 1024;
+const MAX_ROTATED_FILES: usize = 5;
+// Entries `get_access_log` returns without a limit
+const DEFAULT_ENTRY_LIMIT: usize = 1000;
+
+lazy_static::lazy_static! {
+    // Finished requests to served networks, written to the access logs
+    static ref ACCESS_EVENTS: broadcast::Sender<AccessLogEntry> = broadcast::channel(1024).0;
+}
+
+pub fn subscribe_access_events() -> broadcast::Receiver<AccessLogEntry> {
+    ACCESS_EVENTS.subscribe()
+}
+
+// Directory of a network's logs below `root`. Names that aren't safe as a file name are
+// hex-encoded
+fn log_dir(root: &Path, network_name: &str) -> PathBuf {
+    let encoded = encode_path_segment(network_name);
+    let dir_name = if is_safe_file_name(&encoded) {
+        encoded
+    } else {
+        hex::encode(network_name)
+    };
+    root.join(dir_name)
+}
+
+// `access.log` is written to, `access.1.log` is the newest rotated file
+fn log_file_path(dir: &Path, rotation: usize) -> PathBuf {
+    match rotation {
+        0 => dir.join("access.log"),
+        rotation => dir.join(format!("access.{}.log", rotation)),
+    }
+}
+
+struct LogFile {
+    file: File,
+    size: u64,
+}
+
+fn open_log_file(dir: &Path) -> io::Result<LogFile> {
+    fs::create_dir_all(dir)?;
+    let file = OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(log_file_path(dir, 0))?;
+    let size = file.metadata()?.len();
+    Ok(LogFile { file, size })
+}
+
+fn rotate_log_files(dir: &Path) -> io::Result<()> {
+    for rotation in (0..MAX_ROTATED_FILES).rev() {
+        let from = log_file_path(dir, rotation);
+        if from.exists() {
+            fs::rename(&from, log_file_path(dir, rotation + 1))?;
+        }
+    }
+    Ok(())
+}
+
+fn append_entry(
+    root: &Path,
+    log_files: &mut HashMap<String, LogFile>,
+    entry: &AccessLogEntry,
+    max_file_bytes: u64,
+) -> io::Result<()> {
+    let mut line = serde_json::to_string(entry)?;
+    line.push('\n');
+    let dir = log_dir(root, &entry.network_name);
+    if log_files
+        .get(&entry.network_name)
+        .is_some_and(|log_file| log_file.size + line.len() as u64 > max_file_bytes)
+    {
+        log_files.remove(&entry.network_name);
+        rotate_log_files(&dir)?;
+    }
+    let log_file = match log_files.entry(entry.network_name.clone()) {
+        Entry::Occupied(log_file) => log_file.into_mut(),
+        Entry::Vacant(vacant) => vacant.insert(open_log_file(&dir)?),
+    };
+    log_file.file.write_all(line.as_bytes())?;
+    log_file.size += line.len() as u64;
+    Ok(())
+}
+
+// Write every finished request to the access log of its network. Writing and rotating
+// the files blocks, so the loop runs on a blocking thread
+pub async fn write_access_logs() {
+    let mut access_events = subscribe_access_events();
+    let written = tokio::task::spawn_blocking(move || {
+        let mut log_files = HashMap::new();
+        loop {
+            match access_events.blocking_recv() {
+                Ok(entry) => {
+                    let root = Path::new(ACCESS_LOG_DIR_PATH);
+                    if let Err(e) = append_entry(root, &mut log_files, &entry, MAX_LOG_FILE_BYTES) {
+                        eprintln!("Failed to write access log of {}: {}", entry.network_name, e);
+                    }
+                }
+                Err(broadcast::error::RecvError::Lagged(missed)) => {
+                    eprintln!("Access logs are missing {} requests", missed);
+                }
+                Err(broadcast::error::RecvError::Closed) => break,
+            }
+        }
+    })
+    .await;
+    if let Err(e) = written {
+        eprintln!("Access log writer stopped: {}", e);
+    }
+}
+
+// Completed into an entry when the response has been sent, or the peer went away
+struct PendingEntry {
+    entry: AccessLogEntry,
+    started: Instant,
+    bytes_received: Arc<AtomicU64>,
+}
+
+impl PendingEntry {
+    fn count_sent(&mut self, bytes: usize) {
+        self.entry.bytes_sent += bytes as u64;
+    }
+}
+
+impl Drop for PendingEntry {
+    fn drop(&mut self) {
+        self.entry.bytes_received = self.bytes_received.load(Ordering::Relaxed);
+        self.entry.duration_ms = self.started.elapsed().as_millis() as u64;
+        // Nobody listening is fine, the log writer may not have started yet
+        let _ = ACCESS_EVENTS.send(self.entry.clone());
+    }
+}
+
+// Record who requested what from a network, how much went each way and how it ended
+pub async fn log_access(
+    axum::extract::State(network_name): axum::extract::State<String>,
+    request: Request,
+    next: Next,
+) -> Response {
+    let started = Instant::now();
+    let timestamp = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|elapsed| elapsed.as_secs())
+        .unwrap_or_default();
+    let peer = request
+        .extensions()
+        .get::<ConnectInfo<PeerConnection>>()
+        .map(|ConnectInfo(connection)| connection.peer.clone())
+        .unwrap_or_default();
+    let claimed_device_id = request
+        .headers()
+        .get(DEVICE_ID_HEADER)
+        .and_then(|value| value.to_str().ok())
+        .map(str::to_string);
+    let method = request.method().to_string();
+    let path = decode_path_segment(request.uri().path()).unwrap_or_else(|| request.uri().path().to_string());
+
+    let bytes_received = Arc::new(AtomicU64::new(0));
+    let received = bytes_received.clone();
+    let request = request.map(|body| {
+        Body::from_stream(body.into_data_stream().map(move |data| {
+            if let Ok(data) = &data {
+                received.fetch_add(data.len() as u64, Ordering::Relaxed);
+            }
+            data
+        }))
+    });
+
+    let response = next.run(request).await;
+    let mut pending = PendingEntry {
+        entry: AccessLogEntry {
+            timestamp,
+            network_name,
+            peer,
+            claimed_device_id,
+            method,
+            path,
+            status: response.status().as_u16(),
+            bytes_sent: 0,
+            bytes_received: 0,
+            duration_ms: 0,
+        },
+        started,
+        bytes_received,
+    };
+    response.map(|body| {
+        Body::from_stream(body.into_data_stream().map(move |data| {
+            if let Ok(data) = &data {
+                pending.count_sent(data.len());
+            }
+            data
+        }))
+    })
+}
+
+fn matches(entry: &AccessLogEntry, filter: &AccessLogFilter) -> bool {
+    filter
+        .peer
+        .as_ref()
+        .is_none_or(|peer| entry.peer == *peer || entry.claimed_device_id.as_ref() == Some(peer))
+        && filter.path.as_ref().is_none_or(|path| entry.path.contains(path.as_str()))
+        && filter.status.is_none_or(|status| entry.status == status)
+        && filter.since.is_none_or(|since| entry.timestamp >= since)
+        && filter.until.is_none_or(|until| entry.timestamp <= until)
+}
+
+// Entries of the logs in `dir`, newest first, across its rotated files. Blocking
+fn read_entries(dir: &Path, filter: &AccessLogFilter) -> io::Result<Vec<AccessLogEntry>> {
+    let limit = filter.limit.unwrap_or(DEFAULT_ENTRY_LIMIT);
+    let mut entries = Vec::new();
+    for rotation in 0..=MAX_ROTATED_FILES {
+        let file = match File::open(log_file_path(dir, rotation)) {
+            Ok(file) => file,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => break,
+            Err(e) => return Err(e),
+        };
+        let mut file_entries: Vec<AccessLogEntry> = BufReader::new(file)
+            .lines()
+            .map_while(Result::ok)
+            .filter_map(|line| serde_json::from_str(&line).ok())
+            .filter(|entry| matches(entry, filter))
+            .collect();
+        file_entries.reverse();
+        entries.extend(file_entries);
+        if entries.len() >= limit {
+            break;
+        }
+    }
+    entries.truncate(limit);
+    Ok(entries)
+}
+
+// Entries of a network's access log, newest first, across its rotated files
+#[tauri::command]
+pub async fn get_access_log(network_name: String, filter: Option<AccessLogFilter>) -> Result<Vec<AccessLogEntry>, String> {
+    let filter = filter.unwrap_or_default();
+    let dir = log_dir(Path::new(ACCESS_LOG_DIR_PATH), &network_name);
+    tokio::task::spawn_blocking(move || read_entries(&dir, &filter))
+        .await
+        .map_err(|e| e.to_string())?
+        .map_err(|e| e.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn entry(network_name: &str, timestamp: u64, peer: &str, path: &str, status: u16) -> AccessLogEntry {
+        AccessLogEntry {
+            timestamp,
+            network_name: network_name.to_string(),
+            peer: peer.to_string(),
+            claimed_device_id: Some(format!("device-{}", peer)),
+            method: "GET".to_string(),
+            path: path.to_string(),
+            status,
+            bytes_sent: 0,
+            bytes_received: 0,
+            duration_ms: 0,
+        }
+    }
+
+    #[test]
+    fn logs_rotate_and_read_back_newest_first() {
+        let root = std::env::temp_dir().join(format!("topaz-access-{}", uuid::Uuid::new_v4()));
+        let mut log_files = HashMap::new();
+        // Small enough for every file to hold two entries, at most two-digit timestamps
+        let line_len = serde_json::to_string(&entry("net", 10, "peer", "/a", 200)).unwrap().len() as u64 + 1;
+        let written = 2 * (MAX_ROTATED_FILES + 2);
+        for timestamp in 0..written as u64 {
+            let entry = entry("net", timestamp, "peer", "/a", 200);
+            append_entry(&root, &mut log_files, &entry, 2 * line_len).unwrap();
+        }
+        let dir = log_dir(&root, "net");
+        assert!(log_file_path(&dir, MAX_ROTATED_FILES).exists());
+        assert!(!log_file_path(&dir, MAX_ROTATED_FILES + 1).exists());
+
+        // The oldest file rotated out, the rest reads back newest first
+        let timestamps: Vec<u64> = read_entries(&dir, &AccessLogFilter::default())
+            .unwrap()
+            .iter()
+            .map(|entry| entry.timestamp)
+            .collect();
+        let kept = 2 * (MAX_ROTATED_FILES + 1);
+        assert_eq!(timestamps, (written - kept..written).rev().map(|timestamp| timestamp as u64).collect::<Vec<_>>());
+
+        let limited = AccessLogFilter { limit: Some(3), ..Default::default() };
+        assert_eq!(read_entries(&dir, &limited).unwrap().len(), 3);
+        fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[test]
+    fn filters_match_every_set_field() {
+        let entry = entry("net", 100, "10.0.0.2", "/files/docs/a.txt", 404);
+        assert!(matches(&entry, &AccessLogFilter::default()));
+        let matching = [
+            AccessLogFilter { peer: Some("10.0.0.2".to_string()), ..Default::default() },
+            AccessLogFilter { peer: Some("device-10.0.0.2".to_string()), ..Default::default() },
+            AccessLogFilter { path: Some("docs/".to_string()), status: Some(404), ..Default::default() },
+            AccessLogFilter { since: Some(100), until: Some(100), ..Default::default() },
+        ];
+        for filter in &matching {
+            assert!(matches(&entry, filter));
+        }
+        let failing = [
+            AccessLogFilter { peer: Some("10.0.0.3".to_string()), ..Default::default() },
+            AccessLogFilter { path: Some("photos".to_string()), ..Default::default() },
+            AccessLogFilter { path: Some("docs".to_string()), status: Some(200), ..Default::default() },
+            AccessLogFilter { since: Some(101), ..Default::default() },
+            AccessLogFilter { until: Some(99), ..Default::default() },
+        ];
+        for filter in &failing {
+            assert!(!matches(&entry, filter));
+        }
+    }
+
+    #[test]
+    fn entries_written_before_the_rename_keep_their_device() {
+        let line = r#"{"timestamp":1,"network_name":"net","peer":"p","device_id":"d","method":"GET","path":"/","status":200,"bytes_sent":0,"bytes_received":0,"duration_ms":0}"#;
+        let entry: AccessLogEntry = serde_json::from_str(line).unwrap();
+        assert_eq!(entry.claimed_device_id.as_deref(), Some("d"));
+    }
+}
@@ -0,0 +1,320 @@
+use crate::local_dir::read_private_config;
+use crate::types::{BandwidthLimits, ScheduledLimits};
+use axum::body::Bytes;
+use chrono::{DateTime, Datelike, Duration as ChronoDuration, Local, NaiveTime};
+use futures_util::{Stream, StreamExt};
+use std::borrow::BorrowMut;
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex as StdMutex};
+use std::time::{Duration, Instant};
+
+// How long limits read from the private config are used before it is read again
+const CONFIG_CACHE_TTL: Duration = Duration::from_secs(5);
+// How often a running transfer looks up its limits again, so schedules take effect
+// without waiting for it to finish
+const RATE_REFRESH_INTERVAL: Duration = Duration::from_secs(5);
+// Seconds worth of bytes a transfer may send at once after being idle
+const BURST_SECONDS: f64 = 1.0;
+// Past this many limiters, those no transfer uses anymore are dropped
+const MAX_IDLE_LIMITERS: usize = 1024;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Direction {
+    Upload,
+    Download,
+}
+
+struct ConfiguredLimits {
+    global: BandwidthLimits,
+    networks: HashMap<String, BandwidthLimits>,
+}
+
+lazy_static::lazy_static! {
+    static ref CONFIGURED_LIMITS: StdMutex<Option<(Instant, Arc<ConfiguredLimits>)>> = StdMutex::new(None);
+    // Limiters by what they limit, shared by every transfer the limit applies to
+    static ref LIMITERS: StdMutex<HashMap<String, Arc<RateLimiter>>> = StdMutex::new(HashMap::new());
+}
+
+fn read_configured_limits() -> ConfiguredLimits {
+    let config = read_private_config().ok();
+    let global = config
+        .as_ref()
+        .and_then(|config| config.get("bandwidth_limits"))
+        .and_then(|limits| serde_json::from_value(limits.clone()).ok())
+        .unwrap_or_default();
+    let networks = config
+        .as_ref()
+        .and_then(|config| config.get("networks"))
+        .and_then(|networks| networks.as_array())
+        .into_iter()
+        .flatten()
+        .filter_map(|network| {
+            let name = network.get("name")?.as_str()?.to_string();
+            let limits = serde_json::from_value(network.get("bandwidth_limits")?.clone()).ok()?;
+            Some((name, limits))
+        })
+        .collect();
+    ConfiguredLimits { global, networks }
+}
+
+fn configured_limits() -> Arc<ConfiguredLimits> {
+    let mut cached = CONFIGURED_LIMITS.lock().unwrap();
+    match &*cached {
+        Some((read_at, limits)) if read_at.elapsed() < CONFIG_CACHE_TTL => limits.clone(),
+        _ => {
+            let limits = Arc::new(read_configured_limits());
+            *cached = Some((Instant::now(), limits.clone()));
+            limits
+        }
+    }
+}
+
+// Read the limits from the private config again the next time they are needed
+pub fn invalidate_configured_limits() {
+    *CONFIGURED_LIMITS.lock().unwrap() = None;
+}
+
+fn in_window(window: &ScheduledLimits, now: &DateTime<Local>) -> bool {
+    let (Ok(start), Ok(end)) = (
+        NaiveTime::parse_from_str(&window.start, "%H:%M"),
+        NaiveTime::parse_from_str(&window.end, "%H:%M"),
+    ) else {
+        eprintln!("Invalid bandwidth schedule window {}-{}", window.start, window.end);
+        return false;
+    };
+    let time = now.time();
+    // After midnight, a window running past it started the day before
+    let started = if start <= end {
+        (start <= time && time < end).then_some(*now)
+    } else if time >= start {
+        Some(*now)
+    } else {
+        (time < end).then(|| *now - ChronoDuration::days(1))
+    };
+    started.is_some_and(|started| {
+        window.days.is_empty() || window.days.contains(&(started.weekday().num_days_from_monday() as u8))
+    })
+}
+
+// Total and per-peer rates that apply in `direction` right now
+fn active_rates(limits: &BandwidthLimits, direction: Direction, now: &DateTime<Local>) -> (Option<u64>, Option<u64>) {
+    let (upload, download, upload_per_peer, download_per_peer) =
+        match limits.schedule.iter().find(|window| in_window(window, now)) {
+            Some(window) => (window.upload, window.download, window.upload_per_peer, window.download_per_peer),
+            None => (limits.upload, limits.download, limits.upload_per_peer, limits.download_per_peer),
+        };
+    match direction {
+        Direction::Upload => (upload, upload_per_peer),
+        Direction::Download => (download, download_per_peer),
+    }
+}
+
+struct Bucket {
+    rate: Option<u64>,
+    available: f64,
+    updated: Instant,
+}
+
+// Token bucket. Transfers take what they send out of it and wait for it to refill
+pub struct RateLimiter {
+    bucket: StdMutex<Bucket>,
+}
+
+impl RateLimiter {
+    fn new() -> Self {
+        RateLimiter {
+            bucket: StdMutex::new(Bucket {
+                rate: None,
+                available: 0.0,
+                updated: Instant::now(),
+            }),
+        }
+    }
+
+    fn set_rate(&self, rate: Option<u64>) {
+        let mut bucket = self.bucket.lock().unwrap();
+        if bucket.rate != rate {
+            bucket.rate = rate;
+            bucket.available = rate.unwrap_or(0) as f64 * BURST_SECONDS;
+            bucket.updated = Instant::now();
+        }
+    }
+
+    // Take `bytes` out of the bucket and return how long to wait until they may be sent
+    fn reserve(&self, bytes: usize) -> Duration {
+        let mut bucket = self.bucket.lock().unwrap();
+        let Some(rate) = bucket.rate else {
+            return Duration::ZERO;
+        };
+        let rate = rate.max(1) as f64;
+        let now = Instant::now();
+        let refilled = now.duration_since(bucket.updated).as_secs_f64() * rate;
+        bucket.available = (bucket.available + refilled).min(rate * BURST_SECONDS);
+        bucket.updated = now;
+        bucket.available -= bytes as f64;
+        if bucket.available >= 0.0 {
+            Duration::ZERO
+        } else {
+            Duration::from_secs_f64(-bucket.available / rate)
+        }
+    }
+}
+
+fn limiter(key: String) -> Arc<RateLimiter> {
+    let mut limiters = LIMITERS.lock().unwrap();
+    if !limiters.contains_key(&key) && limiters.len() >= MAX_IDLE_LIMITERS {
+        limiters.retain(|_, limiter| Arc::strong_count(limiter) > 1);
+    }
+    limiters.entry(key).or_insert_with(|| Arc::new(RateLimiter::new())).clone()
+}
+
+// Paces one transfer by every limit that applies to it: the device-wide ones, those of
+// its network and those for the peer on the other end
+pub struct Throttle {
+    direction: Direction,
+    network_name: Option<String>,
+    peer: Option<String>,
+    limiters: Vec<Arc<RateLimiter>>,
+    refreshed: Option<Instant>,
+}
+
+impl Throttle {
+    pub fn new(direction: Direction, network_name: Option<&str>, peer: Option<&str>) -> Self {
+        Throttle {
+            direction,
+            network_name: network_name.map(str::to_string),
+            peer: peer.map(str::to_string),
+            limiters: Vec::new(),
+            refreshed: None,
+        }
+    }
+
+    fn refresh(&mut self) {
+        let configured = configured_limits();
+        let now = Local::now();
+        let direction = self.direction;
+        let mut scopes = vec![(format!("{:?}", direction), &configured.global)];
+        if let Some(name) = &self.network_name {
+            if let Some(limits) = configured.networks.get(name) {
+                scopes.push((format!("{:?}/network/{}", direction, name), limits));
+            }
+        }
+
+        self.limiters.clear();
+        for (scope, limits) in scopes {
+            let (total, per_peer) = active_rates(limits, direction, &now);
+            let mut rates = vec![(scope.clone(), total)];
+            if let Some(peer) = &self.peer {
+                rates.push((format!("{}/peer/{}", scope, peer), per_peer));
+            }
+            for (key, rate) in rates {
+                if rate.is_some() {
+                    let limiter = limiter(key);
+                    limiter.set_rate(rate);
+                    self.limiters.push(limiter);
+                }
+            }
+        }
+        self.refreshed = Some(Instant::now());
+    }
+
+    // Wait until `bytes` more may be transferred
+    pub async fn wait(&mut self, bytes: usize) {
+        if self.refreshed.is_none_or(|refreshed| refreshed.elapsed() >= RATE_REFRESH_INTERVAL) {
+            self.refresh();
+        }
+        let delay = self
+            .limiters
+            .iter()
+            .map(|limiter| limiter.reserve(bytes))
+            .max()
+            .unwrap_or_default();
+        if !delay.is_zero() {
+            tokio::time::sleep(delay).await;
+        }
+    }
+}
+
+// Pass a body stream on no faster than `throttle` allows
+pub fn throttle_stream<S, E, T>(stream: S, throttle: T) -> impl Stream<Item = Result<Bytes, E>>
+where
+    S: Stream<Item = Result<Bytes, E>>,
+    T: BorrowMut<Throttle>,
+{
+    futures_util::stream::unfold((Box::pin(stream), throttle), |(mut stream, mut throttle)| async move {
+        let item = stream.next().await?;
+        if let Ok(data) = &item {
+            throttle.borrow_mut().wait(data.len()).await;
+        }
+        Some((item, (stream, throttle)))
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::TimeZone;
+
+    fn at(day: u32, hour: u32, minute: u32) -> DateTime<Local> {
+        // 2026-10-16 is a Friday
+        Local.with_ymd_and_hms(2026, 10, day, hour, minute, 0).unwrap()
+    }
+
+    fn window(start: &str, end: &str, days: Vec<u8>) -> ScheduledLimits {
+        ScheduledLimits {
+            start: start.to_string(),
+            end: end.to_string(),
+            days,
+            upload: None,
+            download: Some(100),
+            upload_per_peer: None,
+            download_per_peer: None,
+        }
+    }
+
+    #[test]
+    fn windows_past_midnight_belong_to_the_day_they_start() {
+        // Friday nights only
+        let night = window("23:00", "06:00", vec![4]);
+        assert!(in_window(&night, &at(16, 23, 30)));
+        assert!(in_window(&night, &at(17, 2, 0)));
+        assert!(!in_window(&night, &at(17, 6, 0)));
+        assert!(!in_window(&night, &at(17, 23, 30)));
+        assert!(!in_window(&night, &at(16, 2, 0)));
+
+        let office = window("09:00", "17:00", Vec::new());
+        assert!(in_window(&office, &at(17, 9, 0)));
+        assert!(!in_window(&office, &at(17, 17, 0)));
+        assert!(!in_window(&window("9am", "17:00", Vec::new()), &at(17, 12, 0)));
+    }
+
+    #[test]
+    fn scheduled_windows_replace_every_regular_limit() {
+        let limits = BandwidthLimits {
+            upload: Some(10),
+            download: Some(20),
+            upload_per_peer: Some(5),
+            download_per_peer: None,
+            schedule: vec![window("23:00", "06:00", Vec::new())],
+        };
+        assert_eq!(active_rates(&limits, Direction::Upload, &at(16, 12, 0)), (Some(10), Some(5)));
+        assert_eq!(active_rates(&limits, Direction::Upload, &at(17, 1, 0)), (None, None));
+        assert_eq!(active_rates(&limits, Direction::Download, &at(17, 1, 0)), (Some(100), None));
+    }
+
+    #[test]
+    fn reservations_wait_once_the_burst_is_spent() {
+        let limiter = RateLimiter::new();
+        assert_eq!(limiter.reserve(1_000_000), Duration::ZERO);
+        limiter.set_rate(Some(1000));
+        // A full burst goes out at once, what follows waits for the bucket to refill
+        assert_eq!(limiter.reserve(1000), Duration::ZERO);
+        let wait = limiter.reserve(500);
+        assert!(wait > Duration::from_millis(400) && wait <= Duration::from_millis(500), "{:?}", wait);
+        let wait = limiter.reserve(500);
+        assert!(wait > Duration::from_millis(900) && wait <= Duration::from_millis(1000), "{:?}", wait);
+        // A new rate starts with a full burst again
+        limiter.set_rate(Some(2000));
+        assert_eq!(limiter.reserve(2000), Duration::ZERO);
+    }
+}
@@ -0,0 +1,242 @@
+use crate::file_hash::cache_key;
+use crate::file_index::with_file_index;
+use crate::local_dir::read_private_linked_paths;
+use crate::types::{Chunk, ChunkLocation, LinkedPath};
+use fastcdc::v2020::StreamCDC;
+use sha2::{Digest, Sha256};
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{self, Read, SeekFrom};
+use std::path::{Path, PathBuf};
+use std::sync::Mutex as StdMutex;
+use std::time::SystemTime;
+use tokio::io::{AsyncReadExt, AsyncSeekExt};
+
+// Content-defined chunk boundaries, so an edit only changes the chunks around it
+const MIN_CHUNK_SIZE: u32 = 16 * 1024;
+const AVG_CHUNK_SIZE: u32 = 64 * 1024;
+const MAX_CHUNK_SIZE: u32 = 256 * 1024;
+
+// Smaller files are cheaper to download whole than to negotiate chunks for
+pub const MIN_CHUNKED_FILE_SIZE: u64 = 1024 * 1024;
+
+struct CachedChunks {
+    len: u64,
+    modified: SystemTime,
+    chunks: Vec<Chunk>,
+}
+
+// Chunk lists of served files, invalidated the same way as the file hash cache
+lazy_static::lazy_static! {
+    static ref CHUNK_CACHE: StdMutex<HashMap<PathBuf, CachedChunks>> = StdMutex::new(HashMap::new());
+}
+
+// Split a file into content-defined chunks. Blocking, call it from `spawn_blocking` in async code
+pub fn file_chunks(path: &Path) -> io::Result<Vec<Chunk>> {
+    let metadata = path.metadata()?;
+    let modified = metadata.modified()?;
+
+    if let Some(cached) = CHUNK_CACHE.lock().unwrap().get(&cache_key(path)) {
+        if cached.len == metadata.len() && cached.modified == modified {
+            return Ok(cached.chunks.clone());
+        }
+    }
+
+    let (_, chunks) = hash_and_chunk(path)?;
+    Ok(chunks)
+}
+
+// Reader that hashes everything read through it
+struct HashingReader<R> {
+    inner: R,
+    hasher: Sha256,
+}
+
+impl<R: Read> Read for HashingReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let read = self.inner.read(buf)?;
+        self.hasher.update(&buf[..read]);
+        Ok(read)
+    }
+}
+
+// Hash a file and split it into chunks in a single read, caching the chunks for
+// `file_chunks`. Blocking
+pub fn hash_and_chunk(path: &Path) -> io::Result<(String, Vec<Chunk>)> {
+    let metadata = path.metadata()?;
+    let mut reader = HashingReader {
+        inner: File::open(path)?,
+        hasher: Sha256::new(),
+    };
+    let mut chunks = Vec::new();
+    for chunk_data in StreamCDC::new(&mut reader, MIN_CHUNK_SIZE, AVG_CHUNK_SIZE, MAX_CHUNK_SIZE) {
+        let chunk_data = chunk_data.map_err(|e| io::Error::other(e.to_string()))?;
+        chunks.push(Chunk {
+            hash: hex::encode(Sha256::digest(&chunk_data.data)),
+            offset: chunk_data.offset,
+            length: chunk_data.length as u64,
+        });
+    }
+    let hash = hex::encode(reader.hasher.finalize());
+
+    CHUNK_CACHE.lock().unwrap().insert(
+        cache_key(path),
+        CachedChunks {
+            len: metadata.len(),
+            modified: metadata.modified()?,
+            chunks: chunks.clone(),
+        },
+    );
+
+    Ok((hash, chunks))
+}
+
+// Blocking
+pub fn invalidate_file_chunks(path: &Path) {
+    let key = cache_key(path);
+    CHUNK_CACHE
+        .lock()
+        .unwrap()
+        .retain(|cached_path, _| !cached_path.starts_with(&key));
+}
+
+// Where chunks can be found on this device: in the files of any linked path, as recorded
+// in their file indexes, so identical content is only fetched from a host once
+#[derive(Default)]
+pub struct LocalChunkIndex {
+    linked_paths: Vec<LinkedPath>,
+    located: HashMap<String, ChunkLocation>,
+}
+
+impl LocalChunkIndex {
+    pub fn load() -> Self {
+        LocalChunkIndex {
+            linked_paths: read_private_linked_paths().unwrap_or_default(),
+            located: HashMap::new(),
+        }
+    }
+
+    // Look up which of `chunks` can be read locally, before reading them with `read_chunk`
+    pub async fn locate(&mut self, chunks: &[Chunk]) {
+        let linked_paths = self.linked_paths.clone();
+        let hashes: Vec<String> = chunks.iter().map(|chunk| chunk.hash.clone()).collect();
+        let located = tokio::task::spawn_blocking(move || {
+            let mut located = HashMap::new();
+            for linked_path in &linked_paths {
+                match with_file_index(linked_path, |index| index.chunk_locations(&hashes)) {
+                    Ok(locations) => located.extend(locations),
+                    Err(e) => eprintln!("Failed to look up chunks in {}: {}", linked_path.name, e),
+                }
+            }
+            located
+        })
+        .await
+        .unwrap_or_default();
+        self.located.extend(located);
+    }
+
+    // Read a located chunk. Locations whose contents changed since they were indexed are
+    // dropped, the file index records their new chunks once it catches up
+    pub async fn read_chunk(&mut self, chunk: &Chunk) -> Option<Vec<u8>> {
+        let location = self.located.get(&chunk.hash)?.clone();
+        match read_location(&location).await {
+            Ok(data) if hex::encode(Sha256::digest(&data)) == chunk.hash => Some(data),
+            _ => {
+                self.located.remove(&chunk.hash);
+                None
+            }
+        }
+    }
+}
+
+async fn read_location(location: &ChunkLocation) -> io::Result<Vec<u8>> {
+    let mut file = tokio::fs::File::open(&location.path).await?;
+    file.seek(SeekFrom::Start(location.offset)).await?;
+    let mut data = vec![0u8; location.length as usize];
+    file.read_exact(&mut data).await?;
+    Ok(data)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::file_hash::compute_file_hash;
+    use std::fs;
+
+    // Incompressible but repeatable contents, so chunk boundaries fall where content
+    // decides and not at the maximum chunk size
+    fn noise(len: usize, mut seed: u64) -> Vec<u8> {
+        (0..len)
+            .map(|_| {
+                seed ^= seed << 13;
+                seed ^= seed >> 7;
+                seed ^= seed << 17;
+                seed as u8
+            })
+            .collect()
+    }
+
+    #[test]
+    fn edits_only_change_the_chunks_around_them() {
+        let dir = std::env::temp_dir().join(format!("topaz-chunks-{}", uuid::Uuid::new_v4()));
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("data.bin");
+        let mut data = noise(2 * 1024 * 1024, 0x2545_f491_4f6c_dd1d);
+        fs::write(&path, &data).unwrap();
+
+        let (hash, chunks) = hash_and_chunk(&path).unwrap();
+        assert_eq!(hash, compute_file_hash(&path).unwrap());
+        assert!(chunks.len() > 2);
+        let mut offset = 0;
+        for chunk in &chunks {
+            assert_eq!(chunk.offset, offset);
+            assert!(chunk.length <= MAX_CHUNK_SIZE as u64);
+            let range = chunk.offset as usize..(chunk.offset + chunk.length) as usize;
+            assert_eq!(chunk.hash, hex::encode(Sha256::digest(&data[range])));
+            offset += chunk.length;
+        }
+        assert_eq!(offset, data.len() as u64);
+
+        data[1024 * 1024] ^= 0xff;
+        fs::write(&path, &data).unwrap();
+        invalidate_file_chunks(&path);
+        let before: Vec<(u64, &str)> = chunks.iter().map(|chunk| (chunk.offset, chunk.hash.as_str())).collect();
+        let edited = file_chunks(&path).unwrap();
+        let unchanged = edited
+            .iter()
+            .filter(|chunk| before.contains(&(chunk.offset, chunk.hash.as_str())))
+            .count();
+        // The edited chunk and maybe the boundary after it
+        assert!(unchanged >= chunks.len() - 2, "{} of {} unchanged", unchanged, chunks.len());
+        assert!(unchanged < edited.len());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn chunks_that_changed_on_disk_are_not_read() {
+        let dir = std::env::temp_dir().join(format!("topaz-chunks-{}", uuid::Uuid::new_v4()));
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("data.bin");
+        fs::write(&path, b"0123456789").unwrap();
+        let chunk = Chunk {
+            hash: hex::encode(Sha256::digest(b"3456")),
+            offset: 0,
+            length: 4,
+        };
+        let mut index = LocalChunkIndex::default();
+        index.located.insert(
+            chunk.hash.clone(),
+            ChunkLocation {
+                path: path.clone(),
+                offset: 3,
+                length: 4,
+            },
+        );
+        assert_eq!(index.read_chunk(&chunk).await.as_deref(), Some(&b"3456"[..]));
+
+        fs::write(&path, b"0123xx6789").unwrap();
+        assert_eq!(index.read_chunk(&chunk).await, None);
+        assert!(index.located.is_empty());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
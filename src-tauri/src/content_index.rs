@@ -0,0 +1,165 @@
+use crate::local_dir::TOPAZ_DIR_NAME;
+use crate::types::{IndexEntry, LinkedPath};
+use std::collections::HashMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex as StdMutex};
+use tantivy::collector::{DocSetCollector, TopDocs};
+use tantivy::directory::MmapDirectory;
+use tantivy::query::{AllQuery, QueryParser};
+use tantivy::schema::{Field, Schema, Value, STORED, STRING, TEXT};
+use tantivy::{doc, Index, IndexReader, IndexWriter, TantivyDocument, Term};
+
+// Full-text index of a linked path's text files, next to its file index
+const CONTENT_INDEX_DIR_NAME: &str = "search";
+// Larger files can only be found by name
+const MAX_INDEXED_FILE_SIZE: u64 = 1024 * 1024;
+// Smallest budget tantivy accepts for a single indexing thread
+const WRITER_MEMORY_BYTES: usize = 15_000_000;
+
+struct ContentIndex {
+    index: Index,
+    reader: IndexReader,
+    path: Field,
+    hash: Field,
+    body: Field,
+    // Hash of every indexed file at the time it was indexed, by path
+    indexed: HashMap<String, String>,
+}
+
+// Open content indexes by linked path root
+lazy_static::lazy_static! {
+    static ref CONTENT_INDEXES: StdMutex<HashMap<PathBuf, Arc<StdMutex<ContentIndex>>>> = StdMutex::new(HashMap::new());
+}
+
+fn local_path(root: &Path, relative_path: &str) -> PathBuf {
+    relative_path
+        .split('/')
+        .fold(root.to_path_buf(), |path, segment| path.join(segment))
+}
+
+// Content of a file if it looks like text
+fn read_text(path: &Path) -> Option<String> {
+    let text = String::from_utf8(fs::read(path).ok()?).ok()?;
+    (!text.contains('\0')).then_some(text)
+}
+
+impl ContentIndex {
+    fn open(linked_path: &LinkedPath) -> tantivy::Result<Self> {
+        let dir = linked_path.path.join(TOPAZ_DIR_NAME).join(CONTENT_INDEX_DIR_NAME);
+        fs::create_dir_all(&dir)?;
+        let mut schema_builder = Schema::builder();
+        let path = schema_builder.add_text_field("path", STRING | STORED);
+        let hash = schema_builder.add_text_field("hash", STRING | STORED);
+        let body = schema_builder.add_text_field("body", TEXT);
+        let index = Index::open_or_create(MmapDirectory::open(&dir)?, schema_builder.build())?;
+        let reader = index.reader()?;
+
+        let searcher = reader.searcher();
+        let mut indexed = HashMap::new();
+        for address in searcher.search(&AllQuery, &DocSetCollector)? {
+            let document: TantivyDocument = searcher.doc(address)?;
+            let indexed_path = document.get_first(path).and_then(|value| value.as_str());
+            let indexed_hash = document.get_first(hash).and_then(|value| value.as_str());
+            if let (Some(indexed_path), Some(indexed_hash)) = (indexed_path, indexed_hash) {
+                indexed.insert(indexed_path.to_string(), indexed_hash.to_string());
+            }
+        }
+
+        Ok(ContentIndex {
+            index,
+            reader,
+            path,
+            hash,
+            body,
+            indexed,
+        })
+    }
+
+    // Index text files whose hash changed since they were last indexed and drop files
+    // that are gone
+    fn update(&mut self, root: &Path, entries: &[IndexEntry]) -> tantivy::Result<()> {
+        let current: HashMap<&str, &str> = entries
+            .iter()
+            .filter(|entry| entry.size <= MAX_INDEXED_FILE_SIZE)
+            .filter_map(|entry| Some((entry.path.as_str(), entry.hash.as_deref()?)))
+            .collect();
+        let stale: Vec<String> = self
+            .indexed
+            .iter()
+            .filter(|(path, hash)| current.get(path.as_str()) != Some(&hash.as_str()))
+            .map(|(path, _)| path.clone())
+            .collect();
+        let fresh: Vec<(&str, &str)> = current
+            .iter()
+            .filter(|(path, hash)| self.indexed.get(**path).map(String::as_str) != Some(**hash))
+            .map(|(path, hash)| (*path, *hash))
+            .collect();
+        if stale.is_empty() && fresh.is_empty() {
+            return Ok(());
+        }
+
+        let mut writer: IndexWriter = self.index.writer_with_num_threads(1, WRITER_MEMORY_BYTES)?;
+        for path in stale {
+            writer.delete_term(Term::from_field_text(self.path, &path));
+            self.indexed.remove(&path);
+        }
+        for (path, hash) in fresh {
+            // Binary files are recorded with an empty body so they aren't read again
+            let body = read_text(&local_path(root, path)).unwrap_or_default();
+            writer.add_document(doc!(self.path => path, self.hash => hash, self.body => body))?;
+            self.indexed.insert(path.to_string(), hash.to_string());
+        }
+        writer.commit()?;
+        self.reader.reload()?;
+        Ok(())
+    }
+
+    fn search(&self, query: &str, limit: usize) -> tantivy::Result<Vec<(String, f32)>> {
+        // `TopDocs` panics on a limit of 0, which peers can ask for
+        if limit == 0 {
+            return Ok(Vec::new());
+        }
+        let parser = QueryParser::for_index(&self.index, vec![self.body]);
+        // Anything users type is searchable, unparsable parts are dropped
+        let (query, _errors) = parser.parse_query_lenient(query);
+        let searcher = self.reader.searcher();
+        let mut hits = Vec::new();
+        for (score, address) in searcher.search(&query, &TopDocs::with_limit(limit))? {
+            let document: TantivyDocument = searcher.doc(address)?;
+            if let Some(path) = document.get_first(self.path).and_then(|value| value.as_str()) {
+                hits.push((path.to_string(), score));
+            }
+        }
+        Ok(hits)
+    }
+}
+
+fn open_content_index(linked_path: &LinkedPath) -> tantivy::Result<Arc<StdMutex<ContentIndex>>> {
+    let mut content_indexes = CONTENT_INDEXES.lock().unwrap();
+    if let Some(content_index) = content_indexes.get(&linked_path.path) {
+        return Ok(content_index.clone());
+    }
+    let content_index = Arc::new(StdMutex::new(ContentIndex::open(linked_path)?));
+    content_indexes.insert(linked_path.path.clone(), content_index.clone());
+    Ok(content_index)
+}
+
+// Bring the content index of a linked path in line with the entries of its file index.
+// Run by the task keeping the file index current, so searches never read files. Blocking
+pub fn update_content_index(linked_path: &LinkedPath, entries: &[IndexEntry]) -> tantivy::Result<()> {
+    open_content_index(linked_path)?
+        .lock()
+        .unwrap()
+        .update(&linked_path.path, entries)
+}
+
+pub fn close_content_index(linked_path: &LinkedPath) {
+    CONTENT_INDEXES.lock().unwrap().remove(&linked_path.path);
+}
+
+// Paths of text files in a linked path whose content matches `query` with their score,
+// best matches first. Blocking
+pub fn search_content(linked_path: &LinkedPath, query: &str, limit: usize) -> tantivy::Result<Vec<(String, f32)>> {
+    open_content_index(linked_path)?.lock().unwrap().search(query, limit)
+}
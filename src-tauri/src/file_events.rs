@@ -0,0 +1,192 @@
+use crate::ignore_rules::IgnoreRules;
+use crate::local_dir::PART_FILE_EXTENSION;
+use crate::types::{FileChangeEvent, FileChangeKind, LinkedPath, LinkedPathChange};
+use notify::event::{EventKind, ModifyKind, RenameMode};
+use std::path::{Component, Path, PathBuf};
+use tokio::sync::broadcast;
+
+lazy_static::lazy_static! {
+    // Changes to files inside linked paths, published by the file watcher
+    static ref FILE_EVENTS: broadcast::Sender<FileChangeEvent> = broadcast::channel(1024).0;
+    // Linked paths the file watcher starts or stops watching
+    static ref LINKED_PATH_CHANGES: broadcast::Sender<LinkedPathChange> = broadcast::channel(64).0;
+}
+
+pub fn subscribe_file_events() -> broadcast::Receiver<FileChangeEvent> {
+    FILE_EVENTS.subscribe()
+}
+
+pub fn publish_file_event(event: FileChangeEvent) {
+    // Nobody listening is fine, events are only useful to running subsystems
+    let _ = FILE_EVENTS.send(event);
+}
+
+pub fn subscribe_linked_path_changes() -> broadcast::Receiver<LinkedPathChange> {
+    LINKED_PATH_CHANGES.subscribe()
+}
+
+pub fn publish_linked_path_change(change: LinkedPathChange) {
+    let _ = LINKED_PATH_CHANGES.send(change);
+}
+
+// Path of a file relative to the linked path containing it, `/`-separated. None for
+// files outside every linked path, ignored files and Topaz's own files
+fn locate<'a>(path: &Path, linked_paths: &'a [LinkedPath]) -> Option<(&'a LinkedPath, String)> {
+    // Files in nested linked paths belong to the innermost one
+    let linked_path = linked_paths
+        .iter()
+        .filter(|linked_path| path.starts_with(&linked_path.path))
+        .max_by_key(|linked_path| linked_path.path.components().count())?;
+
+    let relative_path = path.strip_prefix(&linked_path.path).ok()?;
+    if relative_path
+        .extension()
+        .is_some_and(|extension| extension == PART_FILE_EXTENSION)
+        || IgnoreRules::for_linked_path(linked_path).is_ignored(relative_path, path.is_dir())
+    {
+        return None;
+    }
+    let segments: Vec<String> = relative_path
+        .components()
+        .filter_map(|component| match component {
+            Component::Normal(segment) => Some(segment.to_string_lossy().into_owned()),
+            _ => None,
+        })
+        .collect();
+    if segments.is_empty() {
+        return None;
+    }
+    Some((linked_path, segments.join("/")))
+}
+
+fn change_event(
+    kind: FileChangeKind,
+    path: &Path,
+    previous_path: Option<String>,
+    linked_paths: &[LinkedPath],
+) -> Option<FileChangeEvent> {
+    let (linked_path, relative_path) = locate(path, linked_paths)?;
+    Some(FileChangeEvent {
+        linked_path_name: linked_path.name.clone(),
+        kind,
+        path: path.to_path_buf(),
+        relative_path,
+        previous_path,
+    })
+}
+
+// Turn a raw watcher event into changes of files inside linked paths
+pub fn file_change_events(event: &notify::Event, linked_paths: &[LinkedPath]) -> Vec<FileChangeEvent> {
+    let kind = match event.kind {
+        EventKind::Create(_) => FileChangeKind::Created,
+        EventKind::Remove(_) => FileChangeKind::Removed,
+        EventKind::Modify(ModifyKind::Name(RenameMode::Both)) if event.paths.len() == 2 => {
+            let from = &event.paths[0];
+            let to = &event.paths[1];
+            let previous = locate(from, linked_paths);
+            return match (previous, locate(to, linked_paths)) {
+                // Renamed within the same linked path
+                (Some((from_linked_path, previous_path)), Some((to_linked_path, _)))
+                    if from_linked_path.name == to_linked_path.name =>
+                {
+                    change_event(FileChangeKind::Renamed, to, Some(previous_path), linked_paths)
+                        .into_iter()
+                        .collect()
+                }
+                // Moved across linked paths, or in or out of one
+                _ => change_event(FileChangeKind::Removed, from, None, linked_paths)
+                    .into_iter()
+                    .chain(change_event(FileChangeKind::Created, to, None, linked_paths))
+                    .collect(),
+            };
+        }
+        EventKind::Modify(ModifyKind::Name(RenameMode::From)) => FileChangeKind::Removed,
+        EventKind::Modify(ModifyKind::Name(RenameMode::To)) => FileChangeKind::Created,
+        EventKind::Modify(_) | EventKind::Any => FileChangeKind::Modified,
+        EventKind::Access(_) | EventKind::Other => return Vec::new(),
+    };
+
+    event
+        .paths
+        .iter()
+        .filter_map(|path| change_event(kind, path, None, linked_paths))
+        .collect()
+}
+
+// Files and directories found in a directory as it started being watched. They were
+// created before the watch was in place, so the watcher reports none of them
+pub fn found_file_events(paths: &[PathBuf], linked_paths: &[LinkedPath]) -> Vec<FileChangeEvent> {
+    paths
+        .iter()
+        .filter_map(|path| change_event(FileChangeKind::Created, path, None, linked_paths))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use notify::event::{AccessKind, CreateKind};
+
+    fn linked_path(name: &str, path: PathBuf) -> LinkedPath {
+        LinkedPath {
+            name: name.to_string(),
+            path,
+            writable: false,
+            quota_bytes: None,
+            ignore_patterns: vec!["*.log".to_string()],
+            version_retention: Default::default(),
+        }
+    }
+
+    fn summary(events: Vec<FileChangeEvent>) -> Vec<(String, FileChangeKind, String, Option<String>)> {
+        events
+            .into_iter()
+            .map(|event| (event.linked_path_name, event.kind, event.relative_path, event.previous_path))
+            .collect()
+    }
+
+    #[test]
+    fn watcher_events_map_to_the_innermost_linked_path() {
+        let root = std::env::temp_dir().join(format!("topaz-events-{}", uuid::Uuid::new_v4()));
+        let linked_paths = [
+            linked_path("outer", root.clone()),
+            linked_path("inner", root.join("inner")),
+        ];
+        let event = |kind, paths: &[&str]| {
+            let event = paths
+                .iter()
+                .fold(notify::Event::new(kind), |event, path| event.add_path(root.join(path)));
+            summary(file_change_events(&event, &linked_paths))
+        };
+        let created = |name: &str, path: &str| (name.to_string(), FileChangeKind::Created, path.to_string(), None);
+        let removed = |name: &str, path: &str| (name.to_string(), FileChangeKind::Removed, path.to_string(), None);
+
+        assert_eq!(
+            event(EventKind::Create(CreateKind::File), &["a.txt", "inner/b.txt", "c.log"]),
+            [created("outer", "a.txt"), created("inner", "b.txt")]
+        );
+        assert!(event(EventKind::Create(CreateKind::File), &[&format!("a.{}", PART_FILE_EXTENSION)]).is_empty());
+        assert!(event(EventKind::Access(AccessKind::Any), &["a.txt"]).is_empty());
+
+        let rename = EventKind::Modify(ModifyKind::Name(RenameMode::Both));
+        assert_eq!(
+            event(rename, &["docs/a.txt", "b.txt"]),
+            [(
+                "outer".to_string(),
+                FileChangeKind::Renamed,
+                "b.txt".to_string(),
+                Some("docs/a.txt".to_string())
+            )]
+        );
+        assert_eq!(
+            event(rename, &["a.txt", "inner/a.txt"]),
+            [removed("outer", "a.txt"), created("inner", "a.txt")]
+        );
+        // Renaming to an ignored name is the file going away
+        assert_eq!(event(rename, &["a.txt", "a.log"]), [removed("outer", "a.txt")]);
+        assert_eq!(
+            event(EventKind::Modify(ModifyKind::Name(RenameMode::To)), &["b.txt"]),
+            [created("outer", "b.txt")]
+        );
+    }
+}
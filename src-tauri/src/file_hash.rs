@@ -0,0 +1,113 @@
+use sha2::{Digest, Sha256};
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{self, Read};
+use std::path::{Path, PathBuf};
+use std::sync::Mutex as StdMutex;
+use std::time::SystemTime;
+
+struct CachedHash {
+    len: u64,
+    modified: SystemTime,
+    hash: String,
+}
+
+// Hashes of served files by `cache_key`. Entries are dropped by the file watcher when a
+// file changes and are also checked against size and mtime in case an event was missed
+lazy_static::lazy_static! {
+    static ref HASH_CACHE: StdMutex<HashMap<PathBuf, CachedHash>> = StdMutex::new(HashMap::new());
+}
+
+// Canonical form of a path, so a file reached through a symlinked or relative linked path
+// and the path the file watcher reports for it share a cache entry. Paths that are gone
+// are taken relative to their nearest existing ancestor. Blocking
+pub fn cache_key(path: &Path) -> PathBuf {
+    for ancestor in path.ancestors() {
+        if let Ok(canonical) = ancestor.canonicalize() {
+            return match path.strip_prefix(ancestor) {
+                Ok(rest) if !rest.as_os_str().is_empty() => canonical.join(rest),
+                _ => canonical,
+            };
+        }
+    }
+    path.to_path_buf()
+}
+
+// Hex encoded SHA-256 of a file, computed once and reused until the file changes.
+// Blocking, call it from `spawn_blocking` in async code
+pub fn file_hash(path: &Path) -> io::Result<String> {
+    let metadata = path.metadata()?;
+    let modified = metadata.modified()?;
+    let key = cache_key(path);
+
+    if let Some(cached) = HASH_CACHE.lock().unwrap().get(&key) {
+        if cached.len == metadata.len() && cached.modified == modified {
+            return Ok(cached.hash.clone());
+        }
+    }
+
+    let hash = compute_file_hash(path)?;
+
+    HASH_CACHE.lock().unwrap().insert(
+        key,
+        CachedHash {
+            len: metadata.len(),
+            modified,
+            hash: hash.clone(),
+        },
+    );
+
+    Ok(hash)
+}
+
+// Hash a file from disk, bypassing the cache. Blocking
+pub fn compute_file_hash(path: &Path) -> io::Result<String> {
+    let mut file = File::open(path)?;
+    let mut hasher = Sha256::new();
+    let mut buffer = [0u8; 64 * 1024];
+    loop {
+        let read = file.read(&mut buffer)?;
+        if read == 0 {
+            break;
+        }
+        hasher.update(&buffer[..read]);
+    }
+    Ok(hex::encode(hasher.finalize()))
+}
+
+// Forget cached hashes for a changed path, including everything below it if it was a
+// directory. Blocking
+pub fn invalidate_file_hash(path: &Path) {
+    let key = cache_key(path);
+    HASH_CACHE
+        .lock()
+        .unwrap()
+        .retain(|cached_path, _| !cached_path.starts_with(&key));
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use super::*;
+    use std::fs;
+
+    #[test]
+    fn changes_reported_under_the_real_path_reach_symlinked_files() {
+        let root = std::env::temp_dir().join(format!("topaz-hash-{}", uuid::Uuid::new_v4()));
+        let (real, link) = (root.join("real"), root.join("link"));
+        fs::create_dir_all(&real).unwrap();
+        std::os::unix::fs::symlink(&real, &link).unwrap();
+        let file = real.join("a.txt");
+        fs::write(&file, b"aaaa").unwrap();
+        let old_hash = file_hash(&link.join("a.txt")).unwrap();
+
+        // Same size and mtime, only the watcher can tell the cache it changed
+        let modified = fs::metadata(&file).unwrap().modified().unwrap();
+        fs::write(&file, b"bbbb").unwrap();
+        fs::File::options().write(true).open(&file).unwrap().set_modified(modified).unwrap();
+        assert_eq!(file_hash(&link.join("a.txt")).unwrap(), old_hash);
+
+        invalidate_file_hash(&real.join("a.txt"));
+        assert_eq!(file_hash(&link.join("a.txt")).unwrap(), compute_file_hash(&file).unwrap());
+        fs::remove_dir_all(&root).unwrap();
+    }
+}
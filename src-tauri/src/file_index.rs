@@ -0,0 +1,761 @@
+use crate::chunk_store::{hash_and_chunk, MIN_CHUNKED_FILE_SIZE};
+#[cfg(feature = "content-search")]
+use crate::content_index::{close_content_index, update_content_index};
+use crate::file_hash::{compute_file_hash, file_hash};
+use crate::ignore_rules::{IgnoreRules, IGNORE_FILE_NAME};
+use crate::local_dir::{read_private_linked_paths, PART_FILE_EXTENSION, TOPAZ_DIR_NAME};
+use crate::types::{
+    Chunk, ChunkLocation, FileChangeEvent, FileIndexError, FileVersion, IndexEntry, IndexedFile, LinkedPath, LinkedPathChange, SyncListing,
+    VersionClock,
+};
+use crate::version_clock::bump_clock;
+use rusqlite::{params, Connection, OptionalExtension, Row};
+use std::collections::{HashMap, HashSet};
+use std::fs::{self, Metadata};
+use std::io;
+use std::path::{Component, Path, PathBuf};
+use std::sync::{Arc, Mutex as StdMutex};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tokio::sync::{broadcast, mpsc};
+
+// Database in each linked path's Topaz directory recording every file and directory in it
+pub const FILE_INDEX_FILE_NAME: &str = "index.db";
+
+const ENTRY_COLUMNS: &str = "path, is_dir, size, modified, inode, hash, version";
+
+// Open indexes by linked path root
+lazy_static::lazy_static! {
+    static ref FILE_INDEXES: StdMutex<HashMap<PathBuf, Arc<StdMutex<FileIndex>>>> = StdMutex::new(HashMap::new());
+}
+
+pub struct FileIndex {
+    conn: Connection,
+    linked_path: LinkedPath,
+    // Ignore rules the whole index was last brought in line with
+    rules: RulesStamp,
+}
+
+// Ignore patterns of a linked path and when its `.topazignore` was last modified
+type RulesStamp = (Vec<String>, Option<SystemTime>);
+
+fn rules_stamp(linked_path: &LinkedPath) -> RulesStamp {
+    let modified = linked_path
+        .path
+        .join(IGNORE_FILE_NAME)
+        .metadata()
+        .and_then(|metadata| metadata.modified())
+        .ok();
+    (linked_path.ignore_patterns.clone(), modified)
+}
+
+// Run `f` on the index of a linked path, opening it on first use. Blocking
+pub fn with_file_index<T>(
+    linked_path: &LinkedPath,
+    f: impl FnOnce(&mut FileIndex) -> Result<T, FileIndexError>,
+) -> Result<T, FileIndexError> {
+    let index = {
+        let mut indexes = FILE_INDEXES.lock().unwrap();
+        match indexes.get(&linked_path.path) {
+            Some(index) => index.clone(),
+            None => {
+                let index = Arc::new(StdMutex::new(FileIndex::open(linked_path)?));
+                indexes.insert(linked_path.path.clone(), index.clone());
+                index
+            }
+        }
+    };
+    let mut index = index.lock().unwrap();
+    // Ignore patterns may have changed since the index was opened, and can bring any file
+    // in or out of it
+    index.linked_path = linked_path.clone();
+    if index.rules != rules_stamp(linked_path) {
+        index.refresh()?;
+    }
+    f(&mut index)
+}
+
+fn close_file_index(linked_path: &LinkedPath) {
+    FILE_INDEXES.lock().unwrap().remove(&linked_path.path);
+}
+
+// `/`-separated form of a relative path, as stored in the index
+pub fn relative_path_string(relative_path: &Path) -> String {
+    relative_path
+        .components()
+        .filter_map(|component| match component {
+            Component::Normal(segment) => Some(segment.to_string_lossy().into_owned()),
+            _ => None,
+        })
+        .collect::<Vec<_>>()
+        .join("/")
+}
+
+fn join_relative(prefix: &str, name: &str) -> String {
+    if prefix.is_empty() {
+        name.to_string()
+    } else {
+        format!("{}/{}", prefix, name)
+    }
+}
+
+fn parent_of(relative_path: &str) -> &str {
+    relative_path.rsplit_once('/').map_or("", |(parent, _)| parent)
+}
+
+fn modified_nanos(metadata: &Metadata) -> u64 {
+    metadata
+        .modified()
+        .ok()
+        .and_then(|modified| modified.duration_since(UNIX_EPOCH).ok())
+        .map_or(0, |duration| duration.as_nanos() as u64)
+}
+
+#[cfg(unix)]
+fn inode(metadata: &Metadata) -> u64 {
+    std::os::unix::fs::MetadataExt::ino(metadata)
+}
+
+#[cfg(not(unix))]
+fn inode(_metadata: &Metadata) -> u64 {
+    0
+}
+
+fn row_entry(row: &Row) -> rusqlite::Result<IndexEntry> {
+    Ok(IndexEntry {
+        path: row.get(0)?,
+        is_dir: row.get(1)?,
+        size: row.get(2)?,
+        modified: row.get(3)?,
+        inode: row.get(4)?,
+        hash: row.get(5)?,
+        version: row.get(6)?,
+    })
+}
+
+// Every file and directory below `dir` that isn't ignored, relative to the linked path
+fn walk_dir(
+    dir: &Path,
+    prefix: &str,
+    rules: &IgnoreRules,
+    found: &mut Vec<(String, Metadata)>,
+) -> io::Result<()> {
+    for entry in fs::read_dir(dir)? {
+        let entry = entry?;
+        let Ok(metadata) = entry.metadata() else {
+            continue;
+        };
+        if rules.is_ignored(&entry.path(), metadata.is_dir()) {
+            continue;
+        }
+        let relative_path = join_relative(prefix, &entry.file_name().to_string_lossy());
+        if metadata.is_dir() {
+            // Directories removed while the walk is running are simply left out
+            let _ = walk_dir(&entry.path(), &relative_path, rules, found);
+            found.push((relative_path, metadata));
+        } else if metadata.is_file()
+            && entry.path().extension().is_none_or(|extension| extension != PART_FILE_EXTENSION)
+        {
+            found.push((relative_path, metadata));
+        }
+    }
+    Ok(())
+}
+
+impl FileIndex {
+    fn open(linked_path: &LinkedPath) -> Result<Self, FileIndexError> {
+        if !linked_path.path.is_dir() {
+            return Err(io::Error::from(io::ErrorKind::NotFound).into());
+        }
+        let dir = linked_path.path.join(TOPAZ_DIR_NAME);
+        fs::create_dir_all(&dir)?;
+        let conn = Connection::open(dir.join(FILE_INDEX_FILE_NAME))?;
+        conn.busy_timeout(Duration::from_secs(5))?;
+        conn.execute_batch(
+            "PRAGMA journal_mode = WAL;
+            CREATE TABLE IF NOT EXISTS files (
+                path TEXT PRIMARY KEY,
+                parent TEXT NOT NULL,
+                is_dir INTEGER NOT NULL,
+                size INTEGER NOT NULL,
+                modified INTEGER NOT NULL,
+                inode INTEGER NOT NULL,
+                hash TEXT,
+                version INTEGER NOT NULL
+            );
+            CREATE INDEX IF NOT EXISTS files_parent ON files (parent);
+            CREATE TABLE IF NOT EXISTS chunks (
+                hash TEXT NOT NULL,
+                path TEXT NOT NULL,
+                offset INTEGER NOT NULL,
+                length INTEGER NOT NULL
+            );
+            CREATE INDEX IF NOT EXISTS chunks_hash ON chunks (hash);
+            CREATE INDEX IF NOT EXISTS chunks_path ON chunks (path);
+            CREATE TABLE IF NOT EXISTS clocks (
+                path TEXT PRIMARY KEY,
+                hash TEXT,
+                clock TEXT NOT NULL
+            );",
+        )?;
+        // `maintain_file_indexes` refreshes the index of each linked path it is told of,
+        // under the rules it has then
+        Ok(FileIndex {
+            conn,
+            linked_path: linked_path.clone(),
+            rules: rules_stamp(linked_path),
+        })
+    }
+
+    fn local_path(&self, relative_path: &str) -> PathBuf {
+        relative_path
+            .split('/')
+            .filter(|segment| !segment.is_empty())
+            .fold(self.linked_path.path.clone(), |path, segment| path.join(segment))
+    }
+
+    fn has_chunks(&self, relative_path: &str) -> Result<bool, FileIndexError> {
+        let found = self
+            .conn
+            .query_row("SELECT 1 FROM chunks WHERE path = ?1 LIMIT 1", params![relative_path], |_| Ok(()))
+            .optional()?;
+        Ok(found.is_some())
+    }
+
+    fn replace_chunks(&self, relative_path: &str, chunks: &[Chunk]) -> Result<(), FileIndexError> {
+        self.conn.execute("DELETE FROM chunks WHERE path = ?1", params![relative_path])?;
+        let mut statement = self
+            .conn
+            .prepare_cached("INSERT INTO chunks (hash, path, offset, length) VALUES (?1, ?2, ?3, ?4)")?;
+        for chunk in chunks {
+            statement.execute(params![chunk.hash, relative_path, chunk.offset, chunk.length])?;
+        }
+        Ok(())
+    }
+
+    // Record a file or directory. Files are only hashed again when their size,
+    // modification time or inode changed. Large files are split into chunks while they
+    // are hashed, so downloads can reuse them. Returns whether the record changed
+    fn upsert(
+        &self,
+        relative_path: &str,
+        metadata: &Metadata,
+        known: Option<&IndexEntry>,
+    ) -> Result<bool, FileIndexError> {
+        let is_dir = metadata.is_dir();
+        let size = if is_dir { 0 } else { metadata.len() };
+        let modified = modified_nanos(metadata);
+        let inode = inode(metadata);
+        let chunked = !is_dir && size >= MIN_CHUNKED_FILE_SIZE;
+        // Files recorded before chunks were kept are chunked once
+        if known.is_some_and(|known| {
+            known.is_dir == is_dir && known.size == size && known.modified == modified && known.inode == inode
+        }) && (!chunked || self.has_chunks(relative_path)?)
+        {
+            return Ok(false);
+        }
+
+        let path = self.local_path(relative_path);
+        let hash = if is_dir {
+            None
+        } else if chunked {
+            let (hash, chunks) = hash_and_chunk(&path)?;
+            self.replace_chunks(relative_path, &chunks)?;
+            Some(hash)
+        } else {
+            self.conn.execute("DELETE FROM chunks WHERE path = ?1", params![relative_path])?;
+            Some(file_hash(&path)?)
+        };
+        let version = match known {
+            Some(known) if known.hash == hash => known.version,
+            Some(known) => known.version + 1,
+            None => 1,
+        };
+        self.conn.execute(
+            "INSERT INTO files (path, parent, is_dir, size, modified, inode, hash, version)
+            VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8)
+            ON CONFLICT (path) DO UPDATE SET
+                is_dir = excluded.is_dir, size = excluded.size, modified = excluded.modified,
+                inode = excluded.inode, hash = excluded.hash, version = excluded.version",
+            params![relative_path, parent_of(relative_path), is_dir, size, modified, inode, hash, version],
+        )?;
+        Ok(true)
+    }
+
+    // Bring the records below `dir` in line with the disk, `""` being the whole linked path
+    fn reconcile(&mut self, dir: &str) -> Result<(), FileIndexError> {
+        let rules = IgnoreRules::for_linked_path(&self.linked_path);
+        let mut found = Vec::new();
+        walk_dir(&self.local_path(dir), dir, &rules, &mut found)?;
+        let known: HashMap<String, IndexEntry> = self
+            .entries_below(dir)?
+            .into_iter()
+            .map(|entry| (entry.path.clone(), entry))
+            .collect();
+
+        let transaction = self.conn.unchecked_transaction()?;
+        let mut seen = HashSet::new();
+        for (relative_path, metadata) in &found {
+            match self.upsert(relative_path, metadata, known.get(relative_path)) {
+                Ok(_) => {}
+                // Files removed or unreadable while the walk is running keep their record
+                // until the next update
+                Err(FileIndexError::Io(_)) => {}
+                Err(e) => return Err(e),
+            };
+            seen.insert(relative_path.as_str());
+        }
+        for relative_path in known.keys().filter(|path| !seen.contains(path.as_str())) {
+            transaction.execute("DELETE FROM files WHERE path = ?1", params![relative_path])?;
+            transaction.execute("DELETE FROM chunks WHERE path = ?1", params![relative_path])?;
+        }
+        transaction.commit()?;
+        Ok(())
+    }
+
+    // Re-read the whole linked path. Unchanged files are not hashed again
+    pub fn refresh(&mut self) -> Result<(), FileIndexError> {
+        let rules = rules_stamp(&self.linked_path);
+        self.reconcile("")?;
+        self.rules = rules;
+        Ok(())
+    }
+
+    // Record the current state of one path, and everything below it if it's a directory
+    pub fn update_path(&mut self, relative_path: &str) -> Result<(), FileIndexError> {
+        if relative_path.is_empty() {
+            return self.refresh();
+        }
+        let path = self.local_path(relative_path);
+        let rules = IgnoreRules::for_linked_path(&self.linked_path);
+        match fs::metadata(&path) {
+            Ok(metadata)
+                if (metadata.is_dir() || metadata.is_file())
+                    && !rules.is_ignored(&path, metadata.is_dir()) =>
+            {
+                let known = self.entry(relative_path)?;
+                self.upsert(relative_path, &metadata, known.as_ref())?;
+                if metadata.is_dir() {
+                    self.reconcile(relative_path)?;
+                }
+                Ok(())
+            }
+            Ok(_) => self.remove_path(relative_path),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => self.remove_path(relative_path),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    // Forget a path and everything below it
+    pub fn remove_path(&mut self, relative_path: &str) -> Result<(), FileIndexError> {
+        for table in ["files", "chunks"] {
+            self.conn.execute(
+                &format!(
+                    "DELETE FROM {} WHERE path = ?1 OR substr(path, 1, length(?1) + 1) = ?1 || '/'",
+                    table
+                ),
+                params![relative_path],
+            )?;
+        }
+        Ok(())
+    }
+
+    // Where in this linked path each of the chunks with the given hashes can be read
+    pub fn chunk_locations(&self, hashes: &[String]) -> Result<HashMap<String, ChunkLocation>, FileIndexError> {
+        let mut statement = self
+            .conn
+            .prepare_cached("SELECT path, offset, length FROM chunks WHERE hash = ?1 LIMIT 1")?;
+        let mut locations = HashMap::new();
+        for hash in hashes {
+            let location = statement
+                .query_row(params![hash], |row| {
+                    let path: String = row.get(0)?;
+                    Ok(ChunkLocation {
+                        path: self.local_path(&path),
+                        offset: row.get(1)?,
+                        length: row.get(2)?,
+                    })
+                })
+                .optional()?;
+            if let Some(location) = location {
+                locations.insert(hash.clone(), location);
+            }
+        }
+        Ok(locations)
+    }
+
+    // Apply a change reported by the file watcher
+    pub fn apply_change(&mut self, file_event: &FileChangeEvent) -> Result<(), FileIndexError> {
+        // New ignore rules can bring any file in or out of the index
+        if file_event.relative_path == IGNORE_FILE_NAME {
+            return self.refresh();
+        }
+        if let Some(previous_path) = &file_event.previous_path {
+            self.remove_path(previous_path)?;
+        }
+        self.update_path(&file_event.relative_path)
+    }
+
+    pub fn entry(&self, relative_path: &str) -> Result<Option<IndexEntry>, FileIndexError> {
+        let entry = self
+            .conn
+            .query_row(
+                &format!("SELECT {} FROM files WHERE path = ?1", ENTRY_COLUMNS),
+                params![relative_path],
+                row_entry,
+            )
+            .optional()?;
+        Ok(entry)
+    }
+
+    // Direct children of a directory, `""` being the linked path root
+    pub fn list_dir(&self, dir: &str) -> Result<Vec<IndexEntry>, FileIndexError> {
+        let mut statement = self.conn.prepare(&format!(
+            "SELECT {} FROM files WHERE parent = ?1 ORDER BY path",
+            ENTRY_COLUMNS
+        ))?;
+        let entries = statement
+            .query_map(params![dir], row_entry)?
+            .collect::<Result<Vec<_>, _>>()?;
+        Ok(entries)
+    }
+
+    // Everything below a directory, `""` being the whole linked path
+    pub fn entries_below(&self, dir: &str) -> Result<Vec<IndexEntry>, FileIndexError> {
+        let mut statement = self.conn.prepare(&format!(
+            "SELECT {} FROM files WHERE ?1 = '' OR substr(path, 1, length(?1) + 1) = ?1 || '/'",
+            ENTRY_COLUMNS
+        ))?;
+        let entries = statement
+            .query_map(params![dir], row_entry)?
+            .collect::<Result<Vec<_>, _>>()?;
+        Ok(entries)
+    }
+
+    // Files below a directory with paths relative to it, as compared by peers
+    pub fn files_below(&self, dir: &str) -> Result<Vec<IndexedFile>, FileIndexError> {
+        let prefix_len = if dir.is_empty() { 0 } else { dir.len() + 1 };
+        Ok(self
+            .entries_below(dir)?
+            .into_iter()
+            .filter_map(|entry| {
+                Some(IndexedFile {
+                    path: entry.path[prefix_len..].to_string(),
+                    hash: entry.hash?,
+                    size: entry.size,
+                    modified: entry.modified / 1_000_000_000,
+                })
+            })
+            .collect())
+    }
+
+    // Record the clock a syncing device gave a file, `hash` being None once it's deleted
+    pub fn record_clock(
+        &self,
+        relative_path: &str,
+        hash: Option<&str>,
+        clock: &VersionClock,
+    ) -> Result<(), FileIndexError> {
+        let clock = serde_json::to_string(clock).map_err(io::Error::other)?;
+        self.conn.execute(
+            "INSERT INTO clocks (path, hash, clock) VALUES (?1, ?2, ?3)
+            ON CONFLICT (path) DO UPDATE SET hash = excluded.hash, clock = excluded.clock",
+            params![relative_path, hash, clock],
+        )?;
+        Ok(())
+    }
+
+    // Files below a directory with their clocks, for syncing devices. A file whose content
+    // differs from the one its clock was recorded for was changed on this device, and so
+    // was a recorded file that is gone: both count as a change made by `device`
+    pub fn sync_listing(&self, dir: &str, device: &str) -> Result<SyncListing, FileIndexError> {
+        let mut statement = self.conn.prepare(
+            "SELECT path, hash, clock FROM clocks WHERE ?1 = '' OR substr(path, 1, length(?1) + 1) = ?1 || '/'",
+        )?;
+        let mut recorded: HashMap<String, (Option<String>, VersionClock)> = statement
+            .query_map(params![dir], |row| {
+                let clock: String = row.get(2)?;
+                Ok((row.get(0)?, (row.get(1)?, serde_json::from_str(&clock).unwrap_or_default())))
+            })?
+            .collect::<Result<_, _>>()?;
+
+        let prefix_len = if dir.is_empty() { 0 } else { dir.len() + 1 };
+        let mut listing = SyncListing::default();
+        for entry in self.entries_below(dir)? {
+            let Some(hash) = entry.hash else {
+                continue;
+            };
+            let clock = match recorded.remove(&entry.path) {
+                Some((Some(recorded_hash), clock)) if recorded_hash == hash => clock,
+                known => {
+                    let clock = bump_clock(&known.map(|(_, clock)| clock).unwrap_or_default(), device);
+                    self.record_clock(&entry.path, Some(&hash), &clock)?;
+                    clock
+                }
+            };
+            listing.files.insert(
+                entry.path[prefix_len..].to_string(),
+                FileVersion {
+                    hash,
+                    size: entry.size,
+                    modified: entry.modified / 1_000_000_000,
+                    clock,
+                },
+            );
+        }
+        for (path, (hash, mut clock)) in recorded {
+            if hash.is_some() {
+                clock = bump_clock(&clock, device);
+                self.record_clock(&path, None, &clock)?;
+            }
+            listing.deleted.insert(path[prefix_len..].to_string(), clock);
+        }
+        Ok(listing)
+    }
+
+    // Files whose content no longer matches the recorded hash although their size and
+    // modification time did not change. Reads every file, blocking
+    pub fn verify(&self) -> Result<Vec<String>, FileIndexError> {
+        let mut corrupted = Vec::new();
+        for entry in self.entries_below("")? {
+            let Some(hash) = &entry.hash else {
+                continue;
+            };
+            let path = self.local_path(&entry.path);
+            // Files changed since they were indexed are picked up by the watcher instead
+            let Ok(metadata) = fs::metadata(&path) else {
+                continue;
+            };
+            if metadata.len() != entry.size || modified_nanos(&metadata) != entry.modified {
+                continue;
+            }
+            if compute_file_hash(&path).is_ok_and(|actual| &actual != hash) {
+                corrupted.push(entry.path);
+            }
+        }
+        Ok(corrupted)
+    }
+}
+
+// Record a change made by Topaz itself without waiting for the file watcher
+pub async fn index_path(linked_path: LinkedPath, relative_path: String) {
+    let result = tokio::task::spawn_blocking(move || {
+        with_file_index(&linked_path, |index| index.update_path(&relative_path))
+    })
+    .await;
+    if let Ok(Err(e)) = result {
+        eprintln!("Failed to update file index: {}", e);
+    }
+}
+
+// Record a file a syncing device pushed or deleted along with the clock it gave it
+pub async fn index_synced_path(linked_path: LinkedPath, relative_path: String, clock: VersionClock) {
+    let result = tokio::task::spawn_blocking(move || {
+        with_file_index(&linked_path, |index| {
+            index.update_path(&relative_path)?;
+            let hash = index.entry(&relative_path)?.and_then(|entry| entry.hash);
+            index.record_clock(&relative_path, hash.as_deref(), &clock)
+        })
+    })
+    .await;
+    if let Ok(Err(e)) = result {
+        eprintln!("Failed to update file index: {}", e);
+    }
+}
+
+async fn refresh_file_index(linked_path: LinkedPath) {
+    let name = linked_path.name.clone();
+    let result = tokio::task::spawn_blocking(move || with_file_index(&linked_path, |index| index.refresh())).await;
+    match result {
+        Ok(Ok(())) => println!("File index of {} is up to date", name),
+        Ok(Err(e)) => eprintln!("Failed to index {}: {}", name, e),
+        Err(e) => eprintln!("Failed to index {}: {}", name, e),
+    }
+}
+
+// Work for the task keeping the index of one linked path current
+enum IndexWork {
+    // Read the whole linked path again, with its new config if it changed
+    Refresh(Option<LinkedPath>),
+    Apply(FileChangeEvent),
+}
+
+// Do the work sent for one linked path in order, and close its index once the linked path
+// is no longer watched
+async fn maintain_file_index(mut linked_path: LinkedPath, mut work: mpsc::UnboundedReceiver<IndexWork>) {
+    while let Some(work) = work.recv().await {
+        match work {
+            IndexWork::Refresh(updated) => {
+                if let Some(updated) = updated {
+                    linked_path = updated;
+                }
+                refresh_file_index(linked_path.clone()).await;
+            }
+            IndexWork::Apply(file_event) => {
+                let linked_path = linked_path.clone();
+                let result = tokio::task::spawn_blocking(move || {
+                    with_file_index(&linked_path, |index| index.apply_change(&file_event))
+                })
+                .await;
+                if let Ok(Err(e)) = result {
+                    eprintln!("Failed to update file index: {}", e);
+                }
+            }
+        }
+        // Contents are read once the index has caught up with a burst of changes
+        #[cfg(feature = "content-search")]
+        if work.is_empty() {
+            update_content(linked_path.clone()).await;
+        }
+    }
+    close_file_index(&linked_path);
+    #[cfg(feature = "content-search")]
+    close_content_index(&linked_path);
+}
+
+// Index the contents of text files that changed since they were last indexed
+#[cfg(feature = "content-search")]
+async fn update_content(linked_path: LinkedPath) {
+    let _ = tokio::task::spawn_blocking(move || {
+        let updated = with_file_index(&linked_path, |index| index.entries_below(""))
+            .map_err(|e| e.to_string())
+            .and_then(|entries| update_content_index(&linked_path, &entries).map_err(|e| e.to_string()));
+        if let Err(e) = updated {
+            eprintln!("Failed to index the contents of {}: {}", linked_path.name, e);
+        }
+    })
+    .await;
+}
+
+// Have the task of a linked path's index, started if there is none yet, catch up with
+// everything that changed while it wasn't looking
+fn refresh_file_index_of(workers: &mut HashMap<String, mpsc::UnboundedSender<IndexWork>>, linked_path: LinkedPath) {
+    if let Some(worker) = workers.get(&linked_path.name) {
+        if worker.send(IndexWork::Refresh(Some(linked_path.clone()))).is_ok() {
+            return;
+        }
+    }
+    let (tx, rx) = mpsc::unbounded_channel();
+    let _ = tx.send(IndexWork::Refresh(None));
+    workers.insert(linked_path.name.clone(), tx);
+    tokio::spawn(maintain_file_index(linked_path, rx));
+}
+
+// Keep the indexes of watched linked paths current. Each linked path has its own task, so
+// reading a large one again doesn't hold back changes to the others, and changes made
+// meanwhile wait for it in order. The subscriptions are taken by the caller so nothing
+// published before this task first runs is missed
+pub async fn maintain_file_indexes(
+    mut linked_path_changes: broadcast::Receiver<LinkedPathChange>,
+    mut file_events: broadcast::Receiver<FileChangeEvent>,
+) {
+    let mut workers: HashMap<String, mpsc::UnboundedSender<IndexWork>> = HashMap::new();
+    loop {
+        tokio::select! {
+            change = linked_path_changes.recv() => match change {
+                Ok(LinkedPathChange::Added(linked_path)) => refresh_file_index_of(&mut workers, linked_path),
+                // Dropping the sender ends the task once it's done with what was sent
+                Ok(LinkedPathChange::Removed(linked_path)) => {
+                    workers.remove(&linked_path.name);
+                }
+                Err(broadcast::error::RecvError::Lagged(_)) => {
+                    let linked_paths = read_private_linked_paths().unwrap_or_default();
+                    workers.retain(|name, _| linked_paths.iter().any(|linked_path| &linked_path.name == name));
+                    for linked_path in linked_paths {
+                        refresh_file_index_of(&mut workers, linked_path);
+                    }
+                }
+                Err(broadcast::error::RecvError::Closed) => break,
+            },
+            file_event = file_events.recv() => match file_event {
+                Ok(file_event) => {
+                    if let Some(worker) = workers.get(&file_event.linked_path_name) {
+                        let _ = worker.send(IndexWork::Apply(file_event));
+                    }
+                }
+                // Missed changes can only be recovered by reading everything again
+                Err(broadcast::error::RecvError::Lagged(_)) => {
+                    for worker in workers.values() {
+                        let _ = worker.send(IndexWork::Refresh(None));
+                    }
+                }
+                Err(broadcast::error::RecvError::Closed) => break,
+            },
+        }
+    }
+}
+
+// Check every indexed file of a linked path against its recorded hash and return the
+// paths of files that were corrupted on disk
+#[tauri::command]
+pub async fn verify_linked_path(linked_path_name: String) -> Result<Vec<String>, String> {
+    let linked_path = read_private_linked_paths()
+        .map_err(|e| e.to_string())?
+        .into_iter()
+        .find(|linked_path| linked_path.name == linked_path_name)
+        .ok_or_else(|| format!("No linked path named '{}'", linked_path_name))?;
+    tokio::task::spawn_blocking(move || {
+        with_file_index(&linked_path, |index| {
+            index.refresh()?;
+            index.verify()
+        })
+    })
+    .await
+    .map_err(|e| e.to_string())?
+    .map_err(|e| e.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn refresh_follows_the_disk_and_ignore_rules() {
+        let root = std::env::temp_dir().join(format!("topaz-index-{}", uuid::Uuid::new_v4()));
+        fs::create_dir_all(root.join("docs")).unwrap();
+        fs::write(root.join("docs/a.txt"), b"a").unwrap();
+        fs::write(root.join("docs/b.log"), b"b").unwrap();
+        fs::write(root.join(format!("docs/c.txt.{}", PART_FILE_EXTENSION)), b"c").unwrap();
+        fs::write(root.join(IGNORE_FILE_NAME), "*.log\n").unwrap();
+        let linked_path = LinkedPath {
+            name: "docs".to_string(),
+            path: root.clone(),
+            writable: false,
+            quota_bytes: None,
+            ignore_patterns: Vec::new(),
+            version_retention: Default::default(),
+        };
+        let paths = |index: &mut FileIndex| -> Result<Vec<String>, FileIndexError> {
+            Ok(index.entries_below("")?.into_iter().map(|entry| entry.path).collect())
+        };
+
+        let indexed = with_file_index(&linked_path, |index| {
+            index.refresh()?;
+            paths(index)
+        })
+        .unwrap();
+        assert!(indexed.contains(&"docs".to_string()) && indexed.contains(&"docs/a.txt".to_string()));
+        assert!(!indexed.iter().any(|path| path.ends_with(".log") || path.ends_with(PART_FILE_EXTENSION)));
+        let entry = with_file_index(&linked_path, |index| index.entry("docs/a.txt")).unwrap().unwrap();
+        assert_eq!(entry.hash, Some(compute_file_hash(&root.join("docs/a.txt")).unwrap()));
+
+        // Changed rules bring ignored files in
+        fs::write(root.join(IGNORE_FILE_NAME), "").unwrap();
+        let indexed = with_file_index(&linked_path, |index| {
+            index.refresh()?;
+            paths(index)
+        })
+        .unwrap();
+        assert!(indexed.contains(&"docs/b.log".to_string()));
+
+        fs::remove_dir_all(root.join("docs")).unwrap();
+        let indexed = with_file_index(&linked_path, |index| {
+            index.update_path("docs")?;
+            paths(index)
+        })
+        .unwrap();
+        assert!(!indexed.iter().any(|path| path.starts_with("docs")));
+
+        close_file_index(&linked_path);
+        fs::remove_dir_all(&root).unwrap();
+    }
+}
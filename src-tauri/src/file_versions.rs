@@ -0,0 +1,397 @@
+use crate::file_hash::{compute_file_hash, file_hash};
+use crate::file_index::index_path;
+use crate::local_dir::{read_private_linked_paths, PART_FILE_EXTENSION, TOPAZ_DIR_NAME};
+use crate::types::{IndexedFile, LinkedPath, StoredVersion, VersionDiff, VersionReason, VersionRetention, VersionStoreError};
+use std::collections::{HashMap, HashSet};
+use std::fs::{self, Metadata};
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex as StdMutex;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+// Store in each linked path's Topaz directory holding the content of replaced and
+// deleted files by hash, next to a manifest listing the versions
+const VERSIONS_DIR_NAME: &str = "versions";
+const MANIFEST_FILE_NAME: &str = "manifest.json";
+const SECONDS_PER_DAY: u64 = 24 * 60 * 60;
+
+// Manifests are read, changed and written back under this lock
+lazy_static::lazy_static! {
+    static ref VERSION_STORE_LOCK: StdMutex<()> = StdMutex::new(());
+}
+
+pub fn version_store_dir(linked_path: &LinkedPath) -> PathBuf {
+    linked_path.path.join(TOPAZ_DIR_NAME).join(VERSIONS_DIR_NAME)
+}
+
+fn local_file_path(root: &Path, path: &str) -> PathBuf {
+    path.split('/').fold(root.to_path_buf(), |local_path, segment| local_path.join(segment))
+}
+
+fn unix_secs(time: SystemTime) -> u64 {
+    time.duration_since(UNIX_EPOCH)
+        .map(|duration| duration.as_secs())
+        .unwrap_or(0)
+}
+
+fn modified_secs(metadata: &Metadata) -> u64 {
+    metadata.modified().map(unix_secs).unwrap_or(0)
+}
+
+fn read_manifest(store_dir: &Path) -> Result<Vec<StoredVersion>, VersionStoreError> {
+    match fs::read_to_string(store_dir.join(MANIFEST_FILE_NAME)) {
+        Ok(data) => Ok(serde_json::from_str(&data)?),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(Vec::new()),
+        Err(e) => Err(e.into()),
+    }
+}
+
+fn write_manifest(store_dir: &Path, versions: &[StoredVersion]) -> Result<(), VersionStoreError> {
+    let manifest_path = store_dir.join(MANIFEST_FILE_NAME);
+    let temp_path = manifest_path.with_extension(PART_FILE_EXTENSION);
+    fs::write(&temp_path, serde_json::to_string_pretty(versions)?)?;
+    fs::rename(&temp_path, &manifest_path)?;
+    Ok(())
+}
+
+fn part_path(path: &Path) -> PathBuf {
+    let mut part_name = path.file_name().unwrap_or_default().to_os_string();
+    part_name.push(".");
+    part_name.push(PART_FILE_EXTENSION);
+    path.with_file_name(part_name)
+}
+
+// Copy next to the destination first so it never holds half a file
+fn copy_atomically(from: &Path, to: &Path) -> io::Result<()> {
+    let part_path = part_path(to);
+    fs::copy(from, &part_path)?;
+    fs::rename(&part_path, to)
+}
+
+// Drop versions past the retention limits, oldest first, then the content no version
+// refers to anymore. The version with ID `keep` was just stored and is never dropped, a
+// deleted file would otherwise be gone for good
+fn prune(
+    store_dir: &Path,
+    versions: &mut Vec<StoredVersion>,
+    retention: &VersionRetention,
+    keep: &str,
+) -> io::Result<()> {
+    versions.sort_by(|a, b| b.stored_at.cmp(&a.stored_at));
+
+    if let Some(max_age_days) = retention.max_age_days {
+        let oldest = unix_secs(SystemTime::now()).saturating_sub(max_age_days * SECONDS_PER_DAY);
+        versions.retain(|version| version.id == keep || version.stored_at >= oldest);
+    }
+
+    if let Some(max_versions) = retention.max_versions {
+        let mut counts: HashMap<String, usize> = HashMap::new();
+        versions.retain(|version| {
+            let count = counts.entry(version.path.clone()).or_insert(0);
+            *count += 1;
+            version.id == keep || *count <= max_versions
+        });
+    }
+
+    if let Some(max_bytes) = retention.max_bytes {
+        // Versions with the same content share it
+        let mut counted = HashSet::new();
+        let mut total = 0;
+        versions.retain(|version| {
+            if counted.insert(version.hash.clone()) {
+                total += version.size;
+            }
+            version.id == keep || total <= max_bytes
+        });
+    }
+
+    let referenced: HashSet<&str> = versions.iter().map(|version| version.hash.as_str()).collect();
+    for entry in fs::read_dir(store_dir)?.flatten() {
+        let name = entry.file_name();
+        let name = name.to_string_lossy();
+        if name != MANIFEST_FILE_NAME && !referenced.contains(name.as_ref()) {
+            fs::remove_file(entry.path())?;
+        }
+    }
+    Ok(())
+}
+
+// Keep the file at `relative_path` in the version store before it is replaced, or move
+// it there if it is being deleted. Returns None if there is no such file. Blocking
+fn store_version(
+    linked_path: &LinkedPath,
+    relative_path: &str,
+    reason: VersionReason,
+) -> Result<Option<StoredVersion>, VersionStoreError> {
+    let file_path = local_file_path(&linked_path.path, relative_path);
+    let metadata = match fs::metadata(&file_path) {
+        Ok(metadata) if metadata.is_file() => metadata,
+        Ok(_) => return Ok(None),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+        Err(e) => return Err(e.into()),
+    };
+
+    let _lock = VERSION_STORE_LOCK.lock().unwrap();
+    let store_dir = version_store_dir(linked_path);
+    fs::create_dir_all(&store_dir)?;
+    let hash = compute_file_hash(&file_path)?;
+    let content_path = store_dir.join(&hash);
+    if content_path.exists() {
+        if reason == VersionReason::Deleted {
+            fs::remove_file(&file_path)?;
+        }
+    } else if reason == VersionReason::Deleted {
+        // Linked paths hold their Topaz directory, so this is on the same file system
+        fs::rename(&file_path, &content_path)?;
+    } else {
+        copy_atomically(&file_path, &content_path)?;
+    }
+
+    let version = StoredVersion {
+        id: uuid::Uuid::new_v4().to_string(),
+        path: relative_path.to_string(),
+        hash,
+        size: metadata.len(),
+        modified: modified_secs(&metadata),
+        stored_at: unix_secs(SystemTime::now()),
+        reason,
+    };
+    let mut versions = read_manifest(&store_dir)?;
+    versions.push(version.clone());
+    prune(&store_dir, &mut versions, &linked_path.version_retention, &version.id)?;
+    write_manifest(&store_dir, &versions)?;
+    Ok(Some(version))
+}
+
+// Keep the file at `relative_path` before an upload or a sync replaces it
+pub async fn keep_replaced_file(linked_path: &LinkedPath, relative_path: String) -> Result<(), VersionStoreError> {
+    let linked_path = linked_path.clone();
+    tokio::task::spawn_blocking(move || store_version(&linked_path, &relative_path, VersionReason::Overwritten))
+        .await
+        .map_err(|e| VersionStoreError::Io(io::Error::other(e)))??;
+    Ok(())
+}
+
+// Delete the file at `relative_path` into the trash. Returns whether there was a file
+pub async fn trash_file(linked_path: &LinkedPath, relative_path: String) -> Result<bool, VersionStoreError> {
+    let linked_path = linked_path.clone();
+    let version =
+        tokio::task::spawn_blocking(move || store_version(&linked_path, &relative_path, VersionReason::Deleted))
+            .await
+            .map_err(|e| VersionStoreError::Io(io::Error::other(e)))??;
+    Ok(version.is_some())
+}
+
+fn find_linked_path(linked_path_name: &str) -> Result<LinkedPath, String> {
+    read_private_linked_paths()
+        .map_err(|e| e.to_string())?
+        .into_iter()
+        .find(|linked_path| linked_path.name == linked_path_name)
+        .ok_or_else(|| format!("No linked path named '{}'", linked_path_name))
+}
+
+fn find_version(linked_path: &LinkedPath, version_id: &str) -> Result<StoredVersion, VersionStoreError> {
+    read_manifest(&version_store_dir(linked_path))?
+        .into_iter()
+        .find(|version| version.id == version_id)
+        .ok_or_else(|| VersionStoreError::NotFound(version_id.to_string()))
+}
+
+// Metadata of the file currently at a path, None if there is none
+fn current_file(linked_path: &LinkedPath, relative_path: &str) -> io::Result<Option<IndexedFile>> {
+    let file_path = local_file_path(&linked_path.path, relative_path);
+    let metadata = match fs::metadata(&file_path) {
+        Ok(metadata) if metadata.is_file() => metadata,
+        Ok(_) => return Ok(None),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+        Err(e) => return Err(e),
+    };
+    Ok(Some(IndexedFile {
+        path: relative_path.to_string(),
+        hash: file_hash(&file_path)?,
+        size: metadata.len(),
+        modified: modified_secs(&metadata),
+    }))
+}
+
+fn diff_version(linked_path: &LinkedPath, version_id: &str) -> Result<VersionDiff, VersionStoreError> {
+    let version = find_version(linked_path, version_id)?;
+    let current = current_file(linked_path, &version.path)?;
+    let (same_content, size_change, modified_change) = match &current {
+        Some(current) => (
+            current.hash == version.hash,
+            current.size as i64 - version.size as i64,
+            current.modified as i64 - version.modified as i64,
+        ),
+        None => (false, 0, 0),
+    };
+    Ok(VersionDiff {
+        version,
+        current,
+        same_content,
+        size_change,
+        modified_change,
+    })
+}
+
+// Put a stored version back at its path, keeping the file it replaces as a version
+fn restore_version(linked_path: &LinkedPath, version_id: &str) -> Result<StoredVersion, VersionStoreError> {
+    let version = find_version(linked_path, version_id)?;
+    let file_path = local_file_path(&linked_path.path, &version.path);
+    if current_file(linked_path, &version.path)?.is_some_and(|current| current.hash == version.hash) {
+        return Ok(version);
+    }
+    if let Some(parent) = file_path.parent() {
+        fs::create_dir_all(parent)?;
+    }
+    // Storing the replaced file may prune the version being restored, so its content
+    // is taken out first
+    let restored_path = part_path(&file_path);
+    {
+        let _lock = VERSION_STORE_LOCK.lock().unwrap();
+        fs::copy(version_store_dir(linked_path).join(&version.hash), &restored_path)?;
+    }
+    if let Err(e) = store_version(linked_path, &version.path, VersionReason::Restored) {
+        let _ = fs::remove_file(&restored_path);
+        return Err(e);
+    }
+    fs::rename(&restored_path, &file_path)?;
+    Ok(version)
+}
+
+// Stored versions of a linked path, of one file if `path` is given, newest first
+#[tauri::command]
+pub async fn list_file_versions(linked_path_name: String, path: Option<String>) -> Result<Vec<StoredVersion>, String> {
+    let linked_path = find_linked_path(&linked_path_name)?;
+    let mut versions = tokio::task::spawn_blocking(move || read_manifest(&version_store_dir(&linked_path)))
+        .await
+        .map_err(|e| e.to_string())?
+        .map_err(|e| e.to_string())?;
+    if let Some(path) = path {
+        versions.retain(|version| version.path == path);
+    }
+    versions.sort_by(|a, b| b.stored_at.cmp(&a.stored_at));
+    Ok(versions)
+}
+
+// Deleted files that haven't come back since, newest first. A file deleted more than
+// once is listed with its last deleted version, older ones stay in its version list
+#[tauri::command]
+pub async fn list_trash(linked_path_name: String) -> Result<Vec<StoredVersion>, String> {
+    let linked_path = find_linked_path(&linked_path_name)?;
+    let root = linked_path.path.clone();
+    let versions = list_file_versions(linked_path_name, None).await?;
+    let mut listed = HashSet::new();
+    Ok(versions
+        .into_iter()
+        .filter(|version| version.reason == VersionReason::Deleted)
+        .filter(|version| !local_file_path(&root, &version.path).exists())
+        .filter(|version| listed.insert(version.path.clone()))
+        .collect())
+}
+
+#[tauri::command]
+pub async fn diff_file_version(linked_path_name: String, version_id: String) -> Result<VersionDiff, String> {
+    let linked_path = find_linked_path(&linked_path_name)?;
+    tokio::task::spawn_blocking(move || diff_version(&linked_path, &version_id))
+        .await
+        .map_err(|e| e.to_string())?
+        .map_err(|e| e.to_string())
+}
+
+#[tauri::command]
+pub async fn restore_file_version(linked_path_name: String, version_id: String) -> Result<String, String> {
+    let linked_path = find_linked_path(&linked_path_name)?;
+    let restoring = linked_path.clone();
+    let version = tokio::task::spawn_blocking(move || restore_version(&restoring, &version_id))
+        .await
+        .map_err(|e| e.to_string())?
+        .map_err(|e| e.to_string())?;
+    index_path(linked_path, version.path.clone()).await;
+    Ok(format!("Restored '{}'.", version.path))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const DAY: u64 = SECONDS_PER_DAY;
+
+    fn version(id: &str, path: &str, hash: &str, size: u64, stored_at: u64) -> StoredVersion {
+        StoredVersion {
+            id: id.to_string(),
+            path: path.to_string(),
+            hash: hash.to_string(),
+            size,
+            modified: stored_at,
+            stored_at,
+            reason: VersionReason::Overwritten,
+        }
+    }
+
+    fn retention(max_versions: Option<usize>, max_age_days: Option<u64>, max_bytes: Option<u64>) -> VersionRetention {
+        VersionRetention { max_versions, max_age_days, max_bytes }
+    }
+
+    // Prune `versions` in a store holding their content, returning the IDs left newest
+    // first and the content left in the store
+    fn pruned(versions: &[StoredVersion], retention: &VersionRetention, keep: &str) -> (Vec<String>, Vec<String>) {
+        let store_dir = std::env::temp_dir().join(format!("topaz-versions-{}", uuid::Uuid::new_v4()));
+        fs::create_dir_all(&store_dir).unwrap();
+        write_manifest(&store_dir, versions).unwrap();
+        for version in versions {
+            fs::write(store_dir.join(&version.hash), vec![0u8; version.size as usize]).unwrap();
+        }
+        let mut versions = versions.to_vec();
+        prune(&store_dir, &mut versions, retention, keep).unwrap();
+        let mut contents: Vec<String> = fs::read_dir(&store_dir)
+            .unwrap()
+            .flatten()
+            .map(|entry| entry.file_name().to_string_lossy().into_owned())
+            .filter(|name| name != MANIFEST_FILE_NAME)
+            .collect();
+        contents.sort();
+        fs::remove_dir_all(&store_dir).unwrap();
+        (versions.into_iter().map(|version| version.id).collect(), contents)
+    }
+
+    #[test]
+    fn max_versions_applies_per_file() {
+        let versions = [
+            version("a1", "a", "h1", 1, 100),
+            version("a2", "a", "h2", 1, 200),
+            version("a3", "a", "h3", 1, 300),
+            version("b1", "b", "h4", 1, 150),
+        ];
+        let (kept, contents) = pruned(&versions, &retention(Some(2), None, None), "a3");
+        assert_eq!(kept, ["a3", "a2", "b1"]);
+        assert_eq!(contents, ["h2", "h3", "h4"]);
+    }
+
+    #[test]
+    fn old_versions_expire_but_the_one_just_stored_stays() {
+        let now = unix_secs(SystemTime::now());
+        let versions = [
+            version("old", "a", "h1", 1, now - 10 * DAY),
+            version("recent", "a", "h2", 1, now - DAY),
+            version("stored", "b", "h3", 1, now - 30 * DAY),
+        ];
+        let (kept, contents) = pruned(&versions, &retention(None, Some(7), None), "stored");
+        assert_eq!(kept, ["recent", "stored"]);
+        assert_eq!(contents, ["h2", "h3"]);
+    }
+
+    #[test]
+    fn shared_content_counts_once_towards_max_bytes() {
+        let versions = [
+            version("v1", "a", "big", 60, 100),
+            version("v2", "b", "big", 60, 200),
+            version("v3", "c", "small", 30, 300),
+            version("v4", "d", "other", 30, 50),
+        ];
+        // 30 for `small` and 60 for `big` fit, `other` goes over
+        let (kept, contents) = pruned(&versions, &retention(None, None, Some(100)), "v3");
+        assert_eq!(kept, ["v3", "v2", "v1"]);
+        assert_eq!(contents, ["big", "small"]);
+    }
+}
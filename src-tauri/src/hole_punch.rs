@@ -0,0 +1,287 @@
+use crate::relay_tunnel::{decode_secret, room_id, TunnelStream};
+use crate::types::{RelayError, RelaySettings};
+use crate::quic_transport::{self, bind_udp, hand_over_streams, SERVER_NAME};
+use hmac::{Hmac, Mac};
+use quinn::{Connection, Endpoint, Incoming, RecvStream};
+use sha2::Sha256;
+use std::net::SocketAddr;
+use std::time::Duration;
+use tokio::io::{AsyncBufReadExt, BufReader, Lines};
+use tokio::sync::mpsc;
+
+// How long a client tries to reach a host directly before settling for the relay
+const PUNCH_TIMEOUT: Duration = Duration::from_secs(5);
+// How long a host waits before connecting to the rendezvous service again after losing it
+const RENDEZVOUS_RECONNECT_INTERVAL: Duration = Duration::from_secs(5);
+// Labels of the keying material each side proves it holds the network secret with
+const HOST_PROOF_LABEL: &[u8] = b"EXPORTER-topaz-host";
+const CLIENT_PROOF_LABEL: &[u8] = b"EXPORTER-topaz-client";
+
+fn quic_error(e: impl std::fmt::Display) -> RelayError {
+    RelayError::Quic(e.to_string())
+}
+
+// Endpoint on a fresh UDP port, over IPv4 like the NATs it punches through
+fn endpoint(accept_connections: bool) -> Result<Endpoint, RelayError> {
+    let socket = bind_udp(SocketAddr::from(([0, 0, 0, 0], 0)))?;
+    quic_transport::endpoint(socket, accept_connections).map_err(quic_error)
+}
+
+// Rendezvous services are reached over IPv4, like the endpoints punching through NATs
+async fn resolve(rendezvous: &str) -> Result<SocketAddr, RelayError> {
+    tokio::net::lookup_host(rendezvous)
+        .await?
+        .find(SocketAddr::is_ipv4)
+        .ok_or_else(|| RelayError::Settings(format!("'{}' has no IPv4 address", rendezvous)))
+}
+
+// MAC over keying material only this TLS session has, keyed with the network secret. A
+// peer in the middle would have two sessions, so the proofs it passes on don't match
+fn proof(connection: &Connection, secret: &[u8; 32], label: &[u8]) -> Result<Hmac<Sha256>, RelayError> {
+    let mut keying_material = [0u8; 32];
+    connection
+        .export_keying_material(&mut keying_material, label, &[])
+        .map_err(|_| RelayError::Handshake("failed to export keying material".to_string()))?;
+    let mut mac = Hmac::<Sha256>::new_from_slice(secret).expect("HMAC takes keys of any length");
+    mac.update(&keying_material);
+    Ok(mac)
+}
+
+async fn read_proof(recv: &mut RecvStream) -> Result<[u8; 32], RelayError> {
+    let mut received = [0u8; 32];
+    recv.read_exact(&mut received).await.map_err(quic_error)?;
+    Ok(received)
+}
+
+// Send a request to the rendezvous service and read its answer, `<kind> <value>`
+async fn rendezvous_request(
+    connection: &Connection,
+    request: String,
+) -> Result<(String, Lines<BufReader<RecvStream>>), RelayError> {
+    let (mut send, recv) = connection.open_bi().await.map_err(quic_error)?;
+    send.write_all(format!("{}\n", request).as_bytes()).await.map_err(quic_error)?;
+    let mut lines = BufReader::new(recv).lines();
+    let answer = lines.next_line().await?.ok_or(RelayError::Closed)?;
+    match answer.split_once(' ') {
+        Some(("error", message)) => Err(RelayError::Quic(format!("rendezvous service: {}", message))),
+        Some((_, value)) => Ok((value.to_string(), lines)),
+        None => Err(RelayError::Quic(format!("unexpected answer '{}'", answer))),
+    }
+}
+
+// Register in the network's room, keeping the connection open for clients to be announced on
+async fn wait_at_rendezvous(
+    endpoint: &Endpoint,
+    rendezvous: SocketAddr,
+    room: &str,
+) -> Result<(Connection, Lines<BufReader<RecvStream>>), RelayError> {
+    let connection = endpoint
+        .connect(rendezvous, SERVER_NAME)
+        .map_err(quic_error)?
+        .await
+        .map_err(quic_error)?;
+    let (observed, lines) = rendezvous_request(&connection, format!("host {}", room)).await?;
+    println!("Clients can punch through to this device at {}", observed);
+    Ok((connection, lines))
+}
+
+// Check a client that reached the host directly, then hand each stream it opens to the server
+async fn serve_peer(
+    incoming: Incoming,
+    secret: [u8; 32],
+    streams: mpsc::Sender<TunnelStream>,
+) -> Result<(), RelayError> {
+    let connection = incoming.await.map_err(quic_error)?;
+    let (mut send, mut recv) = connection.accept_bi().await.map_err(quic_error)?;
+    let client_proof = read_proof(&mut recv).await?;
+    if proof(&connection, &secret, CLIENT_PROOF_LABEL)?.verify_slice(&client_proof).is_err() {
+        connection.close(1u32.into(), b"wrong secret");
+        return Err(RelayError::Handshake("the other side has a different secret".to_string()));
+    }
+    let host_proof = proof(&connection, &secret, HOST_PROOF_LABEL)?.finalize().into_bytes();
+    send.write_all(&host_proof).await.map_err(quic_error)?;
+    send.finish().map_err(quic_error)?;
+    println!("Peer at {} connected directly", connection.remote_address());
+    hand_over_streams(connection, streams).await;
+    Ok(())
+}
+
+// Let clients reach this device directly: wait at the rendezvous service and punch a hole
+// towards every client it announces, until `streams` is closed. Streams of clients that
+// prove they hold the network secret are handed to `streams`
+pub async fn listen_for_punched(
+    settings: &RelaySettings,
+    rendezvous: &str,
+    streams: mpsc::Sender<TunnelStream>,
+) -> Result<(), RelayError> {
+    let secret = decode_secret(&settings.secret)?;
+    let room = room_id(&secret);
+    let rendezvous_addr = resolve(rendezvous).await?;
+    let endpoint = endpoint(true)?;
+    let (mut rendezvous_connection, mut announcements) = wait_at_rendezvous(&endpoint, rendezvous_addr, &room).await?;
+
+    let accepting = endpoint.clone();
+    let accepted_streams = streams.clone();
+    tokio::spawn(async move {
+        loop {
+            tokio::select! {
+                _ = accepted_streams.closed() => break,
+                incoming = accepting.accept() => {
+                    let Some(incoming) = incoming else { break };
+                    let streams = accepted_streams.clone();
+                    tokio::spawn(async move {
+                        if let Err(e) = serve_peer(incoming, secret, streams).await {
+                            eprintln!("Direct connection failed: {}", e);
+                        }
+                    });
+                }
+            }
+        }
+        accepting.close(0u32.into(), b"");
+    });
+
+    tokio::spawn(async move {
+        loop {
+            tokio::select! {
+                _ = streams.closed() => break,
+                announcement = announcements.next_line() => match announcement {
+                    Ok(Some(line)) => {
+                        let Some(client) = line.strip_prefix("client ").and_then(|addr| addr.parse().ok()) else {
+                            continue;
+                        };
+                        // The client isn't accepting connections, but sending to it opens
+                        // this side's NAT for its packets
+                        if let Ok(connecting) = endpoint.connect(client, SERVER_NAME) {
+                            tokio::spawn(tokio::time::timeout(PUNCH_TIMEOUT, connecting));
+                        }
+                    }
+                    _ => {
+                        eprintln!("Lost connection to rendezvous service {}", rendezvous_addr);
+                        (rendezvous_connection, announcements) = loop {
+                            tokio::select! {
+                                _ = streams.closed() => return,
+                                _ = tokio::time::sleep(RENDEZVOUS_RECONNECT_INTERVAL) => {}
+                            }
+                            match wait_at_rendezvous(&endpoint, rendezvous_addr, &room).await {
+                                Ok(reconnected) => break reconnected,
+                                Err(e) => eprintln!("Failed to reconnect to rendezvous service: {}", e),
+                            }
+                        };
+                    }
+                },
+            }
+        }
+        rendezvous_connection.close(0u32.into(), b"");
+    });
+    Ok(())
+}
+
+// Reach the host of a network directly through a hole punched with the rendezvous
+// service's help. Fails if either NAT maps addresses per destination
+pub async fn punch_to_host(settings: &RelaySettings, rendezvous: &str) -> Result<Connection, RelayError> {
+    let secret = decode_secret(&settings.secret)?;
+    let room = room_id(&secret);
+    let endpoint = endpoint(false)?;
+    let rendezvous_connection = endpoint
+        .connect(resolve(rendezvous).await?, SERVER_NAME)
+        .map_err(quic_error)?
+        .await
+        .map_err(quic_error)?;
+    let (host, _) = rendezvous_request(&rendezvous_connection, format!("connect {}", room)).await?;
+    rendezvous_connection.close(0u32.into(), b"");
+    let host: SocketAddr = host
+        .parse()
+        .map_err(|_| RelayError::Quic(format!("invalid host address '{}'", host)))?;
+    connect_punched(&endpoint, host, &secret).await
+}
+
+// Connect to a host announced by the rendezvous service and exchange proofs of the secret
+async fn connect_punched(endpoint: &Endpoint, host: SocketAddr, secret: &[u8; 32]) -> Result<Connection, RelayError> {
+    // The first packets may arrive before the host opened its NAT, they are sent again
+    let connecting = endpoint.connect(host, SERVER_NAME).map_err(quic_error)?;
+    let connection = tokio::time::timeout(PUNCH_TIMEOUT, connecting)
+        .await
+        .map_err(|_| RelayError::Quic(format!("no direct route to {}", host)))?
+        .map_err(quic_error)?;
+
+    let (mut send, mut recv) = connection.open_bi().await.map_err(quic_error)?;
+    let client_proof = proof(&connection, secret, CLIENT_PROOF_LABEL)?.finalize().into_bytes();
+    send.write_all(&client_proof).await.map_err(quic_error)?;
+    send.finish().map_err(quic_error)?;
+    let host_proof = read_proof(&mut recv)
+        .await
+        .map_err(|_| RelayError::Handshake("the other side has a different secret".to_string()))?;
+    if proof(&connection, secret, HOST_PROOF_LABEL)?.verify_slice(&host_proof).is_err() {
+        connection.close(1u32.into(), b"wrong secret");
+        return Err(RelayError::Handshake("the other side has a different secret".to_string()));
+    }
+    println!("Connected directly to {}", host);
+    Ok(connection)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::relay_tunnel::generate_relay_secret;
+    use tokio::io::AsyncReadExt;
+
+    // Rendezvous service on a free UDP port on localhost, returning its address
+    async fn start_rendezvous() -> String {
+        let addr = std::net::UdpSocket::bind("127.0.0.1:0").unwrap().local_addr().unwrap();
+        tokio::spawn(topaz_relay::rendezvous::run_rendezvous(addr));
+        // Let the service bind the port before anyone sends to it
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        addr.to_string()
+    }
+
+    // Host waiting at the rendezvous service, with the streams of clients that reach it
+    async fn start_host(rendezvous: &str) -> (RelaySettings, mpsc::Receiver<TunnelStream>) {
+        let settings = RelaySettings {
+            url: "ws://127.0.0.1:9".to_string(),
+            secret: generate_relay_secret(),
+            rendezvous: Some(rendezvous.to_string()),
+        };
+        let (streams, received) = mpsc::channel(4);
+        listen_for_punched(&settings, rendezvous, streams).await.unwrap();
+        (settings, received)
+    }
+
+    #[tokio::test]
+    async fn punched_connection_reaches_host() {
+        let rendezvous = start_rendezvous().await;
+        let (settings, mut received) = start_host(&rendezvous).await;
+
+        let connection = punch_to_host(&settings, &rendezvous).await.unwrap();
+        let (mut send, _recv) = connection.open_bi().await.unwrap();
+        send.write_all(b"ping").await.unwrap();
+        send.finish().unwrap();
+
+        let (mut stream, address) = received.recv().await.unwrap();
+        // Punched peers are told apart by their address
+        assert!(address.is_some_and(|address| address.ip().is_loopback()));
+        let mut message = [0u8; 4];
+        stream.read_exact(&mut message).await.unwrap();
+        assert_eq!(&message, b"ping");
+    }
+
+    #[tokio::test]
+    async fn proof_rejects_wrong_secret() {
+        let rendezvous = start_rendezvous().await;
+        let (settings, _received) = start_host(&rendezvous).await;
+
+        // Find the host through the room of its secret, then prove another one
+        let room = room_id(&decode_secret(&settings.secret).unwrap());
+        let endpoint = endpoint(false).unwrap();
+        let rendezvous_connection = endpoint
+            .connect(resolve(&rendezvous).await.unwrap(), SERVER_NAME)
+            .unwrap()
+            .await
+            .unwrap();
+        let (host, _) = rendezvous_request(&rendezvous_connection, format!("connect {}", room))
+            .await
+            .unwrap();
+        let wrong_secret = decode_secret(&generate_relay_secret()).unwrap();
+        let result = connect_punched(&endpoint, host.parse().unwrap(), &wrong_secret).await;
+        assert!(matches!(result, Err(RelayError::Handshake(_))));
+    }
+}
@@ -0,0 +1,126 @@
+use crate::local_dir::TOPAZ_DIR_NAME;
+use crate::types::LinkedPath;
+use ignore::gitignore::{Gitignore, GitignoreBuilder};
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex as StdMutex};
+use std::time::SystemTime;
+
+// Gitignore-syntax file at the root of a directory listing what is never watched,
+// served or synced
+pub const IGNORE_FILE_NAME: &str = ".topazignore";
+
+struct CachedRules {
+    patterns: Vec<String>,
+    modified: Option<SystemTime>,
+    matcher: Arc<Gitignore>,
+}
+
+// Parsed rules by root directory, rebuilt when the ignore file or the patterns change
+lazy_static::lazy_static! {
+    static ref IGNORE_RULES: StdMutex<HashMap<PathBuf, CachedRules>> = StdMutex::new(HashMap::new());
+}
+
+// What to leave out below a root directory. Topaz's own directory is always left out
+#[derive(Clone)]
+pub struct IgnoreRules {
+    root: PathBuf,
+    matcher: Arc<Gitignore>,
+}
+
+impl IgnoreRules {
+    // Rules of `root` from its `.topazignore` plus extra `patterns`
+    pub fn load(root: &Path, patterns: &[String]) -> Self {
+        let ignore_file = root.join(IGNORE_FILE_NAME);
+        let modified = ignore_file.metadata().and_then(|metadata| metadata.modified()).ok();
+
+        let mut cache = IGNORE_RULES.lock().unwrap();
+        if let Some(cached) = cache.get(root) {
+            if cached.modified == modified && cached.patterns == patterns {
+                return IgnoreRules {
+                    root: root.to_path_buf(),
+                    matcher: cached.matcher.clone(),
+                };
+            }
+        }
+
+        let mut builder = GitignoreBuilder::new(root);
+        if modified.is_some() {
+            if let Some(e) = builder.add(&ignore_file) {
+                eprintln!("Invalid rules in {}: {}", ignore_file.display(), e);
+            }
+        }
+        for pattern in patterns {
+            if let Err(e) = builder.add_line(None, pattern) {
+                eprintln!("Invalid ignore pattern '{}': {}", pattern, e);
+            }
+        }
+        let matcher = Arc::new(builder.build().unwrap_or_else(|e| {
+            eprintln!("Failed to build ignore rules for {}: {}", root.display(), e);
+            Gitignore::empty()
+        }));
+
+        cache.insert(
+            root.to_path_buf(),
+            CachedRules {
+                patterns: patterns.to_vec(),
+                modified,
+                matcher: matcher.clone(),
+            },
+        );
+        IgnoreRules {
+            root: root.to_path_buf(),
+            matcher,
+        }
+    }
+
+    pub fn for_linked_path(linked_path: &LinkedPath) -> Self {
+        Self::load(&linked_path.path, &linked_path.ignore_patterns)
+    }
+
+    // `path` is either below the root or relative to it. The root itself is never ignored
+    pub fn is_ignored(&self, path: &Path, is_dir: bool) -> bool {
+        let relative_path = path.strip_prefix(&self.root).unwrap_or(path);
+        if relative_path.as_os_str().is_empty() || relative_path.has_root() {
+            return false;
+        }
+        relative_path.starts_with(TOPAZ_DIR_NAME)
+            || self
+                .matcher
+                .matched_path_or_any_parents(relative_path, is_dir)
+                .is_ignore()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+
+    #[test]
+    fn rules_come_from_the_ignore_file_and_extra_patterns() {
+        let root = std::env::temp_dir().join(format!("topaz-ignore-{}", uuid::Uuid::new_v4()));
+        fs::create_dir_all(&root).unwrap();
+        fs::write(root.join(IGNORE_FILE_NAME), "*.log\n!keep.log\nbuild/\n").unwrap();
+
+        let rules = IgnoreRules::load(&root, &["/secret.txt".to_string()]);
+        assert!(rules.is_ignored(&root.join("logs/today.log"), false));
+        assert!(!rules.is_ignored(&root.join("logs/keep.log"), false));
+        // Everything below an ignored directory is ignored too
+        assert!(rules.is_ignored(Path::new("src/build/out.o"), false));
+        assert!(!rules.is_ignored(Path::new("build"), false));
+        assert!(rules.is_ignored(Path::new("secret.txt"), false));
+        assert!(!rules.is_ignored(Path::new("docs/secret.txt"), false));
+        assert!(rules.is_ignored(&root.join(TOPAZ_DIR_NAME).join("versions"), true));
+        assert!(!rules.is_ignored(&root, true));
+        assert!(!rules.is_ignored(Path::new("/elsewhere/today.log"), false));
+
+        // Other patterns for the same root replace the cached rules
+        let rules = IgnoreRules::load(&root, &[]);
+        assert!(!rules.is_ignored(Path::new("secret.txt"), false));
+        fs::remove_file(root.join(IGNORE_FILE_NAME)).unwrap();
+        let rules = IgnoreRules::load(&root, &[]);
+        assert!(!rules.is_ignored(Path::new("today.log"), false));
+        fs::remove_dir_all(&root).unwrap();
+    }
+}
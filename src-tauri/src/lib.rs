@@ -1,23 +1,61 @@
 // Modules
+mod access_log;
+mod bandwidth;
+mod chunk_store;
+#[cfg(feature = "content-search")]
+mod content_index;
+mod file_events;
+mod file_hash;
+mod file_index;
+mod file_versions;
+mod hole_punch;
+mod ignore_rules;
 mod local_dir;
+mod net_interfaces;
+mod network_crypto;
+mod peer_book;
+mod peer_discovery;
+mod quic_transport;
+mod relay_tunnel;
+mod search;
+mod server_guard;
 mod server_host;
+mod server_stats;
 mod server_client;
+mod server_upload;
+mod sync_engine;
 mod types;
+mod url_path;
+mod version_clock;
 
 // Uses
+use access_log::{get_access_log, write_access_logs};
 use local_dir::{
-    create_local_network, get_linked_paths, link_directory, read_private_networks, remove_network,
-    select_directory, setup_file_watcher, unlink_directory, PRIVATE_CONFIG_FILE_PATH,
+    create_local_network, get_bandwidth_limits, get_linked_paths, hash_saved_upload_tokens, import_network_keys,
+    link_directory, read_private_networks, remove_network, rotate_network_key, select_directory, run_file_watcher,
+    set_bandwidth_limits, set_network_bandwidth_limits, unlink_directory, PRIVATE_CONFIG_FILE_PATH,
+};
+use types::{FileWatcherShutdown, ShutdownServerMap, ServerIdState, SyncSessionMap};
+use file_events::{subscribe_file_events, subscribe_linked_path_changes};
+use file_index::{maintain_file_indexes, verify_linked_path};
+use file_versions::{diff_file_version, list_file_versions, list_trash, restore_file_version};
+use net_interfaces::list_interfaces;
+use peer_book::{check_peers_periodically, get_peers};
+use peer_discovery::{browse_peers, discover_peers};
+use relay_tunnel::{connect_relay, generate_relay_secret};
+use search::search_network;
+use server_host::{start_file_server_command, stop_file_server_command,get_servers, invalidate_caches_on_file_events};
+use server_stats::{get_server_stats, start_metrics_endpoint, stop_metrics_endpoint};
+use server_client::{download_host_archive, get_host_linked_paths, push_to_host};
+use sync_engine::{
+    get_sync_status, list_conflicts, resolve_conflict, resume_syncs, start_sync, stop_sync,
 };
-use types::{ShutdownServerMap, ServerIdState};
-use server_host::{start_file_server_command, stop_file_server_command,get_servers};
-use server_client::get_host_linked_paths;
 use serde_json::json;
 use std::fs::*;
 use std::io::prelude::*;
 use std::path::Path;
 use std::sync::Arc;
-use tokio::sync::broadcast;
+use tokio::sync::mpsc;
 use std::collections::HashMap;
 use tokio::sync::RwLock;
 use tauri::Manager;
@@ -27,12 +65,14 @@ use std::fs;
 pub fn run() {
     let shutdown_map: ShutdownServerMap = Arc::new(RwLock::new(HashMap::new()));
     let server_id_state = ServerIdState {server_id_counter: Arc::new(RwLock::new(0))} ;
+    let sync_sessions: SyncSessionMap = Arc::new(RwLock::new(HashMap::new()));
     tauri::Builder::default()
         .plugin(tauri_plugin_opener::init())
         .plugin(tauri_plugin_fs::init())
         .plugin(tauri_plugin_dialog::init())
         .manage(shutdown_map)
         .manage(server_id_state)
+        .manage(sync_sessions.clone())
         .invoke_handler(tauri::generate_handler![
             remove_network,
             select_directory,
@@ -42,9 +82,36 @@ pub fn run() {
             start_file_server_command,
             stop_file_server_command,
             get_servers,
+            get_server_stats,
+            start_metrics_endpoint,
+            stop_metrics_endpoint,
+            get_access_log,
             read_private_networks,
             create_local_network,
-            get_host_linked_paths
+            rotate_network_key,
+            import_network_keys,
+            set_network_bandwidth_limits,
+            get_bandwidth_limits,
+            set_bandwidth_limits,
+            get_host_linked_paths,
+            download_host_archive,
+            push_to_host,
+            start_sync,
+            stop_sync,
+            get_sync_status,
+            list_conflicts,
+            resolve_conflict,
+            verify_linked_path,
+            search_network,
+            list_file_versions,
+            list_trash,
+            diff_file_version,
+            restore_file_version,
+            discover_peers,
+            get_peers,
+            list_interfaces,
+            connect_relay,
+            generate_relay_secret
         ])
         .setup(|app| {
 
@@ -70,37 +137,42 @@ pub fn run() {
                 let json_content = serde_json::to_string_pretty(&data).expect("Failed to serialize local networks");
                 file.write_all(json_content.as_bytes()).expect("Failed to write to private_config file");
             }
+            if let Err(e) = hash_saved_upload_tokens() {
+                eprintln!("Failed to hash saved upload tokens: {}", e);
+            }
 
-            let app_handle_clone = app_handle.clone();
+            // Pick up sync sessions from the last run
+            tauri::async_runtime::spawn(resume_syncs(sync_sessions));
+            // Tell the frontend about hosts on the local network
+            tauri::async_runtime::spawn(browse_peers(app_handle.clone()));
+            // Keep the latency of every known peer address up to date
+            tauri::async_runtime::spawn(check_peers_periodically());
+            // Record who fetched what from served networks
+            tauri::async_runtime::spawn(write_access_logs());
+            // Keep served hashes and chunk lists in step with linked path contents
+            tauri::async_runtime::spawn(invalidate_caches_on_file_events());
+            // Subscribed before the file watcher starts so no linked path is missed
+            tauri::async_runtime::spawn(maintain_file_indexes(
+                subscribe_linked_path_changes(),
+                subscribe_file_events(),
+            ));
 
+            // Initialize the file watcher, stopped again when the app exits
+            let (watcher_shutdown_tx, watcher_shutdown_rx) = mpsc::channel(1);
+            app.manage(FileWatcherShutdown { tx: watcher_shutdown_tx });
             tauri::async_runtime::spawn(async move {
-                let (file_watcher_tx, _file_watcher_rx) = broadcast::channel(32);
-                let (priv_paths_file_change_tx, priv_paths_file_change_rx) =
-                    std::sync::mpsc::channel::<()>();
-                // Initialize the file watcher
-                if let Err(e) =
-                    setup_file_watcher(app_handle_clone, file_watcher_tx, priv_paths_file_change_tx)
-                        .await
-                {
+                if let Err(e) = run_file_watcher(app_handle, watcher_shutdown_rx).await {
                     eprintln!("Error setting up file watcher: {}", e);
                 }
-                loop {
-                    match priv_paths_file_change_rx.try_recv() {
-                        Ok(msg) => println!("Received: {:?}", msg),
-                        Err(std::sync::mpsc::TryRecvError::Empty) => {
-                            println!("No more messages yet");
-                            break;
-                        }
-                        Err(std::sync::mpsc::TryRecvError::Disconnected) => {
-                            println!("Channel closed");
-                            break;
-                        }
-                    }
-                }
             });
 
             Ok(())
         })
-        .run(tauri::generate_context!())
-        .expect("error while running tauri application");
+        .build(tauri::generate_context!())
+        .expect("error while building tauri application")
+        .run(|app_handle, event| {
+            if let tauri::RunEvent::Exit = event {
+                let _ = app_handle.state::<FileWatcherShutdown>().tx.try_send(());
+            }
+        });
 }
@@ -1,23 +1,40 @@
 //Uses
-use crate::types::{Error, FileError, LinkedPath, Network};
+use crate::bandwidth::invalidate_configured_limits;
+use crate::file_events::{file_change_events, found_file_events, publish_file_event, publish_linked_path_change};
+use crate::ignore_rules::{IgnoreRules, IGNORE_FILE_NAME};
+use crate::server_host::API_ROUTE_NAME;
+use crate::server_upload::hash_upload_token;
+use crate::types::{
+    BandwidthLimits, EncryptedStore, Error, FileChangeEvent, FileChangeKind, FileError, FileWatcherError, LinkedPath, LinkedPathChange,
+    Network, NetworkEncryption, RelaySettings, VersionRetention,
+};
+use crate::network_crypto::{network_cipher, new_network_key};
+use crate::server_client::reencrypt_host_files;
 use notify::RecommendedWatcher;
 use notify::Watcher;
-use notify_debouncer_full::{new_debouncer, Debouncer, FileIdMap};
+use notify_debouncer_full::{new_debouncer, DebounceEventResult, Debouncer, FileIdMap};
 use serde_json::Value;
-use std::collections::HashSet;
+use std::collections::{HashMap, HashSet};
 use std::fs;
 use std::fs::OpenOptions;
 use std::io::Write;
 use std::path::{Path, PathBuf};
-use std::sync::{Arc, Mutex as StdMutex};
+use std::sync::Arc;
 use tauri::{AppHandle, Emitter};
 use tauri_plugin_dialog::DialogExt;
 use tauri_plugin_dialog::FilePath;
 use tokio::sync::oneshot;
-use tokio::sync::{broadcast, Mutex};
+use tokio::sync::{mpsc, Mutex};
 use tokio::time::Duration;
 
 pub const PRIVATE_CONFIG_FILE_PATH: &str = "../configs/private_config.json";
+// Directory inside each linked path where Topaz keeps its own data. Never served or listed
+pub const TOPAZ_DIR_NAME: &str = ".topaz";
+// Extension of files that are still being downloaded
+pub const PART_FILE_EXTENSION: &str = "topaz-part";
+
+// Directories watched for each linked path, by linked path name
+type WatchedDirs = HashMap<String, HashSet<PathBuf>>;
 
 // Global variable to keep track of watched paths
 lazy_static::lazy_static! {
@@ -52,6 +69,20 @@ pub fn read_private_networks() -> Result<Vec<Network>, FileError> {
     }
 }
 
+// Identifier of this device, created on first use and kept in the private config
+pub fn device_id() -> String {
+    let mut json_value = read_private_config().unwrap();
+    if let Some(device_id) = json_value.get("device_id").and_then(Value::as_str) {
+        return device_id.to_string();
+    }
+    let device_id = uuid::Uuid::new_v4().to_string();
+    json_value["device_id"] = Value::String(device_id.clone());
+    if let Err(e) = write_json_to_file(&json_value) {
+        eprintln!("Failed to save device id: {}", e);
+    }
+    device_id
+}
+
 fn write_json_to_file(json_value: &Value) -> Result<(), FileError> {
     let mut file = OpenOptions::new()
         .write(true)
@@ -99,13 +130,24 @@ pub async fn select_directory(app: AppHandle) -> Result<Option<PathBuf>, Error>
 }
 
 #[tauri::command]
-pub fn link_directory(app: AppHandle, path: String, name: String) -> Result<String, FileError> {
+pub fn link_directory(
+    app: AppHandle,
+    path: String,
+    name: String,
+    writable: Option<bool>,
+    quota_bytes: Option<u64>,
+    ignore_patterns: Option<Vec<String>>,
+    version_retention: Option<VersionRetention>,
+) -> Result<String, FileError> {
     if name == "" {
         return Ok("Name your linked path".to_string());
     };
     if path == "" {
         return Ok("Directory not selected".to_string());
     };
+    if name == API_ROUTE_NAME {
+        return Ok(format!("'{}' is reserved, choose another name", API_ROUTE_NAME));
+    };
 
     let mut json_value = read_private_config()?;
 
@@ -119,6 +161,10 @@ pub fn link_directory(app: AppHandle, path: String, name: String) -> Result<Stri
         let new_linked_path = LinkedPath {
             name: name,
             path: PathBuf::from(path),
+            writable: writable.unwrap_or(false),
+            quota_bytes,
+            ignore_patterns: ignore_patterns.unwrap_or_default(),
+            version_retention: version_retention.unwrap_or_default(),
         };
         // Add the new path
         linked_paths.push(new_linked_path);
@@ -171,6 +217,10 @@ pub fn create_local_network(
     app: AppHandle,
     name: String,
     linked_paths: Vec<LinkedPath>,
+    upload_token: Option<String>,
+    interfaces: Option<Vec<String>>,
+    relay: Option<RelaySettings>,
+    encryption: Option<NetworkEncryption>,
 ) -> Result<String, FileError> {
     if name == "" {
         return Ok("Name your network".to_string());
@@ -187,6 +237,14 @@ pub fn create_local_network(
         let new_network = Network {
             name,
             linked_paths,
+            upload_token: None,
+            upload_token_hash: upload_token
+                .filter(|token| !token.is_empty())
+                .map(|token| hash_upload_token(&token)),
+            interfaces: interfaces.unwrap_or_default(),
+            relay,
+            encryption: encryption.filter(|encryption| !encryption.keys.is_empty()),
+            bandwidth_limits: None,
         };
         networks.push(new_network);
         *networks_value = serde_json::to_value(&networks)?;
@@ -204,6 +262,164 @@ pub fn create_local_network(
     Ok("Network created successfully".to_string())
 }
 
+// Replace upload tokens saved in plain text by earlier versions with their hashes
+pub fn hash_saved_upload_tokens() -> Result<(), FileError> {
+    let mut json_value = read_private_config()?;
+    let Some(networks_value) = json_value.get_mut("networks") else {
+        return Ok(());
+    };
+    let mut networks: Vec<Network> = serde_json::from_value(networks_value.clone())?;
+    if networks.iter().all(|network| network.upload_token.is_none()) {
+        return Ok(());
+    }
+    for network in &mut networks {
+        if let Some(token) = network.upload_token.take() {
+            network.upload_token_hash = Some(hash_upload_token(&token));
+        }
+    }
+    *networks_value = serde_json::to_value(&networks)?;
+    write_json_to_file(&json_value)
+}
+
+// Apply `update` to the network named `network_name` and save the config
+fn update_network(
+    app: &AppHandle,
+    network_name: &str,
+    update: impl FnOnce(&mut Network),
+) -> Result<bool, FileError> {
+    let mut json_value = read_private_config()?;
+    let Some(networks_value) = json_value.get_mut("networks") else {
+        return Err(FileError::MissingLinkedPathsError);
+    };
+    let mut networks: Vec<Network> = serde_json::from_value(networks_value.clone())?;
+    let Some(network) = networks.iter_mut().find(|network| network.name == network_name) else {
+        return Ok(false);
+    };
+    update(network);
+    *networks_value = serde_json::to_value(&networks)?;
+    write_json_to_file(&json_value)?;
+
+    if let Err(e) = app.emit("linked_paths_changed", ()) {
+        eprintln!("Failed to emit event to frontend: {}", e);
+    }
+    Ok(true)
+}
+
+// Add a new key to a network, turning encryption on if it had none, and rewrite what
+// `stores` hold under older keys with it. Older keys are dropped once every store is
+// rewritten, so after removing a device, rotate with all hosts holding the network's files
+// and share the new key with the remaining devices: the removed one can then read neither
+// what was stored before nor what is pushed later
+#[tauri::command]
+pub async fn rotate_network_key(
+    app: AppHandle,
+    network_name: String,
+    stores: Vec<EncryptedStore>,
+) -> Result<String, String> {
+    let mut key_id = 0;
+    let updated = update_network(&app, &network_name, |network| {
+        let encryption = network.encryption.get_or_insert_with(NetworkEncryption::default);
+        key_id = encryption.keys.iter().map(|key| key.id + 1).max().unwrap_or(1);
+        encryption.keys.push(new_network_key(key_id));
+    })
+    .map_err(|e| e.to_string())?;
+    if !updated {
+        return Ok(format!("No network named '{}'", network_name));
+    }
+
+    let cipher = network_cipher(&network_name)?
+        .map(Arc::new)
+        .ok_or_else(|| format!("Network '{}' has no keys", network_name))?;
+    let (mut reencrypted, mut failed) = (0, Vec::new());
+    for store in &stores {
+        let (store_reencrypted, store_failed) = reencrypt_host_files(store, &network_name, &cipher)
+            .await
+            .map_err(|e| {
+                format!("Network '{}' now encrypts with key {}, older keys are kept. {}", network_name, key_id, e)
+            })?;
+        reencrypted += store_reencrypted;
+        failed.extend(store_failed);
+    }
+    if !failed.is_empty() {
+        return Err(format!(
+            "Network '{}' now encrypts with key {}, older keys are kept for {} files that could not be re-encrypted: {}",
+            network_name,
+            key_id,
+            failed.len(),
+            failed.join(", ")
+        ));
+    }
+
+    update_network(&app, &network_name, |network| {
+        if let Some(encryption) = &mut network.encryption {
+            encryption.keys.retain(|key| key.id == key_id);
+        }
+    })
+    .map_err(|e| e.to_string())?;
+    Ok(format!(
+        "Network '{}' now encrypts with key {}, {} stored files re-encrypted and older keys dropped",
+        network_name, key_id, reencrypted
+    ))
+}
+
+// Take keys another device of the network shared. Keys already known are kept, so files
+// stored before a rotation stay readable
+#[tauri::command]
+pub fn import_network_keys(
+    app: AppHandle,
+    network_name: String,
+    encryption: NetworkEncryption,
+) -> Result<String, FileError> {
+    let updated = update_network(&app, &network_name, |network| {
+        let keys = &mut network.encryption.get_or_insert_with(NetworkEncryption::default).keys;
+        for key in encryption.keys {
+            keys.retain(|known| known.id != key.id);
+            keys.push(key);
+        }
+        // The newest key encrypts, wherever it came from
+        keys.sort_by_key(|key| key.id);
+    })?;
+    if !updated {
+        return Ok(format!("No network named '{}'", network_name));
+    }
+    Ok(format!("Keys of network '{}' imported", network_name))
+}
+
+// Limits for serving and downloading a network on top of the device-wide ones. Null
+// removes them
+#[tauri::command]
+pub fn set_network_bandwidth_limits(
+    app: AppHandle,
+    network_name: String,
+    limits: Option<BandwidthLimits>,
+) -> Result<String, FileError> {
+    let updated = update_network(&app, &network_name, |network| network.bandwidth_limits = limits)?;
+    if !updated {
+        return Ok(format!("No network named '{}'", network_name));
+    }
+    invalidate_configured_limits();
+    Ok(format!("Bandwidth limits of network '{}' updated", network_name))
+}
+
+// Limits for every transfer of this device
+#[tauri::command]
+pub fn get_bandwidth_limits() -> Result<BandwidthLimits, FileError> {
+    let json_value = read_private_config()?;
+    match json_value.get("bandwidth_limits") {
+        Some(limits) => Ok(serde_json::from_value(limits.clone())?),
+        None => Ok(BandwidthLimits::default()),
+    }
+}
+
+#[tauri::command]
+pub fn set_bandwidth_limits(limits: BandwidthLimits) -> Result<String, FileError> {
+    let mut json_value = read_private_config()?;
+    json_value["bandwidth_limits"] = serde_json::to_value(&limits)?;
+    write_json_to_file(&json_value)?;
+    invalidate_configured_limits();
+    Ok("Bandwidth limits updated".to_string())
+}
+
 #[tauri::command]
 pub fn remove_network(app: AppHandle, network_name: String) -> Result<String, String> {
     let mut json_value = read_private_config().unwrap();
@@ -237,101 +453,200 @@ pub fn get_linked_paths() -> Result<Vec<LinkedPath>, FileError> {
     Ok(linked_paths)
 }
 
-// Function to set up the file watcher
-pub async fn setup_file_watcher(
+// Watch the private config and the contents of every linked path. Runs until a
+// shutdown signal arrives on `shutdown_rx` or its sender is dropped
+pub async fn run_file_watcher(
     app_handle: AppHandle,
-    tx: broadcast::Sender<LinkedPath>,
-    priv_paths_file_change_tx: std::sync::mpsc::Sender<()>,
-) -> Result<(), Box<dyn std::error::Error>> {
-    // Create a channel to receive file events
-    let (notify_tx, notify_rx) = std::sync::mpsc::channel();
-
-    // Specify the generic parameters for Debouncer
-    let debouncer = new_debouncer(Duration::from_secs(1), None, notify_tx)?;
+    mut shutdown_rx: mpsc::Receiver<()>,
+) -> Result<(), FileWatcherError> {
+    // The debouncer calls back on its own thread, forward its events to this task
+    let (notify_tx, mut notify_rx) = mpsc::unbounded_channel();
+    let mut debouncer = new_debouncer(
+        Duration::from_secs(1),
+        None,
+        move |result: DebounceEventResult| {
+            let _ = notify_tx.send(result);
+        },
+    )
+    .map_err(|e| FileWatcherError::DebouncerCreationError(Box::new(e)))?;
     println!("Debouncer initialized");
 
-    // Wrap channels in Arc and Mutex for concurrent access
-    let tx = Arc::new(Mutex::new(tx));
-    let notify_rx = Arc::new(StdMutex::new(notify_rx));
-    let debouncer = Arc::new(StdMutex::new(debouncer));
-
-    // Spawn blocking task to handle file events
-    let tx_clone = Arc::clone(&tx);
-    let notify_rx_clone = Arc::clone(&notify_rx);
-    let debouncer_clone = Arc::clone(&debouncer);
-    let app_handle_clone_1 = app_handle.clone();
-    let app_handle_clone_2 = app_handle.clone();
-
-    tokio::task::spawn_blocking(move || {
-        println!("Starting blocking task to handle file events");
-        let notify_rx = notify_rx_clone.lock().unwrap();
-        println!("Acquired lock on notify_rx");
-
-        while let Ok(events) = notify_rx.recv() {
-            match events {
-                Ok(debounced_events) => {
-                    println!("Received debounced events");
-                    for debounced_event in debounced_events {
-                        for path in &debounced_event.paths {
-                            if let Ok(canonical_path) = path.canonicalize() {
-                                if canonical_path
-                                    == PathBuf::from(PRIVATE_CONFIG_FILE_PATH)
-                                        .canonicalize()
-                                        .unwrap()
-                                {
-                                    // File was changed, reload linked paths
-                                    let tx_clone = Arc::clone(&tx_clone);
-                                    let debouncer_clone = Arc::clone(&debouncer_clone);
-                                    println!("private_paths.json changed");
-                                    if let Err(e) = priv_paths_file_change_tx.send(()) {
-                                        eprintln!("Error sending file change signal: {}", e);
-                                    }
-                                    tokio::runtime::Runtime::new().unwrap().block_on(async {
-                                        if let Err(e) = handle_file_change(
-                                            app_handle_clone_1.clone(),
-                                            &debouncer_clone,
-                                            &tx_clone,
-                                        )
-                                        .await
-                                        {
-                                            eprintln!("Error handling file change: {}", e);
-                                        }
-                                    });
-                                }
-                            }
-                        }
-                    }
-                }
-                Err(errors) => {
+    let private_config_path = PathBuf::from(PRIVATE_CONFIG_FILE_PATH)
+        .canonicalize()
+        .map_err(|e| FileWatcherError::WatchError(Box::new(e)))?;
+    debouncer
+        .watcher()
+        .watch(&private_config_path, notify::RecursiveMode::NonRecursive)
+        .map_err(|e| FileWatcherError::WatchError(Box::new(e)))?;
+    println!("Started watching path: {:?}", PRIVATE_CONFIG_FILE_PATH);
+
+    // Initial load of paths and start watching them
+    let mut watched_dirs = WatchedDirs::new();
+    if let Err(e) = handle_file_change(&app_handle, &mut debouncer, &mut watched_dirs).await {
+        eprintln!("Error setting up file watcher: {}", e);
+    }
+
+    loop {
+        let debounced_events = tokio::select! {
+            _ = shutdown_rx.recv() => break,
+            result = notify_rx.recv() => match result {
+                Some(Ok(debounced_events)) => debounced_events,
+                Some(Err(errors)) => {
                     eprintln!("File watch error: {:?}", errors);
+                    continue;
+                }
+                None => break,
+            },
+        };
+
+        let linked_paths: Vec<LinkedPath> = WATCHED_LINKEDPATHS.lock().await.iter().cloned().collect();
+        let mut config_changed = false;
+        for debounced_event in debounced_events {
+            // Report changes to files inside linked paths
+            for file_event in file_change_events(&debounced_event.event, &linked_paths) {
+                let found = update_watched_dirs(&mut debouncer, &mut watched_dirs, &linked_paths, &file_event).await;
+                report_file_event(&app_handle, file_event);
+                for found_event in found_file_events(&found, &linked_paths) {
+                    report_file_event(&app_handle, found_event);
                 }
             }
+            config_changed |= debounced_event
+                .paths
+                .iter()
+                .any(|path| path.canonicalize().is_ok_and(|path| path == private_config_path));
         }
-    });
 
-    // Watch the VAULT_PATH initially
-    if let Err(e) = debouncer.lock().unwrap().watcher().watch(
-        Path::new(PRIVATE_CONFIG_FILE_PATH),
-        notify::RecursiveMode::NonRecursive,
-    ) {
-        eprintln!("Failed to watch path {}: {}", PRIVATE_CONFIG_FILE_PATH, e);
-    } else {
-        println!("Started watching path: {:?}", PRIVATE_CONFIG_FILE_PATH);
+        if config_changed {
+            // File was changed, reload linked paths
+            println!("private_config.json changed");
+            if let Err(e) = handle_file_change(&app_handle, &mut debouncer, &mut watched_dirs).await {
+                eprintln!("Error handling file change: {}", e);
+            }
+        }
     }
 
-    // Initial load of paths and start watching them
-    if let Err(e) = handle_file_change(app_handle_clone_2.clone(), &debouncer, &tx).await {
-        eprintln!("Error setting up file watcher: {}", e);
+    // Dropping the debouncer stops its thread and releases all watches
+    WATCHED_LINKEDPATHS.lock().await.clear();
+    println!("File watcher stopped");
+    Ok(())
+}
+
+// Entries of a directory and whether each is a directory. Blocking
+fn list_dir_entries(dir: &Path) -> Vec<(PathBuf, bool)> {
+    let Ok(entries) = fs::read_dir(dir) else {
+        return Vec::new();
+    };
+    entries
+        .flatten()
+        .filter_map(|entry| Some((entry.path(), entry.file_type().ok()?.is_dir())))
+        .collect()
+}
+
+// Watch a directory and every subdirectory that isn't ignored, returning everything
+// found below it. Each directory is watched before it is read, so anything created in it
+// meanwhile is either found or reported by the watcher. Linked paths are watched
+// directory by directory so ignored trees like `node_modules` cost no watches at all
+async fn watch_dir_tree(
+    debouncer: &mut Debouncer<RecommendedWatcher, FileIdMap>,
+    dirs: &mut HashSet<PathBuf>,
+    dir: &Path,
+    rules: &IgnoreRules,
+) -> Vec<PathBuf> {
+    let mut found = Vec::new();
+    let mut pending = vec![dir.to_path_buf()];
+    while let Some(dir) = pending.pop() {
+        if dirs.contains(&dir) || rules.is_ignored(&dir, true) {
+            continue;
+        }
+        if let Err(e) = debouncer
+            .watcher()
+            .watch(&dir, notify::RecursiveMode::NonRecursive)
+        {
+            eprintln!("Failed to watch path {}: {}", dir.display(), e);
+            continue;
+        }
+        dirs.insert(dir.clone());
+
+        let entries = tokio::task::spawn_blocking(move || list_dir_entries(&dir))
+            .await
+            .unwrap_or_default();
+        for (path, is_dir) in entries {
+            if is_dir {
+                pending.push(path.clone());
+            }
+            found.push(path);
+        }
     }
+    found
+}
 
-    Ok(())
+// Stop watching a directory and everything below it
+fn unwatch_dir_tree(
+    debouncer: &mut Debouncer<RecommendedWatcher, FileIdMap>,
+    dirs: &mut HashSet<PathBuf>,
+    dir: &Path,
+) {
+    dirs.retain(|watched_dir| {
+        if !watched_dir.starts_with(dir) {
+            return true;
+        }
+        // Deleted directories are already gone from the watcher
+        let _ = debouncer.watcher().unwatch(watched_dir);
+        false
+    });
 }
 
-// Handle changes to the files
+// Follow directories appearing, moving and disappearing inside a linked path, and
+// apply its ignore rules again when its `.topazignore` changes. Returns what was found in
+// directories that appeared
+async fn update_watched_dirs(
+    debouncer: &mut Debouncer<RecommendedWatcher, FileIdMap>,
+    watched_dirs: &mut WatchedDirs,
+    linked_paths: &[LinkedPath],
+    file_event: &FileChangeEvent,
+) -> Vec<PathBuf> {
+    let linked_path = linked_paths
+        .iter()
+        .find(|linked_path| linked_path.name == file_event.linked_path_name);
+    let (Some(linked_path), Some(dirs)) = (linked_path, watched_dirs.get_mut(&file_event.linked_path_name)) else {
+        return Vec::new();
+    };
+    let rules = IgnoreRules::for_linked_path(linked_path);
+
+    // The file index refreshes the whole linked path on new rules by itself
+    if file_event.relative_path == IGNORE_FILE_NAME {
+        println!("Ignore rules of {} changed", linked_path.name);
+        unwatch_dir_tree(debouncer, dirs, &linked_path.path);
+        watch_dir_tree(debouncer, dirs, &linked_path.path, &rules).await;
+        return Vec::new();
+    }
+    if let Some(previous_path) = &file_event.previous_path {
+        unwatch_dir_tree(debouncer, dirs, &linked_path.path.join(previous_path));
+    }
+    match file_event.kind {
+        FileChangeKind::Removed => {
+            unwatch_dir_tree(debouncer, dirs, &file_event.path);
+            Vec::new()
+        }
+        FileChangeKind::Created | FileChangeKind::Renamed if file_event.path.is_dir() => {
+            watch_dir_tree(debouncer, dirs, &file_event.path, &rules).await
+        }
+        _ => Vec::new(),
+    }
+}
+
+fn report_file_event(app_handle: &AppHandle, file_event: FileChangeEvent) {
+    if let Err(e) = app_handle.emit("linked_path_file_changed", &file_event) {
+        eprintln!("Failed to emit event to frontend: {}", e);
+    }
+    publish_file_event(file_event);
+}
+
+// Bring the watched linked paths in line with the private config
 async fn handle_file_change(
-    app_handle: AppHandle,
-    debouncer: &Arc<StdMutex<Debouncer<RecommendedWatcher, FileIdMap>>>,
-    tx: &Arc<Mutex<broadcast::Sender<LinkedPath>>>,
+    app_handle: &AppHandle,
+    debouncer: &mut Debouncer<RecommendedWatcher, FileIdMap>,
+    watched_dirs: &mut WatchedDirs,
 ) -> Result<(), Box<dyn std::error::Error>> {
     let linked_paths = read_private_linked_paths()?;
     let new_paths: HashSet<LinkedPath> = linked_paths.into_iter().collect();
@@ -349,46 +664,29 @@ async fn handle_file_change(
         .cloned()
         .collect();
 
-    // Add new paths to the watcher
-    for linked_path in &paths_to_add {
-        if let Err(e) = debouncer
-            .lock()
-            .unwrap()
-            .watcher()
-            .watch(&linked_path.path, notify::RecursiveMode::Recursive)
-        {
-            eprintln!("Failed to watch path {}: {}", linked_path.path.display(), e);
-        } else {
-            println!("Started watching path: {:?}", linked_path.path);
-        }
-        watched_linked_paths.insert(linked_path.clone());
-    }
-
-    // Remove paths from the watcher
+    // Remove paths from the watcher first, a changed linked path is removed and added again
     for linked_path in &paths_to_remove {
-        if let Err(e) = debouncer
-            .lock()
-            .unwrap()
-            .watcher()
-            .unwatch(&linked_path.path)
-        {
-            eprintln!(
-                "Failed to unwatch path {}: {}",
-                linked_path.path.display(),
-                e
-            );
-        } else {
-            println!("Stopped watching path: {:?}", linked_path.path);
+        if let Some(mut dirs) = watched_dirs.remove(&linked_path.name) {
+            unwatch_dir_tree(debouncer, &mut dirs, &linked_path.path);
         }
+        println!("Stopped watching path: {:?}", linked_path.path);
         watched_linked_paths.remove(linked_path);
+        publish_linked_path_change(LinkedPathChange::Removed(linked_path.clone()));
     }
 
-    // Notify about the changes
+    // Add new paths to the watcher
     for linked_path in &paths_to_add {
-        let tx = tx.lock().await;
-        if let Err(e) = tx.send(linked_path.clone()) {
-            eprintln!("Failed to send file change event: {}", e);
-        }
+        let mut dirs = HashSet::new();
+        let rules = IgnoreRules::for_linked_path(linked_path);
+        watch_dir_tree(debouncer, &mut dirs, &linked_path.path, &rules).await;
+        println!(
+            "Started watching path: {:?} ({} directories)",
+            linked_path.path,
+            dirs.len()
+        );
+        watched_dirs.insert(linked_path.name.clone(), dirs);
+        watched_linked_paths.insert(linked_path.clone());
+        publish_linked_path_change(LinkedPathChange::Added(linked_path.clone()));
     }
 
     // Emit event with updated paths
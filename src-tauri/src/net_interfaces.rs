@@ -0,0 +1,204 @@
+use crate::types::{Address, AddressKind, InterfaceAddress};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, SocketAddrV6};
+
+// Interface name prefixes of tunnels set up by VPN clients
+const VPN_INTERFACE_PREFIXES: &[&str] = &["tun", "tap", "wg", "utun", "ppp", "ipsec", "tailscale", "zt", "nordlynx"];
+// Interface name prefixes of bridges to containers and virtual machines
+const VIRTUAL_INTERFACE_PREFIXES: &[&str] = &[
+    "docker", "br-", "veth", "virbr", "vmnet", "vboxnet", "lxcbr", "lxdbr", "cni", "podman",
+];
+// Kinds a network is advertised on when it doesn't pick its interfaces
+const DEFAULT_ADVERTISED_KINDS: &[AddressKind] = &[
+    AddressKind::Lan,
+    AddressKind::Vpn,
+    AddressKind::Public,
+    AddressKind::Ipv6Global,
+    AddressKind::Ipv6UniqueLocal,
+];
+
+// 100.64.0.0/10, handed out by overlay VPNs such as Tailscale
+fn is_shared_address(ip: Ipv4Addr) -> bool {
+    ip.octets()[0] == 100 && (ip.octets()[1] & 0xc0) == 64
+}
+
+// fe80::/10, only meaningful together with the interface it is on
+fn is_ipv6_link_local(ip: Ipv6Addr) -> bool {
+    (ip.segments()[0] & 0xffc0) == 0xfe80
+}
+
+// Index of an interface, which scopes its IPv6 link-local addresses
+#[cfg(unix)]
+fn interface_index(name: &str) -> Option<u32> {
+    let name = std::ffi::CString::new(name).ok()?;
+    let index = unsafe { libc::if_nametoindex(name.as_ptr()) };
+    (index != 0).then_some(index)
+}
+
+#[cfg(not(unix))]
+fn interface_index(_name: &str) -> Option<u32> {
+    None
+}
+
+// Scope ID of an IPv6 zone given as an interface index or name
+pub fn zone_scope_id(zone: &str) -> Option<u32> {
+    zone.parse().ok().or_else(|| interface_index(zone))
+}
+
+pub fn classify_address(interface: Option<&str>, ip: IpAddr) -> AddressKind {
+    let named = |prefixes: &[&str]| interface.is_some_and(|name| prefixes.iter().any(|prefix| name.starts_with(prefix)));
+    match ip {
+        _ if ip.is_loopback() => AddressKind::Loopback,
+        IpAddr::V4(ip) if ip.is_link_local() => AddressKind::LinkLocal,
+        IpAddr::V6(ip) if is_ipv6_link_local(ip) => AddressKind::LinkLocal,
+        _ if named(VIRTUAL_INTERFACE_PREFIXES) => AddressKind::Virtual,
+        _ if named(VPN_INTERFACE_PREFIXES) => AddressKind::Vpn,
+        IpAddr::V4(ip) if is_shared_address(ip) => AddressKind::Vpn,
+        IpAddr::V4(ip) if ip.is_private() => AddressKind::Lan,
+        IpAddr::V4(_) => AddressKind::Public,
+        IpAddr::V6(ip) if (ip.segments()[0] & 0xfe00) == 0xfc00 => AddressKind::Ipv6UniqueLocal,
+        IpAddr::V6(_) => AddressKind::Ipv6Global,
+    }
+}
+
+// IPv6 addresses are put in brackets, with the interface as zone if they are link-local
+pub fn base_url(ip: IpAddr, port: u16, interface: Option<&str>) -> String {
+    match (ip, interface) {
+        (IpAddr::V6(ip), Some(interface)) if is_ipv6_link_local(ip) => {
+            format!("http://[{}%25{}]:{}", ip, interface, port)
+        }
+        _ => format!("http://{}", SocketAddr::new(ip, port)),
+    }
+}
+
+pub fn address(ip: IpAddr, port: u16, interface: Option<String>) -> Address {
+    Address {
+        ip: ip.to_string(),
+        port,
+        kind: Some(classify_address(interface.as_deref(), ip)),
+        url: base_url(ip, port, interface.as_deref()),
+        interface,
+    }
+}
+
+// Socket address to listen on an address with. Link-local IPv6 addresses need their
+// interface to be bound
+pub fn socket_addr(address: &Address) -> Option<SocketAddr> {
+    match address.ip.parse().ok()? {
+        IpAddr::V6(ip) if is_ipv6_link_local(ip) => {
+            let scope_id = interface_index(address.interface.as_deref()?)?;
+            Some(SocketAddr::V6(SocketAddrV6::new(ip, address.port, 0, scope_id)))
+        }
+        ip => Some(SocketAddr::new(ip, address.port)),
+    }
+}
+
+// get_if_addrs leaves out IPv6 link-local addresses, Linux lists them with their interface
+#[cfg(target_os = "linux")]
+fn ipv6_link_local_addresses() -> Vec<(String, IpAddr)> {
+    let Ok(data) = std::fs::read_to_string("/proc/net/if_inet6") else {
+        return Vec::new();
+    };
+    data.lines()
+        .filter_map(|line| {
+            let fields: Vec<&str> = line.split_whitespace().collect();
+            let (hex, interface) = (fields.first()?, fields.get(5)?);
+            let ip = Ipv6Addr::from(u128::from_str_radix(hex, 16).ok()?);
+            is_ipv6_link_local(ip).then(|| (interface.to_string(), IpAddr::V6(ip)))
+        })
+        .collect()
+}
+
+#[cfg(not(target_os = "linux"))]
+fn ipv6_link_local_addresses() -> Vec<(String, IpAddr)> {
+    Vec::new()
+}
+
+// Every address of every interface of this device
+pub fn interface_addresses() -> Vec<(String, IpAddr)> {
+    let mut addresses: Vec<(String, IpAddr)> = match get_if_addrs::get_if_addrs() {
+        Ok(interfaces) => interfaces
+            .into_iter()
+            .map(|interface| {
+                let ip = interface.ip();
+                (interface.name, ip)
+            })
+            .collect(),
+        Err(e) => {
+            eprintln!("Failed to list network interfaces: {}", e);
+            Vec::new()
+        }
+    };
+    addresses.extend(ipv6_link_local_addresses());
+    addresses
+}
+
+// Addresses a network is reachable at: those of its selected interfaces, or without a
+// selection those of the kinds worth advertising
+pub fn serving_addresses(selected_interfaces: &[String], port: u16) -> Vec<Address> {
+    let mut addresses: Vec<Address> = interface_addresses()
+        .into_iter()
+        .map(|(interface, ip)| address(ip, port, Some(interface)))
+        .filter(|address| match &address.interface {
+            Some(interface) if !selected_interfaces.is_empty() => selected_interfaces.contains(interface),
+            _ => address.kind.is_some_and(|kind| DEFAULT_ADVERTISED_KINDS.contains(&kind)),
+        })
+        .collect();
+    addresses.sort_by(|a, b| a.interface.cmp(&b.interface).then_with(|| a.ip.cmp(&b.ip)));
+    addresses
+}
+
+// Addresses of this device's interfaces, to pick the ones a network is served on
+#[tauri::command]
+pub fn list_interfaces() -> Vec<InterfaceAddress> {
+    let mut addresses: Vec<InterfaceAddress> = interface_addresses()
+        .into_iter()
+        .map(|(interface, ip)| InterfaceAddress {
+            kind: classify_address(Some(&interface), ip),
+            interface,
+            ip: ip.to_string(),
+        })
+        .collect();
+    addresses.sort_by(|a, b| a.interface.cmp(&b.interface).then_with(|| a.ip.cmp(&b.ip)));
+    addresses
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ip(ip: &str) -> IpAddr {
+        ip.parse().unwrap()
+    }
+
+    #[test]
+    fn addresses_are_classified_by_range_and_interface() {
+        let cases = [
+            (Some("lo"), "127.0.0.1", AddressKind::Loopback),
+            (Some("lo"), "::1", AddressKind::Loopback),
+            (Some("eth0"), "169.254.10.1", AddressKind::LinkLocal),
+            (Some("eth0"), "fe80::1", AddressKind::LinkLocal),
+            (Some("eth0"), "192.168.1.20", AddressKind::Lan),
+            (Some("docker0"), "172.17.0.1", AddressKind::Virtual),
+            (Some("wg0"), "10.8.0.2", AddressKind::Vpn),
+            (Some("eth0"), "100.101.102.103", AddressKind::Vpn),
+            // Only 100.64.0.0/10 is shared, the rest of 100.0.0.0/8 is public
+            (Some("eth0"), "100.128.0.1", AddressKind::Public),
+            (Some("eth0"), "203.0.113.7", AddressKind::Public),
+            (Some("eth0"), "fd12:3456::1", AddressKind::Ipv6UniqueLocal),
+            (Some("eth0"), "2001:db8::1", AddressKind::Ipv6Global),
+            (None, "10.0.0.1", AddressKind::Lan),
+        ];
+        for (interface, address, kind) in cases {
+            assert_eq!(classify_address(interface, ip(address)), kind, "{} on {:?}", address, interface);
+        }
+    }
+
+    #[test]
+    fn ipv6_urls_are_bracketed_and_link_local_ones_scoped() {
+        assert_eq!(base_url(ip("192.168.1.20"), 8080, Some("eth0")), "http://192.168.1.20:8080");
+        assert_eq!(base_url(ip("2001:db8::1"), 8080, Some("eth0")), "http://[2001:db8::1]:8080");
+        // The `%` of the zone is percent-encoded, as URLs require
+        assert_eq!(base_url(ip("fe80::1"), 8080, Some("eth0")), "http://[fe80::1%25eth0]:8080");
+        assert_eq!(base_url(ip("fe80::1"), 8080, None), "http://[fe80::1]:8080");
+    }
+}
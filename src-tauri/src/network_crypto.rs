@@ -0,0 +1,342 @@
+use crate::file_hash::compute_file_hash;
+use crate::local_dir::read_private_networks;
+use crate::types::{CryptoError, NetworkEncryption, NetworkKey};
+use base64::engine::general_purpose::URL_SAFE_NO_PAD;
+use base64::Engine;
+use chacha20poly1305::aead::rand_core::RngCore;
+use chacha20poly1305::aead::{Aead, KeyInit, OsRng, Payload};
+use chacha20poly1305::{XChaCha20Poly1305, XNonce};
+use hkdf::Hkdf;
+use hmac::{Hmac, Mac};
+use sha2::{Digest, Sha256};
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{BufReader, BufWriter, Read, Write};
+use std::path::Path;
+
+// Key derivation labels, so keys made for one purpose are useless for another
+const NAME_KEY_INFO: &[u8] = b"topaz network names";
+const CONTENT_KEY_INFO: &[u8] = b"topaz network contents";
+const NONCE_KEY_INFO: &[u8] = b"topaz network nonces";
+// Encrypted names look like `tpz<key id>.<base64 of nonce and ciphertext>`
+const NAME_PREFIX: &str = "tpz";
+// Longest name that still fits in the 255 bytes file systems allow once encrypted
+const MAX_NAME_LEN: usize = 140;
+// Encrypted files start with the magic bytes, a format version, the key ID and the nonce
+// prefix, followed by the sealed segments. Every segment authenticates the whole header
+const FILE_MAGIC: &[u8; 8] = b"TOPAZENC";
+const FILE_VERSION: u8 = 2;
+// Files written before the header was authenticated, only the magic bytes were
+const UNAUTHENTICATED_HEADER_VERSION: u8 = 1;
+const NONCE_PREFIX_LEN: usize = 19;
+const HEADER_LEN: usize = FILE_MAGIC.len() + 1 + 4 + NONCE_PREFIX_LEN;
+// Plaintext bytes per sealed segment
+const SEGMENT_SIZE: usize = 64 * 1024;
+const TAG_LEN: usize = 16;
+const NONCE_LEN: usize = 24;
+// Times a file that keeps changing while it is encrypted is read again before giving up
+const ENCRYPT_ATTEMPTS: usize = 3;
+
+// Key for a network that had none, or for rotating the current one out
+pub fn new_network_key(id: u32) -> NetworkKey {
+    let mut key = [0u8; 32];
+    OsRng.fill_bytes(&mut key);
+    NetworkKey { id, key: hex::encode(key) }
+}
+
+// Cipher of a network for pushing to and downloading from peers that store it encrypted.
+// `None` if the network has no keys
+pub fn network_cipher(network_name: &str) -> Result<Option<NetworkCipher>, String> {
+    let network = read_private_networks()
+        .map_err(|e| e.to_string())?
+        .into_iter()
+        .find(|network| network.name == network_name)
+        .ok_or_else(|| format!("No network named '{}'", network_name))?;
+    match network.encryption {
+        Some(encryption) if !encryption.keys.is_empty() => {
+            Ok(Some(NetworkCipher::new(&encryption).map_err(|e| e.to_string())?))
+        }
+        _ => Ok(None),
+    }
+}
+
+struct DerivedKeys {
+    name: XChaCha20Poly1305,
+    content: XChaCha20Poly1305,
+    nonce: [u8; 32],
+}
+
+impl DerivedKeys {
+    fn new(key: &NetworkKey) -> Result<Self, CryptoError> {
+        let secret: [u8; 32] = hex::decode(key.key.trim())
+            .ok()
+            .and_then(|bytes| bytes.try_into().ok())
+            .ok_or_else(|| CryptoError::Key(format!("key {} must be 32 hex-encoded bytes", key.id)))?;
+        let hkdf = Hkdf::<Sha256>::new(None, &secret);
+        let derive = |info: &[u8]| {
+            let mut derived = [0u8; 32];
+            hkdf.expand(info, &mut derived).expect("32 bytes is a valid HKDF output length");
+            derived
+        };
+        Ok(DerivedKeys {
+            name: XChaCha20Poly1305::new(&derive(NAME_KEY_INFO).into()),
+            content: XChaCha20Poly1305::new(&derive(CONTENT_KEY_INFO).into()),
+            nonce: derive(NONCE_KEY_INFO),
+        })
+    }
+
+    // Nonces are derived from what they encrypt, so encrypting the same thing twice gives
+    // the same result. Names stay stable across pushes and interrupted uploads resume
+    // where they left off, while different plaintexts never share a nonce
+    fn synthetic_nonce(&self, parts: &[&[u8]]) -> [u8; 32] {
+        let mut mac = <Hmac<Sha256> as Mac>::new_from_slice(&self.nonce).expect("HMAC takes keys of any length");
+        for part in parts {
+            mac.update(&(part.len() as u64).to_be_bytes());
+            mac.update(part);
+        }
+        mac.finalize().into_bytes().into()
+    }
+}
+
+fn segment_nonce(prefix: &[u8], counter: u32, last: bool) -> XNonce {
+    let mut nonce = [0u8; NONCE_LEN];
+    nonce[..NONCE_PREFIX_LEN].copy_from_slice(prefix);
+    nonce[NONCE_PREFIX_LEN..NONCE_LEN - 1].copy_from_slice(&counter.to_be_bytes());
+    nonce[NONCE_LEN - 1] = last as u8;
+    nonce.into()
+}
+
+// Fill `buffer` as far as the reader allows, returning how much was read
+fn read_full(reader: &mut impl Read, buffer: &mut [u8]) -> std::io::Result<usize> {
+    let mut filled = 0;
+    while filled < buffer.len() {
+        match reader.read(&mut buffer[filled..])? {
+            0 => break,
+            read => filled += read,
+        }
+    }
+    Ok(filled)
+}
+
+// Encrypts and decrypts the names and contents of a network's files with its keys
+pub struct NetworkCipher {
+    current: u32,
+    keys: HashMap<u32, DerivedKeys>,
+}
+
+impl NetworkCipher {
+    pub fn new(encryption: &NetworkEncryption) -> Result<Self, CryptoError> {
+        let current = encryption
+            .keys
+            .last()
+            .ok_or_else(|| CryptoError::Key("the network has no keys".to_string()))?
+            .id;
+        let keys = encryption
+            .keys
+            .iter()
+            .map(|key| Ok((key.id, DerivedKeys::new(key)?)))
+            .collect::<Result<_, CryptoError>>()?;
+        Ok(NetworkCipher { current, keys })
+    }
+
+    pub fn current_key_id(&self) -> u32 {
+        self.current
+    }
+
+    fn keys(&self, id: u32) -> Result<&DerivedKeys, CryptoError> {
+        self.keys.get(&id).ok_or(CryptoError::UnknownKey(id))
+    }
+
+    pub fn encrypt_name(&self, name: &str) -> Result<String, CryptoError> {
+        if name.len() > MAX_NAME_LEN {
+            return Err(CryptoError::Format(format!("'{}' is too long to encrypt", name)));
+        }
+        let keys = self.keys(self.current)?;
+        let nonce = XNonce::clone_from_slice(&keys.synthetic_nonce(&[b"name", name.as_bytes()])[..NONCE_LEN]);
+        let ciphertext = keys.name.encrypt(&nonce, name.as_bytes()).map_err(|_| CryptoError::Decrypt)?;
+        let mut sealed = nonce.to_vec();
+        sealed.extend_from_slice(&ciphertext);
+        Ok(format!("{}{}.{}", NAME_PREFIX, self.current, URL_SAFE_NO_PAD.encode(sealed)))
+    }
+
+    // The name and the ID of the key it was encrypted with. `None` for names that aren't
+    // encrypted, like those of files put on the host by other means
+    pub fn decrypt_name(&self, encrypted: &str) -> Result<Option<(String, u32)>, CryptoError> {
+        let Some((id, sealed)) = encrypted.strip_prefix(NAME_PREFIX).and_then(|rest| rest.split_once('.')) else {
+            return Ok(None);
+        };
+        let (Ok(id), Ok(sealed)) = (id.parse::<u32>(), URL_SAFE_NO_PAD.decode(sealed)) else {
+            return Ok(None);
+        };
+        if sealed.len() < NONCE_LEN + TAG_LEN {
+            return Ok(None);
+        }
+        let (nonce, ciphertext) = sealed.split_at(NONCE_LEN);
+        let name = self
+            .keys(id)?
+            .name
+            .decrypt(XNonce::from_slice(nonce), ciphertext)
+            .map_err(|_| CryptoError::Decrypt)?;
+        let name = String::from_utf8(name).map_err(|_| CryptoError::Decrypt)?;
+        Ok(Some((name, id)))
+    }
+
+    // Encrypt `source` into `destination` with the current key. Blocking
+    pub fn encrypt_file(&self, source: &Path, destination: &Path) -> Result<(), CryptoError> {
+        for _ in 0..ENCRYPT_ATTEMPTS {
+            if self.encrypt_file_once(source, destination)? {
+                return Ok(());
+            }
+        }
+        let _ = std::fs::remove_file(destination);
+        Err(CryptoError::Format(format!("'{}' kept changing while it was encrypted", source.display())))
+    }
+
+    // The nonces come from a hash of the file taken before it is read for encryption. The
+    // bytes sealed are hashed again on the way, and if they differ the file changed in
+    // between and the result is thrown away: different contents under the same nonces
+    // would give both away. Returns whether the contents held still
+    fn encrypt_file_once(&self, source: &Path, destination: &Path) -> Result<bool, CryptoError> {
+        let keys = self.keys(self.current)?;
+        // Not the cached hash, which only notices changes to the size and mtime
+        let hash = compute_file_hash(source)?;
+        let nonce_prefix = keys.synthetic_nonce(&[b"content", hash.as_bytes()]);
+        let nonce_prefix = &nonce_prefix[..NONCE_PREFIX_LEN];
+
+        let mut reader = BufReader::new(File::open(source)?);
+        let mut header = FILE_MAGIC.to_vec();
+        header.push(FILE_VERSION);
+        header.extend_from_slice(&self.current.to_be_bytes());
+        header.extend_from_slice(nonce_prefix);
+        let mut writer = BufWriter::new(File::create(destination)?);
+        writer.write_all(&header)?;
+
+        // A short segment ends the file, so one cut off at a segment boundary is noticed
+        let mut segment = vec![0u8; SEGMENT_SIZE];
+        let mut counter: u32 = 0;
+        let mut sealed_hash = Sha256::new();
+        loop {
+            let read = read_full(&mut reader, &mut segment)?;
+            sealed_hash.update(&segment[..read]);
+            let last = read < SEGMENT_SIZE;
+            let nonce = segment_nonce(nonce_prefix, counter, last);
+            let sealed = keys
+                .content
+                .encrypt(&nonce, Payload { msg: &segment[..read], aad: &header })
+                .map_err(|_| CryptoError::Decrypt)?;
+            writer.write_all(&sealed)?;
+            if last {
+                break;
+            }
+            counter = counter
+                .checked_add(1)
+                .ok_or_else(|| CryptoError::Format(format!("'{}' is too large", source.display())))?;
+        }
+        if hex::encode(sealed_hash.finalize()) != hash {
+            drop(writer);
+            std::fs::remove_file(destination)?;
+            return Ok(false);
+        }
+        writer.flush()?;
+        Ok(true)
+    }
+
+    // Decrypt `source` into `destination`, which is removed again if the file doesn't
+    // decrypt to the end. Returns the ID of the key it was encrypted with. Blocking
+    pub fn decrypt_file(&self, source: &Path, destination: &Path) -> Result<u32, CryptoError> {
+        let decrypted = self.decrypt_file_into(source, destination);
+        if decrypted.is_err() {
+            let _ = std::fs::remove_file(destination);
+        }
+        decrypted
+    }
+
+    fn decrypt_file_into(&self, source: &Path, destination: &Path) -> Result<u32, CryptoError> {
+        let mut reader = BufReader::new(File::open(source)?);
+        let mut header = [0u8; HEADER_LEN];
+        if read_full(&mut reader, &mut header)? < HEADER_LEN || &header[..FILE_MAGIC.len()] != FILE_MAGIC {
+            return Err(CryptoError::Format(source.display().to_string()));
+        }
+        // Changing any of the header, the key ID and nonce prefix included, fails every segment
+        let aad: &[u8] = match header[FILE_MAGIC.len()] {
+            FILE_VERSION => &header,
+            UNAUTHENTICATED_HEADER_VERSION => FILE_MAGIC,
+            _ => return Err(CryptoError::Format(format!("{} has an unknown format version", source.display()))),
+        };
+        let id_start = FILE_MAGIC.len() + 1;
+        let id = u32::from_be_bytes(header[id_start..id_start + 4].try_into().unwrap());
+        let nonce_prefix = &header[id_start + 4..];
+        let keys = self.keys(id)?;
+
+        let mut writer = BufWriter::new(File::create(destination)?);
+        let mut segment = vec![0u8; SEGMENT_SIZE + TAG_LEN];
+        let mut counter: u32 = 0;
+        loop {
+            let read = read_full(&mut reader, &mut segment)?;
+            let last = read < segment.len();
+            let nonce = segment_nonce(nonce_prefix, counter, last);
+            let opened = keys
+                .content
+                .decrypt(&nonce, Payload { msg: &segment[..read], aad })
+                .map_err(|_| CryptoError::Decrypt)?;
+            writer.write_all(&opened)?;
+            if last {
+                break;
+            }
+            counter = counter.checked_add(1).ok_or(CryptoError::Decrypt)?;
+        }
+        writer.flush()?;
+        Ok(id)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn cipher(ids: &[u32]) -> NetworkCipher {
+        let keys = ids.iter().map(|id| new_network_key(*id)).collect();
+        NetworkCipher::new(&NetworkEncryption { keys }).unwrap()
+    }
+
+    #[test]
+    fn names_round_trip_and_plain_names_pass() {
+        let cipher = cipher(&[1, 2]);
+        let encrypted = cipher.encrypt_name("notes.txt").unwrap();
+        assert!(encrypted.starts_with("tpz2."));
+        assert_eq!(cipher.encrypt_name("notes.txt").unwrap(), encrypted);
+        assert_eq!(cipher.decrypt_name(&encrypted).unwrap(), Some(("notes.txt".to_string(), 2)));
+        assert_eq!(cipher.decrypt_name("notes.txt").unwrap(), None);
+    }
+
+    #[test]
+    fn files_round_trip_and_tampering_fails() {
+        let cipher = cipher(&[1]);
+        let dir = std::env::temp_dir().join(format!("topaz-crypto-{}", uuid::Uuid::new_v4()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let (plain, sealed, opened) = (dir.join("plain"), dir.join("sealed"), dir.join("opened"));
+
+        // Empty, short, exactly one segment and spanning segments
+        for size in [0, 5, SEGMENT_SIZE, 3 * SEGMENT_SIZE + 7] {
+            let data: Vec<u8> = (0..size).map(|i| (i % 251) as u8).collect();
+            std::fs::write(&plain, &data).unwrap();
+            cipher.encrypt_file(&plain, &sealed).unwrap();
+            assert_eq!(cipher.decrypt_file(&sealed, &opened).unwrap(), 1);
+            assert_eq!(std::fs::read(&opened).unwrap(), data);
+
+            let encrypted = std::fs::read(&sealed).unwrap();
+            // A flipped bit in the nonce prefix, in a segment, or a file cut short
+            let mut header_changed = encrypted.clone();
+            header_changed[HEADER_LEN - 1] ^= 1;
+            let mut body_changed = encrypted.clone();
+            body_changed[HEADER_LEN] ^= 1;
+            let truncated = encrypted[..encrypted.len() - 1].to_vec();
+            for tampered in [header_changed, body_changed, truncated] {
+                std::fs::write(&sealed, tampered).unwrap();
+                assert!(cipher.decrypt_file(&sealed, &opened).is_err());
+                assert!(!opened.exists());
+            }
+        }
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
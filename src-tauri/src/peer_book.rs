@@ -0,0 +1,342 @@
+use crate::quic_transport::QUIC_SCHEME;
+use crate::server_client::{host_client, host_path_url, host_url};
+use crate::sync_engine::{fetch_host_device_id, linked_path_network, read_sync_states};
+use crate::types::{DeviceInfo, DiscoveredPeer, KnownPeer, MeasureLatencyError, PeerAddress, Transport};
+use futures_util::{stream, StreamExt};
+use reqwest::Client;
+use std::collections::HashSet;
+use std::fs;
+use std::sync::Mutex as StdMutex;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use url::Url;
+
+pub const PEERS_FILE_PATH: &str = "../configs/peers.json";
+
+// How often every known address of every peer is checked
+const HEALTH_CHECK_INTERVAL: Duration = Duration::from_secs(60);
+// Addresses that take longer count as unreachable
+const HEALTH_CHECK_TIMEOUT: Duration = Duration::from_secs(5);
+// Requests per check, the fastest one is the round trip time. The first one also has
+// to open the connection
+const LATENCY_SAMPLES: usize = 3;
+// Addresses checked at the same time
+const MAX_CONCURRENT_CHECKS: usize = 16;
+// Addresses that failed this many checks in a row are forgotten
+const MAX_MISSED_CHECKS: u32 = 60;
+
+// The address book is read, changed and written back under this lock
+lazy_static::lazy_static! {
+    static ref PEERS_LOCK: StdMutex<()> = StdMutex::new(());
+}
+
+fn read_peers() -> Vec<KnownPeer> {
+    fs::read_to_string(PEERS_FILE_PATH)
+        .ok()
+        .and_then(|data| serde_json::from_str(&data).ok())
+        .unwrap_or_default()
+}
+
+fn write_peers(peers: &[KnownPeer]) -> std::io::Result<()> {
+    let json_content = serde_json::to_string_pretty(peers)?;
+    fs::write(PEERS_FILE_PATH, json_content)
+}
+
+fn update_peers(f: impl FnOnce(&mut Vec<KnownPeer>)) {
+    let _lock = PEERS_LOCK.lock().unwrap();
+    let mut peers = read_peers();
+    f(&mut peers);
+    if let Err(e) = write_peers(&peers) {
+        eprintln!("Failed to save peers: {}", e);
+    }
+}
+
+fn unix_time() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|duration| duration.as_secs())
+        .unwrap_or(0)
+}
+
+// Same form for the same address however it was written
+fn normalize_base_url(base_url: &str) -> String {
+    Url::parse(base_url)
+        .map(|url| url.to_string())
+        .unwrap_or_else(|_| base_url.to_string())
+}
+
+fn peer_entry<'a>(peers: &'a mut Vec<KnownPeer>, device_id: &str) -> &'a mut KnownPeer {
+    let index = match peers.iter().position(|peer| peer.device_id == device_id) {
+        Some(index) => index,
+        None => {
+            peers.push(KnownPeer {
+                device_id: device_id.to_string(),
+                networks: Vec::new(),
+                addresses: Vec::new(),
+                last_seen: None,
+            });
+            peers.len() - 1
+        }
+    };
+    &mut peers[index]
+}
+
+fn url_transport(base_url: &str) -> Transport {
+    match Url::parse(base_url) {
+        Ok(url) if url.scheme() == QUIC_SCHEME => Transport::Quic,
+        _ => Transport::Tcp,
+    }
+}
+
+// Record an address of a peer. An address first known without a network, from a sync,
+// takes the network it is later seen serving
+fn add_address(peer: &mut KnownPeer, base_url: &str, network_name: Option<&str>) {
+    let base_url = normalize_base_url(base_url);
+    let known = peer.addresses.iter_mut().find(|address| {
+        address.base_url == base_url
+            && (network_name.is_none()
+                || address.network_name.is_none()
+                || address.network_name.as_deref() == network_name)
+    });
+    match known {
+        Some(address) => {
+            if address.network_name.is_none() {
+                address.network_name = network_name.map(str::to_string);
+            }
+        }
+        None => peer.addresses.push(PeerAddress {
+            transport: url_transport(&base_url),
+            base_url,
+            network_name: network_name.map(str::to_string),
+            latency_ms: None,
+            last_checked: None,
+            missed_checks: 0,
+        }),
+    }
+}
+
+// Address with the lowest latency at its last health check
+fn fastest_address<'a>(addresses: impl Iterator<Item = &'a PeerAddress>) -> Option<&'a PeerAddress> {
+    addresses
+        .filter_map(|address| Some((address.latency_ms?, address)))
+        .min_by(|(a, _), (b, _)| a.total_cmp(b))
+        .map(|(_, address)| address)
+}
+
+// Add a peer found on the local network, or the network and addresses it now advertises
+pub fn record_discovered_peer(peer: &DiscoveredPeer) {
+    update_peers(|peers| {
+        let known = peer_entry(peers, &peer.device_id);
+        if !known.networks.contains(&peer.network_name) {
+            known.networks.push(peer.network_name.clone());
+        }
+        for base_url in &peer.base_urls {
+            add_address(known, base_url, Some(&peer.network_name));
+        }
+        known.last_seen = Some(unix_time());
+    });
+}
+
+// Fastest address that answered the last health check of the peer reachable at
+// `base_url`, or `base_url` itself if the address book knows of no other. Only addresses
+// serving `network_name`, or the network `base_url` was seen serving, over the same
+// transport are considered, another server of the peer may not have the same files
+pub fn preferred_base_url(base_url: &str, network_name: Option<&str>) -> String {
+    let normalized = normalize_base_url(base_url);
+    let peers = read_peers();
+    let Some((peer, current)) = peers.iter().find_map(|peer| {
+        let current = peer.addresses.iter().find(|address| address.base_url == normalized)?;
+        Some((peer, current))
+    }) else {
+        return base_url.to_string();
+    };
+    let Some(network_name) = network_name.or(current.network_name.as_deref()) else {
+        return base_url.to_string();
+    };
+    fastest_address(peer.addresses.iter().filter(|address| {
+        address.transport == current.transport && address.network_name.as_deref() == Some(network_name)
+    }))
+    .map(|address| address.base_url.clone())
+    .unwrap_or_else(|| base_url.to_string())
+}
+
+// Fastest address of every reachable peer serving a network
+pub fn network_peer_urls(network_name: &str) -> Vec<String> {
+    read_peers()
+        .iter()
+        .filter_map(|peer| {
+            fastest_address(
+                peer.addresses
+                    .iter()
+                    .filter(|address| address.network_name.as_deref() == Some(network_name)),
+            )
+        })
+        .map(|address| address.base_url.clone())
+        .collect()
+}
+
+// Round trip time to a host, taken from its device endpoint. With `device_id` the host
+// must also answer as that device, so a reused address isn't mistaken for the peer
+pub async fn measure_latency(
+    client: &Client,
+    base_url: &str,
+    device_id: Option<&str>,
+) -> Result<Duration, MeasureLatencyError> {
+    let base = host_url(base_url).await.map_err(MeasureLatencyError::Other)?;
+    let device_url = host_path_url(&base, Some("device"), "").map_err(MeasureLatencyError::Other)?;
+    let mut fastest: Option<Duration> = None;
+    for _ in 0..LATENCY_SAMPLES {
+        let started = Instant::now();
+        let response = client
+            .get(device_url.clone())
+            .timeout(HEALTH_CHECK_TIMEOUT)
+            .send()
+            .await?
+            .error_for_status()?;
+        let elapsed = started.elapsed();
+        let device_info: DeviceInfo = serde_json::from_str(&response.text().await?)
+            .map_err(|e| MeasureLatencyError::Other(e.to_string()))?;
+        if device_id.is_some_and(|device_id| device_info.device_id != device_id) {
+            return Err(MeasureLatencyError::DeviceMismatch(device_info.device_id));
+        }
+        fastest = Some(fastest.map_or(elapsed, |fastest| fastest.min(elapsed)));
+    }
+    fastest.ok_or(MeasureLatencyError::AvgLatencyNotFound)
+}
+
+// Hosts synced with are peers too, identified by asking them for their device ID
+async fn add_sync_targets(client: &Client) {
+    let known: HashSet<String> = read_peers()
+        .iter()
+        .flat_map(|peer| peer.addresses.iter().map(|address| address.base_url.clone()))
+        .collect();
+    for state in read_sync_states() {
+        let base_url = normalize_base_url(&state.target.base_url);
+        if known.contains(&base_url) {
+            continue;
+        }
+        let Ok(base) = host_url(&base_url).await else {
+            continue;
+        };
+        let network = linked_path_network(&state.target.linked_path_name).map(|network| network.name);
+        if let Some(device_id) = fetch_host_device_id(client, &base).await {
+            update_peers(|peers| add_address(peer_entry(peers, &device_id), &base_url, network.as_deref()));
+        }
+    }
+}
+
+// Check every address of every known peer, a few at a time, and record how fast it
+// answered. Addresses that stopped answering long ago are dropped, and peers left
+// without any
+async fn check_peers(client: &Client) {
+    add_sync_targets(client).await;
+    let peers = read_peers();
+    // The same URL may be recorded for several networks, it is checked once
+    let targets: HashSet<(&str, &str)> = peers
+        .iter()
+        .flat_map(|peer| {
+            peer.addresses
+                .iter()
+                .map(move |address| (peer.device_id.as_str(), address.base_url.as_str()))
+        })
+        .collect();
+    let checks = targets.into_iter().map(|(device_id, base_url)| async move {
+        let result = measure_latency(client, base_url, Some(device_id)).await;
+        (device_id, base_url, result)
+    });
+    let results: Vec<_> = stream::iter(checks).buffer_unordered(MAX_CONCURRENT_CHECKS).collect().await;
+
+    let now = unix_time();
+    update_peers(|peers| {
+        for (device_id, base_url, result) in results {
+            let Some(peer) = peers.iter_mut().find(|peer| peer.device_id == device_id) else {
+                continue;
+            };
+            let latency_ms = result.ok().map(|latency| latency.as_secs_f64() * 1000.0);
+            for address in peer.addresses.iter_mut().filter(|address| address.base_url == base_url) {
+                address.last_checked = Some(now);
+                address.latency_ms = latency_ms;
+                address.missed_checks = match latency_ms {
+                    Some(_) => 0,
+                    None => address.missed_checks + 1,
+                };
+            }
+            if latency_ms.is_some() {
+                peer.last_seen = Some(now);
+            }
+        }
+        for peer in peers.iter_mut() {
+            peer.addresses.retain(|address| address.missed_checks < MAX_MISSED_CHECKS);
+        }
+        peers.retain(|peer| !peer.addresses.is_empty());
+    });
+}
+
+pub async fn check_peers_periodically() {
+    let client = host_client();
+    let mut interval = tokio::time::interval(HEALTH_CHECK_INTERVAL);
+    loop {
+        interval.tick().await;
+        check_peers(&client).await;
+    }
+}
+
+// Peers in the address book, most recently seen first, each with its fastest address first
+#[tauri::command]
+pub fn get_peers() -> Vec<KnownPeer> {
+    let mut peers = read_peers();
+    for peer in &mut peers {
+        peer.addresses.sort_by(|a, b| {
+            a.latency_ms
+                .unwrap_or(f64::MAX)
+                .total_cmp(&b.latency_ms.unwrap_or(f64::MAX))
+        });
+    }
+    peers.sort_by(|a, b| b.last_seen.cmp(&a.last_seen));
+    peers
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn address(base_url: &str, network_name: &str, latency_ms: Option<f64>) -> PeerAddress {
+        PeerAddress {
+            base_url: base_url.to_string(),
+            network_name: Some(network_name.to_string()),
+            transport: url_transport(base_url),
+            latency_ms,
+            last_checked: None,
+            missed_checks: 0,
+        }
+    }
+
+    #[test]
+    fn synced_addresses_take_the_network_they_are_seen_serving() {
+        let mut peers = Vec::new();
+        let peer = peer_entry(&mut peers, "device");
+        add_address(peer, "http://192.168.1.20:8080", None);
+        // Written differently, but the same address
+        add_address(peer, "http://192.168.1.20:8080/", Some("home"));
+        assert_eq!(peer.addresses.len(), 1);
+        assert_eq!(peer.addresses[0].network_name.as_deref(), Some("home"));
+        // Another network served at the same address is another entry
+        add_address(peer, "http://192.168.1.20:8080", Some("work"));
+        add_address(peer, "quic://192.168.1.20:8080", Some("home"));
+        assert_eq!(peer.addresses.len(), 3);
+        assert_eq!(peer.addresses[2].transport, Transport::Quic);
+        assert_eq!(peer_entry(&mut peers, "device").addresses.len(), 3);
+        assert_eq!(peers.len(), 1);
+    }
+
+    #[test]
+    fn fastest_address_skips_unreachable_ones() {
+        let addresses = [
+            address("http://10.0.0.1:8080", "home", None),
+            address("http://10.0.0.2:8080", "home", Some(12.5)),
+            address("http://10.0.0.3:8080", "home", Some(3.0)),
+        ];
+        let fastest = fastest_address(addresses.iter()).unwrap();
+        assert_eq!(fastest.base_url, "http://10.0.0.3:8080");
+        assert!(fastest_address(addresses[..1].iter()).is_none());
+    }
+}
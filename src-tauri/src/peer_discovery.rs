@@ -0,0 +1,150 @@
+use crate::local_dir::device_id;
+use crate::peer_book::record_discovered_peer;
+use crate::net_interfaces::{address, base_url};
+use crate::types::{DiscoveredPeer, PeerDiscoveryError};
+use mdns_sd::{ServiceDaemon, ServiceEvent, ServiceInfo};
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::sync::Mutex as StdMutex;
+use tauri::{AppHandle, Emitter};
+
+// DNS-SD service type hosts advertise each running network under
+const SERVICE_TYPE: &str = "_topaz._tcp.local.";
+// Instance names are a single DNS label
+const MAX_INSTANCE_NAME_LEN: usize = 63;
+// TXT record keys
+const NETWORK_KEY: &str = "network";
+const DEVICE_ID_KEY: &str = "device_id";
+const PORT_KEY: &str = "port";
+
+lazy_static::lazy_static! {
+    // Started on first use, shared by advertising and browsing
+    static ref MDNS_DAEMON: StdMutex<Option<ServiceDaemon>> = StdMutex::new(None);
+    // Peers currently on the local network by full service name
+    static ref DISCOVERED_PEERS: StdMutex<HashMap<String, DiscoveredPeer>> = StdMutex::new(HashMap::new());
+}
+
+fn mdns_daemon() -> Result<ServiceDaemon, PeerDiscoveryError> {
+    let mut mdns_daemon = MDNS_DAEMON.lock().unwrap();
+    if let Some(daemon) = mdns_daemon.as_ref() {
+        return Ok(daemon.clone());
+    }
+    let daemon = ServiceDaemon::new()?;
+    *mdns_daemon = Some(daemon.clone());
+    Ok(daemon)
+}
+
+// Instance name of a network on this device. The device comes first so names stay
+// unique when long network names are cut
+fn instance_name(network_name: &str, device_id: &str) -> String {
+    let mut name = format!("{}-{}", device_id.split('-').next().unwrap_or(device_id), network_name);
+    while name.len() > MAX_INSTANCE_NAME_LEN {
+        name.pop();
+    }
+    name
+}
+
+// Advertise a running network on every interface. Returns the full service name to
+// stop advertising it with
+pub fn advertise_network(network_name: &str, port: u16) -> Result<String, PeerDiscoveryError> {
+    let device_id = device_id();
+    let host_name = format!("{}.local.", device_id);
+    let port_value = port.to_string();
+    let properties = [
+        (NETWORK_KEY, network_name),
+        (DEVICE_ID_KEY, device_id.as_str()),
+        (PORT_KEY, port_value.as_str()),
+    ];
+    let service = ServiceInfo::new(
+        SERVICE_TYPE,
+        &instance_name(network_name, &device_id),
+        &host_name,
+        "",
+        port,
+        &properties[..],
+    )?
+    .enable_addr_auto();
+    let fullname = service.get_fullname().to_string();
+    mdns_daemon()?.register(service)?;
+    println!("Advertising network {} as {}", network_name, fullname);
+    Ok(fullname)
+}
+
+pub fn stop_advertising(fullname: &str) {
+    if let Err(e) = mdns_daemon().and_then(|daemon| Ok(daemon.unregister(fullname)?)) {
+        eprintln!("Failed to stop advertising {}: {}", fullname, e);
+    }
+}
+
+fn discovered_peer(service: &ServiceInfo) -> Option<DiscoveredPeer> {
+    let port = service.get_port();
+    let mut sockets: Vec<SocketAddr> = service
+        .get_addresses()
+        .iter()
+        .map(|ip| SocketAddr::new(*ip, port))
+        .collect();
+    sockets.sort();
+    Some(DiscoveredPeer {
+        instance: service.get_fullname().to_string(),
+        network_name: service.get_property_val_str(NETWORK_KEY)?.to_string(),
+        device_id: service.get_property_val_str(DEVICE_ID_KEY)?.to_string(),
+        port,
+        addresses: sockets.iter().map(|socket| address(socket.ip(), port, None)).collect(),
+        base_urls: sockets.iter().map(|socket| base_url(socket.ip(), port, None)).collect(),
+    })
+}
+
+// Follow hosts coming and going on the local network, telling the frontend with
+// `peer_appeared` events, sent again when a peer's addresses change, and
+// `peer_disappeared` events. This device's own networks are left out
+pub async fn browse_peers(app_handle: AppHandle) {
+    let receiver = match mdns_daemon().and_then(|daemon| Ok(daemon.browse(SERVICE_TYPE)?)) {
+        Ok(receiver) => receiver,
+        Err(e) => {
+            eprintln!("Failed to browse for peers: {}", e);
+            return;
+        }
+    };
+    let own_device_id = device_id();
+
+    while let Ok(event) = receiver.recv_async().await {
+        match event {
+            ServiceEvent::ServiceResolved(service) => {
+                let Some(peer) = discovered_peer(&service) else {
+                    continue;
+                };
+                if peer.device_id == own_device_id {
+                    continue;
+                }
+                let previous = DISCOVERED_PEERS
+                    .lock()
+                    .unwrap()
+                    .insert(peer.instance.clone(), peer.clone());
+                // Services are resolved again whenever their records are refreshed
+                if previous.as_ref() != Some(&peer) {
+                    record_discovered_peer(&peer);
+                    if let Err(e) = app_handle.emit("peer_appeared", &peer) {
+                        eprintln!("Failed to emit event to frontend: {}", e);
+                    }
+                }
+            }
+            ServiceEvent::ServiceRemoved(_, fullname) => {
+                let removed = DISCOVERED_PEERS.lock().unwrap().remove(&fullname);
+                if let Some(peer) = removed {
+                    if let Err(e) = app_handle.emit("peer_disappeared", &peer) {
+                        eprintln!("Failed to emit event to frontend: {}", e);
+                    }
+                }
+            }
+            _ => {}
+        }
+    }
+}
+
+// Hosts currently advertising networks on the local network
+#[tauri::command]
+pub fn discover_peers() -> Vec<DiscoveredPeer> {
+    let mut peers: Vec<DiscoveredPeer> = DISCOVERED_PEERS.lock().unwrap().values().cloned().collect();
+    peers.sort_by(|a, b| a.network_name.cmp(&b.network_name).then_with(|| a.instance.cmp(&b.instance)));
+    peers
+}
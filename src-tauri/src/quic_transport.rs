@@ -0,0 +1,266 @@
+use crate::relay_tunnel::TunnelStream;
+use crate::server_client::lookup_host;
+use crate::types::QuicError;
+use quinn::crypto::rustls::QuicClientConfig;
+use quinn::{ClientConfig, Connection, Endpoint, EndpointConfig, ServerConfig, TokioRuntime, TransportConfig};
+use rustls::client::danger::{HandshakeSignatureValid, ServerCertVerified, ServerCertVerifier};
+use rustls::crypto::{verify_tls12_signature, verify_tls13_signature, CryptoProvider};
+use rustls::pki_types::{CertificateDer, PrivatePkcs8KeyDer, ServerName, UnixTime};
+use rustls::{DigitallySignedStruct, SignatureScheme};
+use socket2::{Domain, Protocol, Socket, Type};
+use std::collections::HashMap;
+use std::net::{Ipv4Addr, Ipv6Addr, SocketAddr, UdpSocket};
+use std::sync::{Arc, Mutex as StdMutex};
+use std::time::Duration;
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::{mpsc, Mutex};
+use url::Url;
+
+// Scheme of the base URLs of hosts served over QUIC
+pub const QUIC_SCHEME: &str = "quic";
+// Certificates are self-signed and not checked against a name
+pub const SERVER_NAME: &str = "topaz";
+// Keeps idle connections and the NAT mappings along their way open
+const KEEP_ALIVE_INTERVAL: Duration = Duration::from_secs(15);
+// How long a client tries each address of a host
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(10);
+
+lazy_static::lazy_static! {
+    // Local proxies into hosts served over QUIC by host and port, so each is opened once
+    static ref QUIC_PROXIES: StdMutex<HashMap<(String, u16), String>> = StdMutex::new(HashMap::new());
+}
+
+pub fn quic_error(e: impl std::fmt::Display) -> QuicError {
+    QuicError::Connection(e.to_string())
+}
+
+// Takes any certificate. Hosts served over QUIC are trusted like ones served over plain
+// HTTP, and relayed peers prove they hold the network secret instead
+#[derive(Debug)]
+struct AcceptAnyCertificate(Arc<CryptoProvider>);
+
+impl ServerCertVerifier for AcceptAnyCertificate {
+    fn verify_server_cert(
+        &self,
+        _end_entity: &CertificateDer<'_>,
+        _intermediates: &[CertificateDer<'_>],
+        _server_name: &ServerName<'_>,
+        _ocsp_response: &[u8],
+        _now: UnixTime,
+    ) -> Result<ServerCertVerified, rustls::Error> {
+        Ok(ServerCertVerified::assertion())
+    }
+
+    fn verify_tls12_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        verify_tls12_signature(message, cert, dss, &self.0.signature_verification_algorithms)
+    }
+
+    fn verify_tls13_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        verify_tls13_signature(message, cert, dss, &self.0.signature_verification_algorithms)
+    }
+
+    fn supported_verify_schemes(&self) -> Vec<SignatureScheme> {
+        self.0.signature_verification_algorithms.supported_schemes()
+    }
+}
+
+fn transport_config() -> Arc<TransportConfig> {
+    let mut transport = TransportConfig::default();
+    transport.keep_alive_interval(Some(KEEP_ALIVE_INTERVAL));
+    Arc::new(transport)
+}
+
+fn client_config() -> Result<ClientConfig, QuicError> {
+    let setup_error = |e: &dyn std::fmt::Display| QuicError::Setup(e.to_string());
+    let provider = Arc::new(rustls::crypto::ring::default_provider());
+    let crypto = rustls::ClientConfig::builder_with_provider(provider.clone())
+        .with_protocol_versions(&[&rustls::version::TLS13])
+        .map_err(|e| setup_error(&e))?
+        .dangerous()
+        .with_custom_certificate_verifier(Arc::new(AcceptAnyCertificate(provider)))
+        .with_no_client_auth();
+    let mut config = ClientConfig::new(Arc::new(QuicClientConfig::try_from(crypto).map_err(|e| setup_error(&e))?));
+    config.transport_config(transport_config());
+    Ok(config)
+}
+
+// Clients may move to another address, say from Wi-Fi to Ethernet, and keep their
+// connection. Servers follow them there by default
+fn server_config() -> Result<ServerConfig, QuicError> {
+    let setup_error = |e: &dyn std::fmt::Display| QuicError::Setup(e.to_string());
+    let certified = rcgen::generate_simple_self_signed(vec![SERVER_NAME.to_string()]).map_err(|e| setup_error(&e))?;
+    let key = PrivatePkcs8KeyDer::from(certified.key_pair.serialize_der());
+    let mut config =
+        ServerConfig::with_single_cert(vec![certified.cert.der().clone()], key.into()).map_err(|e| setup_error(&e))?;
+    config.transport_config(transport_config());
+    Ok(config)
+}
+
+// Like TCP listeners, IPv6 sockets are kept to IPv6 so a wildcard IPv4 and IPv6 socket
+// can share a port
+pub fn bind_udp(addr: SocketAddr) -> std::io::Result<UdpSocket> {
+    let socket = Socket::new(Domain::for_address(addr), Type::DGRAM, Some(Protocol::UDP))?;
+    if addr.is_ipv6() {
+        socket.set_only_v6(true)?;
+    }
+    socket.bind(&addr.into())?;
+    Ok(socket.into())
+}
+
+// Endpoint on `socket` that can connect to servers. With `accept_connections` it also
+// accepts connections, with a certificate made up for the occasion
+pub fn endpoint(socket: UdpSocket, accept_connections: bool) -> Result<Endpoint, QuicError> {
+    let server_config = if accept_connections { Some(server_config()?) } else { None };
+    let mut endpoint = Endpoint::new(EndpointConfig::default(), server_config, socket, Arc::new(TokioRuntime))?;
+    endpoint.set_default_client_config(client_config()?);
+    Ok(endpoint)
+}
+
+// Hand each stream a client opens on `connection` to `streams`, until either goes away.
+// Streams carry the client's address, so per-peer limits apply to it
+pub async fn hand_over_streams(connection: Connection, streams: mpsc::Sender<TunnelStream>) {
+    while let Ok((send, recv)) = connection.accept_bi().await {
+        let (server_side, mut tunnel_side) = tokio::io::duplex(64 * 1024);
+        if streams.send((server_side, Some(connection.remote_address()))).await.is_err() {
+            break;
+        }
+        tokio::spawn(async move {
+            let _ = tokio::io::copy_bidirectional(&mut tunnel_side, &mut tokio::io::join(recv, send)).await;
+        });
+    }
+}
+
+// Accept clients on `endpoint` and hand their streams to `streams` until it is closed.
+// Connections already made stay open until the endpoint is closed
+pub async fn accept_clients(endpoint: Endpoint, streams: mpsc::Sender<TunnelStream>) {
+    loop {
+        tokio::select! {
+            _ = streams.closed() => break,
+            incoming = endpoint.accept() => {
+                let Some(incoming) = incoming else { break };
+                let streams = streams.clone();
+                tokio::spawn(async move {
+                    match incoming.await {
+                        Ok(connection) => hand_over_streams(connection, streams).await,
+                        Err(e) => eprintln!("QUIC connection failed: {}", e),
+                    }
+                });
+            }
+        }
+    }
+}
+
+// Carry a local connection to the host on a stream of `connection`
+pub async fn forward_over_quic(connection: Connection, mut stream: TcpStream) -> Result<(), QuicError> {
+    let (send, recv) = connection.open_bi().await.map_err(quic_error)?;
+    tokio::io::copy_bidirectional(&mut stream, &mut tokio::io::join(recv, send)).await?;
+    Ok(())
+}
+
+// Connection to a host served over QUIC, opened again when the host goes away
+struct QuicHost {
+    host: String,
+    port: u16,
+    connection: Mutex<Option<Connection>>,
+}
+
+impl QuicHost {
+    async fn connection(&self) -> Result<Connection, QuicError> {
+        let mut connection = self.connection.lock().await;
+        if let Some(connection) = connection.as_ref().filter(|connection| connection.close_reason().is_none()) {
+            return Ok(connection.clone());
+        }
+        let opened = self.connect().await?;
+        *connection = Some(opened.clone());
+        Ok(opened)
+    }
+
+    // Tries each address of the host in turn
+    async fn connect(&self) -> Result<Connection, QuicError> {
+        let mut last_error = QuicError::Connection(format!("'{}' has no address", self.host));
+        for addr in lookup_host(&self.host, self.port).await? {
+            let unspecified = match addr {
+                SocketAddr::V4(_) => SocketAddr::from((Ipv4Addr::UNSPECIFIED, 0)),
+                SocketAddr::V6(_) => SocketAddr::from((Ipv6Addr::UNSPECIFIED, 0)),
+            };
+            let connecting = endpoint(bind_udp(unspecified)?, false)?.connect(addr, SERVER_NAME).map_err(quic_error);
+            match connecting {
+                Ok(connecting) => match tokio::time::timeout(CONNECT_TIMEOUT, connecting).await {
+                    Ok(Ok(connection)) => return Ok(connection),
+                    Ok(Err(e)) => last_error = quic_error(e),
+                    Err(_) => last_error = QuicError::Connection(format!("{} did not answer", addr)),
+                },
+                Err(e) => last_error = e,
+            }
+        }
+        Err(last_error)
+    }
+}
+
+// Accept connections on a port of this device and carry each one to the host with
+// `forward`. Returns the proxy's base URL
+pub async fn start_proxy<F, Fut, E>(forward: F) -> std::io::Result<String>
+where
+    F: Fn(TcpStream) -> Fut + Send + 'static,
+    Fut: std::future::Future<Output = Result<(), E>> + Send + 'static,
+    E: std::fmt::Display,
+{
+    let listener = TcpListener::bind(("127.0.0.1", 0)).await?;
+    let base_url = format!("http://{}", listener.local_addr()?);
+    tokio::spawn(async move {
+        loop {
+            match listener.accept().await {
+                Ok((stream, _)) => {
+                    let forwarded = forward(stream);
+                    tokio::spawn(async move {
+                        if let Err(e) = forwarded.await {
+                            eprintln!("Proxied connection failed: {}", e);
+                        }
+                    });
+                }
+                Err(e) => eprintln!("Proxy failed to accept a connection: {}", e),
+            }
+        }
+    });
+    Ok(base_url)
+}
+
+// Base URL of a proxy on this device that carries each HTTP connection to the host at a
+// `quic://` URL on a stream of one QUIC connection
+pub async fn connect_quic(url: &Url) -> Result<String, QuicError> {
+    let host = url
+        .host_str()
+        .ok_or_else(|| QuicError::Connection(format!("'{}' has no host", url)))?
+        .trim_start_matches('[')
+        .trim_end_matches(']')
+        .to_string();
+    let port = url.port().ok_or_else(|| QuicError::Connection(format!("'{}' has no port", url)))?;
+    let key = (host.clone(), port);
+    if let Some(base_url) = QUIC_PROXIES.lock().unwrap().get(&key) {
+        return Ok(base_url.clone());
+    }
+
+    // Connect once up front, so an unreachable host is reported here rather than by the
+    // first request
+    let quic_host = Arc::new(QuicHost { host, port, connection: Mutex::new(None) });
+    quic_host.connection().await?;
+    let base_url = start_proxy(move |stream| {
+        let quic_host = quic_host.clone();
+        async move { forward_over_quic(quic_host.connection().await?, stream).await }
+    })
+    .await?;
+
+    println!("Host at {} is reachable at {}", url, base_url);
+    // Another request may have opened a proxy meanwhile, all of them lead to the host
+    Ok(QUIC_PROXIES.lock().unwrap().entry(key).or_insert(base_url).clone())
+}
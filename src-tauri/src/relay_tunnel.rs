@@ -0,0 +1,495 @@
+use crate::hole_punch::punch_to_host;
+use crate::quic_transport::{forward_over_quic, start_proxy};
+use crate::types::{RelayError, RelaySettings};
+use chacha20poly1305::aead::rand_core::RngCore;
+use chacha20poly1305::aead::{Aead, KeyInit, OsRng};
+use chacha20poly1305::{ChaCha20Poly1305, Nonce};
+use futures_util::{SinkExt, StreamExt};
+use hkdf::Hkdf;
+use sha2::Sha256;
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::sync::Mutex as StdMutex;
+use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, DuplexStream};
+use tokio::net::TcpStream;
+use tokio::sync::mpsc;
+use tokio_tungstenite::tungstenite::Message;
+use tokio_tungstenite::{connect_async, MaybeTlsStream, WebSocketStream};
+use x25519_dalek::{EphemeralSecret, PublicKey};
+
+type RelaySocket = WebSocketStream<MaybeTlsStream<TcpStream>>;
+
+// Key derivation labels, so keys made for one purpose are useless for another
+const ROOM_INFO: &[u8] = b"topaz relay room";
+const SESSION_INFO: &[u8] = b"topaz relay session";
+// First frame in each direction. A peer with another secret can't produce it, so a wrong
+// secret or a relay in the middle fails the handshake instead of the first request
+const CONFIRMATION: &[u8] = b"topaz relay v1";
+// Plaintext bytes per encrypted frame
+const FRAME_SIZE: usize = 16 * 1024;
+// How long a host waits before connecting to the relay again after losing it
+const RELAY_RECONNECT_INTERVAL: Duration = Duration::from_secs(5);
+
+lazy_static::lazy_static! {
+    // Local proxies into relays by relay URL and room, so each is opened once
+    static ref RELAY_PROXIES: StdMutex<HashMap<(String, String), String>> = StdMutex::new(HashMap::new());
+}
+
+pub fn decode_secret(secret: &str) -> Result<[u8; 32], RelayError> {
+    hex::decode(secret.trim())
+        .ok()
+        .and_then(|bytes| bytes.try_into().ok())
+        .ok_or_else(|| RelayError::Settings("the secret must be 32 hex-encoded bytes".to_string()))
+}
+
+// Room hosts and clients of a network meet in. Derived from the secret, so the relay
+// learns neither the network name nor anything that helps to guess the secret
+pub fn room_id(secret: &[u8; 32]) -> String {
+    let mut room = [0u8; 16];
+    Hkdf::<Sha256>::new(None, secret)
+        .expand(ROOM_INFO, &mut room)
+        .expect("16 bytes is a valid HKDF output length");
+    hex::encode(room)
+}
+
+// Relay URL of one of its endpoints. `http` URLs are taken as their WebSocket equivalent
+fn relay_endpoint(relay_url: &str, path: &str) -> Result<String, RelayError> {
+    let relay_url = relay_url.trim_end_matches('/');
+    let relay_url = match relay_url.split_once("://") {
+        Some(("ws" | "wss", _)) => relay_url.to_string(),
+        Some(("http", rest)) => format!("ws://{}", rest),
+        Some(("https", rest)) => format!("wss://{}", rest),
+        _ => return Err(RelayError::Settings(format!("'{}' is not a relay URL", relay_url))),
+    };
+    Ok(format!("{}/{}", relay_url, path))
+}
+
+// One direction of an encrypted session. Frames are numbered, so the relay can't
+// reorder, replay or drop them unnoticed
+struct FrameCipher {
+    cipher: ChaCha20Poly1305,
+    counter: u64,
+}
+
+impl FrameCipher {
+    fn new(key: &[u8]) -> Self {
+        FrameCipher {
+            cipher: ChaCha20Poly1305::new_from_slice(key).expect("keys are 32 bytes"),
+            counter: 0,
+        }
+    }
+
+    fn next_nonce(&mut self) -> Nonce {
+        let mut nonce = [0u8; 12];
+        nonce[4..].copy_from_slice(&self.counter.to_be_bytes());
+        self.counter += 1;
+        nonce.into()
+    }
+
+    fn seal(&mut self, plaintext: &[u8]) -> Vec<u8> {
+        let nonce = self.next_nonce();
+        self.cipher
+            .encrypt(&nonce, plaintext)
+            .expect("encrypting into a Vec doesn't fail")
+    }
+
+    fn open(&mut self, frame: &[u8]) -> Result<Vec<u8>, RelayError> {
+        let nonce = self.next_nonce();
+        self.cipher
+            .decrypt(&nonce, frame)
+            .map_err(|_| RelayError::Handshake("received a frame that failed to decrypt".to_string()))
+    }
+}
+
+#[derive(Clone, Copy)]
+enum Role {
+    Host,
+    Client,
+}
+
+async fn next_frame(socket: &mut RelaySocket) -> Result<Vec<u8>, RelayError> {
+    while let Some(message) = socket.next().await {
+        match message? {
+            Message::Binary(data) => return Ok(data.to_vec()),
+            Message::Close(_) => break,
+            _ => {}
+        }
+    }
+    Err(RelayError::Closed)
+}
+
+// Agree on session keys with the other side: an X25519 exchange, with the network secret
+// mixed into the key derivation so only its holders end up with the same keys
+async fn handshake(
+    socket: &mut RelaySocket,
+    secret: &[u8; 32],
+    role: Role,
+) -> Result<(FrameCipher, FrameCipher), RelayError> {
+    let own_secret = EphemeralSecret::random_from_rng(OsRng);
+    let own_public = PublicKey::from(&own_secret);
+    socket
+        .send(Message::Binary(own_public.as_bytes().to_vec().into()))
+        .await?;
+    let peer_public: [u8; 32] = next_frame(socket)
+        .await?
+        .try_into()
+        .map_err(|_| RelayError::Handshake("invalid public key".to_string()))?;
+    let peer_public = PublicKey::from(peer_public);
+    let shared = own_secret.diffie_hellman(&peer_public);
+    if !shared.was_contributory() {
+        return Err(RelayError::Handshake("invalid public key".to_string()));
+    }
+
+    let (client_public, host_public) = match role {
+        Role::Client => (own_public, peer_public),
+        Role::Host => (peer_public, own_public),
+    };
+    let mut keys = [0u8; 64];
+    Hkdf::<Sha256>::new(Some(secret), shared.as_bytes())
+        .expand_multi_info(
+            &[SESSION_INFO, client_public.as_bytes(), host_public.as_bytes()],
+            &mut keys,
+        )
+        .expect("64 bytes is a valid HKDF output length");
+    let (client_key, host_key) = keys.split_at(32);
+    let (mut sending, mut receiving) = match role {
+        Role::Client => (FrameCipher::new(client_key), FrameCipher::new(host_key)),
+        Role::Host => (FrameCipher::new(host_key), FrameCipher::new(client_key)),
+    };
+
+    socket.send(Message::Binary(sending.seal(CONFIRMATION).into())).await?;
+    let confirmation = receiving
+        .open(&next_frame(socket).await?)
+        .map_err(|_| RelayError::Handshake("the other side has a different secret".to_string()))?;
+    if confirmation != CONFIRMATION {
+        return Err(RelayError::Handshake("unexpected confirmation".to_string()));
+    }
+    Ok((sending, receiving))
+}
+
+// Carry a byte stream through the relay in encrypted frames until both sides are done.
+// An empty frame marks the end of one direction
+async fn tunnel<S: AsyncRead + AsyncWrite>(
+    socket: RelaySocket,
+    mut sending: FrameCipher,
+    mut receiving: FrameCipher,
+    stream: S,
+) -> Result<(), RelayError> {
+    let (mut socket_tx, mut socket_rx) = socket.split();
+    let (mut reader, mut writer) = tokio::io::split(stream);
+
+    let outgoing = async {
+        let mut buffer = vec![0u8; FRAME_SIZE];
+        loop {
+            let read = reader.read(&mut buffer).await?;
+            socket_tx
+                .send(Message::Binary(sending.seal(&buffer[..read]).into()))
+                .await?;
+            if read == 0 {
+                return Ok::<_, RelayError>(());
+            }
+        }
+    };
+    let incoming = async {
+        while let Some(message) = socket_rx.next().await {
+            if let Message::Binary(frame) = message? {
+                let data = receiving.open(&frame)?;
+                if data.is_empty() {
+                    writer.shutdown().await?;
+                    return Ok(());
+                }
+                writer.write_all(&data).await?;
+            }
+        }
+        Err(RelayError::Closed)
+    };
+    let result = tokio::try_join!(outgoing, incoming).map(|_| ());
+    if let Ok(mut socket) = socket_tx.reunite(socket_rx) {
+        let _ = socket.close(None).await;
+    }
+    result
+}
+
+// Connection handed to a tunnel listener, with the address of the peer on the other end
+// where the tunnel reveals it. Peers behind a relay only show the relay
+pub type TunnelStream = (DuplexStream, Option<SocketAddr>);
+
+// Where a tunneled connection comes from: the listener's label, and the peer's address
+// if known
+#[derive(Debug, Clone)]
+pub struct TunnelPeer {
+    pub label: String,
+    pub address: Option<SocketAddr>,
+}
+
+// Connections tunneled to a host, decrypted, for `axum::serve`. Whatever feeds it
+// stops once it is dropped
+pub struct TunnelListener {
+    label: String,
+    incoming: mpsc::Receiver<TunnelStream>,
+}
+
+impl axum::serve::Listener for TunnelListener {
+    type Io = DuplexStream;
+    type Addr = TunnelPeer;
+
+    async fn accept(&mut self) -> (Self::Io, Self::Addr) {
+        match self.incoming.recv().await {
+            Some((stream, address)) => (
+                stream,
+                TunnelPeer {
+                    label: self.label.clone(),
+                    address,
+                },
+            ),
+            None => std::future::pending().await,
+        }
+    }
+
+    fn local_addr(&self) -> std::io::Result<Self::Addr> {
+        Ok(TunnelPeer {
+            label: self.label.clone(),
+            address: None,
+        })
+    }
+}
+
+// Listener named `label` in logs, and the sender to hand it connections with
+pub fn tunnel_listener(label: &str) -> (mpsc::Sender<TunnelStream>, TunnelListener) {
+    let (tx, incoming) = mpsc::channel(16);
+    let listener = TunnelListener {
+        label: label.to_string(),
+        incoming,
+    };
+    (tx, listener)
+}
+
+// Connect back to the relay for a client waiting there and hand the tunnel to the server
+async fn accept_client(
+    relay_url: String,
+    room: String,
+    secret: [u8; 32],
+    connection_id: String,
+    incoming: mpsc::Sender<TunnelStream>,
+) -> Result<(), RelayError> {
+    let endpoint = relay_endpoint(&relay_url, &format!("accept/{}/{}", room, connection_id))?;
+    let (mut socket, _) = connect_async(endpoint).await?;
+    let (sending, receiving) = handshake(&mut socket, &secret, Role::Host).await?;
+    let (server_side, tunnel_side) = tokio::io::duplex(FRAME_SIZE * 4);
+    if incoming.send((server_side, None)).await.is_err() {
+        return Ok(());
+    }
+    tunnel(socket, sending, receiving, tunnel_side).await
+}
+
+// Wait in the room for clients until the listener is dropped, reconnecting to the relay
+// whenever the control connection is lost
+async fn host_room(
+    relay_url: String,
+    room: String,
+    secret: [u8; 32],
+    mut control: RelaySocket,
+    incoming: mpsc::Sender<TunnelStream>,
+) {
+    loop {
+        loop {
+            tokio::select! {
+                _ = incoming.closed() => {
+                    let _ = control.close(None).await;
+                    return;
+                }
+                message = control.next() => match message {
+                    Some(Ok(Message::Text(connection_id))) => {
+                        let accepted = accept_client(
+                            relay_url.clone(),
+                            room.clone(),
+                            secret,
+                            connection_id.to_string(),
+                            incoming.clone(),
+                        );
+                        tokio::spawn(async move {
+                            if let Err(e) = accepted.await {
+                                eprintln!("Relayed connection failed: {}", e);
+                            }
+                        });
+                    }
+                    Some(Ok(_)) => {}
+                    Some(Err(e)) => {
+                        eprintln!("Lost connection to relay {}: {}", relay_url, e);
+                        break;
+                    }
+                    None => {
+                        eprintln!("Lost connection to relay {}", relay_url);
+                        break;
+                    }
+                },
+            }
+        }
+
+        control = loop {
+            tokio::select! {
+                _ = incoming.closed() => return,
+                _ = tokio::time::sleep(RELAY_RECONNECT_INTERVAL) => {}
+            }
+            match relay_endpoint(&relay_url, &format!("host/{}", room)) {
+                Ok(endpoint) => match connect_async(endpoint).await {
+                    Ok((control, _)) => break control,
+                    Err(e) => eprintln!("Failed to reconnect to relay {}: {}", relay_url, e),
+                },
+                Err(e) => {
+                    eprintln!("{}", e);
+                    return;
+                }
+            }
+        };
+        println!("Reconnected to relay {}", relay_url);
+    }
+}
+
+// Wait for clients at a relay, handing their connections to `incoming` until it is
+// closed. Fails if the relay can't be reached or another host of the network is already
+// waiting there
+pub async fn listen_on_relay(settings: &RelaySettings, incoming: mpsc::Sender<TunnelStream>) -> Result<(), RelayError> {
+    let secret = decode_secret(&settings.secret)?;
+    let room = room_id(&secret);
+    let (control, _) = connect_async(relay_endpoint(&settings.url, &format!("host/{}", room))?).await?;
+    println!("Waiting for clients at relay {}", settings.url);
+    tokio::spawn(host_room(settings.url.clone(), room, secret, control, incoming));
+    Ok(())
+}
+
+async fn open_client_tunnel(
+    relay_url: &str,
+    room: &str,
+    secret: &[u8; 32],
+) -> Result<(RelaySocket, FrameCipher, FrameCipher), RelayError> {
+    let (mut socket, _) = connect_async(relay_endpoint(relay_url, &format!("connect/{}", room))?).await?;
+    let (sending, receiving) = handshake(&mut socket, secret, Role::Client).await?;
+    Ok((socket, sending, receiving))
+}
+
+async fn proxy_through_relay(relay_url: String, secret: [u8; 32]) -> Result<String, RelayError> {
+    let room = room_id(&secret);
+    // Meet the host once up front, so an unreachable relay, a missing host or a wrong
+    // secret is reported here rather than by the first request
+    let (socket, sending, receiving) = open_client_tunnel(&relay_url, &room, &secret).await?;
+    tokio::spawn(tunnel(socket, sending, receiving, tokio::io::empty()));
+
+    let base_url = start_proxy(move |stream| {
+        let (relay_url, room) = (relay_url.clone(), room.clone());
+        async move {
+            let (socket, sending, receiving) = open_client_tunnel(&relay_url, &room, &secret).await?;
+            tunnel(socket, sending, receiving, stream).await
+        }
+    })
+    .await?;
+    Ok(base_url)
+}
+
+// Reach a network served through a relay. With a rendezvous service a direct connection
+// to the host is tried first. Returns the base URL of a proxy on this device that carries
+// each connection to the host, to use like any host's base URL
+#[tauri::command]
+pub async fn connect_relay(relay: RelaySettings) -> Result<String, String> {
+    let secret = decode_secret(&relay.secret).map_err(|e| e.to_string())?;
+    let key = (relay.url.clone(), room_id(&secret));
+    if let Some(base_url) = RELAY_PROXIES.lock().unwrap().get(&key) {
+        return Ok(base_url.clone());
+    }
+
+    let mut direct = None;
+    if let Some(rendezvous) = &relay.rendezvous {
+        match punch_to_host(&relay, rendezvous).await {
+            Ok(connection) => direct = Some(connection),
+            Err(e) => eprintln!("No direct connection to the host, using relay {}: {}", relay.url, e),
+        }
+    }
+    let base_url = match &direct {
+        Some(connection) => {
+            let connection = connection.clone();
+            start_proxy(move |stream| forward_over_quic(connection.clone(), stream))
+                .await
+                .map_err(|e| e.to_string())?
+        }
+        None => proxy_through_relay(relay.url.clone(), secret).await.map_err(|e| e.to_string())?,
+    };
+
+    println!("Host at {} is reachable at {}", relay.url, base_url);
+    RELAY_PROXIES.lock().unwrap().insert(key.clone(), base_url.clone());
+    // A direct connection doesn't come back once closed, the next call punches again or
+    // falls back to the relay
+    if let Some(connection) = direct {
+        let cached = base_url.clone();
+        tokio::spawn(async move {
+            connection.closed().await;
+            let mut proxies = RELAY_PROXIES.lock().unwrap();
+            if proxies.get(&key) == Some(&cached) {
+                proxies.remove(&key);
+            }
+        });
+    }
+    Ok(base_url)
+}
+
+// Secret for a new relayed network, to share with its devices
+#[tauri::command]
+pub fn generate_relay_secret() -> String {
+    let mut secret = [0u8; 32];
+    OsRng.fill_bytes(&mut secret);
+    hex::encode(secret)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::{routing::get, Router};
+
+    // Relay on a free port on localhost, returning its URL
+    async fn start_relay() -> String {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("ws://{}", listener.local_addr().unwrap());
+        tokio::spawn(async move { axum::serve(listener, topaz_relay::router()).await });
+        url
+    }
+
+    // Host waiting at the relay with a small app, served until the test ends
+    async fn start_host(relay: &RelaySettings) {
+        let (incoming, listener) = tunnel_listener(&relay.url);
+        listen_on_relay(relay, incoming).await.unwrap();
+        let app = Router::new().route("/hello", get(|| async { "hello through the relay" }));
+        tokio::spawn(async move { axum::serve(listener, app).await });
+    }
+
+    fn relay_settings(url: String) -> RelaySettings {
+        RelaySettings {
+            url,
+            secret: generate_relay_secret(),
+            rendezvous: None,
+        }
+    }
+
+    #[tokio::test]
+    async fn request_round_trips_through_relay() {
+        let relay = relay_settings(start_relay().await);
+        start_host(&relay).await;
+
+        let secret = decode_secret(&relay.secret).unwrap();
+        let base_url = proxy_through_relay(relay.url.clone(), secret).await.unwrap();
+        let response = reqwest::get(format!("{}/hello", base_url)).await.unwrap();
+        assert!(response.status().is_success());
+        assert_eq!(response.text().await.unwrap(), "hello through the relay");
+    }
+
+    #[tokio::test]
+    async fn handshake_fails_with_wrong_secret() {
+        let relay = relay_settings(start_relay().await);
+        start_host(&relay).await;
+
+        // Joining the host's room without its secret
+        let room = room_id(&decode_secret(&relay.secret).unwrap());
+        let wrong_secret = decode_secret(&generate_relay_secret()).unwrap();
+        let result = open_client_tunnel(&relay.url, &room, &wrong_secret).await;
+        assert!(matches!(result, Err(RelayError::Handshake(_))));
+    }
+}
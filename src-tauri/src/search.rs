@@ -0,0 +1,237 @@
+#[cfg(feature = "content-search")]
+use crate::content_index::search_content;
+use crate::file_index::with_file_index;
+use crate::local_dir::read_private_networks;
+use crate::peer_book::{network_peer_urls, preferred_base_url};
+use crate::server_client::{host_client, host_path_url, host_url};
+use crate::sync_engine::read_sync_states;
+use crate::types::{IndexEntry, LinkedPath, NetworkSearchResults, SearchQuery, SearchResult};
+use futures_util::future::join_all;
+use globset::{Glob, GlobMatcher};
+use reqwest::Client;
+use std::cmp::Ordering;
+use std::collections::{BTreeSet, HashMap};
+use std::time::Duration;
+
+const DEFAULT_SEARCH_LIMIT: usize = 200;
+// Hosts that take longer are reported as failed
+const HOST_SEARCH_TIMEOUT: Duration = Duration::from_secs(10);
+
+enum NameMatcher {
+    Glob(GlobMatcher),
+    // Lowercased part of a file name
+    Substring(String),
+}
+
+impl NameMatcher {
+    fn new(query: &str) -> Result<Self, String> {
+        if query.contains(['*', '?', '[']) {
+            let glob = Glob::new(query).map_err(|e| format!("Invalid pattern '{}': {}", query, e))?;
+            Ok(NameMatcher::Glob(glob.compile_matcher()))
+        } else {
+            Ok(NameMatcher::Substring(query.to_lowercase()))
+        }
+    }
+
+    fn is_match(&self, path: &str) -> bool {
+        match self {
+            NameMatcher::Glob(glob) => glob.is_match(path),
+            NameMatcher::Substring(part) => {
+                let name = path.rsplit('/').next().unwrap_or(path);
+                name.to_lowercase().contains(part.as_str())
+            }
+        }
+    }
+}
+
+fn search_result(linked_path: &LinkedPath, entry: &IndexEntry, score: Option<f32>) -> SearchResult {
+    SearchResult {
+        linked_path_name: linked_path.name.clone(),
+        path: entry.path.clone(),
+        is_dir: entry.is_dir,
+        size: entry.size,
+        hash: entry.hash.clone(),
+        modified: entry.modified / 1_000_000_000,
+        score,
+        hosts: Vec::new(),
+        local: false,
+    }
+}
+
+// Content matches first by relevance, then everything else by path
+fn compare_results(a: &SearchResult, b: &SearchResult) -> Ordering {
+    match (a.score, b.score) {
+        (Some(a_score), Some(b_score)) => b_score.total_cmp(&a_score),
+        (Some(_), None) => Ordering::Less,
+        (None, Some(_)) => Ordering::Greater,
+        (None, None) => Ordering::Equal,
+    }
+    .then_with(|| a.linked_path_name.cmp(&b.linked_path_name))
+    .then_with(|| a.path.cmp(&b.path))
+}
+
+// Files of a linked path matching a query, looked up in its file index. Blocking
+fn search_linked_path(linked_path: &LinkedPath, query: &SearchQuery, matcher: &NameMatcher) -> Vec<SearchResult> {
+    let limit = query.limit.unwrap_or(DEFAULT_SEARCH_LIMIT);
+    let entries = match with_file_index(linked_path, |index| index.entries_below("")) {
+        Ok(entries) => entries,
+        Err(e) => {
+            eprintln!("Failed to search {}: {}", linked_path.name, e);
+            return Vec::new();
+        }
+    };
+
+    let mut results: Vec<SearchResult> = entries
+        .iter()
+        .filter(|entry| matcher.is_match(&entry.path))
+        .map(|entry| search_result(linked_path, entry, None))
+        .collect();
+
+    #[cfg(feature = "content-search")]
+    if query.content {
+        match search_content(linked_path, &query.q, limit) {
+            Ok(hits) => {
+                let entries: HashMap<&str, &IndexEntry> =
+                    entries.iter().map(|entry| (entry.path.as_str(), entry)).collect();
+                for (path, score) in hits {
+                    match results.iter_mut().find(|result| result.path == path) {
+                        Some(result) => result.score = Some(score),
+                        None => {
+                            if let Some(entry) = entries.get(path.as_str()) {
+                                results.push(search_result(linked_path, entry, Some(score)));
+                            }
+                        }
+                    }
+                }
+            }
+            Err(e) => eprintln!("Failed to search the content of {}: {}", linked_path.name, e),
+        }
+    }
+
+    results.sort_by(compare_results);
+    results.truncate(limit);
+    results
+}
+
+// Search every linked path of a network on this device. Blocking
+pub fn search_linked_paths(linked_paths: &[LinkedPath], query: &SearchQuery) -> Result<Vec<SearchResult>, String> {
+    // Rejected rather than answered with name matches only, which would look like
+    // nothing's content matched
+    if query.content && !cfg!(feature = "content-search") {
+        return Err("This device was built without content search".to_string());
+    }
+    let matcher = NameMatcher::new(&query.q)?;
+    let mut results: Vec<SearchResult> = linked_paths
+        .iter()
+        .flat_map(|linked_path| search_linked_path(linked_path, query, &matcher))
+        .collect();
+    results.sort_by(compare_results);
+    results.truncate(query.limit.unwrap_or(DEFAULT_SEARCH_LIMIT));
+    Ok(results)
+}
+
+async fn search_host(client: &Client, host: &str, query: &SearchQuery) -> Result<Vec<SearchResult>, String> {
+    let base = host_url(host).await?;
+    let mut url = host_path_url(&base, Some("search"), "")?;
+    url.query_pairs_mut()
+        .append_pair("q", &query.q)
+        .append_pair("content", &query.content.to_string())
+        .append_pair("limit", &query.limit.unwrap_or(DEFAULT_SEARCH_LIMIT).to_string());
+    let response = client
+        .get(url)
+        .timeout(HOST_SEARCH_TIMEOUT)
+        .send()
+        .await
+        .map_err(|e| e.to_string())?;
+    let status = response.status();
+    if !status.is_success() {
+        let message = response.text().await.unwrap_or_default();
+        return Err(format!("Search failed with status {}: {}", status, message));
+    }
+    let text = response.text().await.map_err(|e| e.to_string())?;
+    serde_json::from_str(&text).map_err(|e| e.to_string())
+}
+
+// Search a network on this device and on every known host, merging copies of the same
+// file. Known hosts are `hosts`, the ones linked paths of the network sync with and
+// reachable peers seen advertising the network
+#[tauri::command]
+pub async fn search_network(
+    network_name: String,
+    query: String,
+    content: Option<bool>,
+    limit: Option<usize>,
+    hosts: Option<Vec<String>>,
+) -> Result<NetworkSearchResults, String> {
+    let network = read_private_networks()
+        .map_err(|e| e.to_string())?
+        .into_iter()
+        .find(|network| network.name == network_name)
+        .ok_or_else(|| format!("No network named '{}'", network_name))?;
+    let query = SearchQuery {
+        q: query,
+        content: content.unwrap_or(false),
+        limit,
+    };
+
+    let mut known_hosts: BTreeSet<String> = hosts.unwrap_or_default().into_iter().collect();
+    known_hosts.extend(
+        read_sync_states()
+            .into_iter()
+            .filter(|state| {
+                network
+                    .linked_paths
+                    .iter()
+                    .any(|linked_path| linked_path.name == state.target.linked_path_name)
+            })
+            .map(|state| preferred_base_url(&state.target.base_url, Some(&network_name))),
+    );
+    known_hosts.extend(network_peer_urls(&network_name));
+
+    let local_query = query.clone();
+    let linked_paths = network.linked_paths.clone();
+    let local_results = tokio::task::spawn_blocking(move || search_linked_paths(&linked_paths, &local_query))
+        .await
+        .map_err(|e| e.to_string())??;
+
+    let client = host_client();
+    let host_results = join_all(known_hosts.iter().map(|host| search_host(&client, host, &query))).await;
+
+    // Copies of a file on several devices are one result
+    let mut merged: HashMap<(String, String, Option<String>), SearchResult> = HashMap::new();
+    let mut failed_hosts = Vec::new();
+    let local_results = local_results.into_iter().map(|result| (None, result));
+    let remote_results = known_hosts
+        .iter()
+        .zip(host_results)
+        .filter_map(|(host, results)| match results {
+            Ok(results) => Some(results.into_iter().map(move |result| (Some(host.clone()), result))),
+            Err(e) => {
+                eprintln!("Failed to search {}: {}", host, e);
+                failed_hosts.push(host.clone());
+                None
+            }
+        })
+        .flatten();
+    for (host, result) in local_results.chain(remote_results) {
+        let key = (result.linked_path_name.clone(), result.path.clone(), result.hash.clone());
+        let merged_result = merged.entry(key).or_insert_with(|| SearchResult {
+            hosts: Vec::new(),
+            local: false,
+            ..result.clone()
+        });
+        match host {
+            Some(host) => merged_result.hosts.push(host),
+            None => merged_result.local = true,
+        }
+        merged_result.score = match (merged_result.score, result.score) {
+            (Some(a), Some(b)) => Some(a.max(b)),
+            (a, b) => a.or(b),
+        };
+    }
+
+    let mut results: Vec<SearchResult> = merged.into_values().collect();
+    results.sort_by(compare_results);
+    results.truncate(query.limit.unwrap_or(DEFAULT_SEARCH_LIMIT));
+    Ok(NetworkSearchResults { results, failed_hosts })
+}
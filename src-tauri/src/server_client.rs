@@ -1,65 +1,449 @@
-use reqwest::Client;
-use serde::Deserialize;
-use std::fs::{self, File};
+use crate::access_log::DEVICE_ID_HEADER;
+use crate::bandwidth::{throttle_stream, Direction, Throttle};
+use crate::chunk_store::{LocalChunkIndex, MIN_CHUNKED_FILE_SIZE};
+use crate::ignore_rules::IgnoreRules;
+use crate::local_dir::{device_id, PART_FILE_EXTENSION};
+use crate::net_interfaces::zone_scope_id;
+use crate::network_crypto::{network_cipher, NetworkCipher};
+use crate::quic_transport::{connect_quic, QUIC_SCHEME};
+use crate::server_host::{API_ROUTE_NAME, ARCHIVE_ENTRIES_HEADER};
+use crate::types::{Chunk, EncryptedStore, FileEntry, UploadOffset, VersionClock};
+use crate::url_path::is_safe_file_name;
+use crate::version_clock::CLOCK_HEADER;
+use reqwest::dns::{Addrs, Name, Resolve, Resolving};
+use reqwest::{header, Client, RequestBuilder, StatusCode};
+use sha2::{Digest, Sha256};
+use std::net::{Ipv6Addr, SocketAddr, SocketAddrV6};
 use std::path::{Path, PathBuf};
 use std::error::Error;
-use tokio::io::AsyncWriteExt;
+use tokio::io::{AsyncReadExt, AsyncSeekExt, AsyncWriteExt};
 use tokio::fs as tokio_fs;
 use std::sync::Arc;
+use futures_util::TryStreamExt;
+use tokio_util::io::{StreamReader, SyncIoBridge};
+use url::Url;
 
-#[derive(Debug, Deserialize)]
-struct FileEntry {
-    name: String,
-    is_dir: bool,
-}
-
-async fn fetch_directory(client: &Client, base_url: &str) -> Result<Vec<FileEntry>, Box<dyn Error>> {
-    let response = client.get(base_url).send().await?;
+async fn fetch_directory(client: &Client, dir_url: &Url) -> Result<Vec<FileEntry>, Box<dyn Error>> {
+    let response = client.get(dir_url.clone()).send().await?.error_for_status()?;
     let body = response.text().await?;
     let entries: Vec<FileEntry> = serde_json::from_str(&body)?;
     Ok(entries)
 }
 
-async fn download_file(client: &Client, file_url: &str, save_path: &Path) -> Result<(), Box<dyn Error>> {
-    let mut response = client.get(file_url).send().await?;
+// How many times a file is fetched before it is reported as corrupted
+const MAX_DOWNLOAD_ATTEMPTS: usize = 3;
+
+#[derive(Default)]
+pub struct TransferSummary {
+    downloaded: usize,
+    corrupted: Vec<PathBuf>,
+    // Bytes taken from chunks already on this device instead of the network
+    reused_bytes: u64,
+    // Encrypted files none of the network's keys could decrypt
+    undecryptable: Vec<String>,
+}
+
+// Download a file and return the SHA-256 of what was received
+async fn download_file(
+    client: &Client,
+    file_url: &Url,
+    save_path: &Path,
+    throttle: &mut Throttle,
+) -> Result<String, Box<dyn Error>> {
+    let response = client.get(file_url.clone()).send().await?.error_for_status()?;
+    let mut data = std::pin::pin!(throttle_stream(response.bytes_stream(), throttle));
     let mut file = tokio_fs::File::create(save_path).await?;
+    let mut hasher = Sha256::new();
 
-    while let Some(chunk) = response.chunk().await? {
+    while let Some(chunk) = data.try_next().await? {
+        hasher.update(&chunk);
         file.write_all(&chunk).await?;
     }
+    file.flush().await?;
+
+    Ok(hex::encode(hasher.finalize()))
+}
+
+// Chunk list of a file below `host`, the host's base URL. The API routes are below the
+// base URL's path too, which isn't just `/` for a host behind a reverse proxy
+async fn fetch_chunks(client: &Client, host: &Url, file_url: &Url) -> Result<Vec<Chunk>, Box<dyn Error>> {
+    let prefix = host.path().trim_end_matches('/');
+    let file_path = file_url.path().strip_prefix(prefix).unwrap_or(file_url.path());
+    let mut chunks_url = file_url.clone();
+    chunks_url.set_path(&format!("{}/{}/chunks{}", prefix, API_ROUTE_NAME, file_path));
+    let response = client.get(chunks_url).send().await?.error_for_status()?;
+    let chunks: Vec<Chunk> = serde_json::from_str(&response.text().await?)?;
+    Ok(chunks)
+}
+
+// Fetch a run of consecutive chunks with a single range request
+async fn download_range(
+    client: &Client,
+    file_url: &Url,
+    run: &[&Chunk],
+    file: &mut tokio_fs::File,
+    hasher: &mut Sha256,
+    throttle: &mut Throttle,
+) -> Result<(), Box<dyn Error>> {
+    let (Some(first), Some(last)) = (run.first(), run.last()) else {
+        return Ok(());
+    };
+    let range = format!("bytes={}-{}", first.offset, last.offset + last.length - 1);
+    let response = client
+        .get(file_url.clone())
+        .header(header::RANGE, range)
+        .send()
+        .await?
+        .error_for_status()?;
+    if response.status() != StatusCode::PARTIAL_CONTENT {
+        return Err("Host does not support range requests".into());
+    }
+
+    let mut stream = std::pin::pin!(throttle_stream(response.bytes_stream(), throttle));
+    while let Some(data) = stream.try_next().await? {
+        hasher.update(&data);
+        file.write_all(&data).await?;
+    }
 
     Ok(())
 }
 
+// Rebuild a file from its chunk list, reading chunks that already exist on this device
+// and fetching only the missing ones. Returns the SHA-256 of the assembled file
+async fn download_file_chunks(
+    client: &Client,
+    file_url: &Url,
+    save_path: &Path,
+    chunks: &[Chunk],
+    chunk_index: &mut LocalChunkIndex,
+    summary: &mut TransferSummary,
+    throttle: &mut Throttle,
+) -> Result<String, Box<dyn Error>> {
+    chunk_index.locate(chunks).await;
+    let mut file = tokio_fs::File::create(save_path).await?;
+    let mut hasher = Sha256::new();
+    let mut missing_run: Vec<&Chunk> = Vec::new();
+
+    for chunk in chunks {
+        match chunk_index.read_chunk(chunk).await {
+            Some(data) => {
+                download_range(client, file_url, &missing_run, &mut file, &mut hasher, throttle).await?;
+                missing_run.clear();
+                hasher.update(&data);
+                file.write_all(&data).await?;
+                summary.reused_bytes += chunk.length;
+            }
+            None => missing_run.push(chunk),
+        }
+    }
+    download_range(client, file_url, &missing_run, &mut file, &mut hasher, throttle).await?;
+    file.flush().await?;
+
+    Ok(hex::encode(hasher.finalize()))
+}
+
+// Download into a temporary file and only move it into place once it matches the
+// hash advertised by the host. Returns false if every attempt came back corrupted
+#[allow(clippy::too_many_arguments)]
+pub async fn download_verified_file(
+    client: &Client,
+    host: &Url,
+    file_url: &Url,
+    save_path: &Path,
+    entry: &FileEntry,
+    chunk_index: &mut LocalChunkIndex,
+    summary: &mut TransferSummary,
+    throttle: &mut Throttle,
+) -> Result<bool, Box<dyn Error>> {
+    let mut part_name = save_path.file_name().unwrap_or_default().to_os_string();
+    part_name.push(".");
+    part_name.push(PART_FILE_EXTENSION);
+    let part_path = save_path.with_file_name(part_name);
+
+    // Large files are assembled from chunks on the first attempt. Retries fall back to
+    // a plain download in case a local chunk was the cause of the mismatch. Only files
+    // with a known hash are assembled, nothing else would catch a wrong chunk list
+    let mut chunks = None;
+    if entry.hash.is_some() && entry.size.is_some_and(|size| size >= MIN_CHUNKED_FILE_SIZE) {
+        match fetch_chunks(client, host, file_url).await {
+            Ok(file_chunks) => chunks = Some(file_chunks),
+            Err(e) => eprintln!("Failed to fetch chunks for {}: {}", save_path.display(), e),
+        }
+    }
+
+    for attempt in 1..=MAX_DOWNLOAD_ATTEMPTS {
+        let hash = match &chunks {
+            Some(chunks) if attempt == 1 => {
+                download_file_chunks(client, file_url, &part_path, chunks, chunk_index, summary, throttle).await?
+            }
+            _ => download_file(client, file_url, &part_path, throttle).await?,
+        };
+        match entry.hash.as_deref() {
+            Some(expected_hash) if !hash.eq_ignore_ascii_case(expected_hash) => {
+                eprintln!(
+                    "Hash mismatch for {} (attempt {}/{})",
+                    save_path.display(),
+                    attempt,
+                    MAX_DOWNLOAD_ATTEMPTS
+                );
+            }
+            _ => {
+                tokio_fs::rename(&part_path, save_path).await?;
+                return Ok(true);
+            }
+        }
+    }
+
+    tokio_fs::remove_file(&part_path).await?;
+    Ok(false)
+}
+
+// Append a name as a single percent-encoded path segment. Directory URLs keep a
+// trailing slash so the host knows to answer with a listing
+fn join_entry_url(base_url: &Url, name: &str, is_dir: bool) -> Result<Url, Box<dyn Error>> {
+    let mut entry_url = base_url.clone();
+    {
+        let mut segments = entry_url
+            .path_segments_mut()
+            .map_err(|_| format!("'{}' cannot be used as a base URL", base_url))?;
+        segments.pop_if_empty().push(name);
+        if is_dir {
+            segments.push("");
+        }
+    }
+    Ok(entry_url)
+}
+
+// URLs can't carry IPv6 zones, so a scoped link-local host is swapped for a name only
+// `ScopedIpv6Resolver` resolves, made of the address segments and the scope ID
+const SCOPED_IPV6_DOMAIN: &str = ".scoped-ipv6.invalid";
+
+fn scoped_ipv6_host(ip: Ipv6Addr, scope_id: u32) -> String {
+    let segments: Vec<String> = ip.segments().iter().map(|segment| format!("{:x}", segment)).collect();
+    format!("{}-s{}{}", segments.join("-"), scope_id, SCOPED_IPV6_DOMAIN)
+}
+
+fn parse_scoped_ipv6_host(host: &str) -> Option<(Ipv6Addr, u32)> {
+    let mut parts: Vec<&str> = host.strip_suffix(SCOPED_IPV6_DOMAIN)?.split('-').collect();
+    let scope_id = parts.pop()?.strip_prefix('s')?.parse().ok()?;
+    let segments: Vec<u16> = parts
+        .iter()
+        .map(|part| u16::from_str_radix(part, 16).ok())
+        .collect::<Option<_>>()?;
+    let segments: [u16; 8] = segments.try_into().ok()?;
+    Some((Ipv6Addr::from(segments), scope_id))
+}
+
+// Parse the base URL of a host. Besides names and IP literals this takes link-local IPv6
+// addresses with their interface, as in `http://[fe80::1%25eth0]:8080`
+pub fn parse_host_url(base_url: &str) -> Result<Url, String> {
+    let invalid = |e: &dyn std::fmt::Display| format!("Invalid host URL '{}': {}", base_url, e);
+    let scoped = base_url
+        .find('[')
+        .and_then(|start| Some((start, start + base_url[start..].find(']')?)))
+        .and_then(|(start, end)| {
+            let (ip, zone) = base_url[start + 1..end].split_once('%')?;
+            Some((start, end, ip, zone.strip_prefix("25").unwrap_or(zone)))
+        });
+    let Some((start, end, ip, zone)) = scoped else {
+        return Url::parse(base_url).map_err(|e| invalid(&e));
+    };
+    let ip: Ipv6Addr = ip.parse().map_err(|e| invalid(&e))?;
+    let scope_id = zone_scope_id(zone).ok_or_else(|| invalid(&format!("no interface '{}'", zone)))?;
+    let url = format!("{}{}{}", &base_url[..start], scoped_ipv6_host(ip, scope_id), &base_url[end + 1..]);
+    Url::parse(&url).map_err(|e| invalid(&e))
+}
+
+// Base URL of a host to send requests to. Hosts served over QUIC, at `quic://` URLs, are
+// reached through a proxy on this device, so requests to them are plain HTTP like any other
+pub async fn host_url(base_url: &str) -> Result<Url, String> {
+    let url = parse_host_url(base_url)?;
+    if url.scheme() != QUIC_SCHEME {
+        return Ok(url);
+    }
+    let proxy_url = connect_quic(&url).await.map_err(|e| e.to_string())?;
+    let mut proxied = Url::parse(&proxy_url).map_err(|e| e.to_string())?;
+    proxied.set_path(url.path());
+    Ok(proxied)
+}
+
+// Addresses of a host named in a URL `parse_host_url` returned, scoped IPv6 hosts included
+pub async fn lookup_host(host: &str, port: u16) -> std::io::Result<Vec<SocketAddr>> {
+    if let Some((ip, scope_id)) = parse_scoped_ipv6_host(host) {
+        return Ok(vec![SocketAddr::V6(SocketAddrV6::new(ip, port, 0, scope_id))]);
+    }
+    Ok(tokio::net::lookup_host((host, port)).await?.collect())
+}
+
+// Resolves the names `parse_host_url` gives scoped IPv6 hosts, and every other name as usual
+struct ScopedIpv6Resolver;
+
+impl Resolve for ScopedIpv6Resolver {
+    fn resolve(&self, name: Name) -> Resolving {
+        let host = name.as_str().to_string();
+        Box::pin(async move {
+            let addrs = lookup_host(&host, 0).await?;
+            Ok(Box::new(addrs.into_iter()) as Addrs)
+        })
+    }
+}
+
+// Client for talking to hosts, including ones at scoped IPv6 addresses. Requests carry
+// this device's ID for the access logs of hosts
+pub fn host_client() -> Client {
+    let mut headers = header::HeaderMap::new();
+    if let Ok(device_id) = header::HeaderValue::from_str(&device_id()) {
+        headers.insert(DEVICE_ID_HEADER, device_id);
+    }
+    Client::builder()
+        .dns_resolver(Arc::new(ScopedIpv6Resolver))
+        .default_headers(headers)
+        .build()
+        .unwrap_or_else(|e| {
+            eprintln!("Failed to build host client: {}", e);
+            Client::new()
+        })
+}
+
+// URL of a `/`-separated path on a host, optionally below one of its API routes
+pub fn host_path_url(base_url: &Url, api_route: Option<&str>, path: &str) -> Result<Url, String> {
+    let mut url = base_url.clone();
+    {
+        let mut segments = url
+            .path_segments_mut()
+            .map_err(|_| format!("'{}' cannot be used as a base URL", base_url))?;
+        segments.pop_if_empty();
+        if let Some(api_route) = api_route {
+            segments.extend([API_ROUTE_NAME, api_route]);
+        }
+        segments.extend(path.split('/').filter(|segment| !segment.is_empty()));
+    }
+    Ok(url)
+}
+
+// Download a file the host stores encrypted and decrypt it into `save_path`. The encrypted
+// file is downloaded outside of the linked path, and without reusing local chunks since
+// those are of decrypted files
+#[allow(clippy::too_many_arguments)]
+async fn download_encrypted_file(
+    client: &Client,
+    host: &Url,
+    file_url: &Url,
+    save_path: &Path,
+    entry: &FileEntry,
+    cipher: &Arc<NetworkCipher>,
+    summary: &mut TransferSummary,
+    throttle: &mut Throttle,
+) -> Result<bool, Box<dyn Error>> {
+    let encrypted_path = std::env::temp_dir().join(format!("topaz-{}", uuid::Uuid::new_v4()));
+    let mut chunk_index = LocalChunkIndex::default();
+    let downloaded =
+        download_verified_file(client, host, file_url, &encrypted_path, entry, &mut chunk_index, summary, throttle);
+    if !downloaded.await? {
+        return Ok(false);
+    }
+
+    let (cipher, source, destination) = (cipher.clone(), encrypted_path.clone(), save_path.to_path_buf());
+    let decrypted = tokio::task::spawn_blocking(move || cipher.decrypt_file(&source, &destination)).await?;
+    tokio_fs::remove_file(&encrypted_path).await?;
+    if let Err(e) = decrypted {
+        eprintln!("Failed to decrypt {}: {}", save_path.display(), e);
+        summary.undecryptable.push(entry.name.clone());
+    }
+    Ok(true)
+}
+
+#[allow(clippy::too_many_arguments)]
 async fn process_directory(
     client: Arc<Client>,
-    base_url: &str,
+    host: &Url,
+    base_url: &Url,
     local_path: &Path,
+    rules: &IgnoreRules,
+    cipher: Option<&Arc<NetworkCipher>>,
+    chunk_index: &mut LocalChunkIndex,
+    summary: &mut TransferSummary,
+    throttle: &mut Throttle,
 ) -> Result<(), Box<dyn Error>> {
     let entries = fetch_directory(&client, base_url).await?;
 
     for entry in entries {
-        let entry_url = format!("{}/{}", base_url, entry.name);
-        let entry_path = local_path.join(&entry.name);
+        let entry_url = join_entry_url(base_url, &entry.name, entry.is_dir)?;
+        // Names of encrypted entries are decrypted, others are taken as they are
+        let (name, encrypted) = match cipher.map(|cipher| cipher.decrypt_name(&entry.name)) {
+            Some(Ok(Some((name, _)))) => (name, true),
+            Some(Err(e)) => {
+                eprintln!("Failed to decrypt the name '{}': {}", entry.name, e);
+                summary.undecryptable.push(entry.name.clone());
+                continue;
+            }
+            _ => (entry.name.clone(), false),
+        };
+        // Never let a name coming from the host point outside of the local directory
+        if !is_safe_file_name(&name) {
+            return Err(format!("Host sent an invalid file name '{}'", name).into());
+        }
+        let entry_path = local_path.join(&name);
+        // Files the local side ignores are not downloaded
+        if rules.is_ignored(&entry_path, entry.is_dir) {
+            continue;
+        }
 
         if entry.is_dir {
             // Create directory locally
             tokio_fs::create_dir_all(&entry_path).await?;
             // Recursively process the directory
-            Box::pin(process_directory(client.clone(), &entry_url, &entry_path)).await?;
+            Box::pin(process_directory(
+                client.clone(),
+                host,
+                &entry_url,
+                &entry_path,
+                rules,
+                cipher,
+                chunk_index,
+                summary,
+                throttle,
+            ))
+            .await?;
         } else {
             // Download the file
-            download_file(&client, &entry_url, &entry_path).await?;
+            let downloaded = match cipher {
+                Some(cipher) if encrypted => {
+                    download_encrypted_file(&client, host, &entry_url, &entry_path, &entry, cipher, summary, throttle)
+                        .await?
+                }
+                _ => {
+                    download_verified_file(
+                        &client,
+                        host,
+                        &entry_url,
+                        &entry_path,
+                        &entry,
+                        chunk_index,
+                        summary,
+                        throttle,
+                    )
+                    .await?
+                }
+            };
+            if downloaded {
+                summary.downloaded += 1;
+            } else {
+                summary.corrupted.push(entry_path);
+            }
         }
     }
 
     Ok(())
 }
 
+// Download everything a host serves into `local_path`. With `network_name`, files the host
+// stores encrypted with that network's keys are decrypted
 #[tauri::command]
 pub async fn get_host_linked_paths(
     base_url: String,
     local_path: String,
+    network_name: Option<String>,
 ) -> Result<String, String> {
     // Ensure the local directory exists
     let local_path = Path::new(&local_path);
@@ -67,12 +451,486 @@ pub async fn get_host_linked_paths(
         return Err(format!("The specified path '{}' does not exist.", local_path.display()));
     }
 
+    let cipher = match &network_name {
+        Some(network_name) => network_cipher(network_name)?.map(Arc::new),
+        None => None,
+    };
+    let base = host_url(&base_url).await?;
+
     // Create an Arc<Client> so it can be shared across async tasks
-    let client = Arc::new(Client::new());
+    let client = Arc::new(host_client());
 
     // Start processing the directory
-    match process_directory(client.clone(), &base_url, local_path).await {
-        Ok(_) => Ok(format!("Files from '{}' downloaded successfully.", base_url)),
+    let mut summary = TransferSummary::default();
+    let mut chunk_index = LocalChunkIndex::load();
+    // The destination's `.topazignore` decides what is left out
+    let rules = IgnoreRules::load(local_path, &[]);
+    let mut throttle = Throttle::new(Direction::Download, network_name.as_deref(), Some(&base_url));
+    let result = process_directory(
+        client.clone(),
+        &base,
+        &base,
+        local_path,
+        &rules,
+        cipher.as_ref(),
+        &mut chunk_index,
+        &mut summary,
+        &mut throttle,
+    )
+    .await;
+    if result.is_ok() && !summary.undecryptable.is_empty() {
+        return Err(format!(
+            "{} files from '{}' downloaded, {} could not be decrypted with the keys of the network: {}",
+            summary.downloaded,
+            base_url,
+            summary.undecryptable.len(),
+            summary.undecryptable.join(", ")
+        ));
+    }
+    match result {
+        Ok(_) if summary.corrupted.is_empty() => Ok(format!(
+            "{} files from '{}' downloaded successfully, {} bytes reused from local chunks.",
+            summary.downloaded, base_url, summary.reused_bytes
+        )),
+        Ok(_) => {
+            let corrupted: Vec<String> = summary
+                .corrupted
+                .iter()
+                .map(|path| path.display().to_string())
+                .collect();
+            Ok(format!(
+                "{} files from '{}' downloaded, {} failed integrity verification: {}",
+                summary.downloaded,
+                base_url,
+                corrupted.len(),
+                corrupted.join(", ")
+            ))
+        }
         Err(e) => Err(format!("Error during download: {}", e)),
     }
 }
+
+// Unpack a tar archive into `destination` and return how many entries it held. Blocking
+fn unpack_archive(reader: impl std::io::Read, destination: &Path) -> std::io::Result<usize> {
+    let mut unpacked = 0;
+    for entry in tar::Archive::new(reader).entries()? {
+        // Entries with paths leading outside of `destination` are skipped
+        entry?.unpack_in(destination)?;
+        unpacked += 1;
+    }
+    Ok(unpacked)
+}
+
+// Download a whole directory of a host in one request and unpack it into `local_path`.
+// `remote_path` is the linked path name optionally followed by `/`-separated subdirectories
+#[tauri::command]
+pub async fn download_host_archive(
+    base_url: String,
+    remote_path: String,
+    local_path: String,
+) -> Result<String, String> {
+    let local_path = PathBuf::from(local_path);
+    if !local_path.exists() {
+        return Err(format!("The specified path '{}' does not exist.", local_path.display()));
+    }
+
+    let base = host_url(&base_url).await?;
+    let archive_url = host_path_url(&base, Some("archive"), &remote_path)?;
+
+    let response = host_client()
+        .get(archive_url)
+        .send()
+        .await
+        .and_then(|response| response.error_for_status())
+        .map_err(|e| format!("Error during download: {}", e))?;
+    // A stream that ends between two entries looks like a complete archive to the unpacker
+    let expected_entries: usize = response
+        .headers()
+        .get(ARCHIVE_ENTRIES_HEADER)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.parse().ok())
+        .ok_or_else(|| format!("'{}' didn't say how many entries the archive holds", base_url))?;
+
+    // Unpack while the archive is still streaming in
+    let throttle = Throttle::new(Direction::Download, None, Some(&base_url));
+    let stream = Box::pin(throttle_stream(response.bytes_stream(), throttle)).map_err(std::io::Error::other);
+    let reader = SyncIoBridge::new(StreamReader::new(stream));
+    let unpack_path = local_path.clone();
+    tokio::task::spawn_blocking(move || {
+        let unpacked = unpack_archive(reader, &unpack_path)?;
+        if unpacked != expected_entries {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::UnexpectedEof,
+                format!("the archive is incomplete, {} of {} entries", unpacked, expected_entries),
+            ));
+        }
+        Ok(())
+    })
+    .await
+        .map_err(|e| format!("Error during download: {}", e))?
+        .map_err(|e| format!("Error unpacking archive: {}", e))?;
+
+    Ok(format!("'{}' downloaded to '{}'.", remote_path, local_path.display()))
+}
+
+// Size of each request of a resumable upload
+const UPLOAD_CHUNK_SIZE: u64 = 8 * 1024 * 1024;
+
+// Upload one file, continuing from whatever part of it the host already holds
+// Tag an upload request with the clock of the file, which the host records once the
+// upload is complete
+fn with_clock(request: RequestBuilder, clock: Option<&str>) -> RequestBuilder {
+    match clock {
+        Some(clock) => request.header(CLOCK_HEADER, clock),
+        None => request,
+    }
+}
+
+pub async fn push_file(
+    client: &Client,
+    upload_url: &Url,
+    local_file: &Path,
+    token: &str,
+    clock: Option<&VersionClock>,
+    throttle: &mut Throttle,
+) -> Result<(), Box<dyn Error>> {
+    let clock = clock.map(serde_json::to_string).transpose()?;
+    let total = tokio_fs::metadata(local_file).await?.len();
+    if total == 0 {
+        with_clock(client.put(upload_url.clone()), clock.as_deref())
+            .bearer_auth(token)
+            .body(Vec::new())
+            .send()
+            .await?
+            .error_for_status()?;
+        return Ok(());
+    }
+
+    let response = client
+        .get(upload_url.clone())
+        .bearer_auth(token)
+        .send()
+        .await?
+        .error_for_status()?;
+    let mut offset = serde_json::from_str::<UploadOffset>(&response.text().await?)?.offset;
+    // A staged upload longer than the file belongs to an older version of it
+    if offset >= total {
+        offset = 0;
+    }
+
+    let mut file = tokio_fs::File::open(local_file).await?;
+    file.seek(std::io::SeekFrom::Start(offset)).await?;
+    while offset < total {
+        let length = UPLOAD_CHUNK_SIZE.min(total - offset);
+        let mut data = vec![0u8; length as usize];
+        file.read_exact(&mut data).await?;
+        throttle.wait(data.len()).await;
+        with_clock(client.put(upload_url.clone()), clock.as_deref())
+            .bearer_auth(token)
+            .header(
+                header::CONTENT_RANGE,
+                format!("bytes {}-{}/{}", offset, offset + length - 1, total),
+            )
+            .body(data)
+            .send()
+            .await?
+            .error_for_status()?;
+        offset += length;
+    }
+
+    Ok(())
+}
+
+// Encrypt a file into a temporary one and upload that. Encrypting the same contents gives
+// the same file, so an interrupted upload still resumes
+async fn push_encrypted_file(
+    client: &Client,
+    upload_url: &Url,
+    local_file: &Path,
+    token: &str,
+    cipher: &Arc<NetworkCipher>,
+    throttle: &mut Throttle,
+) -> Result<(), Box<dyn Error>> {
+    let encrypted_path = std::env::temp_dir().join(format!("topaz-{}", uuid::Uuid::new_v4()));
+    let (encrypt_cipher, source, destination) = (cipher.clone(), local_file.to_path_buf(), encrypted_path.clone());
+    let encrypted = tokio::task::spawn_blocking(move || encrypt_cipher.encrypt_file(&source, &destination)).await?;
+    // Errors are kept as strings while the temporary file is removed, boxed ones aren't Send
+    let pushed = match encrypted {
+        Ok(()) => push_file(client, upload_url, &encrypted_path, token, None, throttle)
+            .await
+            .map_err(|e| e.to_string()),
+        Err(e) => Err(e.to_string()),
+    };
+    let _ = tokio_fs::remove_file(&encrypted_path).await;
+    Ok(pushed?)
+}
+
+#[allow(clippy::too_many_arguments)]
+async fn push_path(
+    client: &Client,
+    upload_url: &Url,
+    local_path: &Path,
+    rules: &IgnoreRules,
+    token: &str,
+    cipher: Option<&Arc<NetworkCipher>>,
+    throttle: &mut Throttle,
+    pushed: &mut usize,
+) -> Result<(), Box<dyn Error>> {
+    if tokio_fs::metadata(local_path).await?.is_dir() {
+        let mut read_dir = tokio_fs::read_dir(local_path).await?;
+        while let Some(entry) = read_dir.next_entry().await? {
+            if rules.is_ignored(&entry.path(), entry.file_type().await?.is_dir()) {
+                continue;
+            }
+            let mut name = entry.file_name().to_string_lossy().into_owned();
+            if let Some(cipher) = cipher {
+                name = cipher.encrypt_name(&name)?;
+            }
+            let entry_url = join_entry_url(upload_url, &name, false)?;
+            Box::pin(push_path(client, &entry_url, &entry.path(), rules, token, cipher, throttle, pushed)).await?;
+        }
+    } else {
+        match cipher {
+            Some(cipher) => push_encrypted_file(client, upload_url, local_path, token, cipher, throttle).await?,
+            None => push_file(client, upload_url, local_path, token, None, throttle).await?,
+        }
+        *pushed += 1;
+    }
+    Ok(())
+}
+
+// Upload a local file or directory into a writable linked path of a host. `remote_path`
+// is the linked path name optionally followed by `/`-separated subdirectories, as the host
+// lists them. With `network_name`, names and contents are encrypted with the network's
+// current key, so a host without the keys only ever stores ciphertext
+#[tauri::command]
+pub async fn push_to_host(
+    base_url: String,
+    remote_path: String,
+    local_path: String,
+    token: String,
+    network_name: Option<String>,
+) -> Result<String, String> {
+    let local_path = PathBuf::from(local_path);
+    let Some(mut local_name) = local_path.file_name().map(|name| name.to_string_lossy().into_owned()) else {
+        return Err(format!("The specified path '{}' cannot be uploaded.", local_path.display()));
+    };
+    if !local_path.exists() {
+        return Err(format!("The specified path '{}' does not exist.", local_path.display()));
+    }
+    let cipher = match &network_name {
+        Some(network_name) => network_cipher(network_name)?.map(Arc::new),
+        None => None,
+    };
+    if let Some(cipher) = &cipher {
+        local_name = cipher.encrypt_name(&local_name).map_err(|e| e.to_string())?;
+    }
+
+    let base = host_url(&base_url).await?;
+    let upload_url = host_path_url(&base, Some("upload"), &format!("{}/{}", remote_path, local_name))?;
+
+    let client = host_client();
+    let rules = IgnoreRules::load(&local_path, &[]);
+    let mut throttle = Throttle::new(Direction::Upload, network_name.as_deref(), Some(&base_url));
+    let mut pushed = 0;
+    match push_path(&client, &upload_url, &local_path, &rules, &token, cipher.as_ref(), &mut throttle, &mut pushed).await {
+        Ok(_) => Ok(format!("{} files uploaded to '{}'.", pushed, base_url)),
+        Err(e) => Err(format!("Error during upload after {} files: {}", pushed, e)),
+    }
+}
+
+async fn delete_host_file(client: &Client, upload_url: &Url, token: &str) -> Result<(), Box<dyn Error>> {
+    client.delete(upload_url.clone()).bearer_auth(token).send().await?.error_for_status()?;
+    Ok(())
+}
+
+// Download and decrypt a file the host stores under an older key, then push it back under
+// `new_upload_url` with the current one. The old copy is only deleted once the new one is
+// stored. Returns false if it couldn't be decrypted
+#[allow(clippy::too_many_arguments)]
+async fn reencrypt_file(
+    client: &Client,
+    host: &Url,
+    file_url: &Url,
+    entry: &FileEntry,
+    old_upload_url: &Url,
+    new_upload_url: &Url,
+    token: &str,
+    cipher: &Arc<NetworkCipher>,
+    throttles: &mut (Throttle, Throttle),
+) -> Result<bool, Box<dyn Error>> {
+    let plain_path = std::env::temp_dir().join(format!("topaz-{}", uuid::Uuid::new_v4()));
+    let mut summary = TransferSummary::default();
+    let downloaded =
+        download_encrypted_file(client, host, file_url, &plain_path, entry, cipher, &mut summary, &mut throttles.0);
+    if !downloaded.await? || !summary.undecryptable.is_empty() {
+        return Ok(false);
+    }
+    let pushed = push_encrypted_file(client, new_upload_url, &plain_path, token, cipher, &mut throttles.1)
+        .await
+        .map_err(|e| e.to_string());
+    let _ = tokio_fs::remove_file(&plain_path).await;
+    pushed?;
+    delete_host_file(client, old_upload_url, token).await?;
+    Ok(true)
+}
+
+// Re-encrypt everything below a directory of the host that an older key encrypted, its
+// name or the name of a directory above it included. `moved` is set when a directory
+// above was renamed, so every file below has to be stored again under the new path
+#[allow(clippy::too_many_arguments)]
+async fn reencrypt_directory(
+    client: &Client,
+    host: &Url,
+    dir_url: &Url,
+    old_upload_url: &Url,
+    new_upload_url: &Url,
+    moved: bool,
+    token: &str,
+    cipher: &Arc<NetworkCipher>,
+    throttles: &mut (Throttle, Throttle),
+    reencrypted: &mut usize,
+    failed: &mut Vec<String>,
+) -> Result<(), Box<dyn Error>> {
+    for entry in fetch_directory(client, dir_url).await? {
+        // Names that aren't encrypted belong to files put on the host by other means
+        let (name, id) = match cipher.decrypt_name(&entry.name) {
+            Ok(Some(decrypted)) => decrypted,
+            Ok(None) => continue,
+            Err(e) => {
+                eprintln!("Failed to decrypt the name '{}': {}", entry.name, e);
+                failed.push(entry.name.clone());
+                continue;
+            }
+        };
+        let stale = id != cipher.current_key_id();
+        if !stale && !moved && !entry.is_dir {
+            continue;
+        }
+        let new_name = if stale { cipher.encrypt_name(&name)? } else { entry.name.clone() };
+        let entry_url = join_entry_url(dir_url, &entry.name, entry.is_dir)?;
+        let old_entry_upload_url = join_entry_url(old_upload_url, &entry.name, false)?;
+        let new_entry_upload_url = join_entry_url(new_upload_url, &new_name, false)?;
+
+        if entry.is_dir {
+            let failed_before = failed.len();
+            Box::pin(reencrypt_directory(
+                client,
+                host,
+                &entry_url,
+                &old_entry_upload_url,
+                &new_entry_upload_url,
+                moved || stale,
+                token,
+                cipher,
+                throttles,
+                reencrypted,
+                failed,
+            ))
+            .await?;
+            // The emptied directory goes too, its name was encrypted with the old key
+            if (moved || stale) && failed.len() == failed_before {
+                delete_host_file(client, &old_entry_upload_url, token).await?;
+            }
+        } else {
+            let done = reencrypt_file(
+                client,
+                host,
+                &entry_url,
+                &entry,
+                &old_entry_upload_url,
+                &new_entry_upload_url,
+                token,
+                cipher,
+                throttles,
+            );
+            if done.await? {
+                *reencrypted += 1;
+            } else {
+                failed.push(name);
+            }
+        }
+    }
+    Ok(())
+}
+
+// Re-encrypt with the current key what a host stores of `store.remote_path` under older
+// keys of the network. Returns how many files were re-encrypted and the names of those
+// that couldn't be, which still need an older key
+pub async fn reencrypt_host_files(
+    store: &EncryptedStore,
+    network_name: &str,
+    cipher: &Arc<NetworkCipher>,
+) -> Result<(usize, Vec<String>), String> {
+    let base = host_url(&store.base_url).await?;
+    // Directories are listed at their URL with a trailing slash
+    let mut dir_url = host_path_url(&base, None, &store.remote_path)?;
+    dir_url.set_path(&format!("{}/", dir_url.path()));
+    let upload_url = host_path_url(&base, Some("upload"), &store.remote_path)?;
+    let client = host_client();
+    let mut throttles = (
+        Throttle::new(Direction::Download, Some(network_name), Some(&store.base_url)),
+        Throttle::new(Direction::Upload, Some(network_name), Some(&store.base_url)),
+    );
+    let (mut reencrypted, mut failed) = (0, Vec::new());
+    let walked = reencrypt_directory(
+        &client,
+        &base,
+        &dir_url,
+        &upload_url,
+        &upload_url,
+        false,
+        &store.token,
+        cipher,
+        &mut throttles,
+        &mut reencrypted,
+        &mut failed,
+    );
+    match walked.await {
+        Ok(()) => Ok((reencrypted, failed)),
+        Err(e) => Err(format!("Error re-encrypting '{}' after {} files: {}", store.base_url, reencrypted, e)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::url_path::{decode_path_segment, encode_path_segment};
+
+    #[test]
+    fn archives_cut_between_entries_unpack_fewer_entries() {
+        let mut builder = tar::Builder::new(Vec::new());
+        for name in ["a.txt", "b.txt", "c.txt"] {
+            let mut header = tar::Header::new_gnu();
+            header.set_size(4);
+            header.set_mode(0o644);
+            header.set_cksum();
+            builder.append_data(&mut header, name, &b"data"[..]).unwrap();
+        }
+        let archive = builder.into_inner().unwrap();
+        let destination = std::env::temp_dir().join(format!("topaz-unpack-{}", uuid::Uuid::new_v4()));
+        std::fs::create_dir_all(&destination).unwrap();
+
+        assert_eq!(unpack_archive(&archive[..], &destination).unwrap(), 3);
+        // Each entry is a header block and a data block, the end blocks follow
+        assert_eq!(unpack_archive(&archive[..2 * 512], &destination).unwrap(), 1);
+        std::fs::remove_dir_all(&destination).unwrap();
+    }
+
+    #[test]
+    fn entry_urls_round_trip_names() {
+        let base = Url::parse("http://127.0.0.1:8080/api/files/shared/").unwrap();
+        for name in ["with space.txt", "hash#tag", "question?mark", "100%", "a+b", "naïve café ✓", "trailing."] {
+            let file_url = join_entry_url(&base, name, false).unwrap();
+            let segment = file_url.path_segments().unwrap().next_back().unwrap();
+            // The host decodes routes with `url_path`, which must agree with the client
+            assert_eq!(segment, encode_path_segment(name));
+            assert_eq!(decode_path_segment(segment).as_deref(), Some(name));
+            assert_eq!(file_url.query(), None);
+            assert_eq!(file_url.fragment(), None);
+
+            let dir_url = join_entry_url(&base, name, true).unwrap();
+            assert_eq!(dir_url.as_str(), format!("{}/", file_url));
+        }
+    }
+}
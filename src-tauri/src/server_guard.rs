@@ -0,0 +1,427 @@
+use crate::relay_tunnel::TunnelPeer;
+use crate::types::ServerLimits;
+use axum::body::Body;
+use axum::extract::connect_info::Connected;
+use axum::extract::{ConnectInfo, Request};
+use axum::http::{header, StatusCode};
+use axum::middleware::Next;
+use axum::response::{IntoResponse, Response};
+use axum::serve::{IncomingStream, Listener};
+use futures_util::StreamExt;
+use std::collections::{HashMap, VecDeque};
+use std::future::Future;
+use std::io;
+use std::net::SocketAddr;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex as StdMutex};
+use std::task::{Context, Poll};
+use std::time::{Duration, Instant};
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use tokio::sync::{OwnedSemaphorePermit, Semaphore};
+use tokio::time::Sleep;
+
+// Past this many peers, records of those that left nothing worth keeping are dropped
+const MAX_PEER_RECORDS: usize = 4096;
+
+// Identity of the peer on the other end of a listener's connections
+pub trait PeerKey {
+    fn peer_key(&self) -> String;
+
+    // Whether connections from different peers get different keys, so per-peer limits
+    // and bans can be applied to them
+    fn is_distinct(&self) -> bool {
+        true
+    }
+}
+
+impl PeerKey for SocketAddr {
+    fn peer_key(&self) -> String {
+        self.ip().to_string()
+    }
+}
+
+// Tunnel listeners are named after where their connections come from. Peers reached over
+// QUIC are known by their address, but every peer behind a relay shares the listener's
+// name, so limiting or banning one would hit all
+impl PeerKey for TunnelPeer {
+    fn peer_key(&self) -> String {
+        match self.address {
+            Some(address) => address.peer_key(),
+            None => self.label.clone(),
+        }
+    }
+
+    fn is_distinct(&self) -> bool {
+        self.address.is_some()
+    }
+}
+
+#[derive(Default)]
+struct PeerRecord {
+    connections: usize,
+    // Requests the peer may still make, and when they were last topped up
+    request_tokens: f64,
+    counted: Option<Instant>,
+    auth_failures: VecDeque<Instant>,
+    banned_until: Option<Instant>,
+}
+
+impl PeerRecord {
+    fn worth_keeping(&self, now: Instant) -> bool {
+        self.connections > 0
+            || self.banned_until.is_some_and(|until| until > now)
+            || !self.auth_failures.is_empty()
+    }
+}
+
+// Connection and request accounting of one server group
+pub struct ServerGuard {
+    limits: ServerLimits,
+    connections: Option<Arc<Semaphore>>,
+    active_connections: AtomicUsize,
+    peers: StdMutex<HashMap<String, PeerRecord>>,
+}
+
+impl ServerGuard {
+    pub fn new(limits: ServerLimits) -> Arc<Self> {
+        Arc::new(ServerGuard {
+            connections: limits.max_connections.map(|max| Arc::new(Semaphore::new(max))),
+            limits,
+            active_connections: AtomicUsize::new(0),
+            peers: StdMutex::new(HashMap::new()),
+        })
+    }
+
+    pub fn limits(&self) -> &ServerLimits {
+        &self.limits
+    }
+
+    pub fn active_connections(&self) -> usize {
+        self.active_connections.load(Ordering::Relaxed)
+    }
+
+    fn with_peer<T>(&self, peer: &str, f: impl FnOnce(&mut PeerRecord, Instant) -> T) -> T {
+        let now = Instant::now();
+        let mut peers = self.peers.lock().unwrap();
+        if !peers.contains_key(peer) && peers.len() >= MAX_PEER_RECORDS {
+            peers.retain(|_, record| record.worth_keeping(now));
+        }
+        f(peers.entry(peer.to_string()).or_default(), now)
+    }
+
+    // How much longer a peer stays banned
+    fn ban_remaining(&self, peer: &str) -> Option<Duration> {
+        self.with_peer(peer, |record, now| {
+            record.banned_until.and_then(|until| until.checked_duration_since(now))
+        })
+    }
+
+    // Peers that can't be told apart are only held to the server group's total
+    fn open_connection(&self, peer: Option<&str>) -> bool {
+        if let Some(peer) = peer {
+            let max_per_peer = self.limits.max_connections_per_peer;
+            let opened = self.with_peer(peer, |record, now| {
+                if record.banned_until.is_some_and(|until| until > now)
+                    || max_per_peer.is_some_and(|max| record.connections >= max)
+                {
+                    return false;
+                }
+                record.connections += 1;
+                true
+            });
+            if !opened {
+                return false;
+            }
+        }
+        self.active_connections.fetch_add(1, Ordering::Relaxed);
+        true
+    }
+
+    fn close_connection(&self, peer: Option<&str>) {
+        self.active_connections.fetch_sub(1, Ordering::Relaxed);
+        if let Some(peer) = peer {
+            self.with_peer(peer, |record, _| record.connections = record.connections.saturating_sub(1));
+        }
+    }
+
+    // Count a request against the peer's rate, or tell how long until it may make one
+    fn take_request(&self, peer: &str) -> Result<(), Duration> {
+        let Some(per_minute) = self.limits.requests_per_minute_per_peer else {
+            return Ok(());
+        };
+        let per_second = per_minute.max(1) as f64 / 60.0;
+        self.with_peer(peer, |record, now| {
+            let tokens = match record.counted {
+                Some(counted) => record.request_tokens + now.duration_since(counted).as_secs_f64() * per_second,
+                None => per_minute as f64,
+            };
+            record.request_tokens = tokens.min(per_minute as f64);
+            record.counted = Some(now);
+            if record.request_tokens < 1.0 {
+                return Err(Duration::from_secs_f64((1.0 - record.request_tokens) / per_second));
+            }
+            record.request_tokens -= 1.0;
+            Ok(())
+        })
+    }
+
+    fn record_auth_failure(&self, peer: &str) {
+        let Some(max_failures) = self.limits.max_auth_failures else {
+            return;
+        };
+        let window = Duration::from_secs(self.limits.ban_secs);
+        self.with_peer(peer, |record, now| {
+            while record
+                .auth_failures
+                .front()
+                .is_some_and(|failed| now.duration_since(*failed) > window)
+            {
+                record.auth_failures.pop_front();
+            }
+            record.auth_failures.push_back(now);
+            if record.auth_failures.len() >= max_failures as usize {
+                eprintln!("Banning {} for {} seconds after repeated authentication failures", peer, window.as_secs());
+                record.banned_until = Some(now + window);
+                record.auth_failures.clear();
+            }
+        })
+    }
+}
+
+// Requests a connection is busy with. While there are none, the connection has until
+// `idle_since` plus the idle timeout to send the next request
+struct ConnectionActivity {
+    requests: AtomicUsize,
+    idle_since: StdMutex<Instant>,
+}
+
+// Connection a request came in on, as `ConnectInfo`
+#[derive(Clone)]
+pub struct PeerConnection {
+    pub peer: String,
+    // Whether per-peer limits and bans apply to the peer
+    distinct: bool,
+    activity: Arc<ConnectionActivity>,
+}
+
+impl<L> Connected<IncomingStream<'_, GuardedListener<L>>> for PeerConnection
+where
+    L: Listener,
+    L::Addr: PeerKey,
+{
+    fn connect_info(stream: IncomingStream<'_, GuardedListener<L>>) -> Self {
+        PeerConnection {
+            peer: stream.remote_addr().peer_key(),
+            distinct: stream.remote_addr().is_distinct(),
+            activity: stream.io().activity.clone(),
+        }
+    }
+}
+
+// Marks the connection busy until dropped along with the response body
+struct ActiveRequest(Arc<ConnectionActivity>);
+
+impl ActiveRequest {
+    fn begin(activity: &Arc<ConnectionActivity>) -> Self {
+        activity.requests.fetch_add(1, Ordering::SeqCst);
+        ActiveRequest(activity.clone())
+    }
+}
+
+impl Drop for ActiveRequest {
+    fn drop(&mut self) {
+        if self.0.requests.fetch_sub(1, Ordering::SeqCst) == 1 {
+            *self.0.idle_since.lock().unwrap() = Instant::now();
+        }
+    }
+}
+
+// Releases the connection's place in the server group's limits when it closes
+struct ConnectionSlot {
+    guard: Arc<ServerGuard>,
+    peer: Option<String>,
+    _permit: Option<OwnedSemaphorePermit>,
+}
+
+impl Drop for ConnectionSlot {
+    fn drop(&mut self) {
+        self.guard.close_connection(self.peer.as_deref());
+    }
+}
+
+// Connection that fails once it has been idle for too long
+pub struct GuardedIo<T> {
+    io: T,
+    activity: Arc<ConnectionActivity>,
+    idle_timeout: Option<Duration>,
+    deadline: Pin<Box<Sleep>>,
+    _slot: ConnectionSlot,
+}
+
+impl<T> GuardedIo<T> {
+    // Called whenever the connection has nothing to offer. Slowly trickling bytes don't
+    // push the deadline back, so a request has to arrive as a whole in time
+    fn poll_idle(&mut self, cx: &mut Context<'_>) -> io::Result<()> {
+        let Some(idle_timeout) = self.idle_timeout else {
+            return Ok(());
+        };
+        if self.activity.requests.load(Ordering::SeqCst) > 0 {
+            return Ok(());
+        }
+        let deadline = tokio::time::Instant::from_std(*self.activity.idle_since.lock().unwrap() + idle_timeout);
+        if self.deadline.deadline() != deadline {
+            self.deadline.as_mut().reset(deadline);
+        }
+        match self.deadline.as_mut().poll(cx) {
+            Poll::Ready(()) => Err(io::Error::new(io::ErrorKind::TimedOut, "connection idle for too long")),
+            Poll::Pending => Ok(()),
+        }
+    }
+}
+
+impl<T: AsyncRead + Unpin> AsyncRead for GuardedIo<T> {
+    fn poll_read(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut ReadBuf<'_>) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        match Pin::new(&mut this.io).poll_read(cx, buf) {
+            Poll::Pending => match this.poll_idle(cx) {
+                Ok(()) => Poll::Pending,
+                Err(e) => Poll::Ready(Err(e)),
+            },
+            ready => ready,
+        }
+    }
+}
+
+impl<T: AsyncWrite + Unpin> AsyncWrite for GuardedIo<T> {
+    fn poll_write(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8]) -> Poll<io::Result<usize>> {
+        let this = self.get_mut();
+        match Pin::new(&mut this.io).poll_write(cx, buf) {
+            Poll::Pending => match this.poll_idle(cx) {
+                Ok(()) => Poll::Pending,
+                Err(e) => Poll::Ready(Err(e)),
+            },
+            ready => ready,
+        }
+    }
+
+    fn poll_write_vectored(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        bufs: &[io::IoSlice<'_>],
+    ) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.get_mut().io).poll_write_vectored(cx, bufs)
+    }
+
+    fn is_write_vectored(&self) -> bool {
+        self.io.is_write_vectored()
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().io).poll_flush(cx)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().io).poll_shutdown(cx)
+    }
+}
+
+// Listener that holds back connections past the server group's limits and turns away
+// banned peers and peers with too many connections as soon as they connect
+pub struct GuardedListener<L> {
+    inner: L,
+    guard: Arc<ServerGuard>,
+}
+
+impl<L> GuardedListener<L> {
+    pub fn new(inner: L, guard: Arc<ServerGuard>) -> Self {
+        GuardedListener { inner, guard }
+    }
+}
+
+impl<L> Listener for GuardedListener<L>
+where
+    L: Listener,
+    L::Addr: PeerKey,
+{
+    type Io = GuardedIo<L::Io>;
+    type Addr = L::Addr;
+
+    async fn accept(&mut self) -> (Self::Io, Self::Addr) {
+        loop {
+            // Past the limit, connections wait in the backlog until others close
+            let permit = match &self.guard.connections {
+                Some(connections) => Some(
+                    connections
+                        .clone()
+                        .acquire_owned()
+                        .await
+                        .expect("the connection semaphore is never closed"),
+                ),
+                None => None,
+            };
+            let (io, addr) = self.inner.accept().await;
+            let peer = addr.is_distinct().then(|| addr.peer_key());
+            if !self.guard.open_connection(peer.as_deref()) {
+                continue;
+            }
+            let activity = Arc::new(ConnectionActivity {
+                requests: AtomicUsize::new(0),
+                idle_since: StdMutex::new(Instant::now()),
+            });
+            let idle_timeout = self.guard.limits.idle_timeout_secs.map(Duration::from_secs);
+            let slot = ConnectionSlot {
+                guard: self.guard.clone(),
+                peer,
+                _permit: permit,
+            };
+            let io = GuardedIo {
+                io,
+                activity,
+                idle_timeout,
+                deadline: Box::pin(tokio::time::sleep(idle_timeout.unwrap_or_default())),
+                _slot: slot,
+            };
+            return (io, addr);
+        }
+    }
+
+    fn local_addr(&self) -> io::Result<Self::Addr> {
+        self.inner.local_addr()
+    }
+}
+
+// Turn away banned and overly busy peers, ban peers that keep failing to authenticate,
+// and keep the connection from timing out as idle while a request is served
+pub async fn guard_requests(
+    axum::extract::State(guard): axum::extract::State<Arc<ServerGuard>>,
+    request: Request,
+    next: Next,
+) -> Response {
+    let Some(ConnectInfo(connection)) = request.extensions().get::<ConnectInfo<PeerConnection>>().cloned() else {
+        return next.run(request).await;
+    };
+    if connection.distinct {
+        if let Some(remaining) = guard.ban_remaining(&connection.peer) {
+            let headers = [(header::RETRY_AFTER, remaining.as_secs().max(1).to_string())];
+            return (StatusCode::FORBIDDEN, headers).into_response();
+        }
+        if let Err(retry_after) = guard.take_request(&connection.peer) {
+            let headers = [(header::RETRY_AFTER, retry_after.as_secs().max(1).to_string())];
+            return (StatusCode::TOO_MANY_REQUESTS, headers).into_response();
+        }
+    }
+
+    let active = ActiveRequest::begin(&connection.activity);
+    let response = next.run(request).await;
+    if connection.distinct && response.status() == StatusCode::UNAUTHORIZED {
+        guard.record_auth_failure(&connection.peer);
+    }
+    // The request is over once its response has been sent
+    response.map(|body| {
+        Body::from_stream(body.into_data_stream().map(move |data| {
+            let _ = &active;
+            data
+        }))
+    })
+}
@@ -1,26 +1,72 @@
-use crate::types::{  ServerMode, ShutdownServerMap, ServerIdState, NetworkName, Address, Network, ServerGroup, ServerGroupSerde};
+use crate::types::{  ServerMode, Transport, ServerLimits, ShutdownServerMap, ServerIdState, NetworkName, Address, Network, ServerGroup, ServerGroupSerde, FileEntry, LinkedPath, Chunk, IndexedFile, DeviceInfo, SearchQuery, SearchResult, SyncListing};
+use crate::access_log::log_access;
+use crate::bandwidth::{throttle_stream, Direction, Throttle};
+use crate::chunk_store::{file_chunks, invalidate_file_chunks};
+use crate::file_events::subscribe_file_events;
+use crate::file_hash::{file_hash, invalidate_file_hash};
+use crate::file_index::{relative_path_string, with_file_index};
+use crate::ignore_rules::IgnoreRules;
+use crate::local_dir::device_id;
+use crate::net_interfaces::{serving_addresses, socket_addr};
+use crate::peer_discovery::{advertise_network, stop_advertising};
+use crate::hole_punch::listen_for_punched;
+use crate::quic_transport::{self, accept_clients, bind_udp, QUIC_SCHEME};
+use crate::relay_tunnel::{listen_on_relay, tunnel_listener};
+use crate::search::search_linked_paths;
+use crate::server_guard::{guard_requests, GuardedListener, PeerConnection, ServerGuard};
+use crate::server_stats::{record_stats, register_server_stats, ServerStats};
+use crate::server_upload::upload_router;
+use crate::url_path::{decode_path_segment, decode_relative_path, encode_path_segment};
 use tauri::State;
-use tokio::sync::mpsc;
+use tokio::sync::{broadcast, mpsc, oneshot};
+use tokio::task::JoinHandle;
 use axum::{ routing::get, Router,
-    response::Json
+    middleware::{self, Next},
+    extract::{ConnectInfo, Query, Request},
+    response::{IntoResponse, Json, Response},
+    handler::HandlerWithoutStateExt,
+    http::{header, StatusCode, Uri},
+    body::Body,
 };
+use std::collections::{HashMap, HashSet};
 use std::net::SocketAddr;
+use socket2::{Domain, Protocol, Socket, Type};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::Duration;
+use tower::ServiceBuilder;
+use url::Url;
+use futures_util::StreamExt;
+use tokio_util::io::{ReaderStream, SyncIoBridge};
 use tower_http::{
+    compression::CompressionLayer,
     services::ServeDir,
+    timeout::{RequestBodyTimeoutLayer, TimeoutLayer},
     trace::TraceLayer,
 };
 
+// Top-level route reserved for the host API, so no linked path may use this name
+pub const API_ROUTE_NAME: &str = "api";
+// Number of entries an archive holds, directories included
+pub const ARCHIVE_ENTRIES_HEADER: &str = "x-topaz-archive-entries";
+// How often a running server looks for changed interface addresses
+const INTERFACE_POLL_INTERVAL: Duration = Duration::from_secs(10);
+
 #[tauri::command]
 pub async fn start_file_server_command(
     server_mode: ServerMode,
     network: Network,
+    transport: Option<Transport>,
+    limits: Option<ServerLimits>,
     shutdown_map: State<'_, ShutdownServerMap>,
     server_id_state: State<'_, ServerIdState>,
 ) -> tauri::Result<()> {
     let shutdown_map = shutdown_map.inner().clone();
     let server_id_state = server_id_state.inner().clone();
+    let transport = transport.unwrap_or_default();
+    let limits = limits.unwrap_or_default();
     tokio::spawn(async move {
-        match file_server(server_mode, network, shutdown_map, server_id_state).await {
+        match file_server(server_mode, network, transport, limits, shutdown_map, server_id_state).await {
             Ok(_) => {}
             Err(e) => {
                 eprintln!("Server error: {}", e);
@@ -58,79 +104,706 @@ pub async fn stop_file_server_command(
     Ok(())
 }
 
+// Routes of a network: its linked paths and the host API
+fn network_router(network: &Network, guard: &Arc<ServerGuard>, stats: Arc<ServerStats>) -> Router {
+    let linked_paths_entries: Vec<FileEntry> = network.linked_paths.iter().map(|linked_path| FileEntry {
+        name: linked_path.name.clone(),
+        is_dir: true,
+        hash: None,
+        size: None,
+    }).collect();
+    let serve_linked_paths_names = get(Json(linked_paths_entries));
+    let linked_paths = Arc::new(network.linked_paths.clone());
+    let linked_paths_clone = linked_paths.clone();
+    let serve_file_chunks = get(move |uri: Uri| get_file_chunks(linked_paths_clone.clone(), uri));
+    let linked_paths_clone = linked_paths.clone();
+    let serve_archive = get(move |uri: Uri| get_archive(linked_paths_clone.clone(), uri));
+    let linked_paths_clone = linked_paths.clone();
+    let serve_tree = get(move |uri: Uri| get_tree(linked_paths_clone.clone(), uri));
+    let linked_paths_clone = linked_paths.clone();
+    let serve_sync_listing = get(move |uri: Uri| get_sync_listing(linked_paths_clone.clone(), uri));
+    let serve_search = get(move |query: Query<SearchQuery>| search(linked_paths.clone(), query));
+
+    let mut app = Router::new()
+    .route("/", serve_linked_paths_names)
+    .route(&format!("/{}/chunks/{{*path}}", API_ROUTE_NAME), serve_file_chunks)
+    .route(&format!("/{}/archive/{{*path}}", API_ROUTE_NAME), serve_archive)
+    .route(&format!("/{}/tree/{{*path}}", API_ROUTE_NAME), serve_tree)
+    .route(&format!("/{}/sync/{{*path}}", API_ROUTE_NAME), serve_sync_listing)
+    .route(&format!("/{}/search", API_ROUTE_NAME), serve_search)
+    .route(&format!("/{}/device", API_ROUTE_NAME), get(Json(DeviceInfo { device_id: device_id() })));
+
+
+    for linked_path in &network.linked_paths {
+        if linked_path.name == API_ROUTE_NAME {
+            eprintln!("Linked path name '{}' is reserved, not serving it", API_ROUTE_NAME);
+            continue;
+        }
+        println!("{:?}",linked_path.path.clone());
+        let served_path = linked_path.clone();
+        let list_dir = move |uri: Uri| list_directory(served_path.clone(), uri);
+        // Files are served as-is, directories fall through to the JSON listing
+        let dir = ServeDir::new(linked_path.path.clone())
+            .append_index_html_on_directories(false)
+            .fallback(list_dir.into_service());
+        // Routes are matched against the encoded request path
+        let dir = ServiceBuilder::new()
+            .layer(middleware::from_fn_with_state(linked_path.clone(), hide_ignored))
+            .service(dir);
+        app = app.nest_service(&format!("/{}", encode_path_segment(&linked_path.name)), dir);
+    }
+
+    // Uploads only respond once their whole body is in, however long a slow or throttled
+    // peer takes to send it, so only the idle timeout applies to them
+    let limits = guard.limits();
+    if let Some(request_timeout) = limits.request_timeout_secs {
+        app = app.layer(TimeoutLayer::new(Duration::from_secs(request_timeout)));
+    }
+    let app = app.merge(upload_router(network));
+
+    // Compression is negotiated with Accept-Encoding and skipped for already
+    // compressed content types and range responses. Throttling comes after it, so
+    // limits apply to the bytes that go over the network
+    let mut app = app.layer(CompressionLayer::new());
+    if let Some(idle_timeout) = limits.idle_timeout_secs {
+        app = app.layer(RequestBodyTimeoutLayer::new(Duration::from_secs(idle_timeout)));
+    }
+    app.layer(middleware::from_fn_with_state(network.name.clone(), throttle_transfers))
+        .layer(middleware::from_fn_with_state(guard.clone(), guard_requests))
+        .layer(middleware::from_fn_with_state(stats, record_stats))
+        .layer(middleware::from_fn_with_state(network.name.clone(), log_access))
+        .layer(TraceLayer::new_for_http())
+}
+
+// Peer a request came from: its IP address, or the listener it came through for peers
+// behind relays and QUIC streams
+fn peer_addr(request: &Request) -> Option<String> {
+    request
+        .extensions()
+        .get::<ConnectInfo<PeerConnection>>()
+        .map(|ConnectInfo(connection)| connection.peer.clone())
+}
+
+// Pace responses and uploads by the bandwidth limits of the device, the network and the peer
+async fn throttle_transfers(
+    axum::extract::State(network_name): axum::extract::State<String>,
+    request: Request,
+    next: Next,
+) -> Response {
+    let peer = peer_addr(&request);
+    let download = Throttle::new(Direction::Download, Some(&network_name), peer.as_deref());
+    let request = request.map(|body| Body::from_stream(throttle_stream(body.into_data_stream(), download)));
+    let upload = Throttle::new(Direction::Upload, Some(&network_name), peer.as_deref());
+    next.run(request)
+        .await
+        .map(|body| Body::from_stream(throttle_stream(body.into_data_stream(), upload)))
+}
+
 pub async fn file_server(
     server_mode: ServerMode,
     network: Network,
+    transport: Transport,
+    limits: ServerLimits,
     shutdown_map: ShutdownServerMap,
     server_id_state: ServerIdState,
 ) -> tokio::io::Result<()> {
     log::info!("starting HTTP server at http://localhost:8080");
-    let mut addresses = Vec::new();
     let port = 8080;
 
     match server_mode {
         ServerMode::LocalHost => {
-            let linked_paths_names: Vec<String> = network.linked_paths.iter().map(|linked_path| linked_path.name.clone()).collect();
-            let serve_linked_paths_names = get(Json(linked_paths_names));
-            
-
-            let mut app = Router::new()
-            .route("/", serve_linked_paths_names);
+            let guard = ServerGuard::new(limits.clone());
+            let id = server_id_state.generate_server_id().await;
+            // Listed by `get_server_stats` until the server group stops
+            let stats = register_server_stats(&network.name, id, &guard);
+            let app = network_router(&network, &guard, stats.stats());
+            let (tx, mut rx) = mpsc::channel::<()>(1);
 
+            // Without selected interfaces one IPv4 and one IPv6 listener take every address,
+            // otherwise each address of the selected interfaces gets its own
+            let mut listeners = HashMap::new();
+            if network.interfaces.is_empty() {
+                let mut bind_error = None;
+                for addr in [SocketAddr::from(([0, 0, 0, 0], port)), SocketAddr::from(([0u16; 8], port))] {
+                    match serve_listener(addr, app.clone(), transport, &guard).await {
+                        Ok(listener) => {
+                            listeners.insert(addr, listener);
+                        }
+                        Err(e) => {
+                            eprintln!("Failed to listen on {}: {}", addr, e);
+                            bind_error = Some(e);
+                        }
+                    }
+                }
+                // IPv6 may be turned off, but nothing listening at all is an error
+                if let (true, Some(e)) = (listeners.is_empty(), bind_error) {
+                    return Err(e);
+                }
+            }
+            let listens_on_ipv6 = listeners.keys().any(|addr| addr.is_ipv6());
+            let reachable_addresses = |addresses: Vec<Address>| -> Vec<Address> {
+                let addresses = addresses.into_iter().map(|address| with_transport(address, transport));
+                if network.interfaces.is_empty() && !listens_on_ipv6 {
+                    addresses.filter(|address| !address.ip.contains(':')).collect()
+                } else {
+                    addresses.collect()
+                }
+            };
+            let mut addresses = reachable_addresses(serving_addresses(&network.interfaces, port));
+            if !network.interfaces.is_empty() {
+                update_listeners(&mut listeners, &addresses, &app, transport, &guard).await;
+            }
 
-            for linked_path in &network.linked_paths {
-                println!("{:?}",linked_path.path.clone());
-                let dir = ServeDir::new(linked_path.path.clone());
-                app = app.nest_service(&format!("/{}", linked_path.name),dir);
+            println!("Server is accessible at the following addresses:");
+            for address in &addresses {
+                println!("{} ({:?})", address.url, address.kind);
             }
 
-            let addr = SocketAddr::from(([0, 0, 0, 0], port));
-            let listener = tokio::net::TcpListener::bind(addr).await.unwrap();
-            println!("listening on {}", listener.local_addr().unwrap());
-            let (tx, mut rx) = mpsc::channel::<()>(1);
+            // Let peers on the local network find this host. Discovered hosts are
+            // reached over TCP, so only TCP servers are advertised
+            let advertised = (transport == Transport::Tcp)
+                .then(|| {
+                    advertise_network(&network.name, port)
+                        .map_err(|e| eprintln!("Failed to advertise network {}: {}", network.name, e))
+                        .ok()
+                })
+                .flatten();
 
-            println!("Server is accessible at the following addresses:");
+            // TCP and QUIC listen on different protocols, so a server group of each
+            // can share the port
+            {
+                let mut map = shutdown_map.write().await;
+                map.entry(network.name.clone()).or_default().push(ServerGroup {
+                    id,
+                    addresses: addresses.clone(),
+                    transport,
+                    limits,
+                    tx
+                });
+            }
 
-            // Retrieve IP addresses linked to this server
-            if let Ok(if_addrs) = get_if_addrs::get_if_addrs() {
-                for iface in if_addrs {
-                    if iface.ip().is_ipv4() {
-                        let ip = iface.ip().to_string();
-                        println!("http://{}:{}", ip, port);
-                        addresses.push(Address {
-                            ip,
-                            port,
-                        });
+            // Interfaces come and go with Wi-Fi switches and DHCP renewals, so the
+            // addresses are looked up again until the server is stopped
+            let mut interface_poll = tokio::time::interval(INTERFACE_POLL_INTERVAL);
+            loop {
+                tokio::select! {
+                    _ = rx.recv() => break, // Wait for shutdown signal
+                    _ = interface_poll.tick() => {
+                        let current = reachable_addresses(serving_addresses(&network.interfaces, port));
+                        if current == addresses {
+                            continue;
+                        }
+                        if !network.interfaces.is_empty() {
+                            update_listeners(&mut listeners, &current, &app, transport, &guard).await;
+                        }
+                        addresses = current;
+                        println!("Addresses of network {} changed:", network.name);
+                        for address in &addresses {
+                            println!("{} ({:?})", address.url, address.kind);
+                        }
+                        let mut map = shutdown_map.write().await;
+                        let server_group = map
+                            .get_mut(&network.name)
+                            .and_then(|server_groups| server_groups.iter_mut().find(|sg| sg.id == id));
+                        if let Some(server_group) = server_group {
+                            server_group.addresses = addresses.clone();
+                        }
                     }
                 }
             }
 
-            let id =  server_id_state.generate_server_id().await;
+            for listener in listeners.into_values() {
+                listener.stop().await;
+            }
+            if let Some(fullname) = advertised {
+                stop_advertising(&fullname);
+            }
+            return Ok(());
+        }
+        ServerMode::DarkWeb => {
+            return Ok(());
+        }
+        // Internet mode also waits at the rendezvous service for clients to connect
+        // directly, the relay is left for those that can't
+        mode @ (ServerMode::Relay | ServerMode::Internet) => {
+            let Some(relay) = network.relay.clone() else {
+                return Err(std::io::Error::new(
+                    std::io::ErrorKind::InvalidInput,
+                    format!("Network {} has no relay", network.name),
+                ));
+            };
+            let guard = ServerGuard::new(limits.clone());
+            let id = server_id_state.generate_server_id().await;
+            let stats = register_server_stats(&network.name, id, &guard);
+            let app = network_router(&network, &guard, stats.stats());
+            let (tx, mut rx) = mpsc::channel::<()>(1);
+            let (incoming, listener) = tunnel_listener(&relay.url);
+            let punched = match (&mode, &relay.rendezvous) {
+                (ServerMode::Internet, Some(rendezvous)) => {
+                    let punched = listen_for_punched(&relay, rendezvous, incoming.clone()).await;
+                    if let Err(e) = &punched {
+                        eprintln!("Failed to wait at rendezvous service {}: {}", rendezvous, e);
+                    }
+                    punched.is_ok()
+                }
+                (ServerMode::Internet, None) => {
+                    eprintln!("Network {} has no rendezvous service, serving through the relay only", network.name);
+                    false
+                }
+                _ => false,
+            };
+            if let Err(e) = listen_on_relay(&relay, incoming).await {
+                if !punched {
+                    return Err(std::io::Error::other(e));
+                }
+                eprintln!("Failed to wait at relay {}: {}", relay.url, e);
+            }
+
+            // Clients reach the network at the relay, through a proxy of their own
+            let relay_url = Url::parse(&relay.url).ok();
+            let address = Address {
+                ip: relay_url
+                    .as_ref()
+                    .and_then(|url| url.host_str().map(str::to_string))
+                    .unwrap_or_default(),
+                port: relay_url.as_ref().and_then(|url| url.port_or_known_default()).unwrap_or(0),
+                interface: None,
+                kind: None,
+                url: relay.url.clone(),
+            };
 
             {
                 let mut map = shutdown_map.write().await;
-                let mut server_group_vector = Vec::new();
-                server_group_vector.push(ServerGroup {
+                map.entry(network.name.clone()).or_default().push(ServerGroup {
                     id,
-                    addresses,
-                    tx
+                    addresses: vec![address],
+                    transport: Transport::Tcp,
+                    limits,
+                    tx,
                 });
-                map.insert(network.name, server_group_vector);
             }
-            
-            axum::serve(listener, app.layer(TraceLayer::new_for_http()))
+
+            // Dropping the listener at shutdown leaves the relay room
+            let listener = GuardedListener::new(listener, guard);
+            let served = axum::serve(listener, app.into_make_service_with_connect_info::<PeerConnection>())
                 .with_graceful_shutdown(async move {
-                    rx.recv().await; // Wait for shutdown signal
+                    rx.recv().await;
                 })
+                .await;
+            drop(stats);
+            return served;
+        }
+    }
+}
+
+// Serves the app on one address until stopped
+struct ServerListener {
+    stop_tx: oneshot::Sender<()>,
+    task: JoinHandle<()>,
+}
+
+impl ServerListener {
+    // Waits for requests in flight to finish
+    async fn stop(self) {
+        let _ = self.stop_tx.send(());
+        let _ = self.task.await;
+    }
+}
+
+// IPv6 sockets are kept to IPv6 so the wildcard IPv4 and IPv6 listeners don't collide
+// where the system would otherwise map IPv4 onto the IPv6 one
+fn bind_listener(addr: SocketAddr) -> std::io::Result<tokio::net::TcpListener> {
+    let socket = Socket::new(Domain::for_address(addr), Type::STREAM, Some(Protocol::TCP))?;
+    if addr.is_ipv6() {
+        socket.set_only_v6(true)?;
+    }
+    #[cfg(unix)]
+    socket.set_reuse_address(true)?;
+    socket.bind(&addr.into())?;
+    socket.listen(1024)?;
+    socket.set_nonblocking(true)?;
+    tokio::net::TcpListener::from_std(socket.into())
+}
+
+async fn serve_listener(
+    addr: SocketAddr,
+    app: Router,
+    transport: Transport,
+    guard: &Arc<ServerGuard>,
+) -> std::io::Result<ServerListener> {
+    let (stop_tx, stop_rx) = oneshot::channel::<()>();
+    let stopped = async move {
+        let _ = stop_rx.await;
+    };
+    let task = match transport {
+        Transport::Tcp => {
+            let listener = bind_listener(addr)?;
+            println!("listening on {}", listener.local_addr()?);
+            let listener = GuardedListener::new(listener, guard.clone());
+            tokio::spawn(async move {
+                let app = app.into_make_service_with_connect_info::<PeerConnection>();
+                if let Err(e) = axum::serve(listener, app).with_graceful_shutdown(stopped).await {
+                    eprintln!("Server on {} failed: {}", addr, e);
+                }
+            })
+        }
+        // Each stream a client opens is served like a TCP connection
+        Transport::Quic => {
+            let endpoint = quic_transport::endpoint(bind_udp(addr)?, true).map_err(std::io::Error::other)?;
+            println!("listening on {} (QUIC)", endpoint.local_addr()?);
+            let (streams, listener) = tunnel_listener(&format!("{}://{}", QUIC_SCHEME, addr));
+            let listener = GuardedListener::new(listener, guard.clone());
+            tokio::spawn(accept_clients(endpoint.clone(), streams));
+            tokio::spawn(async move {
+                let app = app.into_make_service_with_connect_info::<PeerConnection>();
+                if let Err(e) = axum::serve(listener, app).with_graceful_shutdown(stopped).await {
+                    eprintln!("Server on {} failed: {}", addr, e);
+                }
+                endpoint.close(0u32.into(), b"");
+            })
+        }
+    };
+    Ok(ServerListener { stop_tx, task })
+}
+
+// Address as clients of `transport` reach it
+fn with_transport(mut address: Address, transport: Transport) -> Address {
+    if transport == Transport::Quic {
+        if let Some(rest) = address.url.strip_prefix("http://") {
+            address.url = format!("{}://{}", QUIC_SCHEME, rest);
+        }
+    }
+    address
+}
+
+// Listen on each of `addresses` and stop listening on any other
+async fn update_listeners(
+    listeners: &mut HashMap<SocketAddr, ServerListener>,
+    addresses: &[Address],
+    app: &Router,
+    transport: Transport,
+    guard: &Arc<ServerGuard>,
+) {
+    let wanted: HashSet<SocketAddr> = addresses
+        .iter()
+        .filter_map(socket_addr)
+        .collect();
+    let gone: Vec<SocketAddr> = listeners.keys().filter(|addr| !wanted.contains(addr)).copied().collect();
+    for addr in gone {
+        if let Some(listener) = listeners.remove(&addr) {
+            listener.stop().await;
+        }
+    }
+    for addr in wanted {
+        if listeners.contains_key(&addr) {
+            continue;
+        }
+        match serve_listener(addr, app.clone(), transport, guard).await {
+            Ok(listener) => {
+                listeners.insert(addr, listener);
+            }
+            Err(e) => eprintln!("Failed to listen on {}: {}", addr, e),
+        }
+    }
+}
+
+// Keep ignored files and Topaz's own data directory out of what a linked path serves
+async fn hide_ignored(
+    axum::extract::State(linked_path): axum::extract::State<LinkedPath>,
+    request: Request,
+    next: Next,
+) -> Response {
+    match decode_relative_path(request.uri().path()) {
+        Some(relative_path) if !is_ignored_in(&linked_path, &relative_path) => next.run(request).await,
+        _ => StatusCode::NOT_FOUND.into_response(),
+    }
+}
+
+fn is_ignored_in(linked_path: &LinkedPath, relative_path: &Path) -> bool {
+    let is_dir = linked_path.path.join(relative_path).is_dir();
+    IgnoreRules::for_linked_path(linked_path).is_ignored(relative_path, is_dir)
+}
+
+// List a directory inside a linked path. `uri` is relative to the linked path root
+async fn list_directory(linked_path: LinkedPath, uri: Uri) -> Result<Json<Vec<FileEntry>>, StatusCode> {
+    let relative_path = decode_relative_path(uri.path()).ok_or(StatusCode::BAD_REQUEST)?;
+
+    // Directories the file index doesn't know yet are read from disk
+    let dir = relative_path_string(&relative_path);
+    let index_linked_path = linked_path.clone();
+    let indexed = tokio::task::spawn_blocking(move || {
+        with_file_index(&index_linked_path, |index| index.list_dir(&dir))
+    })
+    .await;
+    if let Ok(Ok(indexed)) = indexed {
+        if !indexed.is_empty() {
+            let entries = indexed
+                .into_iter()
+                .map(|entry| FileEntry {
+                    name: entry.path.rsplit('/').next().unwrap_or_default().to_string(),
+                    is_dir: entry.is_dir,
+                    size: entry.hash.is_some().then_some(entry.size),
+                    hash: entry.hash,
+                })
+                .collect();
+            return Ok(Json(entries));
+        }
+    }
+
+    let dir_path = linked_path.path.join(relative_path);
+    let rules = IgnoreRules::for_linked_path(&linked_path);
+
+    let mut read_dir = tokio::fs::read_dir(&dir_path)
+        .await
+        .map_err(|_| StatusCode::NOT_FOUND)?;
+    let mut entries = Vec::new();
+    while let Ok(Some(entry)) = read_dir.next_entry().await {
+        let Ok(file_type) = entry.file_type().await else {
+            continue;
+        };
+        let is_dir = file_type.is_dir();
+        if rules.is_ignored(&entry.path(), is_dir) {
+            continue;
+        }
+        // Hashes are cached, so only the first listing after a change reads the file
+        let (hash, size) = if is_dir {
+            (None, None)
+        } else {
+            let entry_path = entry.path();
+            let hash = tokio::task::spawn_blocking(move || file_hash(&entry_path).ok())
                 .await
-                .unwrap();
-            return Ok(());
+                .unwrap_or(None);
+            let size = entry.metadata().await.ok().map(|metadata| metadata.len());
+            (hash, size)
+        };
+        entries.push(FileEntry {
+            name: entry.file_name().to_string_lossy().into_owned(),
+            is_dir,
+            hash,
+            size,
+        });
+    }
+    entries.sort_by(|a, b| a.name.cmp(&b.name));
+
+    Ok(Json(entries))
+}
+
+// Split a request path of the form `<linked path name>/<relative path>` into the linked
+// path it points into and the decoded path relative to it
+pub fn resolve_linked_path_parts<'a>(
+    linked_paths: &'a [LinkedPath],
+    path: &str,
+) -> Option<(&'a LinkedPath, PathBuf)> {
+    let path = path.trim_start_matches('/');
+    let (name, relative) = path.split_once('/').unwrap_or((path, ""));
+    let name = decode_path_segment(name)?;
+    let linked_path = linked_paths
+        .iter()
+        .find(|linked_path| linked_path.name == name)?;
+    let relative_path = decode_relative_path(relative)?;
+    if is_ignored_in(linked_path, &relative_path) {
+        return None;
+    }
+    Some((linked_path, relative_path))
+}
+
+// Map a request path of the form `<linked path name>/<relative path>` to a file on disk
+pub fn resolve_linked_path(linked_paths: &[LinkedPath], path: &str) -> Option<PathBuf> {
+    let (linked_path, relative_path) = resolve_linked_path_parts(linked_paths, path)?;
+    Some(linked_path.path.join(relative_path))
+}
+
+// Request path below an API route, still percent-encoded
+pub fn api_route_path<'a>(uri: &'a Uri, route: &str) -> &'a str {
+    let prefix = format!("/{}/{}/", API_ROUTE_NAME, route);
+    uri.path().strip_prefix(prefix.as_str()).unwrap_or_default()
+}
+
+// Chunk list of a served file, used by clients to fetch only the chunks they lack
+async fn get_file_chunks(
+    linked_paths: Arc<Vec<LinkedPath>>,
+    uri: Uri,
+) -> Result<Json<Vec<Chunk>>, StatusCode> {
+    let path = api_route_path(&uri, "chunks");
+    let file_path = resolve_linked_path(&linked_paths, path).ok_or(StatusCode::NOT_FOUND)?;
+    if !file_path.is_file() {
+        return Err(StatusCode::NOT_FOUND);
+    }
+    let chunks = tokio::task::spawn_blocking(move || file_chunks(&file_path))
+        .await
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+    Ok(Json(chunks))
+}
+
+// Every file below a directory with its hash, so peers can compare whole trees at once
+async fn get_tree(linked_paths: Arc<Vec<LinkedPath>>, uri: Uri) -> Result<Json<Vec<IndexedFile>>, StatusCode> {
+    let path = api_route_path(&uri, "tree");
+    let (linked_path, relative_path) =
+        resolve_linked_path_parts(&linked_paths, path).ok_or(StatusCode::NOT_FOUND)?;
+    if !linked_path.path.join(&relative_path).is_dir() {
+        return Err(StatusCode::NOT_FOUND);
+    }
+    // Peers act on missing files, so the index is brought up to date first. Only files
+    // that changed since the last update are hashed
+    let linked_path = linked_path.clone();
+    let dir = relative_path_string(&relative_path);
+    let files = tokio::task::spawn_blocking(move || {
+        with_file_index(&linked_path, |index| {
+            index.update_path(&dir)?;
+            index.files_below(&dir)
+        })
+    })
+    .await
+    .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?
+    .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+    Ok(Json(files))
+}
+
+// Files below a directory with the clocks syncing devices order their versions by
+async fn get_sync_listing(linked_paths: Arc<Vec<LinkedPath>>, uri: Uri) -> Result<Json<SyncListing>, StatusCode> {
+    let path = api_route_path(&uri, "sync");
+    let (linked_path, relative_path) =
+        resolve_linked_path_parts(&linked_paths, path).ok_or(StatusCode::NOT_FOUND)?;
+    if !linked_path.path.join(&relative_path).is_dir() {
+        return Err(StatusCode::NOT_FOUND);
+    }
+    let linked_path = linked_path.clone();
+    let dir = relative_path_string(&relative_path);
+    let listing = tokio::task::spawn_blocking(move || {
+        with_file_index(&linked_path, |index| {
+            index.update_path(&dir)?;
+            index.sync_listing(&dir, &device_id())
+        })
+    })
+    .await
+    .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?
+    .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+    Ok(Json(listing))
+}
+
+// Files of every served linked path matching a name or glob, and their content if asked
+async fn search(
+    linked_paths: Arc<Vec<LinkedPath>>,
+    Query(query): Query<SearchQuery>,
+) -> Result<Json<Vec<SearchResult>>, (StatusCode, String)> {
+    let results = tokio::task::spawn_blocking(move || search_linked_paths(&linked_paths, &query))
+        .await
+        .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?
+        .map_err(|e| (StatusCode::BAD_REQUEST, e))?;
+    Ok(Json(results))
+}
+
+// Entries of a directory to archive, leaving out ignored ones, as their path on disk, their
+// path in the archive and whether they are a directory. Blocking
+fn list_archive_entries(
+    dir: &Path,
+    archive_path: &Path,
+    rules: &IgnoreRules,
+    entries: &mut Vec<(PathBuf, PathBuf, bool)>,
+) -> std::io::Result<()> {
+    entries.push((dir.to_path_buf(), archive_path.to_path_buf(), true));
+    for entry in std::fs::read_dir(dir)? {
+        let entry = entry?;
+        let file_type = entry.file_type()?;
+        if rules.is_ignored(&entry.path(), file_type.is_dir()) {
+            continue;
         }
-        ServerMode::Internet => {
-            return Ok(());
+        let entry_archive_path = archive_path.join(entry.file_name());
+        if file_type.is_dir() {
+            list_archive_entries(&entry.path(), &entry_archive_path, rules, entries)?;
+        } else {
+            entries.push((entry.path(), entry_archive_path, false));
         }
-        ServerMode::DarkWeb => {
-            return Ok(());
+    }
+    Ok(())
+}
+
+// Stream a directory inside a linked path as a tar archive. The entries are listed first
+// and their number sent in a header, so clients can tell the archive was cut short
+async fn get_archive(linked_paths: Arc<Vec<LinkedPath>>, uri: Uri) -> Result<Response, StatusCode> {
+    let path = api_route_path(&uri, "archive");
+    let (linked_path, relative_path) =
+        resolve_linked_path_parts(&linked_paths, path).ok_or(StatusCode::NOT_FOUND)?;
+    let dir_path = linked_path.path.join(relative_path);
+    if !dir_path.is_dir() {
+        return Err(StatusCode::NOT_FOUND);
+    }
+    let rules = IgnoreRules::for_linked_path(linked_path);
+    let archive_name = dir_path
+        .file_name()
+        .map(|name| name.to_string_lossy().into_owned())
+        .unwrap_or_else(|| "archive".to_string());
+
+    let list_path = dir_path.clone();
+    let entries = tokio::task::spawn_blocking(move || {
+        let mut entries = Vec::new();
+        list_archive_entries(&list_path, Path::new("."), &rules, &mut entries).map(|_| entries)
+    })
+    .await
+    .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?
+    .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+    let entry_count = entries.len();
+
+    // The archive is written on a blocking thread into a pipe that feeds the response body
+    let (writer, reader) = tokio::io::duplex(64 * 1024);
+    let (failed_tx, failed_rx) = oneshot::channel();
+    tokio::task::spawn_blocking(move || {
+        let mut builder = tar::Builder::new(SyncIoBridge::new(writer));
+        builder.follow_symlinks(false);
+        let appended = entries.iter().try_for_each(|(path, archive_path, is_dir)| {
+            if *is_dir {
+                builder.append_dir(archive_path, path)
+            } else {
+                builder.append_path_with_name(path, archive_path)
+            }
+        });
+        if let Err(e) = appended.and_then(|_| builder.finish()) {
+            eprintln!("Failed to archive {}: {}", dir_path.display(), e);
+            // Sent before the pipe closes, so the body ends with the error
+            let _ = failed_tx.send(e);
+        }
+    });
+    // The status was sent long before a failure partway through, ending the body with an
+    // error aborts the response instead of passing off the archive as complete
+    let failure = futures_util::stream::once(failed_rx).filter_map(|failed| async move { failed.ok().map(Err) });
+
+    let headers = [
+        (header::CONTENT_TYPE, "application/x-tar".to_string()),
+        (
+            header::CONTENT_DISPOSITION,
+            format!("attachment; filename*=UTF-8''{}.tar", encode_path_segment(&archive_name)),
+        ),
+    ];
+    let entries_header = [(ARCHIVE_ENTRIES_HEADER, entry_count.to_string())];
+    Ok((headers, entries_header, Body::from_stream(ReaderStream::new(reader).chain(failure))).into_response())
+}
+
+// Drop cached hashes and chunk lists of files as soon as they change
+pub async fn invalidate_caches_on_file_events() {
+    let mut file_events = subscribe_file_events();
+    loop {
+        match file_events.recv().await {
+            // Paths are resolved on disk to match the cache keys
+            Ok(file_event) => {
+                let _ = tokio::task::spawn_blocking(move || {
+                    invalidate_file_hash(&file_event.path);
+                    invalidate_file_chunks(&file_event.path);
+                    // The old name of a renamed file is relative to the linked path root
+                    let depth = file_event.relative_path.split('/').count();
+                    if let (Some(previous_path), Some(root)) =
+                        (&file_event.previous_path, file_event.path.ancestors().nth(depth))
+                    {
+                        let previous = root.join(previous_path);
+                        invalidate_file_hash(&previous);
+                        invalidate_file_chunks(&previous);
+                    }
+                })
+                .await;
+            }
+            // Missed events are caught by the size and mtime checks of the caches
+            Err(broadcast::error::RecvError::Lagged(_)) => continue,
+            Err(broadcast::error::RecvError::Closed) => break,
         }
     }
 }
@@ -147,6 +820,8 @@ pub async fn get_servers(
             .map(|sg| ServerGroupSerde {
                 id: sg.id,
                 addresses: sg.addresses.clone(),
+                transport: sg.transport,
+                limits: sg.limits.clone(),
             })
             .collect();
         Ok(server_groups_serde)
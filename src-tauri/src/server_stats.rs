@@ -0,0 +1,455 @@
+use crate::server_guard::ServerGuard;
+use crate::server_host::API_ROUTE_NAME;
+use crate::types::{FileStats, NetworkServerStats, ServerStatsSnapshot};
+use crate::url_path::decode_path_segment;
+use axum::body::Body;
+use axum::extract::Request;
+use axum::http::{header, Method};
+use axum::middleware::Next;
+use axum::response::{IntoResponse, Response};
+use axum::routing::get;
+use axum::Router;
+use futures_util::StreamExt;
+use std::collections::{BTreeMap, HashMap};
+use std::fmt::Write;
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex as StdMutex};
+use std::time::Instant;
+use tokio::sync::oneshot;
+
+// Files counted per server group. Past this, the least requested half is forgotten
+const MAX_TRACKED_FILES: usize = 10_000;
+// Files listed per server group by `get_server_stats` and the metrics endpoint
+const TOP_FILES: usize = 10;
+const DEFAULT_METRICS_PORT: u16 = 9464;
+
+lazy_static::lazy_static! {
+    // Statistics of the running server groups by server ID
+    static ref SERVER_STATS: StdMutex<HashMap<u64, Arc<ServerStats>>> = StdMutex::new(HashMap::new());
+    // Address of the metrics endpoint and how to stop it
+    static ref METRICS_ENDPOINT: StdMutex<Option<(SocketAddr, oneshot::Sender<()>)>> = StdMutex::new(None);
+}
+
+#[derive(Default)]
+struct FileCounters {
+    requests: u64,
+    bytes_sent: u64,
+}
+
+// Counters of one server group since it started
+pub struct ServerStats {
+    network_name: String,
+    server_id: u64,
+    guard: Arc<ServerGuard>,
+    started: Instant,
+    requests: AtomicU64,
+    client_errors: AtomicU64,
+    server_errors: AtomicU64,
+    bytes_sent: AtomicU64,
+    bytes_received: AtomicU64,
+    files: StdMutex<HashMap<String, FileCounters>>,
+}
+
+impl ServerStats {
+    fn count_sent(&self, file: Option<&str>, bytes: usize) {
+        self.bytes_sent.fetch_add(bytes as u64, Ordering::Relaxed);
+        if let Some(file) = file {
+            if let Some(counters) = self.files.lock().unwrap().get_mut(file) {
+                counters.bytes_sent += bytes as u64;
+            }
+        }
+    }
+
+    fn count_file_request(&self, file: &str) {
+        let mut files = self.files.lock().unwrap();
+        if !files.contains_key(file) && files.len() >= MAX_TRACKED_FILES {
+            let mut requests: Vec<u64> = files.values().map(|counters| counters.requests).collect();
+            let (_, median, _) = requests.select_nth_unstable(files.len() / 2);
+            let median = *median;
+            files.retain(|_, counters| counters.requests > median);
+        }
+        files.entry(file.to_string()).or_default().requests += 1;
+    }
+
+    fn top_files(&self) -> Vec<FileStats> {
+        let mut files: Vec<FileStats> = self
+            .files
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|(path, counters)| FileStats {
+                path: path.clone(),
+                requests: counters.requests,
+                bytes_sent: counters.bytes_sent,
+            })
+            .collect();
+        sort_top_files(&mut files);
+        files
+    }
+
+    fn snapshot(&self) -> ServerStatsSnapshot {
+        let mut top_files = self.top_files();
+        top_files.truncate(TOP_FILES);
+        with_error_rate(ServerStatsSnapshot {
+            network_name: self.network_name.clone(),
+            server_id: Some(self.server_id),
+            uptime_secs: self.started.elapsed().as_secs(),
+            requests: self.requests.load(Ordering::Relaxed),
+            client_errors: self.client_errors.load(Ordering::Relaxed),
+            server_errors: self.server_errors.load(Ordering::Relaxed),
+            error_rate: 0.0,
+            bytes_sent: self.bytes_sent.load(Ordering::Relaxed),
+            bytes_received: self.bytes_received.load(Ordering::Relaxed),
+            active_connections: self.guard.active_connections(),
+            top_files,
+        })
+    }
+}
+
+fn sort_top_files(files: &mut [FileStats]) {
+    files.sort_by(|a, b| b.requests.cmp(&a.requests).then(b.bytes_sent.cmp(&a.bytes_sent)));
+}
+
+fn with_error_rate(mut snapshot: ServerStatsSnapshot) -> ServerStatsSnapshot {
+    if snapshot.requests > 0 {
+        snapshot.error_rate = (snapshot.client_errors + snapshot.server_errors) as f64 / snapshot.requests as f64;
+    }
+    snapshot
+}
+
+// Keeps a server group's statistics listed until the server group stops
+pub struct StatsRegistration(Arc<ServerStats>);
+
+impl StatsRegistration {
+    pub fn stats(&self) -> Arc<ServerStats> {
+        self.0.clone()
+    }
+}
+
+impl Drop for StatsRegistration {
+    fn drop(&mut self) {
+        SERVER_STATS.lock().unwrap().remove(&self.0.server_id);
+    }
+}
+
+pub fn register_server_stats(network_name: &str, server_id: u64, guard: &Arc<ServerGuard>) -> StatsRegistration {
+    let stats = Arc::new(ServerStats {
+        network_name: network_name.to_string(),
+        server_id,
+        guard: guard.clone(),
+        started: Instant::now(),
+        requests: AtomicU64::new(0),
+        client_errors: AtomicU64::new(0),
+        server_errors: AtomicU64::new(0),
+        bytes_sent: AtomicU64::new(0),
+        bytes_received: AtomicU64::new(0),
+        files: StdMutex::new(HashMap::new()),
+    });
+    SERVER_STATS.lock().unwrap().insert(server_id, stats.clone());
+    StatsRegistration(stats)
+}
+
+// Counts the bytes of a response as they are sent
+struct SentCounter {
+    stats: Arc<ServerStats>,
+    file: Option<String>,
+}
+
+impl SentCounter {
+    fn count(&self, bytes: usize) {
+        self.stats.count_sent(self.file.as_deref(), bytes);
+    }
+}
+
+// Count requests, errors and bytes going each way, and which files are fetched
+pub async fn record_stats(
+    axum::extract::State(stats): axum::extract::State<Arc<ServerStats>>,
+    request: Request,
+    next: Next,
+) -> Response {
+    let is_get = request.method() == Method::GET;
+    let path = decode_path_segment(request.uri().path()).unwrap_or_else(|| request.uri().path().to_string());
+    let received = stats.clone();
+    let request = request.map(|body| {
+        Body::from_stream(body.into_data_stream().map(move |data| {
+            if let Ok(data) = &data {
+                received.bytes_received.fetch_add(data.len() as u64, Ordering::Relaxed);
+            }
+            data
+        }))
+    });
+
+    let response = next.run(request).await;
+    stats.requests.fetch_add(1, Ordering::Relaxed);
+    let status = response.status();
+    if status.is_client_error() {
+        stats.client_errors.fetch_add(1, Ordering::Relaxed);
+    } else if status.is_server_error() {
+        stats.server_errors.fetch_add(1, Ordering::Relaxed);
+    }
+    // Directory listings and API calls aren't files
+    let is_file = is_get
+        && status.is_success()
+        && !path.ends_with('/')
+        && !path.starts_with(&format!("/{}/", API_ROUTE_NAME));
+    let file = is_file.then_some(path);
+    if let Some(file) = &file {
+        stats.count_file_request(file);
+    }
+    let counter = SentCounter { stats, file };
+    response.map(|body| {
+        Body::from_stream(body.into_data_stream().map(move |data| {
+            if let Ok(data) = &data {
+                counter.count(data.len());
+            }
+            data
+        }))
+    })
+}
+
+fn registered_stats() -> Vec<Arc<ServerStats>> {
+    SERVER_STATS.lock().unwrap().values().cloned().collect()
+}
+
+// Statistics of each running server group and the totals of their networks
+#[tauri::command]
+pub async fn get_server_stats(network_name: Option<String>) -> Result<Vec<NetworkServerStats>, String> {
+    let mut networks: BTreeMap<String, Vec<Arc<ServerStats>>> = BTreeMap::new();
+    for stats in registered_stats() {
+        if network_name.as_ref().is_none_or(|name| *name == stats.network_name) {
+            networks.entry(stats.network_name.clone()).or_default().push(stats);
+        }
+    }
+    Ok(networks
+        .into_iter()
+        .map(|(network_name, server_groups)| network_stats(network_name, &server_groups))
+        .collect())
+}
+
+fn network_stats(network_name: String, server_groups: &[Arc<ServerStats>]) -> NetworkServerStats {
+    let mut totals = ServerStatsSnapshot {
+        network_name,
+        ..Default::default()
+    };
+    let mut files: HashMap<String, FileStats> = HashMap::new();
+    let mut snapshots = Vec::new();
+    for stats in server_groups {
+        let snapshot = stats.snapshot();
+        totals.uptime_secs = totals.uptime_secs.max(snapshot.uptime_secs);
+        totals.requests += snapshot.requests;
+        totals.client_errors += snapshot.client_errors;
+        totals.server_errors += snapshot.server_errors;
+        totals.bytes_sent += snapshot.bytes_sent;
+        totals.bytes_received += snapshot.bytes_received;
+        totals.active_connections += snapshot.active_connections;
+        for file in stats.top_files() {
+            let total = files.entry(file.path.clone()).or_insert(FileStats {
+                path: file.path,
+                requests: 0,
+                bytes_sent: 0,
+            });
+            total.requests += file.requests;
+            total.bytes_sent += file.bytes_sent;
+        }
+        snapshots.push(snapshot);
+    }
+    snapshots.sort_by_key(|snapshot| snapshot.server_id);
+    let mut top_files: Vec<FileStats> = files.into_values().collect();
+    sort_top_files(&mut top_files);
+    top_files.truncate(TOP_FILES);
+    totals.top_files = top_files;
+    NetworkServerStats {
+        network: with_error_rate(totals),
+        server_groups: snapshots,
+    }
+}
+
+// Name, help text and value of a metric
+type Metric<T> = (&'static str, &'static str, fn(&T) -> u64);
+
+// Label values are quoted, with backslashes, quotes and newlines escaped
+fn label_value(value: &str) -> String {
+    value.replace('\\', "\\\\").replace('"', "\\\"").replace('\n', "\\n")
+}
+
+// Counters of every running server group in the Prometheus text format
+fn render_metrics() -> String {
+    let mut stats = registered_stats();
+    stats.sort_by_key(|stats| stats.server_id);
+    let snapshots: Vec<ServerStatsSnapshot> = stats.iter().map(|stats| stats.snapshot()).collect();
+    let labels = |snapshot: &ServerStatsSnapshot| {
+        format!(
+            "network=\"{}\",server_id=\"{}\"",
+            label_value(&snapshot.network_name),
+            snapshot.server_id.unwrap_or_default()
+        )
+    };
+
+    let mut metrics = String::new();
+    let series: [(&str, Metric<ServerStatsSnapshot>); 5] = [
+        ("counter", ("topaz_requests_total", "Requests served", |s| s.requests)),
+        ("counter", ("topaz_bytes_sent_total", "Bytes sent in responses", |s| s.bytes_sent)),
+        ("counter", ("topaz_bytes_received_total", "Bytes received in requests", |s| s.bytes_received)),
+        ("gauge", ("topaz_active_connections", "Connections currently open", |s| s.active_connections as u64)),
+        ("gauge", ("topaz_uptime_seconds", "Seconds since the server group started", |s| s.uptime_secs)),
+    ];
+    for (kind, (name, help, value)) in series {
+        let _ = writeln!(metrics, "# HELP {} {}\n# TYPE {} {}", name, help, name, kind);
+        for snapshot in &snapshots {
+            let _ = writeln!(metrics, "{}{{{}}} {}", name, labels(snapshot), value(snapshot));
+        }
+    }
+
+    let _ = writeln!(metrics, "# HELP topaz_errors_total Responses with an error status\n# TYPE topaz_errors_total counter");
+    for snapshot in &snapshots {
+        let _ = writeln!(metrics, "topaz_errors_total{{{},class=\"4xx\"}} {}", labels(snapshot), snapshot.client_errors);
+        let _ = writeln!(metrics, "topaz_errors_total{{{},class=\"5xx\"}} {}", labels(snapshot), snapshot.server_errors);
+    }
+
+    let file_series: [Metric<FileStats>; 2] = [
+        ("topaz_file_requests_total", "Requests for the most requested files", |f| f.requests),
+        ("topaz_file_bytes_sent_total", "Bytes sent of the most requested files", |f| f.bytes_sent),
+    ];
+    for (name, help, value) in file_series {
+        let _ = writeln!(metrics, "# HELP {} {}\n# TYPE {} counter", name, help, name);
+        for snapshot in &snapshots {
+            for file in &snapshot.top_files {
+                let _ = writeln!(metrics, "{}{{{},path=\"{}\"}} {}", name, labels(snapshot), label_value(&file.path), value(file));
+            }
+        }
+    }
+    metrics
+}
+
+async fn serve_metrics() -> impl IntoResponse {
+    ([(header::CONTENT_TYPE, "text/plain; version=0.0.4")], render_metrics())
+}
+
+// Serve the statistics of all server groups at `/metrics` on loopback only, so they
+// aren't exposed to peers. Returns the endpoint's URL
+#[tauri::command]
+pub async fn start_metrics_endpoint(port: Option<u16>) -> Result<String, String> {
+    let addr = SocketAddr::from(([127, 0, 0, 1], port.unwrap_or(DEFAULT_METRICS_PORT)));
+    if let Some((running, _)) = &*METRICS_ENDPOINT.lock().unwrap() {
+        if port.is_none_or(|port| port == running.port()) {
+            return Ok(format!("http://{}/metrics", running));
+        }
+        return Err(format!("Metrics are already served at {}", running));
+    }
+
+    let listener = tokio::net::TcpListener::bind(addr).await.map_err(|e| e.to_string())?;
+    let addr = listener.local_addr().map_err(|e| e.to_string())?;
+    let (stop_tx, stop_rx) = oneshot::channel::<()>();
+    {
+        let mut endpoint = METRICS_ENDPOINT.lock().unwrap();
+        if let Some((running, _)) = &*endpoint {
+            return Err(format!("Metrics are already served at {}", running));
+        }
+        *endpoint = Some((addr, stop_tx));
+    }
+
+    let app = Router::new().route("/metrics", get(serve_metrics));
+    tokio::spawn(async move {
+        let served = axum::serve(listener, app)
+            .with_graceful_shutdown(async move {
+                let _ = stop_rx.await;
+            })
+            .await;
+        if let Err(e) = served {
+            eprintln!("Metrics endpoint at {} failed: {}", addr, e);
+        }
+        let mut endpoint = METRICS_ENDPOINT.lock().unwrap();
+        if endpoint.as_ref().is_some_and(|(running, _)| *running == addr) {
+            *endpoint = None;
+        }
+    });
+    Ok(format!("http://{}/metrics", addr))
+}
+
+#[tauri::command]
+pub async fn stop_metrics_endpoint() -> Result<(), String> {
+    if let Some((_, stop_tx)) = METRICS_ENDPOINT.lock().unwrap().take() {
+        let _ = stop_tx.send(());
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::types::ServerLimits;
+
+    fn register(network_name: &str, server_id: u64) -> StatsRegistration {
+        register_server_stats(network_name, server_id, &ServerGuard::new(ServerLimits::default()))
+    }
+
+    fn serve(stats: &ServerStats, file: &str, bytes: usize) {
+        stats.requests.fetch_add(1, Ordering::Relaxed);
+        stats.count_file_request(file);
+        stats.count_sent(Some(file), bytes);
+    }
+
+    #[test]
+    fn network_totals_add_up_their_server_groups() {
+        let first = register("totals", 9_100_001);
+        let second = register("totals", 9_100_002);
+        serve(&first.stats(), "/a.txt", 10);
+        serve(&first.stats(), "/b.txt", 5);
+        serve(&second.stats(), "/b.txt", 5);
+        serve(&second.stats(), "/b.txt", 5);
+        second.stats().client_errors.fetch_add(1, Ordering::Relaxed);
+
+        let stats = network_stats("totals".to_string(), &[second.stats(), first.stats()]);
+        assert_eq!(stats.network.server_id, None);
+        assert_eq!(stats.network.requests, 4);
+        assert_eq!(stats.network.bytes_sent, 25);
+        assert_eq!(stats.network.error_rate, 0.25);
+        let top_files: Vec<(&str, u64, u64)> = stats
+            .network
+            .top_files
+            .iter()
+            .map(|file| (file.path.as_str(), file.requests, file.bytes_sent))
+            .collect();
+        assert_eq!(top_files, [("/b.txt", 3, 15), ("/a.txt", 1, 10)]);
+        let server_ids: Vec<Option<u64>> = stats.server_groups.iter().map(|snapshot| snapshot.server_id).collect();
+        assert_eq!(server_ids, [Some(9_100_001), Some(9_100_002)]);
+        assert_eq!(stats.server_groups[1].error_rate, 0.5);
+    }
+
+    #[test]
+    fn least_requested_files_are_forgotten_past_the_limit() {
+        let stats = register("forgotten", 9_200_001).stats();
+        serve(&stats, "/popular.txt", 1);
+        serve(&stats, "/popular.txt", 1);
+        for i in 0..MAX_TRACKED_FILES {
+            stats.count_file_request(&format!("/{}.txt", i));
+        }
+        let files = stats.files.lock().unwrap();
+        assert!(files.len() < MAX_TRACKED_FILES);
+        assert_eq!(files["/popular.txt"].requests, 2);
+    }
+
+    #[test]
+    fn metrics_are_labelled_by_server_group_with_escaped_values() {
+        let registration = register("say \"hi\"\\", 9_300_001);
+        serve(&registration.stats(), "/new\nline.txt", 7);
+        registration.stats().server_errors.fetch_add(1, Ordering::Relaxed);
+
+        let metrics = render_metrics();
+        let labels = "network=\"say \\\"hi\\\"\\\\\",server_id=\"9300001\"";
+        for line in [
+            "# TYPE topaz_requests_total counter".to_string(),
+            "# TYPE topaz_active_connections gauge".to_string(),
+            format!("topaz_requests_total{{{}}} 1", labels),
+            format!("topaz_bytes_sent_total{{{}}} 7", labels),
+            format!("topaz_errors_total{{{},class=\"4xx\"}} 0", labels),
+            format!("topaz_errors_total{{{},class=\"5xx\"}} 1", labels),
+            format!("topaz_file_bytes_sent_total{{{},path=\"/new\\nline.txt\"}} 7", labels),
+        ] {
+            assert!(metrics.lines().any(|metric| metric == line), "{} missing from\n{}", line, metrics);
+        }
+        drop(registration);
+        assert!(!render_metrics().contains("9300001"));
+    }
+}
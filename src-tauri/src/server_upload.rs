@@ -0,0 +1,501 @@
+use crate::file_index::{index_path, index_synced_path, relative_path_string};
+use crate::file_versions::{keep_replaced_file, trash_file, version_store_dir};
+use crate::local_dir::TOPAZ_DIR_NAME;
+use crate::server_host::{api_route_path, resolve_linked_path_parts, API_ROUTE_NAME};
+use crate::types::{LinkedPath, Network, UploadOffset, VersionClock};
+use crate::url_path::is_safe_file_name;
+use crate::version_clock::CLOCK_HEADER;
+use axum::{
+    body::Body,
+    extract::{DefaultBodyLimit, Multipart, State},
+    http::{header, HeaderMap, StatusCode, Uri},
+    response::Json,
+    routing::get,
+    Router,
+};
+use futures_util::{Stream, StreamExt};
+use sha2::{Digest, Sha256};
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex as StdMutex};
+use std::time::{Duration, Instant};
+use tokio::fs as tokio_fs;
+use tokio::io::AsyncWriteExt;
+
+const UPLOAD_ROUTE: &str = "upload";
+// How long the space used by a linked path is tracked from what uploads wrote and freed,
+// before it is counted on disk again to catch up with local changes
+const USAGE_RECOUNT_INTERVAL: Duration = Duration::from_secs(60);
+
+lazy_static::lazy_static! {
+    // Bytes used by linked paths with a quota, and when they were last counted on disk
+    static ref QUOTA_USAGE: StdMutex<HashMap<PathBuf, (Instant, u64)>> = StdMutex::new(HashMap::new());
+}
+
+struct UploadState {
+    linked_paths: Vec<LinkedPath>,
+    upload_token_hash: Option<String>,
+}
+
+// Range of a resumable upload chunk, from a `Content-Range: bytes <start>-<end>/<total>` header
+struct UploadRange {
+    start: u64,
+    total: u64,
+}
+
+// Routes that let peers write into the writable linked paths of a network:
+// `GET` returns how much of a resumable upload the host already has, `PUT` writes a
+// file or one chunk of it, `POST` takes multipart form files for a directory and
+// `DELETE` removes a file
+pub fn upload_router(network: &Network) -> Router {
+    let state = Arc::new(UploadState {
+        linked_paths: network.linked_paths.clone(),
+        // Configs not yet migrated by `hash_saved_upload_tokens` still hold the token
+        upload_token_hash: network
+            .upload_token_hash
+            .clone()
+            .or_else(|| network.upload_token.as_deref().map(hash_upload_token)),
+    });
+    Router::new()
+        .route(
+            &format!("/{}/{}/{{*path}}", API_ROUTE_NAME, UPLOAD_ROUTE),
+            get(upload_offset)
+                .put(upload_file)
+                .post(upload_multipart)
+                .delete(delete_file),
+        )
+        // Upload size is bounded by the linked path's quota instead
+        .layer(DefaultBodyLimit::disable())
+        .with_state(state)
+}
+
+// Hex SHA-256 of an upload token, as saved in the config
+pub fn hash_upload_token(token: &str) -> String {
+    hex::encode(Sha256::digest(token.as_bytes()))
+}
+
+fn authorize(state: &UploadState, headers: &HeaderMap) -> Result<(), StatusCode> {
+    let Some(upload_token_hash) = &state.upload_token_hash else {
+        return Err(StatusCode::FORBIDDEN);
+    };
+    let bearer = headers
+        .get(header::AUTHORIZATION)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix("Bearer "))
+        .ok_or(StatusCode::UNAUTHORIZED)?;
+    // Digests are compared, so the check takes the same time however much of the token matches
+    if !hash_upload_token(bearer).eq_ignore_ascii_case(upload_token_hash) {
+        return Err(StatusCode::UNAUTHORIZED);
+    }
+    Ok(())
+}
+
+fn writable_target<'a>(state: &'a UploadState, uri: &Uri) -> Result<(&'a LinkedPath, PathBuf), StatusCode> {
+    let path = api_route_path(uri, UPLOAD_ROUTE);
+    let (linked_path, relative_path) =
+        resolve_linked_path_parts(&state.linked_paths, path).ok_or(StatusCode::NOT_FOUND)?;
+    if !linked_path.writable {
+        return Err(StatusCode::FORBIDDEN);
+    }
+    Ok((linked_path, relative_path))
+}
+
+// Uploads are assembled inside the linked path's Topaz directory, on the same file
+// system as the target, so the final rename is atomic
+fn staging_path(linked_path: &LinkedPath, relative_path: &Path) -> PathBuf {
+    let name = hex::encode(Sha256::digest(relative_path.to_string_lossy().as_bytes()));
+    linked_path
+        .path
+        .join(TOPAZ_DIR_NAME)
+        .join("uploads")
+        .join(format!("{}.part", name))
+}
+
+// Multipart uploads can't be resumed, so each gets its own staging file and doesn't
+// touch a resumable upload of the same file
+fn multipart_staging_path(linked_path: &LinkedPath) -> PathBuf {
+    linked_path
+        .path
+        .join(TOPAZ_DIR_NAME)
+        .join("uploads")
+        .join(format!("{}.multipart", uuid::Uuid::new_v4()))
+}
+
+fn parse_content_range(headers: &HeaderMap) -> Result<Option<UploadRange>, StatusCode> {
+    let Some(value) = headers.get(header::CONTENT_RANGE) else {
+        return Ok(None);
+    };
+    let range = value
+        .to_str()
+        .ok()
+        .and_then(|value| value.strip_prefix("bytes "))
+        .and_then(|value| value.split_once('/'))
+        .and_then(|(range, total)| {
+            let (start, _end) = range.split_once('-')?;
+            Some(UploadRange {
+                start: start.parse().ok()?,
+                total: total.parse().ok()?,
+            })
+        })
+        .ok_or(StatusCode::BAD_REQUEST)?;
+    Ok(Some(range))
+}
+
+// Clock a syncing device gave the file it pushes or deletes
+fn parse_clock(headers: &HeaderMap) -> Result<Option<VersionClock>, StatusCode> {
+    let Some(value) = headers.get(CLOCK_HEADER) else {
+        return Ok(None);
+    };
+    serde_json::from_slice(value.as_bytes())
+        .map(Some)
+        .map_err(|_| StatusCode::BAD_REQUEST)
+}
+
+fn content_length(headers: &HeaderMap) -> Option<u64> {
+    headers
+        .get(header::CONTENT_LENGTH)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.parse().ok())
+}
+
+fn dir_size(path: &Path) -> u64 {
+    let Ok(entries) = std::fs::read_dir(path) else {
+        return 0;
+    };
+    entries
+        .flatten()
+        .map(|entry| match entry.file_type() {
+            Ok(file_type) if file_type.is_dir() => dir_size(&entry.path()),
+            Ok(_) => entry.metadata().map(|metadata| metadata.len()).unwrap_or(0),
+            Err(_) => 0,
+        })
+        .sum()
+}
+
+// Bytes the linked path uses. Previous versions of files don't count, so deleting
+// files frees space. Counting walks the whole linked path, so between recounts the
+// bytes uploads write and free are tracked instead
+async fn used_bytes(linked_path: &LinkedPath) -> u64 {
+    if let Some((counted, used)) = QUOTA_USAGE.lock().unwrap().get(&linked_path.path) {
+        if counted.elapsed() < USAGE_RECOUNT_INTERVAL {
+            return *used;
+        }
+    }
+    let path = linked_path.path.clone();
+    let versions_path = version_store_dir(linked_path);
+    let used = tokio::task::spawn_blocking(move || dir_size(&path).saturating_sub(dir_size(&versions_path)))
+        .await
+        .unwrap_or(u64::MAX);
+    QUOTA_USAGE
+        .lock()
+        .unwrap()
+        .insert(linked_path.path.clone(), (Instant::now(), used));
+    used
+}
+
+// Only linked paths with a quota are tracked
+fn count_written(linked_path: &LinkedPath, bytes: u64) {
+    if let Some((_, used)) = QUOTA_USAGE.lock().unwrap().get_mut(&linked_path.path) {
+        *used = used.saturating_add(bytes);
+    }
+}
+
+// Bytes of staged uploads that were dropped and of files that were replaced or deleted,
+// which move to the version store
+fn count_freed(linked_path: &LinkedPath, bytes: u64) {
+    if let Some((_, used)) = QUOTA_USAGE.lock().unwrap().get_mut(&linked_path.path) {
+        *used = used.saturating_sub(bytes);
+    }
+}
+
+async fn file_size(path: &Path) -> u64 {
+    tokio_fs::metadata(path)
+        .await
+        .map(|metadata| metadata.len())
+        .unwrap_or(0)
+}
+
+// How many more bytes the linked path may take, or None if it has no quota
+async fn remaining_quota(linked_path: &LinkedPath) -> Option<u64> {
+    let quota_bytes = linked_path.quota_bytes?;
+    Some(quota_bytes.saturating_sub(used_bytes(linked_path).await))
+}
+
+// Append a request body to a staging file, refusing to grow it past `limit` bytes
+async fn write_body<S, E>(file: &mut tokio_fs::File, stream: S, limit: Option<u64>) -> Result<u64, StatusCode>
+where
+    S: Stream<Item = Result<axum::body::Bytes, E>>,
+{
+    let mut stream = std::pin::pin!(stream);
+    let mut written = 0u64;
+    while let Some(data) = stream.next().await {
+        let data = data.map_err(|_| StatusCode::BAD_REQUEST)?;
+        written += data.len() as u64;
+        if limit.is_some_and(|limit| written > limit) {
+            return Err(StatusCode::PAYLOAD_TOO_LARGE);
+        }
+        file.write_all(&data)
+            .await
+            .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+    }
+    file.flush().await.map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+    Ok(written)
+}
+
+// Move a finished upload into place, keeping the file it replaces as a version
+async fn finish_upload(
+    linked_path: &LinkedPath,
+    relative_path: &Path,
+    staging_path: &Path,
+    clock: Option<VersionClock>,
+) -> Result<(), StatusCode> {
+    let target_path = linked_path.path.join(relative_path);
+    let replaced_size = file_size(&target_path).await;
+    if let Err(e) = keep_replaced_file(linked_path, relative_path_string(relative_path)).await {
+        eprintln!("Failed to keep the previous version of {}: {}", target_path.display(), e);
+        return Err(StatusCode::INTERNAL_SERVER_ERROR);
+    }
+    if let Some(parent) = target_path.parent() {
+        tokio_fs::create_dir_all(parent)
+            .await
+            .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+    }
+    tokio_fs::rename(staging_path, &target_path)
+        .await
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+    count_freed(linked_path, replaced_size);
+    println!("Upload finished: {}", target_path.display());
+    match clock {
+        Some(clock) => index_synced_path(linked_path.clone(), relative_path_string(relative_path), clock).await,
+        None => index_path(linked_path.clone(), relative_path_string(relative_path)).await,
+    }
+    Ok(())
+}
+
+async fn upload_offset(
+    State(state): State<Arc<UploadState>>,
+    headers: HeaderMap,
+    uri: Uri,
+) -> Result<Json<UploadOffset>, StatusCode> {
+    authorize(&state, &headers)?;
+    let (linked_path, relative_path) = writable_target(&state, &uri)?;
+    let offset = tokio_fs::metadata(staging_path(linked_path, &relative_path))
+        .await
+        .map(|metadata| metadata.len())
+        .unwrap_or(0);
+    Ok(Json(UploadOffset { offset }))
+}
+
+async fn upload_file(
+    State(state): State<Arc<UploadState>>,
+    headers: HeaderMap,
+    uri: Uri,
+    body: Body,
+) -> Result<StatusCode, StatusCode> {
+    authorize(&state, &headers)?;
+    let (linked_path, relative_path) = writable_target(&state, &uri)?;
+    if relative_path.as_os_str().is_empty() {
+        return Err(StatusCode::BAD_REQUEST);
+    }
+    let target_path = linked_path.path.join(&relative_path);
+    let staging_path = staging_path(linked_path, &relative_path);
+    let clock = parse_clock(&headers)?;
+
+    // Without a Content-Range header the body is the whole file
+    let (start, total) = match parse_content_range(&headers)? {
+        Some(range) => (range.start, Some(range.total)),
+        None => (0, content_length(&headers)),
+    };
+
+    // The file being replaced does not count against the quota
+    let replaced_size = file_size(&target_path).await;
+    // Chunks staged so far are already counted as used
+    let limit = remaining_quota(linked_path)
+        .await
+        .map(|remaining| remaining + replaced_size);
+    if let (Some(limit), Some(total)) = (limit, total) {
+        if total.saturating_sub(start) > limit {
+            return Err(StatusCode::PAYLOAD_TOO_LARGE);
+        }
+    }
+
+    if let Some(parent) = staging_path.parent() {
+        tokio_fs::create_dir_all(parent)
+            .await
+            .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+    }
+    let held = file_size(&staging_path).await;
+    // Chunks must continue exactly where the staged upload ends, a fresh upload starts over
+    // and drops what was staged before
+    let mut file = if start == 0 {
+        count_freed(linked_path, held);
+        tokio_fs::File::create(&staging_path).await
+    } else if start == held {
+        tokio_fs::OpenOptions::new().append(true).open(&staging_path).await
+    } else {
+        return Err(StatusCode::CONFLICT);
+    }
+    .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+
+    let written = match write_body(&mut file, body.into_data_stream(), limit).await {
+        Ok(written) => written,
+        Err(status) => {
+            // Keep the chunks received before this one, so the upload can resume from them
+            if let Err(e) = file.set_len(start).await {
+                eprintln!("Failed to discard a partial chunk of {}: {}", staging_path.display(), e);
+                if tokio_fs::remove_file(&staging_path).await.is_ok() {
+                    count_freed(linked_path, start);
+                }
+            }
+            return Err(status);
+        }
+    };
+    count_written(linked_path, written);
+
+    if total.is_some_and(|total| start + written < total) {
+        return Ok(StatusCode::ACCEPTED);
+    }
+    finish_upload(linked_path, &relative_path, &staging_path, clock).await?;
+    Ok(StatusCode::CREATED)
+}
+
+async fn upload_multipart(
+    State(state): State<Arc<UploadState>>,
+    headers: HeaderMap,
+    uri: Uri,
+    mut multipart: Multipart,
+) -> Result<StatusCode, StatusCode> {
+    authorize(&state, &headers)?;
+    let (linked_path, dir_path) = writable_target(&state, &uri)?;
+    let mut remaining = remaining_quota(linked_path).await;
+
+    while let Some(field) = multipart.next_field().await.map_err(|_| StatusCode::BAD_REQUEST)? {
+        let Some(file_name) = field.file_name().map(str::to_string) else {
+            continue;
+        };
+        if !is_safe_file_name(&file_name) {
+            return Err(StatusCode::BAD_REQUEST);
+        }
+        let relative_path = dir_path.join(&file_name);
+        let staging_path = multipart_staging_path(linked_path);
+        if let Some(parent) = staging_path.parent() {
+            tokio_fs::create_dir_all(parent)
+                .await
+                .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+        }
+
+        let mut file = tokio_fs::File::create(&staging_path)
+            .await
+            .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+        let written = match write_body(&mut file, field, remaining).await {
+            Ok(written) => written,
+            Err(status) => {
+                let _ = tokio_fs::remove_file(&staging_path).await;
+                return Err(status);
+            }
+        };
+        remaining = remaining.map(|remaining| remaining - written);
+        count_written(linked_path, written);
+        finish_upload(linked_path, &relative_path, &staging_path, None).await?;
+    }
+
+    Ok(StatusCode::CREATED)
+}
+
+async fn delete_file(
+    State(state): State<Arc<UploadState>>,
+    headers: HeaderMap,
+    uri: Uri,
+) -> Result<StatusCode, StatusCode> {
+    authorize(&state, &headers)?;
+    let (linked_path, relative_path) = writable_target(&state, &uri)?;
+    let clock = parse_clock(&headers)?;
+    let target_path = linked_path.path.join(&relative_path);
+    if relative_path.as_os_str().is_empty() {
+        return Err(StatusCode::NOT_FOUND);
+    }
+    // Directories are only removed once empty, like those left by moving their files
+    if target_path.is_dir() {
+        return match tokio_fs::remove_dir(&target_path).await {
+            Ok(()) => Ok(StatusCode::NO_CONTENT),
+            Err(_) => Err(StatusCode::CONFLICT),
+        };
+    }
+    if !target_path.is_file() {
+        return Err(StatusCode::NOT_FOUND);
+    }
+    let deleted_size = file_size(&target_path).await;
+    trash_file(linked_path, relative_path_string(&relative_path))
+        .await
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+    count_freed(linked_path, deleted_size);
+    println!("Deleted by peer into the trash: {}", target_path.display());
+    match clock {
+        Some(clock) => index_synced_path(linked_path.clone(), relative_path_string(&relative_path), clock).await,
+        None => index_path(linked_path.clone(), relative_path_string(&relative_path)).await,
+    }
+    Ok(StatusCode::NO_CONTENT)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::body::Bytes;
+    use axum::http::HeaderValue;
+
+    fn headers(name: header::HeaderName, value: &str) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        headers.insert(name, HeaderValue::from_str(value).unwrap());
+        headers
+    }
+
+    #[test]
+    fn content_ranges_give_the_chunk_start_and_file_size() {
+        let range = parse_content_range(&headers(header::CONTENT_RANGE, "bytes 100-199/1000")).unwrap().unwrap();
+        assert_eq!((range.start, range.total), (100, 1000));
+        assert!(parse_content_range(&HeaderMap::new()).unwrap().is_none());
+        for invalid in ["bytes 100-199/*", "items 0-1/2", "bytes 100/1000", "bytes -1-2/3"] {
+            assert_eq!(
+                parse_content_range(&headers(header::CONTENT_RANGE, invalid)).err(),
+                Some(StatusCode::BAD_REQUEST),
+                "{}",
+                invalid
+            );
+        }
+    }
+
+    #[test]
+    fn uploads_need_the_configured_token() {
+        let state = UploadState {
+            linked_paths: Vec::new(),
+            upload_token_hash: Some(hash_upload_token("secret").to_uppercase()),
+        };
+        assert_eq!(authorize(&state, &headers(header::AUTHORIZATION, "Bearer secret")), Ok(()));
+        assert_eq!(
+            authorize(&state, &headers(header::AUTHORIZATION, "Bearer secrets")),
+            Err(StatusCode::UNAUTHORIZED)
+        );
+        assert_eq!(authorize(&state, &HeaderMap::new()), Err(StatusCode::UNAUTHORIZED));
+        // Without a token uploads are off
+        let state = UploadState {
+            linked_paths: Vec::new(),
+            upload_token_hash: None,
+        };
+        assert_eq!(
+            authorize(&state, &headers(header::AUTHORIZATION, "Bearer secret")),
+            Err(StatusCode::FORBIDDEN)
+        );
+    }
+
+    #[tokio::test]
+    async fn bodies_past_the_limit_are_refused() {
+        let path = std::env::temp_dir().join(format!("topaz-upload-{}.part", uuid::Uuid::new_v4()));
+        let body = || futures_util::stream::iter([Ok::<_, std::io::Error>(Bytes::from("abc")), Ok(Bytes::from("def"))]);
+        let mut file = tokio_fs::File::create(&path).await.unwrap();
+        assert_eq!(write_body(&mut file, body(), Some(6)).await, Ok(6));
+        assert_eq!(write_body(&mut file, body(), None).await, Ok(6));
+        assert_eq!(tokio_fs::read(&path).await.unwrap(), b"abcdefabcdef");
+        assert_eq!(write_body(&mut file, body(), Some(5)).await, Err(StatusCode::PAYLOAD_TOO_LARGE));
+        tokio_fs::remove_file(&path).await.unwrap();
+    }
+}
@@ -0,0 +1,790 @@
+use crate::bandwidth::{Direction, Throttle};
+use crate::chunk_store::LocalChunkIndex;
+use crate::file_events::subscribe_file_events;
+use crate::file_index::with_file_index;
+use crate::file_versions::{keep_replaced_file, trash_file};
+use crate::ignore_rules::IgnoreRules;
+use crate::local_dir::{device_id, read_private_linked_paths, read_private_networks};
+use crate::peer_book::preferred_base_url;
+use crate::server_client::{
+    download_verified_file, host_client, host_path_url, host_url, parse_host_url, push_file, TransferSummary,
+};
+use crate::types::{
+    ConflictPolicy, ConflictResolution, DeviceInfo, FileEntry, FileVersion, IndexedFile, LinkedPath, Network,
+    SyncConflict, SyncListing, SyncSession, SyncSessionMap, SyncState, SyncStatus, SyncTarget, VersionClock,
+};
+use crate::version_clock::{bump_clock, clock_descends, merge_clocks, CLOCK_HEADER};
+use reqwest::Client;
+use std::collections::{BTreeSet, HashMap};
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex as StdMutex};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tauri::State;
+use tokio::sync::{broadcast, mpsc, Mutex, Notify};
+use url::Url;
+
+pub const SYNC_STATE_FILE_PATH: &str = "../configs/sync_state.json";
+
+// How often hosts are polled for remote changes
+const REMOTE_POLL_INTERVAL: Duration = Duration::from_secs(30);
+// Wait for a burst of local changes to settle before syncing them
+const LOCAL_CHANGE_SETTLE_TIME: Duration = Duration::from_secs(2);
+
+lazy_static::lazy_static! {
+    // Serializes read-modify-write cycles of the sync state file
+    static ref SYNC_STATE_LOCK: StdMutex<()> = StdMutex::new(());
+}
+
+pub fn read_sync_states() -> Vec<SyncState> {
+    fs::read_to_string(SYNC_STATE_FILE_PATH)
+        .ok()
+        .and_then(|data| serde_json::from_str(&data).ok())
+        .unwrap_or_default()
+}
+
+fn write_sync_states(states: &[SyncState]) -> std::io::Result<()> {
+    let json_content = serde_json::to_string_pretty(states)?;
+    fs::write(SYNC_STATE_FILE_PATH, json_content)
+}
+
+fn save_sync_state(state: &SyncState) -> std::io::Result<()> {
+    let _lock = SYNC_STATE_LOCK.lock().unwrap();
+    let mut states = read_sync_states();
+    states.retain(|saved| saved.target.linked_path_name != state.target.linked_path_name);
+    states.push(state.clone());
+    write_sync_states(&states)
+}
+
+fn remove_sync_state(linked_path_name: &str) -> std::io::Result<()> {
+    let _lock = SYNC_STATE_LOCK.lock().unwrap();
+    let mut states = read_sync_states();
+    states.retain(|saved| saved.target.linked_path_name != linked_path_name);
+    write_sync_states(&states)
+}
+
+fn unix_time() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|duration| duration.as_secs())
+        .unwrap_or(0)
+}
+
+fn to_versions(files: Vec<IndexedFile>) -> HashMap<String, FileVersion> {
+    files
+        .into_iter()
+        .map(|file| {
+            (
+                file.path,
+                FileVersion {
+                    hash: file.hash,
+                    size: file.size,
+                    modified: file.modified,
+                    clock: VersionClock::new(),
+                },
+            )
+        })
+        .collect()
+}
+
+// Network a linked path is served in
+pub fn linked_path_network(linked_path_name: &str) -> Option<Network> {
+    read_private_networks()
+        .ok()?
+        .into_iter()
+        .find(|network| network.linked_paths.iter().any(|linked_path| linked_path.name == linked_path_name))
+}
+
+fn local_file_path(root: &Path, path: &str) -> PathBuf {
+    path.split('/').fold(root.to_path_buf(), |local_path, segment| local_path.join(segment))
+}
+
+async fn fetch_sync_listing(client: &Client, base: &Url, target: &SyncTarget) -> Result<SyncListing, String> {
+    let listing_url = host_path_url(base, Some("sync"), &target.remote_path)?;
+    let response = client
+        .get(listing_url)
+        .send()
+        .await
+        .and_then(|response| response.error_for_status())
+        .map_err(|e| e.to_string())?;
+    let body = response.text().await.map_err(|e| e.to_string())?;
+    serde_json::from_str(&body).map_err(|e| e.to_string())
+}
+
+async fn delete_remote_file(
+    client: &Client,
+    base: &Url,
+    target: &SyncTarget,
+    path: &str,
+    clock: &VersionClock,
+) -> Result<(), String> {
+    let upload_url = host_path_url(base, Some("upload"), &format!("{}/{}", target.remote_path, path))?;
+    let clock = serde_json::to_string(clock).map_err(|e| e.to_string())?;
+    client
+        .delete(upload_url)
+        .bearer_auth(&target.token)
+        .header(CLOCK_HEADER, clock)
+        .send()
+        .await
+        .and_then(|response| response.error_for_status())
+        .map_err(|e| e.to_string())?;
+    Ok(())
+}
+
+fn same_content(a: Option<&FileVersion>, b: Option<&FileVersion>) -> bool {
+    a.map(|version| &version.hash) == b.map(|version| &version.hash)
+}
+
+// `name.conflict-<device>-<date>.ext` next to the original file
+fn conflict_copy_path(local_path: &Path, device: &str) -> PathBuf {
+    let stem = local_path
+        .file_stem()
+        .map(|stem| stem.to_string_lossy().into_owned())
+        .unwrap_or_default();
+    let date = chrono::Local::now().format("%Y%m%d-%H%M%S");
+    let short_device: String = device.chars().take(8).collect();
+    let mut name = format!("{}.conflict-{}-{}", stem, short_device, date);
+    if let Some(extension) = local_path.extension() {
+        name.push('.');
+        name.push_str(&extension.to_string_lossy());
+    }
+    local_path.with_file_name(name)
+}
+
+pub async fn fetch_host_device_id(client: &Client, base: &Url) -> Option<String> {
+    let device_url = host_path_url(base, Some("device"), "").ok()?;
+    let response = client.get(device_url).send().await.ok()?.error_for_status().ok()?;
+    let device_info: DeviceInfo = serde_json::from_str(&response.text().await.ok()?).ok()?;
+    Some(device_info.device_id)
+}
+
+// Everything a sync pass needs to move a single file between the two sides
+struct SyncContext<'a> {
+    client: &'a Client,
+    base: Url,
+    target: SyncTarget,
+    linked_path: &'a LinkedPath,
+    // Network the linked path is served in, whose bandwidth limits apply
+    network_name: Option<String>,
+    root: &'a Path,
+    chunk_index: LocalChunkIndex,
+}
+
+impl SyncContext<'_> {
+    async fn pull_file(&mut self, path: &str, version: &FileVersion) -> Result<(), String> {
+        let file_url = host_path_url(&self.base, None, &format!("{}/{}", self.target.remote_path, path))?;
+        let save_path = local_file_path(self.root, path);
+        if let Some(parent) = save_path.parent() {
+            tokio::fs::create_dir_all(parent).await.map_err(|e| e.to_string())?;
+        }
+        let entry = FileEntry {
+            name: path.to_string(),
+            is_dir: false,
+            hash: Some(version.hash.clone()),
+            size: Some(version.size),
+        };
+        let mut summary = TransferSummary::default();
+        let mut throttle = Throttle::new(
+            Direction::Download,
+            self.network_name.as_deref(),
+            Some(&self.target.base_url),
+        );
+        let verified = download_verified_file(
+            self.client,
+            &self.base,
+            &file_url,
+            &save_path,
+            &entry,
+            &mut self.chunk_index,
+            &mut summary,
+            &mut throttle,
+        )
+        .await
+        .map_err(|e| e.to_string())?;
+        if !verified {
+            return Err("failed integrity verification".to_string());
+        }
+        Ok(())
+    }
+
+    async fn push_local_file(&self, path: &str, clock: &VersionClock) -> Result<(), String> {
+        let upload_url = host_path_url(&self.base, Some("upload"), &format!("{}/{}", self.target.remote_path, path))?;
+        let mut throttle = Throttle::new(Direction::Upload, self.network_name.as_deref(), Some(&self.target.base_url));
+        let local_path = local_file_path(self.root, path);
+        push_file(self.client, &upload_url, &local_path, &self.target.token, Some(clock), &mut throttle)
+            .await
+            .map_err(|e| e.to_string())
+    }
+
+    // Make the host match the local side, recording `clock` for the file there
+    async fn apply_local(
+        &mut self,
+        path: &str,
+        local: Option<&FileVersion>,
+        clock: &VersionClock,
+    ) -> Result<(), String> {
+        match local {
+            Some(_) => self.push_local_file(path, clock).await,
+            None => delete_remote_file(self.client, &self.base, &self.target, path, clock).await,
+        }
+    }
+
+    // Make the local side match the host. Replaced and deleted local files are kept in
+    // the version store
+    async fn apply_remote(&mut self, path: &str, remote: Option<&FileVersion>) -> Result<(), String> {
+        match remote {
+            Some(version) => {
+                keep_replaced_file(self.linked_path, path.to_string())
+                    .await
+                    .map_err(|e| e.to_string())?;
+                self.pull_file(path, version).await
+            }
+            None => trash_file(self.linked_path, path.to_string())
+                .await
+                .map(|_| ())
+                .map_err(|e| e.to_string()),
+        }
+    }
+
+    // Move the local edit aside and take the remote one. The copy is a new local file,
+    // so the next pass sends it to the host as well. A local deletion leaves nothing to
+    // move aside
+    async fn keep_both(
+        &mut self,
+        path: &str,
+        local: Option<&FileVersion>,
+        remote: Option<&FileVersion>,
+        device: &str,
+    ) -> Result<(), String> {
+        if local.is_some() {
+            let local_path = local_file_path(self.root, path);
+            let copy_path = conflict_copy_path(&local_path, device);
+            tokio::fs::rename(&local_path, &copy_path)
+                .await
+                .map_err(|e| e.to_string())?;
+            println!("Kept conflicting local copy as {}", copy_path.display());
+        }
+        self.apply_remote(path, remote).await
+    }
+}
+
+// Decide which side wins a conflict. None leaves it for the user
+fn choose_resolution(
+    policy: ConflictPolicy,
+    local: Option<&FileVersion>,
+    remote: Option<&FileVersion>,
+) -> Option<ConflictResolution> {
+    match (policy, local, remote) {
+        (ConflictPolicy::Manual, _, _) => None,
+        // An edit always beats a deletion
+        (_, Some(_), None) => Some(ConflictResolution::KeepLocal),
+        (_, None, Some(_)) => Some(ConflictResolution::KeepRemote),
+        (ConflictPolicy::NewestWins, Some(local), Some(remote)) if local.modified > remote.modified => {
+            Some(ConflictResolution::KeepLocal)
+        }
+        (ConflictPolicy::NewestWins, _, _) => Some(ConflictResolution::KeepRemote),
+        (ConflictPolicy::KeepBoth, _, _) => Some(ConflictResolution::KeepBoth),
+    }
+}
+
+// Clock of the local version of a file: the one recorded in the last pass, with a change
+// made on this device if the file differs from the recorded version
+fn local_clock(state: &SyncState, path: &str, local: Option<&FileVersion>, device: &str) -> VersionClock {
+    let synced = state.files.get(path);
+    let synced_clock = match synced {
+        Some(version) => version.clock.clone(),
+        None => state.deleted.get(path).cloned().unwrap_or_default(),
+    };
+    if same_content(local, synced) {
+        synced_clock
+    } else {
+        bump_clock(&synced_clock, device)
+    }
+}
+
+// Which side of a file holds every change the other does. None when each side has
+// changes the other lacks, which is a conflict
+fn resolve_by_clock(local_clock: &VersionClock, remote_clock: &VersionClock) -> Option<ConflictResolution> {
+    if clock_descends(remote_clock, local_clock) {
+        Some(ConflictResolution::KeepRemote)
+    } else if clock_descends(local_clock, remote_clock) {
+        Some(ConflictResolution::KeepLocal)
+    } else {
+        None
+    }
+}
+
+// Order the local and remote versions of each file by their clocks. The host keeps the
+// clock every device pushed with a file, so a version that descends from the other wins
+// whichever device made it, and concurrent changes are handled by the conflict policy
+async fn sync_pass(client: &Client, linked_path: &LinkedPath, state: &mut SyncState) -> Result<(), String> {
+    let root = linked_path.path.as_path();
+    let rules = IgnoreRules::for_linked_path(linked_path);
+    let target = state.target.clone();
+    // The host may be reachable at a faster address than the one the sync started with
+    let network_name = linked_path_network(&linked_path.name).map(|network| network.name);
+    let base = host_url(&preferred_base_url(&target.base_url, network_name.as_deref())).await?;
+    let device = device_id();
+
+    // Refreshing the index only hashes files that changed since it was last updated
+    let index_linked_path = linked_path.clone();
+    let local_files = tokio::task::spawn_blocking(move || {
+        with_file_index(&index_linked_path, |index| {
+            index.refresh()?;
+            index.files_below("")
+        })
+    })
+    .await
+    .map_err(|e| e.to_string())?
+    .map_err(|e| e.to_string())?;
+    let local_files = to_versions(local_files);
+    let mut remote = fetch_sync_listing(client, &base, &target).await?;
+    // Ignored files are outside the sync, on either side and in the recorded state, so
+    // ignoring a file never reads as its deletion
+    remote.files.retain(|path, _| !rules.is_ignored(Path::new(path), false));
+    state.files.retain(|path, _| !rules.is_ignored(Path::new(path), false));
+    state.deleted.retain(|path, _| !rules.is_ignored(Path::new(path), false));
+
+    let paths: BTreeSet<String> = local_files
+        .keys()
+        .chain(remote.files.keys())
+        .chain(state.files.keys())
+        .cloned()
+        .collect();
+
+    let mut context = SyncContext {
+        client,
+        base,
+        target,
+        linked_path,
+        network_name,
+        root,
+        chunk_index: LocalChunkIndex::load(),
+    };
+    let mut errors = Vec::new();
+    for path in paths {
+        let local = local_files.get(&path).cloned();
+        let remote_version = remote.files.get(&path).cloned();
+        let local_clock = local_clock(state, &path, local.as_ref(), &device);
+        let remote_clock = match &remote_version {
+            Some(version) => version.clock.clone(),
+            None => remote.deleted.get(&path).cloned().unwrap_or_default(),
+        };
+
+        let resolution = if same_content(local.as_ref(), remote_version.as_ref()) {
+            state.conflicts.remove(&path);
+            None
+        } else if let Some(resolution) = resolve_by_clock(&local_clock, &remote_clock) {
+            Some(resolution)
+        } else {
+            // A resolution was chosen for the versions recorded with the conflict. If
+            // either side changed again since, it is a new conflict
+            if state.conflicts.get(&path).is_some_and(|conflict| {
+                !same_content(local.as_ref(), conflict.local.as_ref())
+                    || !same_content(remote_version.as_ref(), conflict.remote.as_ref())
+            }) {
+                state.conflicts.remove(&path);
+            }
+            let chosen = state
+                .conflicts
+                .get(&path)
+                .and_then(|conflict| conflict.resolution)
+                .or_else(|| choose_resolution(state.conflict_policy, local.as_ref(), remote_version.as_ref()));
+            if chosen.is_none() && !state.conflicts.contains_key(&path) {
+                eprintln!("Sync conflict on {}, waiting for it to be resolved", path);
+                state.conflicts.insert(
+                    path.clone(),
+                    SyncConflict {
+                        linked_path_name: context.target.linked_path_name.clone(),
+                        path: path.clone(),
+                        local: local.clone(),
+                        remote: remote_version.clone(),
+                        detected_at: unix_time(),
+                        resolution: None,
+                    },
+                );
+            }
+            match chosen {
+                Some(resolution) => Some(resolution),
+                None => continue,
+            }
+        };
+
+        // A pushed version includes the changes of both sides. Otherwise the host's version
+        // is taken as is, and a local change it replaced is no longer part of its history
+        let (version, clock) = match resolution {
+            Some(ConflictResolution::KeepLocal) => (local.clone(), merge_clocks(&local_clock, &remote_clock)),
+            _ => (remote_version.clone(), remote_clock),
+        };
+        let result = match resolution {
+            None => Ok(()),
+            Some(ConflictResolution::KeepLocal) => context.apply_local(&path, local.as_ref(), &clock).await,
+            Some(ConflictResolution::KeepRemote) => context.apply_remote(&path, remote_version.as_ref()).await,
+            Some(ConflictResolution::KeepBoth) => {
+                context
+                    .keep_both(&path, local.as_ref(), remote_version.as_ref(), &device)
+                    .await
+            }
+        };
+
+        match result {
+            Ok(()) => {
+                state.conflicts.remove(&path);
+                // Both sides now hold the same version
+                match version {
+                    Some(version) => {
+                        state.deleted.remove(&path);
+                        state.files.insert(path, FileVersion { clock, ..version });
+                    }
+                    None => {
+                        state.files.remove(&path);
+                        state.deleted.insert(path, clock);
+                    }
+                }
+            }
+            Err(e) => errors.push(format!("{}: {}", path, e)),
+        }
+    }
+
+    if errors.is_empty() {
+        Ok(())
+    } else {
+        Err(errors.join("; "))
+    }
+}
+
+async fn run_sync_session(
+    state: Arc<Mutex<SyncState>>,
+    resolutions: Arc<StdMutex<HashMap<String, ConflictResolution>>>,
+    sync_now: Arc<Notify>,
+    linked_path: LinkedPath,
+    mut rx: mpsc::Receiver<()>,
+) {
+    let client = host_client();
+    let linked_path_name = state.lock().await.target.linked_path_name.clone();
+    let mut local_changes = subscribe_file_events();
+    let mut poll_interval = tokio::time::interval(REMOTE_POLL_INTERVAL);
+
+    loop {
+        tokio::select! {
+            _ = rx.recv() => break,
+            _ = poll_interval.tick() => {}
+            _ = sync_now.notified() => {}
+            change = local_changes.recv() => {
+                match change {
+                    Ok(event) if event.linked_path_name != linked_path_name => continue,
+                    Err(broadcast::error::RecvError::Closed) => continue,
+                    _ => {}
+                }
+                tokio::time::sleep(LOCAL_CHANGE_SETTLE_TIME).await;
+                while local_changes.try_recv().is_ok() {}
+            }
+        }
+
+        let mut state = state.lock().await;
+        for (path, resolution) in resolutions.lock().unwrap().drain() {
+            if let Some(conflict) = state.conflicts.get_mut(&path) {
+                conflict.resolution = Some(resolution);
+            }
+        }
+        let result = sync_pass(&client, &linked_path, &mut state).await;
+        if let Err(e) = &result {
+            eprintln!("Sync of {} failed: {}", state.target.linked_path_name, e);
+        }
+        state.last_error = result.err();
+        state.last_synced = Some(unix_time());
+        if let Err(e) = save_sync_state(&state) {
+            eprintln!("Failed to save sync state: {}", e);
+        }
+    }
+    println!("Stopped syncing {}", state.lock().await.target.linked_path_name);
+}
+
+async fn start_sync_session(sessions: &SyncSessionMap, state: SyncState) -> Result<(), String> {
+    let linked_paths = read_private_linked_paths().map_err(|e| e.to_string())?;
+    let linked_path = linked_paths
+        .into_iter()
+        .find(|linked_path| linked_path.name == state.target.linked_path_name)
+        .ok_or_else(|| format!("No linked path named '{}'", state.target.linked_path_name))?;
+    // Syncing moves files as they are, which would hand plaintext to peers the network's
+    // keys are meant to keep it from
+    if let Some(network) = linked_path_network(&linked_path.name) {
+        if network.encryption.is_some_and(|encryption| !encryption.keys.is_empty()) {
+            return Err(format!(
+                "'{}' belongs to the encrypted network '{}', push it with its keys instead of syncing",
+                linked_path.name, network.name
+            ));
+        }
+    }
+
+    let mut map = sessions.write().await;
+    if let Some(session) = map.remove(&state.target.linked_path_name) {
+        let _ = session.tx.send(()).await;
+    }
+    let linked_path_name = state.target.linked_path_name.clone();
+    let state = Arc::new(Mutex::new(state));
+    let resolutions = Arc::new(StdMutex::new(HashMap::new()));
+    let sync_now = Arc::new(Notify::new());
+    let (tx, rx) = mpsc::channel::<()>(1);
+    map.insert(
+        linked_path_name.clone(),
+        SyncSession {
+            state: state.clone(),
+            resolutions: resolutions.clone(),
+            sync_now: sync_now.clone(),
+            tx,
+        },
+    );
+    println!("Started syncing {}", linked_path_name);
+    tokio::spawn(run_sync_session(state, resolutions, sync_now, linked_path, rx));
+    Ok(())
+}
+
+// Restart every sync session that was running when the app last exited
+pub async fn resume_syncs(sessions: SyncSessionMap) {
+    for state in read_sync_states() {
+        if let Err(e) = start_sync_session(&sessions, state).await {
+            eprintln!("Failed to resume sync: {}", e);
+        }
+    }
+}
+
+#[tauri::command]
+pub async fn start_sync(
+    linked_path_name: String,
+    base_url: String,
+    remote_path: String,
+    token: String,
+    conflict_policy: Option<ConflictPolicy>,
+    sessions: State<'_, SyncSessionMap>,
+) -> Result<String, String> {
+    parse_host_url(&base_url)?;
+    let target = SyncTarget {
+        linked_path_name,
+        base_url,
+        remote_path,
+        token,
+    };
+
+    // Keep the recorded versions when syncing with the same target again
+    let mut state = read_sync_states()
+        .into_iter()
+        .find(|saved| saved.target == target)
+        .unwrap_or(SyncState {
+            target: target.clone(),
+            files: HashMap::new(),
+            deleted: HashMap::new(),
+            conflict_policy: ConflictPolicy::default(),
+            conflicts: HashMap::new(),
+            last_synced: None,
+            last_error: None,
+        });
+    if let Some(conflict_policy) = conflict_policy {
+        state.conflict_policy = conflict_policy;
+    }
+    save_sync_state(&state).map_err(|e| e.to_string())?;
+    start_sync_session(sessions.inner(), state).await?;
+
+    Ok(format!("Syncing '{}' with '{}'.", target.linked_path_name, target.base_url))
+}
+
+#[tauri::command]
+pub async fn stop_sync(
+    linked_path_name: String,
+    sessions: State<'_, SyncSessionMap>,
+) -> Result<String, String> {
+    if let Some(session) = sessions.write().await.remove(&linked_path_name) {
+        let _ = session.tx.send(()).await;
+    }
+    remove_sync_state(&linked_path_name).map_err(|e| e.to_string())?;
+    Ok(format!("Stopped syncing '{}'.", linked_path_name))
+}
+
+#[tauri::command]
+pub async fn get_sync_status(sessions: State<'_, SyncSessionMap>) -> Result<Vec<SyncStatus>, String> {
+    let map = sessions.read().await;
+    Ok(read_sync_states()
+        .into_iter()
+        .map(|state| SyncStatus {
+            running: map.contains_key(&state.target.linked_path_name),
+            tracked_files: state.files.len(),
+            conflict_policy: state.conflict_policy,
+            conflicts: state.conflicts.len(),
+            target: state.target,
+            last_synced: state.last_synced,
+            last_error: state.last_error,
+        })
+        .collect())
+}
+
+#[tauri::command]
+pub async fn list_conflicts() -> Result<Vec<SyncConflict>, String> {
+    let mut conflicts: Vec<SyncConflict> = read_sync_states()
+        .into_iter()
+        .flat_map(|state| state.conflicts.into_values())
+        .collect();
+    conflicts.sort_by(|a, b| (&a.linked_path_name, &a.path).cmp(&(&b.linked_path_name, &b.path)));
+    Ok(conflicts)
+}
+
+// Record how a conflict should be resolved and sync right away to apply it. The
+// resolution only applies to the versions the conflict was recorded with, if either
+// side changed since, the conflict is recorded again
+#[tauri::command]
+pub async fn resolve_conflict(
+    linked_path_name: String,
+    path: String,
+    resolution: ConflictResolution,
+    sessions: State<'_, SyncSessionMap>,
+) -> Result<String, String> {
+    let map = sessions.read().await;
+    let session = map
+        .get(&linked_path_name)
+        .ok_or_else(|| format!("'{}' is not being synced", linked_path_name))?;
+
+    // The state saved after the last pass, a pass running now holds the session's
+    let recorded = read_sync_states()
+        .into_iter()
+        .find(|state| state.target.linked_path_name == linked_path_name)
+        .is_some_and(|state| state.conflicts.contains_key(&path));
+    if !recorded {
+        return Err(format!("No conflict on '{}'", path));
+    }
+    session.resolutions.lock().unwrap().insert(path.clone(), resolution);
+    session.sync_now.notify_one();
+
+    Ok(format!("Resolving conflict on '{}'.", path))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Host side of one synced file: its content, None once deleted, and recorded clock
+    type Host = (Option<String>, VersionClock);
+
+    struct Device {
+        id: &'static str,
+        file: Option<String>,
+        state: SyncState,
+    }
+
+    impl Device {
+        fn new(id: &'static str) -> Self {
+            Device {
+                id,
+                file: None,
+                state: SyncState {
+                    target: SyncTarget {
+                        linked_path_name: "docs".to_string(),
+                        base_url: "http://host".to_string(),
+                        remote_path: "docs".to_string(),
+                        token: String::new(),
+                    },
+                    files: HashMap::new(),
+                    deleted: HashMap::new(),
+                    conflict_policy: ConflictPolicy::Manual,
+                    conflicts: HashMap::new(),
+                    last_synced: None,
+                    last_error: None,
+                },
+            }
+        }
+
+        // One sync pass over the file, the way `sync_pass` runs it. Err on a conflict
+        // unless `chosen` resolves it
+        fn sync(
+            &mut self,
+            host: &mut Host,
+            chosen: Option<ConflictResolution>,
+        ) -> Result<Option<ConflictResolution>, ()> {
+            let local = self.file.as_ref().map(|hash| version(hash, VersionClock::new()));
+            let remote = host.0.as_ref().map(|hash| version(hash, host.1.clone()));
+            let local_clock = local_clock(&self.state, "f", local.as_ref(), self.id);
+            let resolution = if same_content(local.as_ref(), remote.as_ref()) {
+                None
+            } else {
+                Some(resolve_by_clock(&local_clock, &host.1).or(chosen).ok_or(())?)
+            };
+            let (content, clock) = match resolution {
+                Some(ConflictResolution::KeepLocal) => {
+                    let clock = merge_clocks(&local_clock, &host.1);
+                    *host = (self.file.clone(), clock.clone());
+                    (self.file.clone(), clock)
+                }
+                _ => {
+                    self.file = host.0.clone();
+                    (host.0.clone(), host.1.clone())
+                }
+            };
+            match content {
+                Some(hash) => {
+                    self.state.deleted.remove("f");
+                    self.state.files.insert("f".to_string(), version(&hash, clock));
+                }
+                None => {
+                    self.state.files.remove("f");
+                    self.state.deleted.insert("f".to_string(), clock);
+                }
+            }
+            Ok(resolution)
+        }
+    }
+
+    fn version(hash: &str, clock: VersionClock) -> FileVersion {
+        FileVersion {
+            hash: hash.to_string(),
+            size: 0,
+            modified: 0,
+            clock,
+        }
+    }
+
+    #[test]
+    fn clocks_order_changes_across_three_devices() {
+        let mut host: Host = (None, VersionClock::new());
+        let (mut a, mut b, mut c) = (Device::new("a"), Device::new("b"), Device::new("c"));
+
+        a.file = Some("a1".to_string());
+        assert_eq!(a.sync(&mut host, None), Ok(Some(ConflictResolution::KeepLocal)));
+        assert_eq!(b.sync(&mut host, None), Ok(Some(ConflictResolution::KeepRemote)));
+        assert_eq!(c.sync(&mut host, None), Ok(Some(ConflictResolution::KeepRemote)));
+
+        // `b` and `c` both edit the version `a` made
+        b.file = Some("b2".to_string());
+        c.file = Some("c2".to_string());
+        assert_eq!(b.sync(&mut host, None), Ok(Some(ConflictResolution::KeepLocal)));
+        assert_eq!(c.sync(&mut host, None), Err(()));
+        // `a` did not change the file, so it takes the edit of `b` without a conflict
+        assert_eq!(a.sync(&mut host, None), Ok(Some(ConflictResolution::KeepRemote)));
+        assert_eq!(a.file.as_deref(), Some("b2"));
+
+        // Keeping the edit of `c` pushes a version that includes the edit of `b`, so `b`
+        // takes it instead of seeing a conflict of its own
+        assert_eq!(
+            c.sync(&mut host, Some(ConflictResolution::KeepLocal)),
+            Ok(Some(ConflictResolution::KeepLocal))
+        );
+        assert_eq!(b.sync(&mut host, None), Ok(Some(ConflictResolution::KeepRemote)));
+        assert_eq!(a.sync(&mut host, None), Ok(Some(ConflictResolution::KeepRemote)));
+        assert_eq!(b.file.as_deref(), Some("c2"));
+        assert_eq!(a.file.as_deref(), Some("c2"));
+
+        // A deletion is ordered like an edit
+        a.file = None;
+        assert_eq!(a.sync(&mut host, None), Ok(Some(ConflictResolution::KeepLocal)));
+        assert_eq!(b.sync(&mut host, None), Ok(Some(ConflictResolution::KeepRemote)));
+        assert_eq!(b.file, None);
+        c.file = Some("c3".to_string());
+        assert_eq!(c.sync(&mut host, None), Err(()));
+
+        // Everyone in sync again stays in sync
+        assert_eq!(
+            c.sync(&mut host, Some(ConflictResolution::KeepRemote)),
+            Ok(Some(ConflictResolution::KeepRemote))
+        );
+        for device in [&mut a, &mut b, &mut c] {
+            assert_eq!(device.sync(&mut host, None), Ok(None));
+        }
+    }
+}
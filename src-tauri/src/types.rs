@@ -1,13 +1,47 @@
 use serde::{Deserialize, Serialize};
 use std::path::PathBuf;
 use std::sync::Arc;
-use tokio::sync::{ RwLock, mpsc};
+use tokio::sync::{ RwLock, Mutex, Notify, mpsc};
 use std::collections::HashMap;
 
 #[derive(Serialize, Deserialize, Debug, Clone, Eq, PartialEq, Hash)]
 pub struct LinkedPath {
     pub name: String,
     pub path: PathBuf,
+    // Whether peers with the network's upload token may write into this path
+    #[serde(default)]
+    pub writable: bool,
+    // Maximum size of the directory in bytes that uploads may grow it to
+    #[serde(default)]
+    pub quota_bytes: Option<u64>,
+    // Gitignore-style patterns applied on top of the path's `.topazignore`
+    #[serde(default)]
+    pub ignore_patterns: Vec<String>,
+    // How long files replaced or deleted by peers and syncs are kept
+    #[serde(default)]
+    pub version_retention: VersionRetention,
+}
+
+// Limits on the previous versions kept in a linked path's version store. The oldest
+// versions go first once any limit is exceeded, limits set to null don't apply
+#[derive(Serialize, Deserialize, Debug, Clone, Eq, PartialEq, Hash)]
+#[serde(default)]
+pub struct VersionRetention {
+    // Versions kept per file
+    pub max_versions: Option<usize>,
+    pub max_age_days: Option<u64>,
+    // Size of the whole store
+    pub max_bytes: Option<u64>,
+}
+
+impl Default for VersionRetention {
+    fn default() -> Self {
+        VersionRetention {
+            max_versions: Some(10),
+            max_age_days: Some(30),
+            max_bytes: None,
+        }
+    }
 }
 
 // Enum to represent the Network type
@@ -16,12 +50,147 @@ pub struct LinkedPath {
 pub struct Network {
         pub name: String,
         pub linked_paths: Vec<LinkedPath>,
+        // Bearer token peers must present to upload. Only taken when the network is
+        // created, the config keeps `upload_token_hash` instead
+        #[serde(default)]
+        pub upload_token: Option<String>,
+        // Hex SHA-256 of the upload token. Uploads are disabled without one
+        #[serde(default)]
+        pub upload_token_hash: Option<String>,
+        // Interfaces the network is served and advertised on, by name. Without any, it
+        // is served everywhere and advertised on LAN, VPN and public addresses
+        #[serde(default)]
+        pub interfaces: Vec<String>,
+        // Relay the network is served through in `ServerMode::Relay` and `ServerMode::Internet`
+        #[serde(default)]
+        pub relay: Option<RelaySettings>,
+        // Keys files are encrypted with by `push_to_host` and decrypted with by
+        // `get_host_linked_paths`. Without any, files are pushed as they are. The sync
+        // engine doesn't encrypt, so linked paths of networks with keys can't be synced
+        #[serde(default)]
+        pub encryption: Option<NetworkEncryption>,
+        // Limits on serving and downloading this network, on top of the device-wide ones
+        #[serde(default)]
+        pub bandwidth_limits: Option<BandwidthLimits>,
+}
+// Keys shared by the devices of a network. The last one encrypts, older ones are kept to
+// read what was stored before a rotation until it is re-encrypted
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct NetworkEncryption {
+    pub keys: Vec<NetworkKey>,
+}
+// Host storing a network's files encrypted, with what is needed to rewrite them when
+// the keys are rotated
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct EncryptedStore {
+    pub base_url: String,
+    // Linked path name optionally followed by subdirectories, as given to `push_to_host`
+    pub remote_path: String,
+    // Upload token of the host's network
+    pub token: String,
+}
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct NetworkKey {
+    // Stored in encrypted names and file headers to tell which key to decrypt with
+    pub id: u32,
+    // 32 random bytes, hex-encoded
+    pub key: String,
+}
+// Transfer rates in bytes per second. Uploads are what this device sends, files it serves
+// included, downloads what it receives. Limits set to null don't apply
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq, Eq)]
+#[serde(default)]
+pub struct BandwidthLimits {
+    pub upload: Option<u64>,
+    pub download: Option<u64>,
+    // Limits for each peer on its own, by IP address for peers served and by host for
+    // peers downloaded from
+    pub upload_per_peer: Option<u64>,
+    pub download_per_peer: Option<u64>,
+    // Windows in which other limits apply, the first matching one wins
+    pub schedule: Vec<ScheduledLimits>,
+}
+// Limits that replace all of the regular ones during a window of local time, like no
+// limits at all at night
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct ScheduledLimits {
+    // `HH:MM`. A window ending before it starts runs past midnight
+    pub start: String,
+    pub end: String,
+    // Days the window starts on, 0 is Monday. Every day if empty
+    #[serde(default)]
+    pub days: Vec<u8>,
+    #[serde(default)]
+    pub upload: Option<u64>,
+    #[serde(default)]
+    pub download: Option<u64>,
+    #[serde(default)]
+    pub upload_per_peer: Option<u64>,
+    #[serde(default)]
+    pub download_per_peer: Option<u64>,
+}
+// A relay host and clients both connect out to, for peers that can't reach each other
+// directly. Only devices holding the secret find each other there, and what passes
+// through is encrypted end to end
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct RelaySettings {
+    // `ws://` or `wss://` URL of a topaz-relay server
+    pub url: String,
+    // 32 random bytes, hex-encoded, shared with the devices of the network
+    pub secret: String,
+    // `host:port` of a rendezvous service, usually the relay's own, to connect peers
+    // directly in `ServerMode::Internet` before falling back to the relay
+    #[serde(default)]
+    pub rendezvous: Option<String>,
 }
 #[derive(Serialize, Deserialize, Clone)]
 pub enum ServerMode {
     LocalHost,
     Internet,
     DarkWeb,
+    Relay,
+}
+// How clients reach a server group in `ServerMode::LocalHost`. QUIC carries each HTTP
+// connection on a stream of one connection per client, which copes better with lossy
+// and high-latency links and survives the client changing networks
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Transport {
+    #[default]
+    Tcp,
+    Quic,
+}
+// Protection of a server group against slow, greedy and hostile peers. Unset limits take
+// the defaults below, those set to null don't apply. Peers reaching it through a relay
+// can't be told apart, so per-peer limits and bans don't apply to them
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+#[serde(default)]
+pub struct ServerLimits {
+    // Seconds a request may take until its response starts
+    pub request_timeout_secs: Option<u64>,
+    // Seconds a connection may take to send the headers of a request, counted from when
+    // it opened or its previous request finished. Also how long an upload may stall
+    pub idle_timeout_secs: Option<u64>,
+    pub max_connections: Option<usize>,
+    pub max_connections_per_peer: Option<usize>,
+    pub requests_per_minute_per_peer: Option<u32>,
+    // Peers sending this many wrong upload tokens within `ban_secs` are banned for as long
+    pub max_auth_failures: Option<u32>,
+    pub ban_secs: u64,
+}
+
+impl Default for ServerLimits {
+    fn default() -> Self {
+        ServerLimits {
+            request_timeout_secs: Some(60),
+            idle_timeout_secs: Some(30),
+            max_connections: Some(512),
+            max_connections_per_peer: Some(64),
+            // Browsing and syncing large directories makes many requests in a burst
+            requests_per_minute_per_peer: None,
+            max_auth_failures: Some(10),
+            ban_secs: 15 * 60,
+        }
+    }
 }
 pub type NetworkName = String;
 
@@ -32,19 +201,471 @@ pub type ShutdownServerMap = Arc<RwLock<HashMap<NetworkName, Vec<ServerGroup>>>>
 pub struct ServerGroup {
     pub id: u64,
     pub addresses: Vec<Address>,
+    pub transport: Transport,
+    pub limits: ServerLimits,
     pub tx: mpsc::Sender<()>,
 }
 #[derive(Clone,Serialize, Deserialize)]
 pub struct ServerGroupSerde {
     pub id: u64,
     pub addresses: Vec<Address>,
+    #[serde(default)]
+    pub transport: Transport,
+    #[serde(default)]
+    pub limits: ServerLimits,
 }
 
-#[derive(Clone,Serialize, Deserialize)]
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FileChangeKind {
+    Created,
+    Modified,
+    Removed,
+    Renamed,
+}
+
+// Change to a file inside a linked path, sent to the frontend as `linked_path_file_changed`
+// and to subscribers of the file event bus
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct FileChangeEvent {
+    pub linked_path_name: String,
+    pub kind: FileChangeKind,
+    pub path: PathBuf,
+    // Relative to the linked path, `/`-separated
+    pub relative_path: String,
+    // Former relative path of a renamed file
+    pub previous_path: Option<String>,
+}
+
+// Linked path the file watcher started or stopped watching
+#[derive(Debug, Clone)]
+pub enum LinkedPathChange {
+    Added(LinkedPath),
+    Removed(LinkedPath),
+}
+
+// Stops the file watcher when the app exits
+pub struct FileWatcherShutdown {
+    pub tx: mpsc::Sender<()>,
+}
+
+// Request a peer made to a served network, as kept in its access log
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct AccessLogEntry {
+    // Seconds since the Unix epoch
+    pub timestamp: u64,
+    pub network_name: String,
+    // IP address of the peer, or the relay or QUIC listener it came through
+    pub peer: String,
+    // Device ID the peer sent along. Anyone can send any ID, so it only tells who the
+    // peer says it is. Read as `device_id` from logs written before the rename
+    #[serde(alias = "device_id")]
+    pub claimed_device_id: Option<String>,
+    pub method: String,
+    // Decoded request path
+    pub path: String,
+    pub status: u16,
+    pub bytes_sent: u64,
+    pub bytes_received: u64,
+    // Until the last byte of the response was handed over
+    pub duration_ms: u64,
+}
+
+// Entries of an access log to return, newest first. Unset fields match everything
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+#[serde(default)]
+pub struct AccessLogFilter {
+    // Matched against the IP address or claimed device ID of the peer
+    pub peer: Option<String>,
+    // Part of the path
+    pub path: Option<String>,
+    pub status: Option<u16>,
+    // Seconds since the Unix epoch
+    pub since: Option<u64>,
+    pub until: Option<u64>,
+    pub limit: Option<usize>,
+}
+
+// How busy a server group, or all server groups of a network together, has been since
+// it started
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct ServerStatsSnapshot {
+    pub network_name: String,
+    // Not set for the totals of a network
+    pub server_id: Option<u64>,
+    pub uptime_secs: u64,
+    pub requests: u64,
+    // Responses with 4xx and 5xx statuses
+    pub client_errors: u64,
+    pub server_errors: u64,
+    // Share of requests that ended in either kind of error
+    pub error_rate: f64,
+    pub bytes_sent: u64,
+    pub bytes_received: u64,
+    pub active_connections: usize,
+    // Most requested files, most requests first
+    pub top_files: Vec<FileStats>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct FileStats {
+    // Decoded request path
+    pub path: String,
+    pub requests: u64,
+    pub bytes_sent: u64,
+}
+
+// Result of the `get_server_stats` command
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct NetworkServerStats {
+    pub network: ServerStatsSnapshot,
+    pub server_groups: Vec<ServerStatsSnapshot>,
+}
+
+// Entry of a directory listing served by the host
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct FileEntry {
+    pub name: String,
+    pub is_dir: bool,
+    // SHA-256 of the file contents, hex encoded. Not set for directories
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub hash: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub size: Option<u64>,
+}
+
+// File below a directory, as listed recursively by the host and the sync engine
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct IndexedFile {
+    pub path: String,
+    pub hash: String,
+    pub size: u64,
+    // Modification time in seconds since the Unix epoch
+    #[serde(default)]
+    pub modified: u64,
+}
+
+// File or directory recorded in a linked path's file index
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct IndexEntry {
+    // Relative to the linked path, `/`-separated
+    pub path: String,
+    pub is_dir: bool,
+    pub size: u64,
+    // Nanoseconds since the Unix epoch
+    pub modified: u64,
+    pub inode: u64,
+    // Only files have a hash
+    pub hash: Option<String>,
+    // Bumped every time the content of the file changes
+    pub version: u64,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum VersionReason {
+    // The file was replaced by an upload or a sync
+    Overwritten,
+    // The file was deleted, the version is in the trash
+    Deleted,
+    // The file was replaced by restoring an older version
+    Restored,
+}
+
+// Previous content of a file kept in a linked path's version store
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct StoredVersion {
+    pub id: String,
+    // Relative to the linked path, `/`-separated
+    pub path: String,
+    pub hash: String,
+    pub size: u64,
+    // Modification time of the file when it was stored, in seconds since the Unix epoch
+    pub modified: u64,
+    // Seconds since the Unix epoch
+    pub stored_at: u64,
+    pub reason: VersionReason,
+}
+
+// Metadata of a stored version next to the file currently at its path
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct VersionDiff {
+    pub version: StoredVersion,
+    // None if the file no longer exists
+    pub current: Option<IndexedFile>,
+    pub same_content: bool,
+    // Current size minus the stored size
+    pub size_change: i64,
+    // Current modification time minus the stored one, in seconds
+    pub modified_change: i64,
+}
+
+// Query of `/api/search` and the `search_network` command
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct SearchQuery {
+    // Glob matched against whole paths if it contains `*`, `?` or `[`, otherwise a
+    // case-insensitive part of the file name
+    pub q: String,
+    // Also match the content of text files. Devices built without content search reject
+    // the query
+    #[serde(default)]
+    pub content: bool,
+    #[serde(default)]
+    pub limit: Option<usize>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct SearchResult {
+    pub linked_path_name: String,
+    // Relative to the linked path, `/`-separated
+    pub path: String,
+    pub is_dir: bool,
+    pub size: u64,
+    pub hash: Option<String>,
+    // Modification time in seconds since the Unix epoch
+    pub modified: u64,
+    // Relevance of a content match, absent for name matches
+    #[serde(default)]
+    pub score: Option<f32>,
+    // Base URLs of the hosts that have this file, filled in by the searching client
+    #[serde(default)]
+    pub hosts: Vec<String>,
+    // Whether this device has the file
+    #[serde(default)]
+    pub local: bool,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct NetworkSearchResults {
+    pub results: Vec<SearchResult>,
+    // Hosts that could not be searched
+    pub failed_hosts: Vec<String>,
+}
+
+// Content-defined chunk of a file, as listed by the host
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Chunk {
+    pub hash: String,
+    pub offset: u64,
+    pub length: u64,
+}
+
+// Place on this device where a chunk with a given hash was last seen
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct ChunkLocation {
+    pub path: PathBuf,
+    pub offset: u64,
+    pub length: u64,
+}
+
+// Bytes of a resumable upload the host already holds
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct UploadOffset {
+    pub offset: u64,
+}
+
+#[derive(Clone,Serialize, Deserialize, Debug, PartialEq, Eq)]
 pub struct Address {
     pub ip: String,
     pub port: u16,
+    // Name of the interface the address belongs to, if known
+    #[serde(default)]
+    pub interface: Option<String>,
+    #[serde(default)]
+    pub kind: Option<AddressKind>,
+    // Base URL to reach the address at, IPv6 addresses in brackets
+    #[serde(default)]
+    pub url: String,
 }
+
+// What kind of network an address reaches
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum AddressKind {
+    Loopback,
+    LinkLocal,
+    // Private IPv4 range on a physical interface
+    Lan,
+    // Tunnel interface or carrier-grade NAT range as used by overlay VPNs
+    Vpn,
+    // Bridge to containers or virtual machines
+    Virtual,
+    // Globally routable IPv4
+    Public,
+    Ipv6Global,
+    // IPv6 unique local address, fc00::/7
+    Ipv6UniqueLocal,
+}
+
+// Address of a network interface of this device, as listed for picking interfaces
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct InterfaceAddress {
+    pub interface: String,
+    pub ip: String,
+    pub kind: AddressKind,
+}
+
+// One way of reaching a known peer and how it answered the last health check
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct PeerAddress {
+    pub base_url: String,
+    // Network served at the address, None if it was only synced with
+    #[serde(default)]
+    pub network_name: Option<String>,
+    #[serde(default)]
+    pub transport: Transport,
+    // Round trip time of the last health check, None if the peer didn't answer
+    #[serde(default)]
+    pub latency_ms: Option<f64>,
+    // Seconds since the Unix epoch
+    #[serde(default)]
+    pub last_checked: Option<u64>,
+    // Health checks failed in a row
+    #[serde(default)]
+    pub missed_checks: u32,
+}
+
+// Host this device has found or synced with, persisted in the peer address book
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct KnownPeer {
+    pub device_id: String,
+    // Networks the peer was seen advertising
+    #[serde(default)]
+    pub networks: Vec<String>,
+    pub addresses: Vec<PeerAddress>,
+    // Last time the peer was discovered or answered a health check, in seconds since
+    // the Unix epoch
+    #[serde(default)]
+    pub last_seen: Option<u64>,
+}
+
+// Host advertising one of its running networks on the local network
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct DiscoveredPeer {
+    // Full mDNS service name, one per advertised network
+    pub instance: String,
+    pub network_name: String,
+    pub device_id: String,
+    pub port: u16,
+    pub addresses: Vec<Address>,
+    // Base URL for each address, as taken by `get_host_linked_paths`
+    pub base_urls: Vec<String>,
+}
+// Linked path kept in sync with a directory on a host
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct SyncTarget {
+    pub linked_path_name: String,
+    pub base_url: String,
+    // Linked path name on the host optionally followed by `/`-separated subdirectories
+    pub remote_path: String,
+    pub token: String,
+}
+
+// Number of changes to a file made on each device, by device ID
+pub type VersionClock = HashMap<String, u64>;
+
+// Version of a file as seen by the sync engine. Compare versions by `hash`, the other
+// fields differ between devices holding the same content
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct FileVersion {
+    pub hash: String,
+    pub size: u64,
+    // Modification time in seconds since the Unix epoch
+    #[serde(default)]
+    pub modified: u64,
+    // Changes that led to this version, ordering it against other devices' versions
+    #[serde(default)]
+    pub clock: VersionClock,
+}
+
+// Files below a synced directory of a host with their clocks, paths relative to it
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct SyncListing {
+    pub files: HashMap<String, FileVersion>,
+    // Clocks of deleted files, so a deletion can be told apart from a file not yet synced
+    #[serde(default)]
+    pub deleted: HashMap<String, VersionClock>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum ConflictPolicy {
+    // The most recently modified side replaces the other
+    NewestWins,
+    // The local edit is kept next to the remote one as `name.conflict-<device>-<date>.ext`
+    #[default]
+    KeepBoth,
+    // Conflicts wait for `resolve_conflict`
+    Manual,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ConflictResolution {
+    KeepLocal,
+    KeepRemote,
+    KeepBoth,
+}
+
+// File changed on both sides since the last sync pass
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct SyncConflict {
+    pub linked_path_name: String,
+    pub path: String,
+    // None where that side deleted the file
+    pub local: Option<FileVersion>,
+    pub remote: Option<FileVersion>,
+    pub detected_at: u64,
+    // Chosen with `resolve_conflict`, applied on the next sync pass
+    #[serde(default)]
+    pub resolution: Option<ConflictResolution>,
+}
+
+// Persisted per synced linked path so syncing resumes where it left off after a restart
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct SyncState {
+    pub target: SyncTarget,
+    // Last version both sides agreed on
+    #[serde(default)]
+    pub files: HashMap<String, FileVersion>,
+    // Clocks of files both sides agreed to delete
+    #[serde(default)]
+    pub deleted: HashMap<String, VersionClock>,
+    #[serde(default)]
+    pub conflict_policy: ConflictPolicy,
+    #[serde(default)]
+    pub conflicts: HashMap<String, SyncConflict>,
+    #[serde(default)]
+    pub last_synced: Option<u64>,
+    #[serde(default)]
+    pub last_error: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct SyncStatus {
+    pub target: SyncTarget,
+    pub running: bool,
+    pub tracked_files: usize,
+    pub conflict_policy: ConflictPolicy,
+    pub conflicts: usize,
+    pub last_synced: Option<u64>,
+    pub last_error: Option<String>,
+}
+
+#[derive(Clone)]
+pub struct SyncSession {
+    pub state: Arc<Mutex<SyncState>>,
+    // Resolutions chosen with `resolve_conflict`, by path. Kept apart from the state,
+    // which a running sync pass holds, and moved into it before the next pass
+    pub resolutions: Arc<std::sync::Mutex<HashMap<String, ConflictResolution>>>,
+    // Runs a sync pass right away instead of waiting for the next change or poll
+    pub sync_now: Arc<Notify>,
+    pub tx: mpsc::Sender<()>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct DeviceInfo {
+    pub device_id: String,
+}
+
+// Running sync sessions by linked path name
+pub type SyncSessionMap = Arc<RwLock<HashMap<String, SyncSession>>>;
+
 #[derive(Clone)]
 pub struct ServerIdState {
     pub server_id_counter: Arc<RwLock<u64>>,
@@ -114,6 +735,70 @@ pub enum FileWatcherError {
     RecvError(#[source] Box<dyn std::error::Error + Send>),
 }
 
+#[derive(Debug, thiserror::Error)]
+pub enum FileIndexError {
+    #[error("File index database error: {0}")]
+    Database(#[from] rusqlite::Error),
+    #[error("Failed to read linked path: {0}")]
+    Io(#[from] std::io::Error),
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum VersionStoreError {
+    #[error("Failed to access the version store: {0}")]
+    Io(#[from] std::io::Error),
+    #[error("Invalid version manifest: {0}")]
+    Manifest(#[from] serde_json::Error),
+    #[error("No version '{0}'")]
+    NotFound(String),
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum PeerDiscoveryError {
+    #[error("mDNS error: {0}")]
+    Mdns(#[from] mdns_sd::Error),
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum RelayError {
+    #[error("Invalid relay settings: {0}")]
+    Settings(String),
+    #[error("Relay connection failed: {0}")]
+    WebSocket(#[from] tokio_tungstenite::tungstenite::Error),
+    #[error("Relay handshake failed: {0}")]
+    Handshake(String),
+    #[error("Relay closed the connection")]
+    Closed,
+    #[error("Direct connection failed: {0}")]
+    Quic(String),
+    #[error("I/O error: {0}")]
+    Io(#[from] std::io::Error),
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum CryptoError {
+    #[error("Invalid network key: {0}")]
+    Key(String),
+    #[error("No key with ID {0}, it may have been shared before a rotation")]
+    UnknownKey(u32),
+    #[error("Decryption failed, the data was changed or encrypted with another key")]
+    Decrypt,
+    #[error("Not encrypted by Topaz: {0}")]
+    Format(String),
+    #[error("I/O error: {0}")]
+    Io(#[from] std::io::Error),
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum QuicError {
+    #[error("Failed to set up QUIC: {0}")]
+    Setup(String),
+    #[error("QUIC connection failed: {0}")]
+    Connection(String),
+    #[error("I/O error: {0}")]
+    Io(#[from] std::io::Error),
+}
+
 #[derive(Debug, thiserror::Error)]
 pub enum MeasureLatencyError {
     #[error("Failed to execute command: {0}")]
@@ -130,6 +815,12 @@ pub enum MeasureLatencyError {
 
     #[error("Error occurred: {0}")]
     Other(String),
+
+    #[error("Health check request failed: {0}")]
+    RequestError(#[from] reqwest::Error),
+
+    #[error("Host answered as device {0}")]
+    DeviceMismatch(String),
 }
 
 #[derive(Debug, thiserror::Error)]
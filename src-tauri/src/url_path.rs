@@ -0,0 +1,98 @@
+use percent_encoding::{percent_decode_str, utf8_percent_encode, AsciiSet, CONTROLS};
+use std::path::{Component, Path, PathBuf};
+
+// Same set the `url` crate uses for a single path segment, so routes built on the host
+// match the URLs the client produces with `Url::path_segments_mut().push()`
+const PATH_SEGMENT: &AsciiSet = &CONTROLS
+    .add(b' ')
+    .add(b'"')
+    .add(b'#')
+    .add(b'<')
+    .add(b'>')
+    .add(b'`')
+    .add(b'?')
+    .add(b'{')
+    .add(b'}')
+    .add(b'/')
+    .add(b'%');
+
+// Percent-encode a single file or directory name for use in a URL path
+pub fn encode_path_segment(name: &str) -> String {
+    utf8_percent_encode(name, PATH_SEGMENT).to_string()
+}
+
+pub fn decode_path_segment(segment: &str) -> Option<String> {
+    percent_decode_str(segment)
+        .decode_utf8()
+        .ok()
+        .map(|decoded| decoded.into_owned())
+}
+
+// Check that a name received from a peer is a single plain path component
+pub fn is_safe_file_name(name: &str) -> bool {
+    let mut components = Path::new(name).components();
+    matches!(
+        (components.next(), components.next()),
+        (Some(Component::Normal(_)), None)
+    ) && !name.contains(['/', '\\'])
+}
+
+// Decode a request path into a relative filesystem path, rejecting anything that
+// would escape the served directory
+pub fn decode_relative_path(url_path: &str) -> Option<PathBuf> {
+    let mut relative_path = PathBuf::new();
+    for segment in url_path.split('/').filter(|segment| !segment.is_empty()) {
+        let decoded = decode_path_segment(segment)?;
+        if !is_safe_file_name(&decoded) {
+            return None;
+        }
+        relative_path.push(decoded);
+    }
+    Some(relative_path)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const NAMES: &[&str] = &[
+        "plain.txt",
+        "with space.txt",
+        "hash#tag",
+        "question?mark",
+        "100%",
+        "a+b",
+        "naïve café ✓",
+        "trailing.",
+    ];
+
+    #[test]
+    fn path_segments_round_trip() {
+        for name in NAMES {
+            let encoded = encode_path_segment(name);
+            assert!(!encoded.contains([' ', '#', '?', '/']), "{} encoded as {}", name, encoded);
+            assert_eq!(decode_path_segment(&encoded).as_deref(), Some(*name));
+        }
+        // A plus is a literal plus in a path, not a space
+        assert_eq!(decode_path_segment("a+b").as_deref(), Some("a+b"));
+    }
+
+    #[test]
+    fn relative_paths_decode_segment_by_segment() {
+        let url_path = NAMES.iter().map(|name| encode_path_segment(name)).collect::<Vec<_>>().join("/");
+        let expected: PathBuf = NAMES.iter().collect();
+        assert_eq!(decode_relative_path(&url_path), Some(expected));
+    }
+
+    #[test]
+    fn unsafe_names_are_rejected() {
+        for name in ["", ".", "..", "a/b", "/", "a\\b", "\\"] {
+            assert!(!is_safe_file_name(name), "{:?} accepted", name);
+        }
+        assert!(is_safe_file_name("trailing."));
+
+        for url_path in ["..", "a/../b", "%2E%2E", "a/%2e%2e/b", "a%2Fb", "%2F", "a%5Cb", "%5C"] {
+            assert_eq!(decode_relative_path(url_path), None, "{:?} accepted", url_path);
+        }
+    }
+}
@@ -0,0 +1,46 @@
+use crate::types::VersionClock;
+
+// Header carrying the version vector of a file a syncing device pushes or deletes, as JSON
+pub const CLOCK_HEADER: &str = "x-topaz-clock";
+
+// Whether `newer` includes every change recorded in `older`
+pub fn clock_descends(newer: &VersionClock, older: &VersionClock) -> bool {
+    older
+        .iter()
+        .all(|(device, count)| newer.get(device).copied().unwrap_or(0) >= *count)
+}
+
+// Record one more change made on `device`
+pub fn bump_clock(clock: &VersionClock, device: &str) -> VersionClock {
+    let mut clock = clock.clone();
+    *clock.entry(device.to_string()).or_insert(0) += 1;
+    clock
+}
+
+// Clock including the changes of both
+pub fn merge_clocks(a: &VersionClock, b: &VersionClock) -> VersionClock {
+    let mut clock = a.clone();
+    for (device, count) in b {
+        let entry = clock.entry(device.clone()).or_insert(0);
+        *entry = (*entry).max(*count);
+    }
+    clock
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn concurrent_clocks_descend_from_neither() {
+        let base = bump_clock(&VersionClock::new(), "a");
+        let left = bump_clock(&base, "b");
+        let right = bump_clock(&base, "c");
+        assert!(clock_descends(&left, &base));
+        assert!(!clock_descends(&base, &left));
+        assert!(!clock_descends(&left, &right));
+        assert!(!clock_descends(&right, &left));
+        let merged = merge_clocks(&left, &right);
+        assert!(clock_descends(&merged, &left) && clock_descends(&merged, &right));
+    }
+}
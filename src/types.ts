@@ -1,10 +1,76 @@
 interface LinkedPath {
     name: string
     path: string
+    writable?: boolean
+    quota_bytes?: number | null
+    ignore_patterns?: string[]
+    version_retention?: VersionRetention
+}
+// Limits set to null don't apply
+interface VersionRetention {
+    max_versions?: number | null
+    max_age_days?: number | null
+    max_bytes?: number | null
 }
 interface BaseNetwork {
     name: string
     linked_paths: LinkedPath[]
+    // Only sent when creating a network, networks read back carry its hash instead
+    upload_token?: string | null
+    upload_token_hash?: string | null
+    // Interface names to serve on, all of them if empty
+    interfaces?: string[]
+    // Relay to serve through in 'Relay' and 'Internet' mode
+    relay?: RelaySettings | null
+    // Keys `push_to_host` encrypts with, see `rotate_network_key`. Linked paths of
+    // networks with keys can't be synced
+    encryption?: NetworkEncryption | null
+    // On top of the device-wide limits, see `set_network_bandwidth_limits`
+    bandwidth_limits?: BandwidthLimits | null
+}
+// Bytes per second, null for no limit. Per-peer limits apply to each peer on its own
+interface BandwidthLimits {
+    upload?: number | null
+    download?: number | null
+    upload_per_peer?: number | null
+    download_per_peer?: number | null
+    // The first window that matches replaces all of the limits above
+    schedule?: ScheduledLimits[]
+}
+// `start` and `end` are local `HH:MM` times, `days` the weekdays a window starts on
+// with 0 for Monday
+interface ScheduledLimits {
+    start: string
+    end: string
+    days?: number[]
+    upload?: number | null
+    download?: number | null
+    upload_per_peer?: number | null
+    download_per_peer?: number | null
+}
+// The last key encrypts, older ones still decrypt. Shared with the devices of the
+// network through `import_network_keys`
+interface NetworkEncryption {
+    keys: NetworkKey[]
+}
+// Host storing a network's files encrypted, passed to `rotate_network_key` to rewrite
+// them under the new key. `remote_path` as given to `push_to_host`
+interface EncryptedStore {
+    base_url: string
+    remote_path: string
+    token: string
+}
+// `key` is 32 hex-encoded bytes
+interface NetworkKey {
+    id: number
+    key: string
+}
+// `secret` is 32 hex-encoded bytes, see `generate_relay_secret`
+interface RelaySettings {
+    url: string
+    secret: string
+    // `host:port` of a rendezvous service for direct connections in 'Internet' mode
+    rendezvous?: string | null
 }
 
 interface LocalNetwork extends BaseNetwork {
@@ -27,13 +93,186 @@ interface LocalServerResponse {
 // Union of network types
 type Network = LocalNetwork | InternetNetwork | DarkWebNetwork
 
-type ServerMode = 'LocalHost' | 'Internet' | 'DarkWeb'
+type ServerMode = 'LocalHost' | 'Internet' | 'DarkWeb' | 'Relay'
+// QUIC servers are reached at `quic://` base URLs, which every client command accepts
+type Transport = 'Tcp' | 'Quic'
 
 interface ServerGroup {
     id: number
     addresses: Address[]
+    transport?: Transport
+    limits?: ServerLimits
+}
+// Passed to `start_file_server_command`. Unset limits take safe defaults, null ones
+// don't apply. Per-peer limits and bans don't apply to peers behind a relay
+interface ServerLimits {
+    request_timeout_secs?: number | null
+    idle_timeout_secs?: number | null
+    max_connections?: number | null
+    max_connections_per_peer?: number | null
+    requests_per_minute_per_peer?: number | null
+    max_auth_failures?: number | null
+    ban_secs?: number
 }
 interface Address {
     ip: string
     port: number
+    interface?: string | null
+    kind?: AddressKind | null
+    url: string
+}
+
+type AddressKind = 'Loopback' | 'LinkLocal' | 'Lan' | 'Vpn' | 'Virtual' | 'Public' | 'Ipv6Global' | 'Ipv6UniqueLocal'
+
+// Returned by `list_interfaces`
+interface InterfaceAddress {
+    interface: string
+    ip: string
+    kind: AddressKind
+}
+
+// Payload of the `peer_appeared` and `peer_disappeared` events
+interface DiscoveredPeer {
+    instance: string
+    network_name: string
+    device_id: string
+    port: number
+    addresses: Address[]
+    base_urls: string[]
+}
+
+type FileChangeKind = 'Created' | 'Modified' | 'Removed' | 'Renamed'
+
+// Payload of the `linked_path_file_changed` event
+interface FileChangeEvent {
+    linked_path_name: string
+    kind: FileChangeKind
+    path: string
+    relative_path: string
+    previous_path?: string | null
+}
+
+interface SearchResult {
+    linked_path_name: string
+    path: string
+    is_dir: boolean
+    size: number
+    hash?: string | null
+    // Seconds since the Unix epoch
+    modified: number
+    // Content match relevance, missing for name-only matches
+    score?: number | null
+    // Hosts holding this version of the file
+    hosts: string[]
+    local: boolean
+}
+
+// Result of the `search_network` command
+interface NetworkSearchResults {
+    results: SearchResult[]
+    failed_hosts: string[]
+}
+
+type VersionReason = 'Overwritten' | 'Deleted' | 'Restored'
+
+interface StoredVersion {
+    id: string
+    path: string
+    hash: string
+    size: number
+    modified: number
+    stored_at: number
+    reason: VersionReason
+}
+
+interface IndexedFile {
+    path: string
+    hash: string
+    size: number
+    modified: number
+}
+
+// Result of the `diff_file_version` command
+interface VersionDiff {
+    version: StoredVersion
+    current?: IndexedFile | null
+    same_content: boolean
+    size_change: number
+    modified_change: number
+}
+
+interface PeerAddress {
+    base_url: string
+    // Network served at the address, missing if it was only synced with
+    network_name?: string | null
+    transport?: Transport
+    // Round trip time of the last health check, missing if the peer didn't answer
+    latency_ms?: number | null
+    last_checked?: number | null
+    // Health checks failed in a row, the address is forgotten after 60
+    missed_checks?: number
+}
+
+// Entry of the peer address book returned by `get_peers`
+interface KnownPeer {
+    device_id: string
+    networks: string[]
+    // Fastest first
+    addresses: PeerAddress[]
+    last_seen?: number | null
+}
+
+// Entry of a network's access log, returned by `get_access_log`
+interface AccessLogEntry {
+    // Seconds since the Unix epoch
+    timestamp: number
+    network_name: string
+    peer: string
+    // Device ID the peer says it has. Anyone can send any ID, it is not verified
+    claimed_device_id?: string | null
+    method: string
+    path: string
+    status: number
+    bytes_sent: number
+    bytes_received: number
+    duration_ms: number
+}
+
+// Unset fields match everything. `peer` matches an IP address or claimed device ID
+interface AccessLogFilter {
+    peer?: string | null
+    path?: string | null
+    status?: number | null
+    since?: number | null
+    until?: number | null
+    limit?: number | null
+}
+
+// How busy a server group, or all server groups of a network together, has been
+interface ServerStatsSnapshot {
+    network_name: string
+    // Not set for the totals of a network
+    server_id?: number | null
+    uptime_secs: number
+    requests: number
+    client_errors: number
+    server_errors: number
+    error_rate: number
+    bytes_sent: number
+    bytes_received: number
+    active_connections: number
+    // Most requests first
+    top_files: FileStats[]
+}
+
+interface FileStats {
+    path: string
+    requests: number
+    bytes_sent: number
+}
+
+// Returned by `get_server_stats`
+interface NetworkServerStats {
+    network: ServerStatsSnapshot
+    server_groups: ServerStatsSnapshot[]
 }